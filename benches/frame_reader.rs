@@ -0,0 +1,70 @@
+//! Measures receiving messages over a loopback TCP connection: one at a time across a range of
+//! sizes, and bursts of small ones sent back to back so several arrive in each read.
+//! Run with `cargo bench --bench frame_reader`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pirates::{InternalTransport, TcpTransport};
+
+fn connected_pair(runtime: &tokio::runtime::Runtime) -> (TcpTransport, TcpTransport) {
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) =
+            tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+        let (client, (accepted, _from)) = (client.unwrap(), accepted.unwrap());
+        // Otherwise bursts of small messages wait on Nagle's algorithm rather than the reader
+        client.set_nodelay(true).unwrap();
+        (TcpTransport::new(client), TcpTransport::new(accepted))
+    })
+}
+
+fn bench_receive(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (mut sender, mut receiver) = connected_pair(&runtime);
+    let mut group = c.benchmark_group("receive");
+    for size in [64, 4 * 1024, 64 * 1024, 1024 * 1024, 16 * 1024 * 1024] {
+        let message = vec![7u8; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let (sent, received) =
+                        tokio::join!(sender.send(message), receiver.receive(None));
+                    sent.unwrap();
+                    received.unwrap()
+                })
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("receive_burst");
+    let message = vec![7u8; 64];
+    for burst in [10, 1000] {
+        group.throughput(Throughput::Elements(burst));
+        group.bench_with_input(BenchmarkId::from_parameter(burst), &burst, |b, &burst| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let send = async {
+                        for _ in 0..burst {
+                            sender.send(&message).await.unwrap();
+                        }
+                    };
+                    let receive = async {
+                        for _ in 0..burst {
+                            receiver.receive(None).await.unwrap();
+                        }
+                    };
+                    tokio::join!(send, receive)
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_receive);
+criterion_main!(benches);
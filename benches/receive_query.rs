@@ -0,0 +1,121 @@
+//! Measures receiving a query over a loopback TCP connection across a range of sizes, on the
+//! owned path, which decodes the package into owned buffers, and the borrowed path, which
+//! decodes it in place from the received buffer, copying only the query bytes out.
+//! Both use postcard, wrapped in [BenchPostcard] so borrowing can be turned off, leaving the
+//! copies saved as the only difference.
+//! Run with `cargo bench --bench receive_query --features transport_postcard`
+
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pirates::{
+    Bytes, DeserializeSeed, OwnedBytes, RpcName, TcpTransport, Transport, TransportConfig,
+    TransportError, TransportWireConfig, WireCodec,
+};
+use serde::{Deserialize, Serialize};
+
+/// Postcard, with [borrow] choosing which path received queries are decoded on
+#[derive(Debug)]
+struct BenchPostcard {
+    borrow: bool,
+}
+
+impl WireCodec for BenchPostcard {
+    fn name(&self) -> &'static str {
+        if self.borrow {
+            "borrowed"
+        } else {
+            "owned"
+        }
+    }
+
+    fn serialize(&self, val: &dyn erased_serde::Serialize) -> Result<OwnedBytes, TransportError> {
+        postcard::to_allocvec(val).map_err(|e| TransportError::SerialiseError(format!("{}", e)))
+    }
+
+    fn deserialize<'a>(
+        &self,
+        bytes: Bytes<'a>,
+        seed: &mut DeserializeSeed<'_, 'a>,
+    ) -> Result<(), TransportError> {
+        let mut deserializer = postcard::Deserializer::from_bytes(bytes);
+        seed(&mut <dyn erased_serde::Deserializer>::erase(
+            &mut deserializer,
+        ))
+    }
+
+    fn supports_borrowed_bytes(&self) -> bool {
+        self.borrow
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Serialize, Deserialize, Clone, Debug)]
+enum BenchRpcName {
+    Upload,
+}
+
+impl Display for BenchRpcName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl RpcName for BenchRpcName {}
+
+type BenchTransport = Transport<TcpTransport, BenchRpcName>;
+
+fn connected_pair(
+    runtime: &tokio::runtime::Runtime,
+    wire_config: TransportWireConfig,
+) -> (BenchTransport, BenchTransport) {
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) =
+            tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+        let (client, (accepted, _from)) = (client.unwrap(), accepted.unwrap());
+        client.set_nodelay(true).unwrap();
+        let config = TransportConfig {
+            wire_config,
+            ..Default::default()
+        };
+        (
+            Transport::new(TcpTransport::new(client), config.clone()),
+            Transport::new(TcpTransport::new(accepted), config),
+        )
+    })
+}
+
+fn bench_receive_query(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("receive_query");
+    for borrow in [false, true] {
+        let wire_config = TransportWireConfig::Custom(Arc::new(BenchPostcard { borrow }));
+        let (mut sender, mut receiver) = connected_pair(&runtime, wire_config.clone());
+        for size in [64, 4 * 1024, 32 * 1024, 64 * 1024, 1024 * 1024] {
+            let query = vec![7u8; size];
+            group.throughput(Throughput::Bytes(size as u64));
+            let id = BenchmarkId::new(wire_config.wire_config_name(), size);
+            group.bench_with_input(id, &query, |b, query| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        let (sent, received) = tokio::join!(
+                            sender.send_notification(query, &BenchRpcName::Upload),
+                            receiver.receive_query()
+                        );
+                        sent.unwrap();
+                        received.unwrap()
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_receive_query);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+//! Compares how long each wire format takes to serialise and deserialise a typical query.
+//! Run with `cargo bench --features transport_postcard,transport_bincode`, adding any other
+//! transport_* codec features to compare those too
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use pirates::TransportWireConfig;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+struct Order {
+    id: u64,
+    customer: String,
+    items: Vec<Item>,
+    notes: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Item {
+    sku: String,
+    quantity: u32,
+    price_pennies: i64,
+}
+
+fn order(num_items: usize) -> Order {
+    Order {
+        id: 1_234_567,
+        customer: String::from("Gaspode the wonder dog"),
+        items: (0..num_items)
+            .map(|i| Item {
+                sku: format!("SKU-{:06}", i),
+                quantity: i as u32 % 7 + 1,
+                price_pennies: 1999 + i as i64,
+            })
+            .collect(),
+        notes: Some(String::from("Leave it with the parrot")),
+    }
+}
+
+fn wire_configs() -> Vec<TransportWireConfig> {
+    vec![
+        TransportWireConfig::pickle(),
+        TransportWireConfig::Postcard,
+        TransportWireConfig::Bincode,
+        #[cfg(feature = "transport_msgpack")]
+        TransportWireConfig::MessagePack,
+        #[cfg(feature = "transport_cbor")]
+        TransportWireConfig::Cbor,
+        #[cfg(feature = "transport_json")]
+        TransportWireConfig::Json,
+    ]
+}
+
+fn bench_wire_formats(c: &mut Criterion) {
+    for num_items in [1, 100] {
+        let order = order(num_items);
+        let mut serialize = c.benchmark_group(format!("serialize_{}_items", num_items));
+        for wire_config in wire_configs() {
+            serialize.bench_with_input(
+                BenchmarkId::from_parameter(wire_config.wire_config_name()),
+                &wire_config,
+                |b, wire_config| b.iter(|| wire_config.serialize(black_box(&order)).unwrap()),
+            );
+        }
+        serialize.finish();
+
+        let mut deserialize = c.benchmark_group(format!("deserialize_{}_items", num_items));
+        for wire_config in wire_configs() {
+            let bytes = wire_config.serialize(&order).unwrap();
+            deserialize.bench_with_input(
+                BenchmarkId::from_parameter(wire_config.wire_config_name()),
+                &wire_config,
+                |b, wire_config| {
+                    b.iter(|| {
+                        let order: Order = wire_config.deserialize(black_box(&bytes)).unwrap();
+                        order
+                    })
+                },
+            );
+        }
+        deserialize.finish();
+    }
+}
+
+criterion_group!(benches, bench_wire_formats);
+criterion_main!(benches);
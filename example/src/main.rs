@@ -1,5 +1,5 @@
 use clap::{arg, value_parser};
-use pirates::{call_client, RpcDefinition, RpcName, RpcServer, TransportConfig};
+use pirates::{call_client, RpcDefinition, RpcName, RpcServer, ServerConfig, TransportConfig};
 use serde::{Deserialize, Serialize};
 use std::fmt::Formatter;
 use std::sync::{Arc, Mutex};
@@ -66,7 +66,12 @@ async fn server(addr: &str) {
     let state = ServerState { names: Vec::new() };
     let state_ref = Arc::new(Mutex::new(state));
     let transport_config = TransportConfig::default();
-    let mut server = RpcServer::new(state_ref, transport_config);
+    // Lets pirates-cli list the rpcs served
+    let server_config = ServerConfig {
+        reflection: true,
+        ..Default::default()
+    };
+    let mut server = RpcServer::with_config(state_ref, transport_config, server_config);
     server.add_rpc(Box::new(rpcs::AddName::server()));
     server.add_rpc(Box::new(rpcs::GetNames::server()));
     println!("Serving on {}!", addr);
@@ -116,7 +121,7 @@ mod rpcs {
     }
 
     pub struct GetNames {}
-    #[pirates::rpc_definition]
+    #[pirates::rpc_definition(idempotent)]
     impl GetNames {
         fn name() -> RpcId {
             RpcId::GetNames
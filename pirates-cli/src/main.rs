@@ -0,0 +1,183 @@
+//! Calls rpcs on any pirates server without a bespoke client, for debugging deployed services.
+//! Queries are written as JSON, converted to the server's wire format, and responses printed
+//! back as JSON:
+//!
+//! ```text
+//! pirates-cli --addr 127.0.0.1:5858 list
+//! pirates-cli --addr 127.0.0.1:5858 call AddName '"Gaspode the wonder dog"'
+//! pirates-cli --addr 127.0.0.1:5858 call GetNames
+//! ```
+//!
+//! Listing needs the server to have `ServerConfig::reflection` on. Rpc names are sent as
+//! strings, which is how pickle and JSON encode unit enum variants, the usual [pirates::RpcName].
+//! For formats that don't, have client and server agree on `NameEncoding::Utf8String`
+use std::fmt::Formatter;
+use std::process::ExitCode;
+
+use clap::{arg, value_parser};
+use pirates::error::RpcResult;
+use pirates::{
+    connect_tcp_transport, NameEncoding, Rpc, RpcClient, RpcName, TransportConfig,
+    TransportWireConfig,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Any rpc's name, taken from the command line
+#[derive(PartialEq, Eq, Hash, Serialize, Deserialize, Clone, Debug)]
+#[serde(transparent)]
+struct CliRpcName(String);
+
+impl std::fmt::Display for CliRpcName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl RpcName for CliRpcName {
+    fn from_utf8_name(name: &str) -> Option<Self> {
+        Some(Self(String::from(name)))
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cmd = clap::Command::new("pirates-cli")
+        .about("Make ad-hoc calls to a pirates server")
+        .subcommand_required(true)
+        .arg(
+            arg!(-a --addr <ADDR> "address of the server")
+                .required(true)
+                .value_parser(value_parser!(String)),
+        )
+        .arg(
+            arg!(-w --wire <WIRE> "wire format the server speaks")
+                .value_parser(["pickle", "json"])
+                .default_value("pickle"),
+        )
+        .arg(arg!(--"utf8-names" "send rpc names as UTF-8 strings, see NameEncoding::Utf8String"))
+        .arg(
+            arg!(-t --token <TOKEN> "bearer token to authenticate with")
+                .value_parser(value_parser!(String)),
+        )
+        .subcommand(clap::Command::new("list").about("List the rpcs the server serves"))
+        .subcommand(
+            clap::Command::new("call")
+                .about("Call an rpc and print its response")
+                .arg(
+                    arg!(<RPC> "name of the rpc to call")
+                        .required(true)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!([QUERY] "query as JSON, null if not given")
+                        .value_parser(value_parser!(String)),
+                ),
+        )
+        .get_matches();
+
+    let addr = cmd.get_one::<String>("addr").unwrap();
+    let mut config = TransportConfig {
+        wire_config: match cmd.get_one::<String>("wire").unwrap().as_str() {
+            "json" => TransportWireConfig::Json,
+            _ => TransportWireConfig::default(),
+        },
+        ..Default::default()
+    };
+    if cmd.get_flag("utf8-names") {
+        config.name_encoding = NameEncoding::Utf8String;
+    }
+    if let Some(token) = cmd.get_one::<String>("token") {
+        config = config.with_bearer_token(token);
+    }
+    let result = match cmd.subcommand() {
+        Some(("list", _)) => list(addr, config).await,
+        Some(("call", sub_match)) => {
+            let rpc_name = sub_match.get_one::<String>("RPC").unwrap().clone();
+            let query = sub_match
+                .get_one::<String>("QUERY")
+                .map(String::as_str)
+                .unwrap_or("null");
+            match serde_json::from_str(query) {
+                Ok(query) => call(addr, config, rpc_name, query).await.map(|response| {
+                    println!("{}", serde_json::to_string_pretty(&response).unwrap())
+                }),
+                Err(e) => {
+                    eprintln!("Query isn't valid JSON: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => Ok(()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn list(addr: &str, config: TransportConfig) -> RpcResult<()> {
+    let mut transport = connect_tcp_transport::<CliRpcName>(addr, config).await?;
+    let description = transport.reflect().await?;
+    println!("Wire format: {}", description.wire_config);
+    for rpc in description.rpcs {
+        match rpc.types {
+            Some(types) => println!(
+                "{} ({:?}): {} -> {}  [{}]",
+                rpc.name, rpc.kind, types.query, types.response, types.schema_hash
+            ),
+            None => println!("{} ({:?})", rpc.name, rpc.kind),
+        }
+    }
+    Ok(())
+}
+
+async fn call(
+    addr: &str,
+    config: TransportConfig,
+    rpc_name: String,
+    query: Value,
+) -> RpcResult<Value> {
+    let mut transport = connect_tcp_transport(addr, config).await?;
+    let rpc: Rpc<CliRpcName, Value, Value> = Rpc::new(CliRpcName(rpc_name));
+    RpcClient::new(rpc).call(query, &mut transport).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pirates::{RpcImpl, RpcServer, TcpListener};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn json_query_converted_to_servers_wire_format() {
+        let mut server = RpcServer::new(Arc::new(Mutex::new(())), TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new(
+            CliRpcName(String::from("Double")),
+            Box::new(|_state: &mut (), n: u32| Ok(n * 2)),
+        )));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let calls = async {
+            let config = TransportConfig::default();
+            let doubled = call(
+                &addr,
+                config.clone(),
+                String::from("Double"),
+                Value::from(21),
+            )
+            .await;
+            let unknown = call(&addr, config, String::from("Halve"), Value::from(21)).await;
+            shutdown.shutdown();
+            (doubled, unknown)
+        };
+
+        let ((), (doubled, unknown)) = tokio::join!(server.serve_listener(listener), calls);
+        assert_eq!(Value::from(42), doubled.unwrap());
+        assert!(unknown.is_err());
+    }
+}
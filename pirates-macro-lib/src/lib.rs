@@ -1,6 +1,11 @@
 use proc_macro::TokenStream;
+use proc_macro2::Span;
 use quote::quote;
-use syn::{parse_macro_input, AttributeArgs, ImplItem, ImplItemMethod, ItemImpl, ReturnType, Type};
+use syn::{
+    parse_macro_input, AttributeArgs, Data, DeriveInput, Fields, FnArg, Ident, ImplItem,
+    ImplItemMethod, ItemImpl, ItemTrait, Lit, Meta, NestedMeta, Pat, ReturnType, TraitItem, Type,
+    Variant,
+};
 
 /*
 The macro takes:
@@ -24,16 +29,13 @@ and generates an extra impl block:
     }
 */
 
-fn find_fn_by_name<'a, 'b>(name: &'b str, items: &'a Vec<ImplItem>) -> Option<&'a ImplItemMethod> {
+fn find_fn_by_name<'a>(name: &str, items: &'a Vec<ImplItem>) -> Option<&'a ImplItemMethod> {
     for item in items {
-        match item {
-            ImplItem::Method(impl_item_method) => {
-                if impl_item_method.sig.ident == name {
-                    return Some(impl_item_method);
-                }
+        if let ImplItem::Method(impl_item_method) = item {
+            if impl_item_method.sig.ident == name {
+                return Some(impl_item_method);
             }
-            _ => (),
-        };
+        }
     }
     None
 }
@@ -54,7 +56,7 @@ fn unpack_rpcresult_type(in_: &Type) -> &Type {
                 if let syn::GenericArgument::Type(ty) =
                     angle_bracketed_generic_arguments.args.first().unwrap()
                 {
-                    &ty
+                    ty
                 } else {
                     panic!("Angle bracketed arg is not type")
                 }
@@ -68,7 +70,16 @@ fn unpack_rpcresult_type(in_: &Type) -> &Type {
 
 #[proc_macro_attribute]
 pub fn rpc_definition(args: TokenStream, item: TokenStream) -> TokenStream {
-    let _args = parse_macro_input!(args as AttributeArgs);
+    // #[rpc_definition(idempotent)] marks the client's rpc as safe to retry
+    let args = parse_macro_input!(args as AttributeArgs);
+    let idempotent = args.iter().any(|arg| {
+        matches!(arg, syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("idempotent"))
+    });
+    let mark_idempotent = if idempotent {
+        quote! { .idempotent() }
+    } else {
+        quote! {}
+    };
     let mut output_tokens = item.clone();
     eprintln!("Original Tokens:\n\n{:?}\n.\n.\n", item);
     let item = parse_macro_input!(item as ItemImpl);
@@ -120,7 +131,7 @@ pub fn rpc_definition(args: TokenStream, item: TokenStream) -> TokenStream {
     let new_block: TokenStream = quote! {
         impl pirates::RpcDefinition<#ty_name, #ty_state, #ty_query, #ty_response> for #ty_rpc_impl {
             fn client() -> pirates::Rpc<#ty_name, #ty_query, #ty_response> {
-                pirates::Rpc::new(Self::name())
+                pirates::Rpc::new(Self::name())#mark_idempotent
             }
 
             fn server() -> pirates::RpcImpl<#ty_name, #ty_state, #ty_query, #ty_response> {
@@ -133,3 +144,327 @@ pub fn rpc_definition(args: TokenStream, item: TokenStream) -> TokenStream {
     output_tokens.extend(new_block);
     output_tokens
 }
+
+/*
+The derive takes an enum of unit variants:
+
+    #[derive(Clone, Hash, PartialEq, Eq, RpcName)]
+    enum NAME {
+        A,
+        #[rpc_name(rename = "b")]
+        B,
+    }
+
+and generates Display, serde Serialize and Deserialize, and RpcName impls, all using each
+variant's id: its name unless renamed. Opcodes are the variants' positions.
+*/
+
+/// The id [variant] is sent and displayed as, from `#[rpc_name(rename = "...")]` if given
+fn variant_id(variant: &Variant) -> String {
+    for attr in &variant.attrs {
+        if !attr.path.is_ident("rpc_name") {
+            continue;
+        }
+        let nested = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list.nested,
+            _ => panic!("Expected #[rpc_name(rename = \"...\")]"),
+        };
+        match nested.first() {
+            Some(NestedMeta::Meta(Meta::NameValue(name_value)))
+                if nested.len() == 1 && name_value.path.is_ident("rename") =>
+            {
+                match &name_value.lit {
+                    Lit::Str(id) => return id.value(),
+                    _ => panic!("rpc_name rename must be a string"),
+                }
+            }
+            _ => panic!("Unknown rpc_name attribute, only rename is supported"),
+        }
+    }
+    variant.ident.to_string()
+}
+
+#[proc_macro_derive(RpcName, attributes(rpc_name))]
+pub fn derive_rpc_name(item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as DeriveInput);
+    let ty_name = &item.ident;
+    let variants = match &item.data {
+        Data::Enum(data_enum) => &data_enum.variants,
+        _ => panic!("RpcName can only be derived for enums"),
+    };
+    for variant in variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            panic!("RpcName variant {} must not have fields", variant.ident);
+        }
+    }
+    let idents: Vec<_> = variants.iter().map(|variant| &variant.ident).collect();
+    let ids: Vec<_> = variants.iter().map(variant_id).collect();
+    let opcodes: Vec<_> = (0..variants.len())
+        .map(|i| u16::try_from(i).expect("Too many variants for u16 opcodes"))
+        .collect();
+
+    quote! {
+        impl std::fmt::Display for #ty_name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(match self {
+                    #(Self::#idents => #ids,)*
+                })
+            }
+        }
+
+        impl pirates::serde::Serialize for #ty_name {
+            fn serialize<S: pirates::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.to_string())
+            }
+        }
+
+        impl<'de> pirates::serde::Deserialize<'de> for #ty_name {
+            fn deserialize<D: pirates::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let id = <std::string::String as pirates::serde::Deserialize>::deserialize(deserializer)?;
+                <Self as pirates::RpcName>::from_utf8_name(&id).ok_or_else(|| {
+                    <D::Error as pirates::serde::de::Error>::unknown_variant(&id, &[#(#ids),*])
+                })
+            }
+        }
+
+        impl pirates::RpcName for #ty_name {
+            fn opcode(&self) -> Option<u16> {
+                Some(match self {
+                    #(Self::#idents => #opcodes,)*
+                })
+            }
+            fn from_opcode(opcode: u16) -> Option<Self> {
+                match opcode {
+                    #(#opcodes => Some(Self::#idents),)*
+                    _ => None,
+                }
+            }
+            fn from_utf8_name(name: &str) -> Option<Self> {
+                match name {
+                    #(#ids => Some(Self::#idents),)*
+                    _ => None,
+                }
+            }
+        }
+    }
+    .into()
+}
+
+/*
+The service macro takes a trait of sync methods, each taking &mut self and any number of query
+arguments and returning RpcResult<RESPONSE>:
+
+    #[pirates::service]
+    pub trait NAME {
+        fn METHOD(&mut self, ARG: ARG_TYPE, ...) -> RpcResult<RESPONSE>;
+    }
+
+and alongside it generates:
+
+    #[derive(RpcName)]
+    pub enum NAMERpcName { METHOD, ... }
+    impl NAMERpcName {
+        pub fn METHOD() -> Rpc<NAMERpcName, QUERY, RESPONSE> { ... }
+        pub fn register<S: NAME + 'static>(server: &mut RpcServer<S, NAMERpcName>) { ... }
+    }
+    pub struct NAMEClient<I> { ... }
+    impl<I: InternalTransport> NAMEClient<I> {
+        pub async fn METHOD(&mut self, ARG: ARG_TYPE, ...) -> RpcResult<RESPONSE> { ... }
+    }
+
+where QUERY is () with no arguments, ARG_TYPE with one, or a tuple of them with more
+*/
+
+fn camel_case(snake_case: &str) -> String {
+    snake_case
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// An rpc of the service, from one of its trait's methods
+struct ServiceRpc<'a> {
+    method: &'a syn::TraitItemMethod,
+    variant: Ident,
+    arg_names: Vec<&'a Ident>,
+    arg_types: Vec<&'a Type>,
+    ty_response: &'a Type,
+}
+
+impl<'a> ServiceRpc<'a> {
+    /// Fails for an async method, as a compile error pointing at it
+    fn of_method(method: &'a syn::TraitItemMethod) -> syn::Result<Self> {
+        let sig = &method.sig;
+        if let Some(asyncness) = &sig.asyncness {
+            return Err(syn::Error::new_spanned(
+                asyncness,
+                format!(
+                    "Service method {} must not be async: it's called with the server state \
+                     locked. The generated client's methods are async",
+                    sig.ident
+                ),
+            ));
+        }
+        let mut inputs = sig.inputs.iter();
+        match inputs.next() {
+            Some(FnArg::Receiver(receiver))
+                if receiver.reference.is_some() && receiver.mutability.is_some() => {}
+            _ => panic!("Service method {} must take &mut self", sig.ident),
+        }
+        let (arg_names, arg_types) = inputs
+            .map(|input| match input {
+                FnArg::Typed(pat_type) => match pat_type.pat.as_ref() {
+                    Pat::Ident(pat_ident) => (&pat_ident.ident, pat_type.ty.as_ref()),
+                    _ => panic!("Service method {} arguments must be named", sig.ident),
+                },
+                FnArg::Receiver(_) => panic!("Unexpected self argument"),
+            })
+            .unzip();
+        let ty_response = match &sig.output {
+            ReturnType::Default => panic!("Output must be a type"),
+            ReturnType::Type(_, ty) => unpack_rpcresult_type(ty),
+        };
+        Ok(Self {
+            method,
+            variant: Ident::new(&camel_case(&sig.ident.to_string()), sig.ident.span()),
+            arg_names,
+            arg_types,
+            ty_response,
+        })
+    }
+
+    fn ty_query(&self) -> proc_macro2::TokenStream {
+        let arg_types = &self.arg_types;
+        match arg_types.as_slice() {
+            [ty] => quote!(#ty),
+            _ => quote!((#(#arg_types),*)),
+        }
+    }
+
+    fn query(&self) -> proc_macro2::TokenStream {
+        let arg_names = &self.arg_names;
+        match arg_names.as_slice() {
+            [name] => quote!(#name),
+            _ => quote!((#(#arg_names),*)),
+        }
+    }
+}
+
+#[proc_macro_attribute]
+pub fn service(args: TokenStream, item: TokenStream) -> TokenStream {
+    let _args = parse_macro_input!(args as AttributeArgs);
+    let item = parse_macro_input!(item as ItemTrait);
+    let vis = &item.vis;
+    let ty_service = &item.ident;
+    let ty_name = Ident::new(&format!("{}RpcName", ty_service), Span::call_site());
+    let ty_client = Ident::new(&format!("{}Client", ty_service), Span::call_site());
+    let rpcs: syn::Result<Vec<_>> = item
+        .items
+        .iter()
+        .map(|trait_item| match trait_item {
+            TraitItem::Method(method) => ServiceRpc::of_method(method),
+            _ => panic!("Service traits may only contain methods"),
+        })
+        .collect();
+    let rpcs = match rpcs {
+        Ok(rpcs) => rpcs,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let variants = rpcs.iter().map(|rpc| {
+        let variant = &rpc.variant;
+        let id = rpc.method.sig.ident.to_string();
+        quote! {
+            #[rpc_name(rename = #id)]
+            #variant
+        }
+    });
+    let rpc_fns = rpcs.iter().map(|rpc| {
+        let fn_name = &rpc.method.sig.ident;
+        let variant = &rpc.variant;
+        let ty_query = rpc.ty_query();
+        let ty_response = rpc.ty_response;
+        quote! {
+            #vis fn #fn_name() -> pirates::Rpc<Self, #ty_query, #ty_response> {
+                pirates::Rpc::new(Self::#variant)
+            }
+        }
+    });
+    let registrations = rpcs.iter().map(|rpc| {
+        let fn_name = &rpc.method.sig.ident;
+        let variant = &rpc.variant;
+        let ty_query = rpc.ty_query();
+        let query = rpc.query();
+        let arg_names = &rpc.arg_names;
+        quote! {
+            server.add_rpc(std::boxed::Box::new(pirates::RpcImpl::new(
+                Self::#variant,
+                std::boxed::Box::new(|state: &mut S, #query: #ty_query| state.#fn_name(#(#arg_names),*)),
+            )));
+        }
+    });
+    let client_fns = rpcs.iter().map(|rpc| {
+        let attrs = &rpc.method.attrs;
+        let fn_name = &rpc.method.sig.ident;
+        let output = &rpc.method.sig.output;
+        let arg_names = &rpc.arg_names;
+        let arg_types = &rpc.arg_types;
+        let query = rpc.query();
+        quote! {
+            #(#attrs)*
+            #vis async fn #fn_name(&mut self, #(#arg_names: #arg_types),*) #output {
+                pirates::RpcClient::new(#ty_name::#fn_name())
+                    .call(#query, &mut self.transport)
+                    .await
+            }
+        }
+    });
+    let name_doc = format!("Names of the [{}] rpcs", ty_service);
+    let register_doc = format!(
+        "Add every [{}] rpc to [server], answered by its state's impl of the trait",
+        ty_service
+    );
+    let client_doc = format!(
+        "Calls the [{}] rpcs over a [pirates::Transport]",
+        ty_service
+    );
+
+    quote! {
+        #item
+
+        #[doc = #name_doc]
+        #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug, pirates::RpcName)]
+        #vis enum #ty_name {
+            #(#variants,)*
+        }
+
+        impl #ty_name {
+            #(#rpc_fns)*
+
+            #[doc = #register_doc]
+            #vis fn register<S: #ty_service + 'static>(server: &mut pirates::RpcServer<S, Self>) {
+                #(#registrations)*
+            }
+        }
+
+        #[doc = #client_doc]
+        #vis struct #ty_client<I> {
+            pub transport: pirates::Transport<I, #ty_name>,
+        }
+
+        impl<I: pirates::InternalTransport> #ty_client<I> {
+            #vis fn new(transport: pirates::Transport<I, #ty_name>) -> Self {
+                Self { transport }
+            }
+
+            #(#client_fns)*
+        }
+    }
+    .into()
+}
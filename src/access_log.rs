@@ -0,0 +1,288 @@
+use std::fmt::Formatter;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::Level;
+
+use crate::clock::{Clock, SystemClock};
+use crate::core::RpcName;
+use crate::error::{ErrorCode, RpcError, RpcResult};
+use crate::metadata::Metadata;
+use crate::middleware::{Interceptor, Next};
+use crate::transport::ReceivedQuery;
+use crate::OwnedBytes;
+
+/// The [log] target access log lines are written under, so they can be routed apart from the
+/// rest of the server's logging
+pub const ACCESS_LOG_TARGET: &str = "pirates::access";
+
+/// One completed call, as written by [AccessLog]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessLogEntry {
+    pub rpc_name: String,
+    pub peer_addr: Option<SocketAddr>,
+    pub connection_id: u64,
+    /// Who called, if a [crate::Authentication] interceptor said
+    pub subject: Option<String>,
+    pub duration: Duration,
+    /// The error the call failed with, and its message, if it did
+    pub error: Option<(ErrorCode, String)>,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    /// The values of the metadata keys asked for with [AccessLog::metadata_field]
+    pub metadata: Metadata,
+}
+
+/// Written as space separated `key=value` pairs, quoting values with spaces in
+impl std::fmt::Display for AccessLogEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write_field(f, "rpc", &self.rpc_name)?;
+        if let Some(peer_addr) = &self.peer_addr {
+            f.write_str(" ")?;
+            write_field(f, "peer", &format!("{}", peer_addr))?;
+        }
+        write!(f, " connection={}", self.connection_id)?;
+        if let Some(subject) = &self.subject {
+            f.write_str(" ")?;
+            write_field(f, "subject", subject)?;
+        }
+        write!(f, " duration={:?}", self.duration)?;
+        match &self.error {
+            None => f.write_str(" result=ok")?,
+            Some((code, message)) => {
+                write!(f, " result=error code={:?} ", code)?;
+                write_field(f, "error", message)?;
+            }
+        }
+        write!(
+            f,
+            " bytes_in={} bytes_out={}",
+            self.bytes_in, self.bytes_out
+        )?;
+        for (key, value) in &self.metadata {
+            f.write_str(" ")?;
+            write_field(f, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_field(f: &mut Formatter<'_>, key: &str, value: &str) -> std::fmt::Result {
+    if value.is_empty() || value.contains([' ', '"', '=']) {
+        write!(f, "{}={:?}", key, value)
+    } else {
+        write!(f, "{}={}", key, value)
+    }
+}
+
+type Redact = Box<dyn Fn(&mut AccessLogEntry)>;
+type Sink = Box<dyn Fn(&AccessLogEntry)>;
+
+/// Server [Interceptor] writing one line per completed call to [log], under
+/// [ACCESS_LOG_TARGET], for audit logs without an interceptor of your own. Unary calls are
+/// logged once answered, and queries an interceptor refused once refused, though stream rpcs
+/// aren't logged. Add it with [crate::RpcServer::layer] after any [crate::Authentication], so
+/// the caller is known, and ahead of the rest, so its durations cover them.
+/// Anything sensitive can be scrubbed from entries first with [redact]
+pub struct AccessLog {
+    level: Level,
+    metadata_fields: Vec<String>,
+    redact: Option<Redact>,
+    sink: Option<Sink>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AccessLog {
+    pub fn new() -> Self {
+        Self {
+            level: Level::Info,
+            metadata_fields: Vec::new(),
+            redact: None,
+            sink: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Log at [level] rather than info
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Include the value the caller sent for [key] in its [Metadata], e.g. a tenant id
+    pub fn metadata_field(mut self, key: impl Into<String>) -> Self {
+        self.metadata_fields.push(key.into());
+        self
+    }
+
+    /// Change each entry before it's written, e.g. to mask the peer address or drop error
+    /// messages that might carry personal data
+    pub fn redact(mut self, redact: impl Fn(&mut AccessLogEntry) + 'static) -> Self {
+        self.redact = Some(Box::new(redact));
+        self
+    }
+
+    /// Hand entries to [sink] rather than [log], e.g. to write them to an audit file
+    pub fn with_sink(mut self, sink: impl Fn(&AccessLogEntry) + 'static) -> Self {
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn write<Name: RpcName>(
+        &self,
+        query: &ReceivedQuery<Name>,
+        duration: Duration,
+        result: Result<usize, &RpcError>,
+    ) {
+        let metadata = self
+            .metadata_fields
+            .iter()
+            .filter_map(|key| Some((key.clone(), query.metadata.get(key)?.clone())))
+            .collect();
+        let mut entry = AccessLogEntry {
+            rpc_name: format!("{}", query.name),
+            peer_addr: query.peer_addr,
+            connection_id: query.connection_id,
+            subject: query
+                .identity
+                .as_ref()
+                .map(|identity| identity.subject.clone()),
+            duration,
+            error: result
+                .as_ref()
+                .err()
+                .map(|error| (error.code(), format!("{}", error))),
+            bytes_in: query.query_bytes.len(),
+            bytes_out: *result.as_ref().unwrap_or(&0),
+            metadata,
+        };
+        if let Some(redact) = &self.redact {
+            redact(&mut entry);
+        }
+        match &self.sink {
+            Some(sink) => sink(&entry),
+            None => log::log!(target: ACCESS_LOG_TARGET, self.level, "{}", entry),
+        }
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait(?Send)]
+impl<Name: RpcName> Interceptor<Name> for AccessLog {
+    fn on_refused(&self, query: &ReceivedQuery<Name>, error: &RpcError) {
+        self.write(query, Duration::ZERO, Err(error));
+    }
+
+    async fn around(
+        &self,
+        query: &ReceivedQuery<Name>,
+        next: Next<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        let started = self.clock.now();
+        let result = next.run(query).await;
+        let duration = self.clock.now().duration_since(started);
+        self.write(query, duration, result.as_ref().map(Vec::len));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Authentication, Identity};
+    use crate::client::RpcClient;
+    use crate::clock::MockClock;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+
+    #[test]
+    fn entry_written_as_key_values() {
+        let entry = AccessLogEntry {
+            rpc_name: String::from("HelloWorld"),
+            peer_addr: Some("127.0.0.1:5555".parse().unwrap()),
+            connection_id: 7,
+            subject: Some(String::from("jack")),
+            duration: Duration::from_millis(3),
+            error: Some((ErrorCode::Internal, String::from("Out of rum"))),
+            bytes_in: 12,
+            bytes_out: 0,
+            metadata: Metadata::from([(String::from("tenant"), String::from("ankh"))]),
+        };
+        assert_eq!(
+            "rpc=HelloWorld peer=127.0.0.1:5555 connection=7 subject=jack duration=3ms \
+             result=error code=Internal error=\"Out of rum\" bytes_in=12 bytes_out=0 tenant=ankh",
+            format!("{}", entry)
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_and_refusals_logged() {
+        let entries = Rc::new(RefCell::new(Vec::new()));
+        let sink_entries = entries.clone();
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.layer(Authentication::new(|token: &str| {
+            (token == "doubloon").then(|| Identity::new("jack"))
+        }));
+        server.layer(
+            AccessLog::new()
+                .metadata_field("tenant")
+                .redact(|entry| entry.subject = entry.subject.as_ref().map(|_| "***".into()))
+                .with_sink(move |entry| sink_entries.borrow_mut().push(entry.clone()))
+                .with_clock(Arc::new(MockClock::new())),
+        );
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut config = TransportConfig::default().with_bearer_token("doubloon");
+            config
+                .metadata
+                .insert(String::from("tenant"), String::from("ankh"));
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            let hello = RpcClient::new(make_hello_world_rpc());
+            let authenticated = hello.call("Foo".into(), &mut transport).await;
+            transport.config = TransportConfig::default();
+            let refused = hello.call("Foo".into(), &mut transport).await;
+            (authenticated, refused)
+        };
+
+        let ((), (authenticated, refused)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        authenticated.unwrap();
+        assert!(matches!(refused, Err(RpcError::Unauthenticated(_))));
+        let entries = entries.borrow();
+        assert_eq!(2, entries.len());
+        let hello = &entries[0];
+        assert_eq!("HelloWorld", hello.rpc_name);
+        assert_eq!(Some(String::from("***")), hello.subject);
+        assert_eq!(None, hello.error);
+        assert!(hello.bytes_in > 0 && hello.bytes_out > 0);
+        assert_eq!(Some(&String::from("ankh")), hello.metadata.get("tenant"));
+        let refused = &entries[1];
+        assert_eq!(None, refused.subject);
+        assert!(matches!(
+            refused.error,
+            Some((ErrorCode::Unauthenticated, _))
+        ));
+        assert!(refused.metadata.is_empty());
+        assert_eq!(HelloWorldRpcName::HelloWorld.to_string(), refused.rpc_name);
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::call_context::read_call_context;
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::metadata::Metadata;
+use crate::middleware::{ClientInterceptor, ClientNext, Interceptor, OutgoingQuery, ServerRequest};
+use crate::transport::{ReceivedQuery, TransportConfig};
+use crate::OwnedBytes;
+
+/// The [crate::Metadata] key the bearer token is sent under, as `Bearer <token>`
+pub const AUTHORIZATION: &str = "authorization";
+
+const BEARER: &str = "Bearer ";
+
+/// Who the caller of an rpc is, as worked out from their token by an [Authenticator]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub subject: String,
+    pub roles: BTreeSet<String>,
+}
+
+impl Identity {
+    pub fn new(subject: impl Into<String>) -> Self {
+        Self {
+            subject: subject.into(),
+            roles: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_role(mut self, role: impl Into<String>) -> Self {
+        self.roles.insert(role.into());
+        self
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.contains(role)
+    }
+}
+
+/// Checks the bearer token sent with a query, giving who it belongs to or None if it isn't
+/// valid. Any `Fn(&str) -> Option<Identity>` will do for simple cases like a fixed set of tokens
+pub trait Authenticator {
+    fn authenticate(&self, token: &str) -> Option<Identity>;
+}
+
+impl<F: Fn(&str) -> Option<Identity>> Authenticator for F {
+    fn authenticate(&self, token: &str) -> Option<Identity> {
+        self(token)
+    }
+}
+
+/// Server [Interceptor] refusing queries without a bearer token its [Authenticator] accepts,
+/// with [RpcError::Unauthenticated], before they reach any handler. Add it with
+/// [crate::RpcServer::layer] ahead of interceptors that rely on [ReceivedQuery::identity].
+/// Handlers can read the caller's identity with [call_identity]
+pub struct Authentication<A> {
+    authenticator: A,
+}
+
+impl<A: Authenticator> Authentication<A> {
+    pub fn new(authenticator: A) -> Self {
+        Self { authenticator }
+    }
+
+    /// Who the bearer token in [metadata] belongs to
+    fn identify(&self, metadata: &Metadata) -> RpcResult<Identity> {
+        let token = metadata
+            .get(AUTHORIZATION)
+            .and_then(|authorization| authorization.strip_prefix(BEARER))
+            .ok_or_else(|| RpcError::Unauthenticated(String::from("no bearer token sent")))?;
+        self.authenticator
+            .authenticate(token)
+            .ok_or_else(|| RpcError::Unauthenticated(String::from("bearer token refused")))
+    }
+}
+
+/// Requests the server answers itself need a bearer token too, see [ServerRequest], which
+/// clients send from their [TransportConfig::metadata], e.g. with
+/// [TransportConfig::with_bearer_token]
+#[async_trait(?Send)]
+impl<Name: RpcName, A: Authenticator> Interceptor<Name> for Authentication<A> {
+    async fn on_query(&self, query: &mut ReceivedQuery<Name>) -> RpcResult<()> {
+        query.identity = Some(self.identify(&query.metadata)?);
+        Ok(())
+    }
+
+    async fn on_server_request(&self, request: &mut ServerRequest) -> RpcResult<()> {
+        request.identity = Some(self.identify(&request.metadata)?);
+        Ok(())
+    }
+}
+
+/// Decides whether a caller may call an rpc, once [Authentication] has found who they are.
+/// Any `Fn(&Identity, &Name) -> bool` will do, or see [RolePolicy] for the common case
+pub trait Authorizer<Name: RpcName> {
+    fn authorize(&self, identity: &Identity, rpc_name: &Name) -> bool;
+}
+
+impl<Name: RpcName, F: Fn(&Identity, &Name) -> bool> Authorizer<Name> for F {
+    fn authorize(&self, identity: &Identity, rpc_name: &Name) -> bool {
+        self(identity, rpc_name)
+    }
+}
+
+/// An [Authorizer] requiring a role for some rpcs, e.g. an "admin" role for admin-only ones.
+/// Rpcs without a required role can be called by anyone authenticated
+pub struct RolePolicy<Name: RpcName> {
+    required_roles: HashMap<Name, String>,
+}
+
+impl<Name: RpcName> RolePolicy<Name> {
+    pub fn new() -> Self {
+        Self {
+            required_roles: HashMap::new(),
+        }
+    }
+
+    /// Only let callers with [role] call [rpc_name]
+    pub fn require(mut self, rpc_name: Name, role: impl Into<String>) -> Self {
+        self.required_roles.insert(rpc_name, role.into());
+        self
+    }
+}
+
+impl<Name: RpcName> Default for RolePolicy<Name> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Name: RpcName> Authorizer<Name> for RolePolicy<Name> {
+    fn authorize(&self, identity: &Identity, rpc_name: &Name) -> bool {
+        match self.required_roles.get(rpc_name) {
+            Some(role) => identity.has_role(role),
+            None => true,
+        }
+    }
+}
+
+/// Server [Interceptor] refusing queries its [Authorizer] doesn't allow, with
+/// [RpcError::PermissionDenied], before they reach any handler. It must be added with
+/// [crate::RpcServer::layer] after [Authentication], which sets the identity it checks
+pub struct Authorization<Z> {
+    authorizer: Z,
+}
+
+impl<Z> Authorization<Z> {
+    pub fn new(authorizer: Z) -> Self {
+        Self { authorizer }
+    }
+}
+
+#[async_trait(?Send)]
+impl<Name: RpcName, Z: Authorizer<Name>> Interceptor<Name> for Authorization<Z> {
+    async fn on_query(&self, query: &mut ReceivedQuery<Name>) -> RpcResult<()> {
+        let identity = query.identity.as_ref().ok_or_else(|| {
+            RpcError::Unauthenticated(String::from("caller's identity not known"))
+        })?;
+        if self.authorizer.authorize(identity, &query.name) {
+            Ok(())
+        } else {
+            Err(RpcError::PermissionDenied(format!(
+                "{} may not call {}",
+                identity.subject, query.name
+            )))
+        }
+    }
+}
+
+/// Client [ClientInterceptor] sending a bearer token with every query, fetched per call, e.g.
+/// to pick up a refreshed token. For a fixed token, [TransportConfig::with_bearer_token] does
+/// the same without an interceptor
+pub struct BearerToken {
+    token: Arc<dyn Fn() -> String + Send + Sync>,
+}
+
+impl BearerToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        let token = token.into();
+        Self::from_fn(move || token.clone())
+    }
+
+    pub fn from_fn(token: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        Self {
+            token: Arc::new(token),
+        }
+    }
+}
+
+#[async_trait]
+impl<Name: RpcName> ClientInterceptor<Name> for BearerToken {
+    async fn around(
+        &self,
+        mut query: OutgoingQuery<Name>,
+        mut next: ClientNext<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        let authorization = format!("{}{}", BEARER, (self.token)());
+        query
+            .metadata
+            .insert(String::from(AUTHORIZATION), authorization);
+        next.run(query).await
+    }
+}
+
+impl TransportConfig {
+    /// Send [token] as the bearer token with every query, see [Authentication]
+    pub fn with_bearer_token(mut self, token: &str) -> Self {
+        self.metadata
+            .insert(String::from(AUTHORIZATION), format!("{}{}", BEARER, token));
+        self
+    }
+}
+
+/// Who the caller of the rpc being handled is, if the server has [Authentication], i.e. the
+/// [crate::CallContext::identity] of [crate::call_context]
+pub fn call_identity() -> Option<Identity> {
+    read_call_context(|context| context.identity.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, make_hello_world_rpc_impl,
+        HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{channel_listener, Transport};
+    use std::sync::Mutex;
+
+    fn authenticate(token: &str) -> Option<Identity> {
+        match token {
+            "doubloon" => Some(Identity::new("jack").with_role("captain")),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bearer_token_authenticated() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        // Answers with who the caller is
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, _query: ()| Ok(call_identity().map(|identity| identity.subject))),
+        )));
+        server.layer(Authentication::new(authenticate));
+        let (connector, listener) = channel_listener(3);
+        let client_calls = async move {
+            let whoami = RpcClient::new(Rpc::<_, (), Option<String>>::new(
+                HelloWorldRpcName::HelloWorld,
+            ));
+            let config = TransportConfig::default().with_bearer_token("doubloon");
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            let with_config_token = whoami.call((), &mut transport).await;
+
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let without_token = whoami.call((), &mut transport).await;
+            transport.layer(BearerToken::from_fn(|| String::from("piece of eight")));
+            let with_bad_token = whoami.call((), &mut transport).await;
+            (with_config_token, without_token, with_bad_token)
+        };
+
+        let ((), (with_config_token, without_token, with_bad_token)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(Some(String::from("jack")), with_config_token.unwrap());
+        assert!(matches!(without_token, Err(RpcError::Unauthenticated(_))));
+        assert!(matches!(with_bad_token, Err(RpcError::Unauthenticated(_))));
+        assert_eq!(None, call_identity());
+    }
+
+    #[tokio::test]
+    async fn admin_only_rpc_authorized() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.layer(Authentication::new(|token: &str| match token {
+            "doubloon" => Some(Identity::new("jack").with_role("captain")),
+            "biscuit" => Some(Identity::new("gibbs")),
+            _ => None,
+        }));
+        server.layer(Authorization::new(
+            RolePolicy::new().require(HelloWorldRpcName::GetI, "captain"),
+        ));
+        let (connector, listener) = channel_listener(2);
+        let client_calls = async move {
+            let config = TransportConfig::default().with_bearer_token("biscuit");
+            let mut crew = Transport::new(connector.connect().await.unwrap(), config);
+            let crew_hello = RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut crew)
+                .await;
+            let crew_get_i = RpcClient::new(make_get_i_rpc()).call((), &mut crew).await;
+
+            let config = TransportConfig::default().with_bearer_token("doubloon");
+            let mut captain = Transport::new(connector.connect().await.unwrap(), config);
+            let captain_get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut captain)
+                .await;
+            (crew_hello, crew_get_i, captain_get_i)
+        };
+
+        let ((), (crew_hello, crew_get_i, captain_get_i)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!("Hello world: 3:\"Foo\"", crew_hello.unwrap());
+        match crew_get_i {
+            Err(RpcError::PermissionDenied(s)) => assert_eq!("gibbs may not call GetI", s),
+            other => panic!("Expected GetI to be denied, got {:?}", other),
+        }
+        assert_eq!(3, captain_get_i.unwrap());
+    }
+}
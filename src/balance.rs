@@ -0,0 +1,573 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::discovery::{Resolver, StaticResolver};
+use crate::error::{RpcError, RpcResult};
+use crate::pool::{leaves_connection_unusable, ClientPool};
+use crate::transport::{TransportConfig, TransportError};
+use log::{debug, info, warn};
+
+/// How a [BalancedClient] picks the endpoint for each call
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Each endpoint in turn
+    #[default]
+    RoundRobin,
+    /// Whichever endpoint has the fewest calls in flight, so slower servers are given fewer
+    LeastOutstanding,
+}
+
+/// BalanceConfig defines how a [BalancedClient] spreads calls over its endpoints
+/// [max_connections] is the most connections kept to each endpoint, as for [ClientPool]
+/// An endpoint failing [failures_to_evict] calls in a row, by failing to connect or with a
+/// transport error or timeout, is evicted: left out for [eviction_period], after which it's
+/// given calls again, and evicted again by one more failure. Should every endpoint be evicted,
+/// calls go to the one due back soonest rather than failing outright
+/// [resolve_interval] is how often the endpoints are looked up again, for a client made
+/// [BalancedClient::with_resolver]
+/// [hedge_after], if set, hedges calls to idempotent rpcs: one not answered within it is sent
+/// again to another endpoint, and whichever answers first is taken, the other being given up
+/// on and its connection dropped. This cuts the tail latency of a slow server at the cost of
+/// some extra load, so set it around the latency calls rarely exceed, e.g. the 95th percentile
+#[derive(Clone, Debug)]
+pub struct BalanceConfig {
+    pub strategy: BalanceStrategy,
+    pub max_connections: usize,
+    pub failures_to_evict: usize,
+    pub eviction_period: Duration,
+    pub resolve_interval: Duration,
+    pub hedge_after: Option<Duration>,
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        Self {
+            strategy: BalanceStrategy::default(),
+            max_connections: 8,
+            failures_to_evict: 3,
+            eviction_period: Duration::from_secs(10),
+            resolve_interval: Duration::from_secs(30),
+            hedge_after: None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct EndpointHealth {
+    consecutive_failures: usize,
+    evicted_until: Option<Instant>,
+}
+
+struct Endpoint<Name: RpcName> {
+    addr: String,
+    clients: ClientPool<Name>,
+    outstanding: AtomicUsize,
+    health: Mutex<EndpointHealth>,
+}
+
+impl<Name: RpcName + 'static> Endpoint<Name> {
+    fn new(addr: &str, config: &BalanceConfig, transport_config: &TransportConfig) -> Self {
+        Self {
+            addr: addr.to_string(),
+            clients: ClientPool::new(addr, config.max_connections, transport_config.clone()),
+            outstanding: AtomicUsize::new(0),
+            health: Mutex::new(EndpointHealth::default()),
+        }
+    }
+
+    fn evicted_until(&self, now: Instant) -> Option<Instant> {
+        self.health
+            .lock()
+            .unwrap()
+            .evicted_until
+            .filter(|until| *until > now)
+    }
+}
+
+/// Counts a call in flight on an endpoint until dropped, even if the call's given up on
+struct Outstanding<'a>(&'a AtomicUsize);
+
+impl<'a> Outstanding<'a> {
+    fn new(outstanding: &'a AtomicUsize) -> Self {
+        outstanding.fetch_add(1, Ordering::Relaxed);
+        Self(outstanding)
+    }
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A [BalancedClient] makes rpc calls to any of several servers serving the same rpcs, e.g.
+/// replicas of a horizontally scaled service, spreading them per [BalanceConfig]. Each
+/// endpoint has a [ClientPool] of its own, connected lazily.
+/// A call that fails to connect is tried on the other endpoints in turn, as the server can't
+/// have seen the query, while any other failure is returned, as for [ClientPool]. Calls to
+/// idempotent rpcs can be hedged, see [BalanceConfig::hedge_after].
+/// The endpoints can be looked up with a [Resolver], see [with_resolver], and an endpoint
+/// that's gone from those resolved is dropped along with its connections
+pub struct BalancedClient<Name: RpcName> {
+    endpoints: Mutex<Vec<Arc<Endpoint<Name>>>>,
+    resolver: Box<dyn Resolver>,
+    resolved_at: tokio::sync::Mutex<Option<Instant>>,
+    config: BalanceConfig,
+    transport_config: TransportConfig,
+    clock: Arc<dyn Clock>,
+    next: AtomicUsize,
+}
+
+impl<Name: RpcName + 'static> BalancedClient<Name> {
+    /// Calls to the servers at [addrs], which don't change
+    pub fn new(addrs: &[&str], config: BalanceConfig, transport_config: TransportConfig) -> Self {
+        let endpoints = addrs
+            .iter()
+            .map(|addr| Arc::new(Endpoint::new(addr, &config, &transport_config)))
+            .collect();
+        let mut client = Self::with_resolver(StaticResolver::new(addrs), config, transport_config);
+        client.endpoints = Mutex::new(endpoints);
+        client
+    }
+
+    /// Calls to the servers [resolver] gives, looked up on the first call, then again every
+    /// [BalanceConfig::resolve_interval]
+    pub fn with_resolver(
+        resolver: impl Resolver + 'static,
+        config: BalanceConfig,
+        transport_config: TransportConfig,
+    ) -> Self {
+        Self {
+            endpoints: Mutex::new(Vec::new()),
+            resolver: Box::new(resolver),
+            resolved_at: tokio::sync::Mutex::new(None),
+            config,
+            clock: transport_config.clock.clone(),
+            transport_config,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn call<Q: RpcType, R: RpcType>(
+        &self,
+        query: Q,
+        rpc: Rpc<Name, Q, R>,
+    ) -> RpcResult<R> {
+        self.refresh_if_due().await;
+        let endpoints = self.endpoints.lock().unwrap().clone();
+        // Shared by both attempts of a hedged call, so neither goes to the other's endpoint
+        let tried = Mutex::new(Vec::new());
+        let hedge_after = match self.config.hedge_after {
+            Some(hedge_after) if rpc.idempotent && endpoints.len() > 1 => hedge_after,
+            _ => return self.call_any(&endpoints, &tried, &query, &rpc).await,
+        };
+        let mut first = std::pin::pin!(self.call_any(&endpoints, &tried, &query, &rpc));
+        if let Ok(result) = tokio::time::timeout(hedge_after, &mut first).await {
+            return result;
+        }
+        debug!("{} not answered in {:?}, hedging", rpc.name, hedge_after);
+        let mut second = std::pin::pin!(self.call_any(&endpoints, &tried, &query, &rpc));
+        // The first to answer is taken, unless it failed and the other might not
+        tokio::select! {
+            result = &mut first => match result {
+                Err(_) => second.await,
+                result => result,
+            },
+            result = &mut second => match result {
+                Err(_) => first.await,
+                result => result,
+            },
+        }
+    }
+
+    /// Call an endpoint of [endpoints] other than those [tried], trying others while they fail
+    /// to connect
+    async fn call_any<Q: RpcType, R: RpcType>(
+        &self,
+        endpoints: &[Arc<Endpoint<Name>>],
+        tried: &Mutex<Vec<String>>,
+        query: &Q,
+        rpc: &Rpc<Name, Q, R>,
+    ) -> RpcResult<R> {
+        loop {
+            let endpoint = {
+                let mut tried = tried.lock().unwrap();
+                let endpoint = self.pick(endpoints, &tried);
+                tried.extend(endpoint.iter().map(|endpoint| endpoint.addr.clone()));
+                endpoint
+            };
+            let endpoint = endpoint.ok_or_else(|| {
+                RpcError::TransportError(TransportError::ConnectError(String::from(
+                    "No endpoints to call",
+                )))
+            })?;
+            let result = {
+                let _outstanding = Outstanding::new(&endpoint.outstanding);
+                endpoint.clients.call(query.clone(), rpc.clone()).await
+            };
+            match &result {
+                Err(e) if leaves_connection_unusable(e) => self.failed(&endpoint, e),
+                _ => self.succeeded(&endpoint),
+            }
+            match result {
+                Err(RpcError::TransportError(TransportError::ConnectError(e)))
+                    if tried.lock().unwrap().len() < endpoints.len() =>
+                {
+                    warn!(
+                        "Couldn't connect to {}, trying another: {}",
+                        endpoint.addr, e
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Look up the endpoints again now, rather than waiting for
+    /// [BalanceConfig::resolve_interval] to pass. Endpoints still there keep their
+    /// connections and health
+    pub async fn refresh(&self) -> Result<(), TransportError> {
+        let mut resolved_at = self.resolved_at.lock().await;
+        self.resolve(&mut resolved_at).await
+    }
+
+    async fn refresh_if_due(&self) {
+        let mut resolved_at = match self.resolved_at.try_lock() {
+            Ok(resolved_at) => resolved_at,
+            // Another call's resolving, which there's no need to wait for with endpoints to call
+            Err(_) if !self.endpoints.lock().unwrap().is_empty() => return,
+            Err(_) => self.resolved_at.lock().await,
+        };
+        let due = resolved_at.is_none_or(|resolved_at| {
+            self.clock.now() >= resolved_at + self.config.resolve_interval
+        });
+        if due {
+            if let Err(e) = self.resolve(&mut resolved_at).await {
+                warn!(
+                    "Resolving endpoints failed, calling those last known: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    async fn resolve(&self, resolved_at: &mut Option<Instant>) -> Result<(), TransportError> {
+        // Even on failure, so a failing resolver isn't tried on every call
+        *resolved_at = Some(self.clock.now());
+        let addrs = self.resolver.resolve().await?;
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let resolved: Vec<_> = addrs
+            .iter()
+            .map(
+                |addr| match endpoints.iter().find(|endpoint| &endpoint.addr == addr) {
+                    Some(endpoint) => endpoint.clone(),
+                    None => {
+                        info!("Endpoint {} added", addr);
+                        Arc::new(Endpoint::new(addr, &self.config, &self.transport_config))
+                    }
+                },
+            )
+            .collect();
+        for endpoint in endpoints.iter() {
+            if !addrs.contains(&endpoint.addr) {
+                info!("Endpoint {} removed", endpoint.addr);
+            }
+        }
+        *endpoints = resolved;
+        Ok(())
+    }
+
+    /// Addresses of the endpoints not evicted
+    pub fn healthy_endpoints(&self) -> Vec<String> {
+        let now = self.clock.now();
+        self.endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|endpoint| endpoint.evicted_until(now).is_none())
+            .map(|endpoint| endpoint.addr.clone())
+            .collect()
+    }
+
+    /// The endpoint of [endpoints] for the next call, other than those [tried]
+    fn pick(
+        &self,
+        endpoints: &[Arc<Endpoint<Name>>],
+        tried: &[String],
+    ) -> Option<Arc<Endpoint<Name>>> {
+        let now = self.clock.now();
+        let untried = || {
+            endpoints
+                .iter()
+                .filter(|endpoint| !tried.contains(&endpoint.addr))
+        };
+        let available: Vec<_> = untried()
+            .filter(|endpoint| endpoint.evicted_until(now).is_none())
+            .collect();
+        if available.is_empty() {
+            return untried()
+                .min_by_key(|endpoint| endpoint.evicted_until(now))
+                .cloned();
+        }
+        // Starting from the next in turn, so ties are spread round too
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % available.len();
+        let mut in_turn = available.iter().cycle().skip(start).take(available.len());
+        let picked = match self.config.strategy {
+            BalanceStrategy::RoundRobin => in_turn.next(),
+            BalanceStrategy::LeastOutstanding => {
+                in_turn.min_by_key(|endpoint| endpoint.outstanding.load(Ordering::Relaxed))
+            }
+        };
+        picked.map(|endpoint| (*endpoint).clone())
+    }
+
+    fn failed(&self, endpoint: &Endpoint<Name>, e: &RpcError) {
+        let mut health = endpoint.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.config.failures_to_evict {
+            warn!(
+                "Evicting {} for {:?} after {} failures in a row, the last: {}",
+                endpoint.addr, self.config.eviction_period, health.consecutive_failures, e
+            );
+            health.evicted_until = Some(self.clock.now() + self.config.eviction_period);
+        }
+    }
+
+    fn succeeded(&self, endpoint: &Endpoint<Name>) {
+        let mut health = endpoint.health.lock().unwrap();
+        if health.consecutive_failures >= self.config.failures_to_evict {
+            info!("{} is answering again", endpoint.addr);
+        }
+        *health = EndpointHealth::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::listener::TcpListener;
+    use crate::server::RpcServer;
+    use crate::tests::{make_get_i_rpc, make_get_i_rpc_impl, HelloWorldRpcName, HelloWorldState};
+
+    async fn get_i_server(
+        i: usize,
+    ) -> (RpcServer<HelloWorldState, HelloWorldRpcName>, TcpListener) {
+        let state = Arc::new(Mutex::new(HelloWorldState { i }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        (server, listener)
+    }
+
+    #[tokio::test]
+    async fn calls_spread_round_robin_and_failed_endpoint_evicted() {
+        let (server_a, listener_a) = get_i_server(3).await;
+        let (server_b, listener_b) = get_i_server(7).await;
+        let (addr_a, addr_b) = (
+            listener_a.local_addr().unwrap().to_string(),
+            listener_b.local_addr().unwrap().to_string(),
+        );
+        let (shutdown_a, shutdown_b) = (server_a.shutdown_handle(), server_b.shutdown_handle());
+        let config = BalanceConfig {
+            failures_to_evict: 2,
+            ..Default::default()
+        };
+        let client: BalancedClient<HelloWorldRpcName> =
+            BalancedClient::new(&[&addr_a, &addr_b], config, TransportConfig::default());
+        let client_calls = async {
+            let mut before = Vec::new();
+            for _ in 0..4 {
+                before.push(client.call((), make_get_i_rpc()).await.unwrap());
+            }
+            shutdown_a.shutdown();
+            // Calls to the server that's gone are tried on the other, as it only reads so is
+            // safe to send again after finding its pooled connection closed
+            let mut after = Vec::new();
+            for _ in 0..4 {
+                after.push(
+                    client
+                        .call((), make_get_i_rpc().idempotent())
+                        .await
+                        .unwrap(),
+                );
+            }
+            shutdown_b.shutdown();
+            (before, after)
+        };
+
+        let ((), (), (mut before, after)) = tokio::join!(
+            server_a.serve_listener(listener_a),
+            server_b.serve_listener(listener_b),
+            client_calls
+        );
+        before.sort();
+        assert_eq!(vec![3, 3, 7, 7], before);
+        assert_eq!(vec![7, 7, 7, 7], after);
+        assert_eq!(vec![addr_b], client.healthy_endpoints());
+    }
+
+    #[tokio::test]
+    async fn idempotent_calls_hedged_to_faster_endpoint() {
+        // Answers, but slowly
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server_a = RpcServer::new(state, TransportConfig::default());
+        server_a.add_async_rpc(Box::new(crate::core::AsyncRpcImpl::reading(
+            HelloWorldRpcName::GetI,
+            Arc::new(tokio::sync::RwLock::new(3)),
+            Box::new(|i: &usize, _query: ()| {
+                let i = *i;
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    Ok(i)
+                })
+            }),
+        )));
+        let listener_a = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (server_b, listener_b) = get_i_server(7).await;
+        let (addr_a, addr_b) = (
+            listener_a.local_addr().unwrap().to_string(),
+            listener_b.local_addr().unwrap().to_string(),
+        );
+        let (shutdown_a, shutdown_b) = (server_a.shutdown_handle(), server_b.shutdown_handle());
+        let config = BalanceConfig {
+            hedge_after: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let client: BalancedClient<HelloWorldRpcName> =
+            BalancedClient::new(&[&addr_a, &addr_b], config, TransportConfig::default());
+        let client_calls = async {
+            let started = Instant::now();
+            let mut hedged = Vec::new();
+            for _ in 0..2 {
+                hedged.push(
+                    client
+                        .call((), make_get_i_rpc().idempotent())
+                        .await
+                        .unwrap(),
+                );
+            }
+            let hedged_took = started.elapsed();
+            // Not idempotent, so each waits for the endpoint it's sent to
+            let mut unhedged = Vec::new();
+            for _ in 0..2 {
+                unhedged.push(client.call((), make_get_i_rpc()).await.unwrap());
+            }
+            shutdown_a.shutdown();
+            shutdown_b.shutdown();
+            (hedged, hedged_took, unhedged)
+        };
+
+        let ((), (), (hedged, hedged_took, mut unhedged)) = tokio::join!(
+            server_a.serve_listener(listener_a),
+            server_b.serve_listener(listener_b),
+            client_calls
+        );
+        assert_eq!(vec![7, 7], hedged);
+        assert!(
+            hedged_took < Duration::from_millis(500),
+            "{:?}",
+            hedged_took
+        );
+        unhedged.sort();
+        assert_eq!(vec![3, 7], unhedged);
+        assert_eq!(2, client.healthy_endpoints().len());
+    }
+
+    /// Address of the endpoint [client] would call next, other than those [tried]
+    fn picked(client: &BalancedClient<HelloWorldRpcName>, tried: &[&str]) -> Option<String> {
+        let endpoints = client.endpoints.lock().unwrap().clone();
+        let tried: Vec<String> = tried.iter().map(|addr| addr.to_string()).collect();
+        let endpoint = client.pick(&endpoints, &tried)?;
+        Some(endpoint.addr.clone())
+    }
+
+    #[test]
+    fn least_outstanding_picked_and_evicted_endpoints_come_back() {
+        let clock = Arc::new(MockClock::new());
+        let transport_config = TransportConfig {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let config = BalanceConfig {
+            strategy: BalanceStrategy::LeastOutstanding,
+            failures_to_evict: 1,
+            ..Default::default()
+        };
+        let client: BalancedClient<HelloWorldRpcName> =
+            BalancedClient::new(&["a:1", "b:1", "c:1"], config, transport_config);
+        let endpoints = client.endpoints.lock().unwrap().clone();
+        endpoints[0].outstanding.store(2, Ordering::Relaxed);
+        endpoints[1].outstanding.store(1, Ordering::Relaxed);
+        endpoints[2].outstanding.store(3, Ordering::Relaxed);
+        assert_eq!(Some("b:1".into()), picked(&client, &[]));
+        assert_eq!(Some("a:1".into()), picked(&client, &["b:1"]));
+
+        let e = RpcError::TransportError(TransportError::ConnectionClosed);
+        client.failed(&endpoints[1], &e);
+        assert_eq!(vec!["a:1", "c:1"], client.healthy_endpoints());
+        assert_eq!(Some("a:1".into()), picked(&client, &[]));
+        // With every other endpoint tried, the evicted one's still tried
+        assert_eq!(Some("b:1".into()), picked(&client, &["a:1", "c:1"]));
+        assert_eq!(None, picked(&client, &["a:1", "b:1", "c:1"]));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(3, client.healthy_endpoints().len());
+        assert_eq!(Some("b:1".into()), picked(&client, &[]));
+    }
+
+    /// Resolves to whatever [addrs] holds at the time
+    struct MovingResolver {
+        addrs: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Resolver for MovingResolver {
+        async fn resolve(&self) -> Result<Vec<String>, TransportError> {
+            Ok(self.addrs.lock().unwrap().clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn endpoints_follow_resolver() {
+        let (server_a, listener_a) = get_i_server(3).await;
+        let (server_b, listener_b) = get_i_server(7).await;
+        let (addr_a, addr_b) = (
+            listener_a.local_addr().unwrap().to_string(),
+            listener_b.local_addr().unwrap().to_string(),
+        );
+        let (shutdown_a, shutdown_b) = (server_a.shutdown_handle(), server_b.shutdown_handle());
+        let addrs = Arc::new(Mutex::new(vec![addr_a.clone()]));
+        let resolver = MovingResolver {
+            addrs: addrs.clone(),
+        };
+        let config = BalanceConfig {
+            resolve_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let client: BalancedClient<HelloWorldRpcName> =
+            BalancedClient::with_resolver(resolver, config, TransportConfig::default());
+        let client_calls = async {
+            let first = client.call((), make_get_i_rpc()).await.unwrap();
+            // The server moves, which isn't looked up again until due
+            *addrs.lock().unwrap() = vec![addr_b.clone()];
+            let before_refresh = client.call((), make_get_i_rpc()).await.unwrap();
+            client.refresh().await.unwrap();
+            let after_refresh = client.call((), make_get_i_rpc()).await.unwrap();
+            shutdown_a.shutdown();
+            shutdown_b.shutdown();
+            (first, before_refresh, after_refresh)
+        };
+
+        let ((), (), calls) = tokio::join!(
+            server_a.serve_listener(listener_a),
+            server_b.serve_listener(listener_b),
+            client_calls
+        );
+        assert_eq!((3, 3, 7), calls);
+        assert_eq!(vec![addr_b], client.healthy_endpoints());
+    }
+}
@@ -0,0 +1,41 @@
+use std::time::{Duration, Instant};
+
+/// A transfer rate limit in bytes per second, see [crate::TransportConfig]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BytesPerSecond(pub u64);
+
+/// Token bucket over bytes, holding at most one second's worth of tokens.
+/// Transfers larger than what's available put the bucket into debt, and [take] sleeps until the
+/// debt is paid off, so a transfer is delayed rather than rejected
+pub(crate) struct ByteBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl ByteBucket {
+    pub(crate) fn new(limit: BytesPerSecond) -> Self {
+        let rate = limit.0 as f64;
+        Self {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    pub(crate) async fn take(&mut self, num_bytes: usize) {
+        self.refill();
+        self.tokens -= num_bytes as f64;
+        if self.tokens < 0.0 {
+            let wait = Duration::from_secs_f64(-self.tokens / self.rate);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
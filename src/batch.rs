@@ -0,0 +1,283 @@
+use std::marker::PhantomData;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::{into_rpc_result_transport, RpcError, RpcResult};
+use crate::transport::{InternalTransport, PackageKind, Transport, TransportWireConfig};
+use crate::OwnedBytes;
+
+/// Put ahead of a [TransportPackageBatch] in place of a single query, and ahead of the
+/// [TransportResponseBatch] answering it. Like [crate::HealthReport] requests, it's fixed bytes
+/// so can't clash with any rpc
+pub(crate) const BATCH: &[u8] = b"PIRATES-BATCH";
+
+/// Several queries sent at once, each a package as [Transport::send_query] would have sent it,
+/// with its index in the batch as its correlation id. [concurrent] has the server handle them
+/// at once rather than in order
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TransportPackageBatch {
+    pub(crate) concurrent: bool,
+    pub(crate) packages: Vec<OwnedBytes>,
+}
+
+/// The responses to a [TransportPackageBatch], one response envelope per package, in order
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TransportResponseBatch {
+    pub(crate) responses: Vec<OwnedBytes>,
+}
+
+/// Unary calls gathered to send to the server together in one round trip, for chatty callers
+/// on links where the round trip costs more than the calls, see [Transport::batch]:
+///
+/// ```rust,ignore
+/// let mut batch = transport.batch();
+/// let hello = batch.add(&make_hello_world_rpc(), "Foo".into())?;
+/// let get_i = batch.add(&make_get_i_rpc(), ())?;
+/// let mut results = batch.send().await?;
+/// let i = results.take(get_i)?;
+/// ```
+///
+/// Each call is handled like any other, interceptors and all, and fails or succeeds by itself.
+/// They're handled in order unless [concurrent]. Only unary rpcs can be batched, and the whole
+/// batch shares one [crate::TransportConfig::rcv_timeout]. [crate::ClientInterceptor]s and
+/// retries don't apply
+pub struct Batch<'a, I, Name: RpcName> {
+    transport: &'a mut Transport<I, Name>,
+    names: Vec<Name>,
+    packages: Vec<OwnedBytes>,
+    concurrent: bool,
+}
+
+/// A call added to a [Batch], to [BatchResults::take] its response with
+pub struct BatchCall<R> {
+    index: usize,
+    _response_phantom: PhantomData<R>,
+}
+
+impl<'a, I: InternalTransport, Name: RpcName> Batch<'a, I, Name> {
+    pub(crate) fn new(transport: &'a mut Transport<I, Name>) -> Self {
+        Self {
+            transport,
+            names: Vec::new(),
+            packages: Vec::new(),
+            concurrent: false,
+        }
+    }
+
+    /// Add a call of [rpc] with [query], failing if the query can't be serialised
+    pub fn add<Q: RpcType, R: RpcType>(
+        &mut self,
+        rpc: &Rpc<Name, Q, R>,
+        query: Q,
+    ) -> RpcResult<BatchCall<R>> {
+        let config = &self.transport.config;
+        let query_bytes = config
+            .wire_config
+            .serialize(&query)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", rpc.name)))?;
+        let index = self.packages.len();
+        let package_bytes = config.encode_package(
+            &rpc.name,
+            PackageKind::Query,
+            &query_bytes,
+            index as u64,
+            Some(config.rcv_timeout),
+            &config.metadata,
+        )?;
+        self.names.push(rpc.name.clone());
+        self.packages.push(package_bytes);
+        Ok(BatchCall {
+            index,
+            _response_phantom: PhantomData,
+        })
+    }
+
+    /// Have the server handle the calls at once, rather than one after another. Only for calls
+    /// that don't depend on each other's effects
+    pub fn concurrent(mut self) -> Self {
+        self.concurrent = true;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.packages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    /// Send the calls and wait for all their responses. Fails only if the batch as a whole
+    /// does, e.g. the connection dropped, while each call's own result is in the
+    /// [BatchResults]
+    pub async fn send(self) -> RpcResult<BatchResults> {
+        let batch = TransportPackageBatch {
+            concurrent: self.concurrent,
+            packages: self.packages,
+        };
+        let responses = self.transport.send_batch(&batch).await?;
+        if responses.len() != self.names.len() {
+            return Err(RpcError::Custom(format!(
+                "Batch of {} calls answered with {} responses",
+                self.names.len(),
+                responses.len()
+            )));
+        }
+        let config = &self.transport.config;
+        let results = responses
+            .iter()
+            .zip(&self.names)
+            .map(|(response_bytes, rpc_name)| {
+                let envelope = config.decode_response(response_bytes, rpc_name)?;
+                envelope.response.into_result()
+            })
+            .map(Some)
+            .collect();
+        Ok(BatchResults {
+            wire_config: config.wire_config.clone(),
+            results,
+        })
+    }
+}
+
+/// The results of the calls in a sent [Batch]
+pub struct BatchResults {
+    wire_config: TransportWireConfig,
+    results: Vec<Option<RpcResult<OwnedBytes>>>,
+}
+
+impl BatchResults {
+    /// The result of [call]
+    pub fn take<R: RpcType>(&mut self, call: BatchCall<R>) -> RpcResult<R> {
+        let response_bytes = self.results[call.index]
+            .take()
+            .expect("Each BatchCall is taken once")?;
+        into_rpc_result_transport(self.wire_config.deserialize(&response_bytes))
+    }
+
+    /// The serialised result of every call, in the order they were added, e.g. for a batch of
+    /// calls to the same rpc. Results already taken are left out
+    pub fn into_results(self) -> Vec<RpcResult<OwnedBytes>> {
+        self.results.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, make_hello_world_rpc_impl,
+        CountToRpc, HelloWorldRpcName, HelloWorldState, IncrIRpc,
+    };
+    use crate::transport::{channel_listener, TransportConfig};
+    use crate::{RpcDefinition, StreamRpcDefinition};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn concurrent_batch_calls_capped_at_max_concurrent_queries() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = crate::ServerConfig {
+            max_concurrent_queries: 2,
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        // How many calls are running, and the most there have been at once
+        let running = Arc::new(tokio::sync::RwLock::new((
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+        )));
+        server.add_async_rpc(Box::new(crate::core::AsyncRpcImpl::reading(
+            HelloWorldRpcName::HelloWorld,
+            running.clone(),
+            Box::new(|(now, most): &(AtomicUsize, AtomicUsize), query: String| {
+                Box::pin(async move {
+                    most.fetch_max(now.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                    now.fetch_sub(1, Ordering::SeqCst);
+                    Ok(query)
+                })
+            }),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let mut batch = transport.batch().concurrent();
+            for i in 0..6 {
+                batch.add(&make_hello_world_rpc(), i.to_string()).unwrap();
+            }
+            batch.send().await.unwrap().into_results()
+        };
+
+        let ((), results) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(6, results.len());
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(2, running.read().await.1.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn batched_calls_answered_in_one_round_trip() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.add_stream_rpc(Box::new(CountToRpc::server()));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let mut batch = transport.batch();
+            let incr_i = batch.add(&IncrIRpc::client(), ()).unwrap();
+            let get_i = batch.add(&make_get_i_rpc(), ()).unwrap();
+            let not_served = Rpc::<_, (), ()>::new(HelloWorldRpcName::MassiveRpc);
+            let not_served = batch.add(&not_served, ()).unwrap();
+            let stream = Rpc::<_, usize, usize>::new(HelloWorldRpcName::CountTo);
+            let stream = batch.add(&stream, 5).unwrap();
+            let mut in_order = batch.send().await.unwrap();
+            let in_order = (
+                in_order.take(incr_i),
+                in_order.take(get_i),
+                in_order.take(not_served),
+                in_order.take(stream),
+            );
+            let mut batch = transport.batch().concurrent();
+            for name in ["Foo", "Bar"] {
+                batch.add(&make_hello_world_rpc(), name.into()).unwrap();
+            }
+            let concurrent = batch.send().await.unwrap().into_results();
+            // The connection is left ready for single calls
+            let hello = RpcClient::new(make_hello_world_rpc())
+                .call("Baz".into(), &mut transport)
+                .await;
+            (in_order, concurrent, hello)
+        };
+
+        let ((), (in_order, concurrent, hello)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        let (incr_i, get_i, not_served, stream) = in_order;
+        incr_i.unwrap();
+        assert_eq!(4, get_i.unwrap());
+        assert!(matches!(not_served, Err(RpcError::UnknownRpc(_))));
+        assert!(matches!(stream, Err(RpcError::Remote(_))));
+        assert_eq!(2, concurrent.len());
+        let wire_config = TransportWireConfig::default();
+        let hellos: Vec<String> = concurrent
+            .into_iter()
+            .map(|result| wire_config.deserialize(&result.unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            vec!["Hello world: 4:\"Foo\"", "Hello world: 4:\"Bar\""],
+            hellos
+        );
+        assert_eq!("Hello world: 4:\"Baz\"", hello.unwrap());
+    }
+}
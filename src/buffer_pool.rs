@@ -0,0 +1,187 @@
+use std::sync::{Arc, Mutex};
+
+use crate::OwnedBytes;
+
+/// Buffers over this many bytes aren't kept by default, see [BufferPool::with_max_capacity]
+pub const DEFAULT_MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+
+/// How a [BufferPool] has been used, for tuning it. Few [reused] of those [taken] means
+/// messages are mostly bigger than the buffers kept, or that too few are kept, in which case
+/// many are [discarded]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers asked for with [BufferPool::take]
+    pub taken: u64,
+    /// Of those taken, how many were kept buffers rather than newly allocated
+    pub reused: u64,
+    pub given_back: u64,
+    /// Of those given back, how many were dropped as the pool was full or they were too big
+    pub discarded: u64,
+    /// Buffers kept now
+    pub kept: usize,
+    /// The capacity of the buffers kept now, all told
+    pub kept_bytes: usize,
+}
+
+#[derive(Default)]
+struct Pooled {
+    buffers: Vec<OwnedBytes>,
+    stats: BufferPoolStats,
+}
+
+/// Byte buffers kept for reuse, so a busy server or client moves messages through buffers it
+/// already has rather than allocating fresh ones for each. Give one to
+/// [crate::TransportConfig::buffer_pool], and the framed transports read messages into
+/// buffers taken from it, and responses are serialised into them. Buffers are given back once
+/// done with: received queries once handled, responses once sent and read.
+/// Clones share the buffers, so one pool can serve every connection. At most [max_buffers]
+/// are kept, and none over [with_max_capacity], so one huge message doesn't pin its memory.
+/// [stats] tells how well it's working
+#[derive(Clone)]
+pub struct BufferPool {
+    pooled: Arc<Mutex<Pooled>>,
+    max_buffers: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            pooled: Arc::new(Mutex::new(Pooled::default())),
+            max_buffers,
+            max_capacity: DEFAULT_MAX_POOLED_CAPACITY,
+        }
+    }
+
+    /// Drop buffers given back with over [max_capacity] bytes of room, rather than keeping
+    /// those over [DEFAULT_MAX_POOLED_CAPACITY]
+    pub fn with_max_capacity(mut self, max_capacity: usize) -> Self {
+        self.max_capacity = max_capacity;
+        self
+    }
+
+    /// An empty buffer with room for at least [capacity] bytes, reusing a kept one if any is
+    /// big enough. Kept buffers too small are left for smaller messages
+    pub fn take(&self, capacity: usize) -> OwnedBytes {
+        let mut pooled = self.pooled.lock().unwrap();
+        pooled.stats.taken += 1;
+        let Some(i) = pooled
+            .buffers
+            .iter()
+            .rposition(|buffer| buffer.capacity() >= capacity)
+        else {
+            return Vec::with_capacity(capacity);
+        };
+        let buffer = pooled.buffers.swap_remove(i);
+        pooled.stats.reused += 1;
+        pooled.stats.kept_bytes -= buffer.capacity();
+        buffer
+    }
+
+    /// Keep [buffer] for a later [take], unless the pool's full or it's too big to keep
+    pub fn give_back(&self, mut buffer: OwnedBytes) {
+        let mut pooled = self.pooled.lock().unwrap();
+        pooled.stats.given_back += 1;
+        if buffer.capacity() == 0
+            || buffer.capacity() > self.max_capacity
+            || pooled.buffers.len() >= self.max_buffers
+        {
+            pooled.stats.discarded += 1;
+            return;
+        }
+        buffer.clear();
+        pooled.stats.kept_bytes += buffer.capacity();
+        pooled.buffers.push(buffer);
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        let pooled = self.pooled.lock().unwrap();
+        BufferPoolStats {
+            kept: pooled.buffers.len(),
+            ..pooled.stats
+        }
+    }
+}
+
+impl std::fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BufferPool({:?})", self.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::listener::TcpListener;
+    use crate::server::RpcServer;
+    use crate::tests::{make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldState};
+    use crate::transport::{TcpTransport, Transport, TransportConfig};
+
+    #[tokio::test]
+    async fn calls_reuse_pooled_buffers() {
+        let pool = BufferPool::new(8);
+        let config = TransportConfig {
+            buffer_pool: Some(pool.clone()),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, config.clone());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let shutdown = server.shutdown_handle();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client_calls = async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut transport = Transport::new(TcpTransport::new(stream), config);
+            let mut hellos = Vec::new();
+            for name in ["Jack", "Will", "Elizabeth", "Hector"] {
+                let hello = RpcClient::new(make_hello_world_rpc())
+                    .call(name.into(), &mut transport)
+                    .await;
+                hellos.push(hello.unwrap());
+            }
+            shutdown.shutdown();
+            hellos
+        };
+
+        let ((), hellos) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert_eq!("Hello world: 3:\"Hector\"", hellos[3]);
+        // Each call takes four buffers, for the query and response at each end, and every call
+        // after the first reuses those given back by the calls before
+        let stats = pool.stats();
+        assert_eq!(16, stats.taken);
+        assert_eq!(4, stats.taken - stats.reused, "{:?}", stats);
+        assert!(stats.kept <= 8);
+    }
+
+    #[test]
+    fn buffers_reused_once_given_back() {
+        let pool = BufferPool::new(2).with_max_capacity(1024);
+        let small = pool.take(16);
+        let mut large = pool.take(512);
+        large.extend_from_slice(b"rum");
+        pool.give_back(small);
+        pool.give_back(large);
+        // Too big to keep
+        pool.give_back(Vec::with_capacity(2048));
+
+        let reused = pool.take(256);
+        assert!(reused.is_empty());
+        assert!(reused.capacity() >= 512);
+        // Nothing kept is big enough
+        assert!(pool.take(1024).capacity() >= 1024);
+        let stats = pool.stats();
+        assert_eq!(4, stats.taken);
+        assert_eq!(1, stats.reused);
+        assert_eq!(3, stats.given_back);
+        assert_eq!(1, stats.discarded);
+        assert_eq!(1, stats.kept);
+        assert!(stats.kept_bytes >= 16);
+
+        pool.give_back(reused);
+        pool.give_back(vec![1]);
+        assert_eq!(2, pool.stats().kept);
+        assert_eq!(2, pool.stats().discarded);
+    }
+}
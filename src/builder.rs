@@ -0,0 +1,278 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::core::{RpcName, StoredAsyncRpc, StoredRpc, StoredStreamRpc};
+use crate::error::{RpcError, RpcResult};
+use crate::metrics::MetricsRecorder;
+use crate::middleware::Interceptor;
+use crate::overload::OverloadPolicy;
+use crate::server::{RpcServer, ServerConfig};
+use crate::transport::TransportConfig;
+use log::info;
+
+type ShutdownSignal = Pin<Box<dyn Future<Output = ()>>>;
+type AddExtension<S, Name> = Box<dyn FnOnce(&mut RpcServer<S, Name>)>;
+
+/// Gathers everything an [RpcServer] needs in one place, then [build]s a [RunnableServer]:
+///
+/// ```rust,ignore
+/// RpcServerBuilder::new(state)
+///     .rpc(AddName::server())
+///     .layer(Authentication::new(authenticate))
+///     .bind("127.0.0.1:5555")
+///     .max_connections(100)
+///     .shutdown_on(async { tokio::signal::ctrl_c().await.unwrap() })
+///     .build()
+///     .run()
+///     .await?;
+/// ```
+pub struct RpcServerBuilder<S, Name: RpcName> {
+    state: Arc<Mutex<S>>,
+    transport_config: TransportConfig,
+    server_config: ServerConfig,
+    rpcs: Vec<Box<dyn StoredRpc<S, Name>>>,
+    async_rpcs: Vec<Box<dyn StoredAsyncRpc<Name>>>,
+    stream_rpcs: Vec<Box<dyn StoredStreamRpc<S, Name>>>,
+    interceptors: Vec<Box<dyn Interceptor<Name>>>,
+    extensions: Vec<AddExtension<S, Name>>,
+    bind_address: Option<String>,
+    shutdown_signal: Option<ShutdownSignal>,
+}
+
+impl<S: 'static, Name: RpcName + 'static> RpcServerBuilder<S, Name> {
+    pub fn new(state: Arc<Mutex<S>>) -> Self {
+        Self {
+            state,
+            transport_config: TransportConfig::default(),
+            server_config: ServerConfig::default(),
+            rpcs: Vec::new(),
+            async_rpcs: Vec::new(),
+            stream_rpcs: Vec::new(),
+            interceptors: Vec::new(),
+            extensions: Vec::new(),
+            bind_address: None,
+            shutdown_signal: None,
+        }
+    }
+
+    pub fn transport_config(mut self, transport_config: TransportConfig) -> Self {
+        self.transport_config = transport_config;
+        self
+    }
+
+    pub fn server_config(mut self, server_config: ServerConfig) -> Self {
+        self.server_config = server_config;
+        self
+    }
+
+    /// See [RpcServer::add_rpc]
+    pub fn rpc(mut self, rpc: impl StoredRpc<S, Name> + 'static) -> Self {
+        self.rpcs.push(Box::new(rpc));
+        self
+    }
+
+    /// See [RpcServer::add_async_rpc]
+    pub fn async_rpc(mut self, async_rpc: impl StoredAsyncRpc<Name> + 'static) -> Self {
+        self.async_rpcs.push(Box::new(async_rpc));
+        self
+    }
+
+    /// See [RpcServer::add_stream_rpc]
+    pub fn stream_rpc(mut self, stream_rpc: impl StoredStreamRpc<S, Name> + 'static) -> Self {
+        self.stream_rpcs.push(Box::new(stream_rpc));
+        self
+    }
+
+    /// See [RpcServer::layer]
+    pub fn layer(mut self, interceptor: impl Interceptor<Name> + 'static) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// See [RpcServer::add_extension]
+    pub fn extension<T: Send + Sync + 'static>(mut self, extension: Arc<T>) -> Self {
+        self.extensions
+            .push(Box::new(move |server| server.add_extension(extension)));
+        self
+    }
+
+    /// See [RpcServer::record_metrics]
+    pub fn metrics(mut self, recorder: impl MetricsRecorder + 'static) -> Self {
+        self.extensions
+            .push(Box::new(move |server| server.record_metrics(recorder)));
+        self
+    }
+
+    /// The TCP address for [RunnableServer::run] to listen on
+    pub fn bind(mut self, address: impl Into<String>) -> Self {
+        self.bind_address = Some(address.into());
+        self
+    }
+
+    /// See [ServerConfig::max_connections]
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.server_config.max_connections = Some(max_connections);
+        self
+    }
+
+    /// See [ServerConfig::max_concurrent_queries]
+    pub fn max_concurrent_queries(mut self, max_concurrent_queries: usize) -> Self {
+        self.server_config.max_concurrent_queries = max_concurrent_queries;
+        self
+    }
+
+    /// See [ServerConfig::max_queued_queries]
+    pub fn max_queued_queries(mut self, max_queued_queries: usize) -> Self {
+        self.server_config.max_queued_queries = max_queued_queries;
+        self
+    }
+
+    /// See [ServerConfig::max_queued_queries_total]
+    pub fn max_queued_queries_total(mut self, max_queued_queries_total: usize) -> Self {
+        self.server_config.max_queued_queries_total = Some(max_queued_queries_total);
+        self
+    }
+
+    /// See [ServerConfig::overload_policy]
+    pub fn overload_policy(mut self, overload_policy: OverloadPolicy) -> Self {
+        self.server_config.overload_policy = overload_policy;
+        self
+    }
+
+    /// See [ServerConfig::handler_timeout]
+    pub fn handler_timeout(mut self, handler_timeout: Duration) -> Self {
+        self.server_config.handler_timeout = Some(handler_timeout);
+        self
+    }
+
+    /// See [ServerConfig::reflection]
+    pub fn reflection(mut self) -> Self {
+        self.server_config.reflection = true;
+        self
+    }
+
+    /// See [ServerConfig::drain_timeout]
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.server_config.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Shut the server down gracefully once [signal] completes, see [crate::ShutdownHandle]
+    pub fn shutdown_on(mut self, signal: impl Future<Output = ()> + 'static) -> Self {
+        self.shutdown_signal = Some(Box::pin(signal));
+        self
+    }
+
+    pub fn build(self) -> RunnableServer<S, Name> {
+        let mut server =
+            RpcServer::with_config(self.state, self.transport_config, self.server_config);
+        for rpc in self.rpcs {
+            server.add_rpc(rpc);
+        }
+        for async_rpc in self.async_rpcs {
+            server.add_async_rpc(async_rpc);
+        }
+        for stream_rpc in self.stream_rpcs {
+            server.add_stream_rpc(stream_rpc);
+        }
+        for interceptor in self.interceptors {
+            server.layer_boxed(interceptor);
+        }
+        for add_extension in self.extensions {
+            add_extension(&mut server);
+        }
+        RunnableServer {
+            server,
+            bind_address: self.bind_address,
+            shutdown_signal: self.shutdown_signal,
+        }
+    }
+}
+
+/// An [RpcServer] ready to [run], made by [RpcServerBuilder]
+pub struct RunnableServer<S, Name: RpcName> {
+    server: RpcServer<S, Name>,
+    bind_address: Option<String>,
+    shutdown_signal: Option<ShutdownSignal>,
+}
+
+impl<S, Name: RpcName> RunnableServer<S, Name> {
+    /// The server itself, e.g. to serve it some other way than over TCP
+    pub fn server(&self) -> &RpcServer<S, Name> {
+        &self.server
+    }
+
+    /// Serve over TCP on the bound address until shut down, either by the shutdown signal or
+    /// a [crate::ShutdownHandle] taken from [server]
+    pub async fn run(self) -> RpcResult<()> {
+        let bind_address = self
+            .bind_address
+            .ok_or_else(|| RpcError::Custom(String::from("No address to serve on, see bind")))?;
+        let serve = self.server.serve(bind_address.as_str());
+        futures_util::pin_mut!(serve);
+        if let Some(shutdown_signal) = self.shutdown_signal {
+            tokio::select! {
+                () = &mut serve => return Ok(()),
+                () = shutdown_signal => {
+                    info!("Shutdown signalled");
+                    self.server.shutdown_handle().shutdown();
+                }
+            }
+        }
+        serve.await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{connect_tcp_transport, RpcClient};
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, make_hello_world_rpc_impl,
+        HelloWorldRpcName, HelloWorldState,
+    };
+
+    #[tokio::test]
+    async fn built_server_runs_until_signalled() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let server = RpcServerBuilder::<_, HelloWorldRpcName>::new(state)
+            .rpc(make_hello_world_rpc_impl())
+            .rpc(make_get_i_rpc_impl())
+            .bind("127.0.0.1:5572")
+            .max_connections(4)
+            .drain_timeout(Duration::from_millis(50))
+            .shutdown_on(async {
+                let _ = signal.await;
+            })
+            .build();
+        let client_calls = async move {
+            let mut transport = connect_tcp_transport("127.0.0.1:5572", TransportConfig::default())
+                .await
+                .unwrap();
+            let hello = RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut transport)
+                .await;
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            shutdown.send(()).unwrap();
+            (hello, get_i)
+        };
+
+        let (run, (hello, get_i)) = tokio::join!(server.run(), client_calls);
+        run.unwrap();
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap());
+        assert_eq!(3, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn run_needs_address() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server = RpcServerBuilder::<_, HelloWorldRpcName>::new(state).build();
+        assert!(matches!(server.run().await, Err(RpcError::Custom(_))));
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::clock::{Clock, SystemClock};
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::RpcResult;
+use crate::middleware::{ClientInterceptor, ClientNext, Interceptor, Next, OutgoingQuery};
+use crate::query_hash::{DefaultQueryHasher, QueryHasher};
+use crate::transport::ReceivedQuery;
+use crate::{Bytes, OwnedBytes};
+
+/// How a [ResponseCache] has done since it was made
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Calls answered from the cache
+    pub hits: u64,
+    /// Calls to cached rpcs that weren't, so were sent on
+    pub misses: u64,
+    /// Responses dropped to make room for newer ones
+    pub evictions: u64,
+    /// Responses held now, some of which may have expired
+    pub entries: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct CacheKey<Name> {
+    rpc_name: Name,
+    /// Server side, responses are in the codec the query came in, see
+    /// [crate::TransportConfig::tag_wire_format]
+    wire_format: &'static str,
+    query_hash: OwnedBytes,
+}
+
+struct Cached {
+    /// Compared on a hit, so queries whose hashes collide aren't answered alike
+    query_bytes: OwnedBytes,
+    response: OwnedBytes,
+    expires: Instant,
+}
+
+/// A call to a cached rpc, as looked up and then stored
+struct Lookup<Name> {
+    key: CacheKey<Name>,
+    query_bytes: OwnedBytes,
+    ttl: Duration,
+}
+
+struct Entries<Name> {
+    cached: HashMap<CacheKey<Name>, Cached>,
+    /// Bumped by each invalidation, so a response to a call made before one isn't stored
+    /// after it
+    generation: u64,
+    stats: CacheStats,
+}
+
+/// Answers repeated calls to the rpcs it's told to [cache] with the response to the first,
+/// for as long as their [crate::Rpc::cache_ttl], keyed by rpc name and a hash of the query
+/// bytes, see [QueryHasher]. Only successful responses are cached.
+/// It's a [ClientInterceptor], saving the trip to the server, and a server [Interceptor],
+/// saving running the handler. Either way the key leaves out [crate::Metadata] and who's
+/// calling, so only cache rpcs answering every caller alike, and on a server layer it after
+/// any auth interceptors.
+/// Responses are dropped before they expire by a successful call to an rpc they're
+/// [invalidated_by], or with [invalidate], [invalidate_rpc] or [clear], and a call still
+/// running when they're dropped doesn't store its response. Past [max_entries],
+/// the response soonest to expire makes room.
+/// Clones share their cache, so keep one to invalidate through once it's been layered
+#[derive(Clone)]
+pub struct ResponseCache<Name: RpcName> {
+    ttls: HashMap<Name, Duration>,
+    invalidations: HashMap<Name, Vec<Name>>,
+    entries: Arc<Mutex<Entries<Name>>>,
+    max_entries: usize,
+    hasher: Arc<dyn QueryHasher>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<Name: RpcName> ResponseCache<Name> {
+    /// Holding at most [max_entries] responses, and caching no rpcs until told to [cache]
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            ttls: HashMap::new(),
+            invalidations: HashMap::new(),
+            entries: Arc::new(Mutex::new(Entries {
+                cached: HashMap::new(),
+                generation: 0,
+                stats: CacheStats::default(),
+            })),
+            max_entries,
+            hasher: Arc::new(DefaultQueryHasher),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Cache responses of [rpc] for its [crate::Rpc::cache_ttl], if it has one
+    pub fn cache<Q: RpcType, R: RpcType>(mut self, rpc: &Rpc<Name, Q, R>) -> Self {
+        if let Some(ttl) = rpc.cache_ttl {
+            self.ttls.insert(rpc.name.clone(), ttl);
+        }
+        self
+    }
+
+    /// Drop every response cached for [cached] once a call to [rpc_name] succeeds, e.g. for
+    /// an rpc changing what [cached] reads
+    pub fn invalidated_by(mut self, cached: Name, rpc_name: Name) -> Self {
+        self.invalidations.entry(rpc_name).or_default().push(cached);
+        self
+    }
+
+    pub fn with_hasher(mut self, hasher: impl QueryHasher + 'static) -> Self {
+        self.hasher = Arc::new(hasher);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Drop the response cached for [rpc_name] with [query_bytes], the query as serialised
+    /// for sending
+    pub fn invalidate(&self, rpc_name: &Name, query_bytes: Bytes) {
+        let query_hash = self.hasher.hash_query(query_bytes);
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries
+            .cached
+            .retain(|key, _| !(key.rpc_name == *rpc_name && key.query_hash == query_hash));
+    }
+
+    /// Drop every response cached for [rpc_name]
+    pub fn invalidate_rpc(&self, rpc_name: &Name) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.cached.retain(|key, _| key.rpc_name != *rpc_name);
+    }
+
+    /// Drop every response cached
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.cached.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            entries: entries.cached.len(),
+            ..entries.stats
+        }
+    }
+
+    /// How to look a call up, if [rpc_name] is cached at all
+    fn lookup(
+        &self,
+        rpc_name: &Name,
+        wire_format: &'static str,
+        query_bytes: Bytes,
+    ) -> Option<Lookup<Name>> {
+        let ttl = *self.ttls.get(rpc_name)?;
+        let key = CacheKey {
+            rpc_name: rpc_name.clone(),
+            wire_format,
+            query_hash: self.hasher.hash_query(query_bytes),
+        };
+        Some(Lookup {
+            key,
+            query_bytes: query_bytes.to_vec(),
+            ttl,
+        })
+    }
+
+    /// Answer from the cache if [lookup]'s there, or with [call], caching its response and
+    /// dropping any it invalidates
+    async fn through(
+        &self,
+        rpc_name: &Name,
+        lookup: Option<Lookup<Name>>,
+        call: impl Future<Output = RpcResult<OwnedBytes>>,
+    ) -> RpcResult<OwnedBytes> {
+        let mut generation = 0;
+        if let Some(lookup) = &lookup {
+            let now = self.clock.now();
+            let mut entries = self.entries.lock().unwrap();
+            match entries.cached.get(&lookup.key) {
+                Some(cached)
+                    if cached.expires > now && cached.query_bytes == lookup.query_bytes =>
+                {
+                    let response = cached.response.clone();
+                    entries.stats.hits += 1;
+                    return Ok(response);
+                }
+                _ => entries.stats.misses += 1,
+            }
+            generation = entries.generation;
+        }
+        let response = call.await?;
+        if let Some(lookup) = lookup {
+            self.store(lookup, response.clone(), generation);
+        }
+        for cached in self.invalidations.get(rpc_name).into_iter().flatten() {
+            self.invalidate_rpc(cached);
+        }
+        Ok(response)
+    }
+
+    /// Cache [response], unless something's been invalidated since [generation]
+    fn store(&self, lookup: Lookup<Name>, response: OwnedBytes, generation: u64) {
+        let Lookup {
+            key,
+            query_bytes,
+            ttl,
+        } = lookup;
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation != generation {
+            return;
+        }
+        if entries.cached.len() >= self.max_entries && !entries.cached.contains_key(&key) {
+            entries.cached.retain(|_, cached| cached.expires > now);
+            if entries.cached.len() >= self.max_entries {
+                let soonest = entries
+                    .cached
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    entries.cached.remove(&soonest);
+                    entries.stats.evictions += 1;
+                }
+            }
+        }
+        if self.max_entries > 0 {
+            let cached = Cached {
+                query_bytes,
+                response,
+                expires: now + ttl,
+            };
+            entries.cached.insert(key, cached);
+        }
+    }
+}
+
+#[async_trait]
+impl<Name: RpcName> ClientInterceptor<Name> for ResponseCache<Name> {
+    async fn around(
+        &self,
+        query: OutgoingQuery<Name>,
+        mut next: ClientNext<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        let rpc_name = query.rpc_name.clone();
+        let lookup = self.lookup(&rpc_name, "", &query.query_bytes);
+        self.through(&rpc_name, lookup, next.run(query)).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<Name: RpcName> Interceptor<Name> for ResponseCache<Name> {
+    async fn around(
+        &self,
+        query: &ReceivedQuery<Name>,
+        next: Next<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        let lookup = self.lookup(&query.name, query.wire_format, &query.query_bytes);
+        self.through(&query.name, lookup, next.run(query)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::clock::MockClock;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, HelloWorldRpcName, HelloWorldState, IncrIRpc,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use crate::RpcDefinition;
+
+    #[tokio::test]
+    async fn client_answered_from_cache_until_invalidated_or_expired() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let (connector, listener) = channel_listener(1);
+        let clock = Arc::new(MockClock::new());
+        let cache = ResponseCache::new(16)
+            .cache(&make_get_i_rpc().cache_for(Duration::from_secs(10)))
+            .invalidated_by(HelloWorldRpcName::GetI, HelloWorldRpcName::IncrI)
+            .with_clock(clock.clone());
+        let client_calls = async {
+            let connector = connector;
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            transport.layer(cache.clone());
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut seen = Vec::new();
+            seen.push(get_i.call((), &mut transport).await.unwrap());
+            // Changed behind the cache's back, so still answered with the old value
+            let mut other_transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            incr_i.call((), &mut other_transport).await.unwrap();
+            seen.push(get_i.call((), &mut transport).await.unwrap());
+            clock.advance(Duration::from_secs(10));
+            seen.push(get_i.call((), &mut transport).await.unwrap());
+            incr_i.call((), &mut transport).await.unwrap();
+            seen.push(get_i.call((), &mut transport).await.unwrap());
+            seen
+        };
+
+        let ((), seen) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(vec![3, 3, 4, 5], seen);
+        assert_eq!(
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                evictions: 0,
+                entries: 1,
+            },
+            cache.stats()
+        );
+        cache.clear();
+        assert_eq!(0, cache.stats().entries);
+    }
+
+    #[tokio::test]
+    async fn server_answers_from_cache_without_handler() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let cache =
+            ResponseCache::new(16).cache(&make_get_i_rpc().cache_for(Duration::from_secs(10)));
+        server.layer(cache.clone());
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let first = get_i.call((), &mut transport).await.unwrap();
+            state.lock().unwrap().i = 4;
+            let second = get_i.call((), &mut transport).await.unwrap();
+            (first, second)
+        };
+
+        let ((), seen) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!((3, 3), seen);
+        assert_eq!(1, cache.stats().hits);
+    }
+
+    /// Hashes every query alike
+    struct Colliding;
+
+    impl QueryHasher for Colliding {
+        fn hash_query(&self, _query_bytes: Bytes) -> OwnedBytes {
+            vec![0]
+        }
+    }
+
+    #[test]
+    fn soonest_to_expire_evicted() {
+        let cache = ResponseCache::new(2).cache(&make_get_i_rpc().cache_for(Duration::ZERO));
+        let lookup = |query: &[u8], ttl| Lookup {
+            ttl,
+            ..cache.lookup(&HelloWorldRpcName::GetI, "", query).unwrap()
+        };
+        let key = |query: &[u8]| lookup(query, Duration::ZERO).key;
+        cache.store(lookup(b"a", Duration::from_secs(30)), vec![1], 0);
+        cache.store(lookup(b"b", Duration::from_secs(10)), vec![2], 0);
+        cache.store(lookup(b"c", Duration::from_secs(20)), vec![3], 0);
+        assert_eq!(1, cache.stats().evictions);
+        let entries = cache.entries.lock().unwrap();
+        assert!(entries.cached.contains_key(&key(b"a")));
+        assert!(!entries.cached.contains_key(&key(b"b")));
+        assert!(entries.cached.contains_key(&key(b"c")));
+    }
+
+    #[tokio::test]
+    async fn colliding_queries_and_stale_responses_not_served() {
+        let cache = ResponseCache::new(16)
+            .cache(&make_get_i_rpc().cache_for(Duration::from_secs(10)))
+            .with_hasher(Colliding);
+        let call = |query: &[u8], response: u8| {
+            let lookup = cache.lookup(&HelloWorldRpcName::GetI, "", query);
+            cache.through(&HelloWorldRpcName::GetI, lookup, async move {
+                Ok(vec![response])
+            })
+        };
+        assert_eq!(vec![1], call(b"a", 1).await.unwrap());
+        assert_eq!(vec![1], call(b"a", 2).await.unwrap());
+        // Same hash, but not the same query
+        assert_eq!(vec![3], call(b"b", 3).await.unwrap());
+
+        // Invalidated while the call's running, so what it got may be out of date
+        cache.clear();
+        let lookup = cache.lookup(&HelloWorldRpcName::GetI, "", b"a");
+        let invalidated_meanwhile = async {
+            cache.invalidate_rpc(&HelloWorldRpcName::GetI);
+            Ok(vec![4])
+        };
+        cache
+            .through(&HelloWorldRpcName::GetI, lookup, invalidated_meanwhile)
+            .await
+            .unwrap();
+        assert_eq!(0, cache.stats().entries);
+    }
+}
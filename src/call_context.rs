@@ -0,0 +1,138 @@
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::auth::Identity;
+use crate::extensions::Extensions;
+use crate::metadata::Metadata;
+use crate::session::Session;
+use crate::OwnedBytes;
+
+/// Everything about the rpc being handled beyond its query: who's on the other end of the
+/// connection it was called over, e.g. for an IP allowlist or to log the caller, what they
+/// sent with it and what the server has for it. Handlers get it from [call_context], or as an
+/// argument with [crate::RpcImpl::with_context], while [crate::call_deadline],
+/// [crate::call_metadata], [crate::call_identity], [crate::call_session],
+/// [crate::call_extension] and [call_cancellation] each read one part of it
+#[derive(Clone, Debug, Default)]
+pub struct CallContext {
+    /// Telling apart the connections calls come in on, see [crate::Transport::connection_id]
+    pub connection_id: u64,
+    /// For transports that know, see [crate::InternalTransport::peer_addr]
+    pub peer_addr: Option<SocketAddr>,
+    /// The DER encoded certificate the client showed, for TLS servers that ask for one, see
+    /// [crate::TlsServerConfigBuilder::add_client_root_certificate]
+    pub peer_certificate: Option<Arc<OwnedBytes>>,
+    /// Who called, if a [crate::Authentication] interceptor said
+    pub identity: Option<Identity>,
+    /// The [crate::TransportWireConfig::wire_config_name] of the codec the query came in,
+    /// which the response goes back in
+    pub wire_format: &'static str,
+    /// See [crate::call_deadline]
+    pub deadline: Option<Instant>,
+    /// See [crate::call_metadata]
+    pub metadata: Metadata,
+    /// See [crate::call_session]
+    pub session: Session,
+    /// See [crate::call_extension]
+    pub extensions: Arc<Extensions>,
+    /// See [call_cancellation]
+    pub cancellation: CancellationToken,
+}
+
+thread_local! {
+    static CALL_CONTEXT: RefCell<Option<CallContext>> = const { RefCell::new(None) };
+}
+
+/// The [CallContext] of the rpc being handled. This is only set while the handler itself
+/// runs, so a streaming handler should take what it needs up front for its stream to use
+pub fn call_context() -> Option<CallContext> {
+    CALL_CONTEXT.with(|call_context| call_context.borrow().clone())
+}
+
+/// Cancelled once the caller cancels the rpc being handled, e.g. by dropping a
+/// [crate::MultiplexedClient] call, for a handler to stop work no one's waiting for. An async
+/// handler is dropped at its next await once it's cancelled anyway, so this is for work
+/// handed off elsewhere, e.g. to a spawned task
+pub fn call_cancellation() -> Option<CancellationToken> {
+    read_call_context(|context| Some(context.cancellation.clone()))
+}
+
+/// [f] of the [CallContext] of the rpc being handled, for reading part of it without cloning
+/// the rest
+pub(crate) fn read_call_context<T>(f: impl FnOnce(&CallContext) -> Option<T>) -> Option<T> {
+    CALL_CONTEXT.with(|call_context| f(call_context.borrow().as_ref()?))
+}
+
+/// Puts back the outer context when dropped, even if the handler panicked
+struct RestoreContext(Option<CallContext>);
+
+impl Drop for RestoreContext {
+    fn drop(&mut self) {
+        let outer = self.0.take();
+        CALL_CONTEXT.with(|call_context| call_context.replace(outer));
+    }
+}
+
+/// Run [f] with [call_context] giving [context]
+pub(crate) fn with_call_context<T>(context: &CallContext, f: impl FnOnce() -> T) -> T {
+    let _restore = RestoreContext(
+        CALL_CONTEXT.with(|call_context| call_context.replace(Some(context.clone()))),
+    );
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Authentication;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::error::RpcError;
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn context_given_to_handler() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::with_context(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, context: CallContext, ()| {
+                let subject = context.identity.as_ref().map(|identity| &identity.subject);
+                if subject.is_none_or(|subject| subject != "jack") {
+                    return Err(RpcError::Custom(String::from("Not on the crew")));
+                }
+                Ok((context.connection_id, String::from(context.wire_format)))
+            }),
+        )));
+        server.layer(Authentication::new(|token: &str| {
+            Some(Identity::new(token))
+        }));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let config = TransportConfig::default().with_bearer_token("jack");
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            let whoami = RpcClient::new(Rpc::<_, (), (u64, String)>::new(
+                HelloWorldRpcName::HelloWorld,
+            ));
+            let jack = whoami.call((), &mut transport).await;
+            let again = whoami.call((), &mut transport).await;
+            transport.config = TransportConfig::default().with_bearer_token("davy");
+            let davy = whoami.call((), &mut transport).await;
+            (jack, again, davy)
+        };
+
+        let ((), (jack, again, davy)) = tokio::join!(server.serve_channel(listener), client_calls);
+        let (connection_id, wire_format) = jack.unwrap();
+        assert_eq!("pickle", wire_format);
+        assert_eq!(connection_id, again.unwrap().0);
+        assert!(matches!(davy, Err(RpcError::Remote(_))));
+        assert!(call_context().is_none());
+    }
+}
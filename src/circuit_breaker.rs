@@ -0,0 +1,279 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{info, warn};
+
+use crate::clock::{Clock, SystemClock};
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::middleware::{ClientInterceptor, ClientNext, OutgoingQuery};
+use crate::OwnedBytes;
+
+/// CircuitBreakerConfig defines when a [CircuitBreaker] opens and closes again
+/// [failure_threshold] calls failing in a row open it, for [cooldown]. After that,
+/// [half_open_trials] calls are let through as trials, with others still failing fast: the
+/// first to succeed closes it, while one failing opens it for another [cooldown]. With no
+/// trials it closes outright once [cooldown]'s passed
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: usize,
+    pub cooldown: Duration,
+    pub half_open_trials: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+            half_open_trials: 1,
+        }
+    }
+}
+
+/// Whether a [CircuitBreaker] lets calls through
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through
+    Closed,
+    /// Calls fail fast with [RpcError::CircuitOpen]
+    Open,
+    /// The cooldown's passed, and trial calls go through to see if the server's back
+    HalfOpen,
+}
+
+enum State {
+    Closed { failures: usize },
+    Open { until: Instant },
+    HalfOpen { trials: usize },
+}
+
+/// Client [ClientInterceptor] failing calls fast with [RpcError::CircuitOpen] while the server
+/// looks down, rather than have each wait to fail, and piling more load on a server that's
+/// struggling, see [CircuitBreakerConfig]. Only failures that might not happen again count,
+/// see [RpcError::is_retryable], as an error from the handler shows the server's up.
+/// Clones share their state, so one breaker can guard every connection to a server
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    state: Arc<Mutex<State>>,
+    config: CircuitBreakerConfig,
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+            config,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The state the next call would find
+    pub fn state(&self) -> CircuitState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { until } if self.clock.now() < until => CircuitState::Open,
+            State::Open { .. } if self.config.half_open_trials == 0 => CircuitState::Closed,
+            State::Open { .. } | State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Let a call through, saying whether it's a trial, or fail it with how long until calls
+    /// will be let through again. That's none while trials are still going
+    fn admit(&self) -> Result<bool, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = self.clock.now();
+        match *state {
+            State::Closed { .. } => Ok(false),
+            State::Open { until } if now < until => Err(until - now),
+            State::Open { .. } if self.config.half_open_trials == 0 => {
+                *state = State::Closed { failures: 0 };
+                Ok(false)
+            }
+            State::Open { .. } => {
+                *state = State::HalfOpen { trials: 1 };
+                Ok(true)
+            }
+            State::HalfOpen { ref mut trials } if *trials < self.config.half_open_trials => {
+                *trials += 1;
+                Ok(true)
+            }
+            State::HalfOpen { .. } => Err(Duration::ZERO),
+        }
+    }
+
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        match (&mut *state, failed) {
+            (State::Closed { failures }, false) => *failures = 0,
+            (State::Closed { failures }, true) => {
+                *failures += 1;
+                if *failures >= self.config.failure_threshold {
+                    warn!(
+                        "Opening circuit for {:?} after {} failures in a row",
+                        self.config.cooldown, failures
+                    );
+                    *state = self.open();
+                }
+            }
+            (State::HalfOpen { .. }, false) => {
+                info!("Closing circuit, the server's answering again");
+                *state = State::Closed { failures: 0 };
+            }
+            (State::HalfOpen { .. }, true) => {
+                warn!(
+                    "Trial call failed, opening circuit for {:?}",
+                    self.config.cooldown
+                );
+                *state = self.open();
+            }
+            // Let through before it opened
+            (State::Open { .. }, _) => (),
+        }
+    }
+
+    /// Free the place of a trial call given up on before it finished
+    fn trial_given_up(&self) {
+        if let State::HalfOpen { trials } = &mut *self.state.lock().unwrap() {
+            *trials = trials.saturating_sub(1);
+        }
+    }
+
+    fn open(&self) -> State {
+        State::Open {
+            until: self.clock.now() + self.config.cooldown,
+        }
+    }
+}
+
+/// Frees a trial call's place if dropped while it's still [Some], i.e. unfinished
+struct TrialInFlight<'a>(Option<&'a CircuitBreaker>);
+
+impl Drop for TrialInFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(breaker) = self.0 {
+            breaker.trial_given_up();
+        }
+    }
+}
+
+#[async_trait]
+impl<Name: RpcName> ClientInterceptor<Name> for CircuitBreaker {
+    async fn around(
+        &self,
+        query: OutgoingQuery<Name>,
+        mut next: ClientNext<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        let trial = self.admit().map_err(|retry_after| {
+            RpcError::CircuitOpen(format!("{}", query.rpc_name), retry_after)
+        })?;
+        let mut in_flight = TrialInFlight(trial.then_some(self));
+        let result = next.run(query).await;
+        in_flight.0 = None;
+        self.record(matches!(&result, Err(e) if e.is_retryable()));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::clock::MockClock;
+    use crate::server::RpcServer;
+    use crate::tests::{make_get_i_rpc, make_get_i_rpc_impl, HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig, TransportError};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Fails queries as if the connection broke while [failing], counting those that get past
+    struct Flaky {
+        failing: Arc<AtomicBool>,
+        sent: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ClientInterceptor<HelloWorldRpcName> for Flaky {
+        async fn around(
+            &self,
+            query: OutgoingQuery<HelloWorldRpcName>,
+            mut next: ClientNext<'_, HelloWorldRpcName>,
+        ) -> RpcResult<OwnedBytes> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(RpcError::TransportError(TransportError::ConnectionClosed));
+            }
+            next.run(query).await
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_after_failures_then_closes_after_trial() {
+        let state = Arc::new(std::sync::Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let clock = Arc::new(MockClock::new());
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(10),
+            half_open_trials: 1,
+        })
+        .with_clock(clock.clone());
+        let failing = Arc::new(AtomicBool::new(true));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let client_calls = async {
+            // Moved in, so the server stops once it's dropped
+            let connector = connector;
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            transport.layer(breaker.clone());
+            transport.layer(Flaky {
+                failing: failing.clone(),
+                sent: sent.clone(),
+            });
+            let client = RpcClient::new(make_get_i_rpc());
+            let mut results = Vec::new();
+            for _ in 0..3 {
+                results.push(client.call((), &mut transport).await);
+            }
+            let open_sent = sent.load(Ordering::SeqCst);
+            // A failing trial opens it again
+            clock.advance(Duration::from_secs(10));
+            assert_eq!(CircuitState::HalfOpen, breaker.state());
+            results.push(client.call((), &mut transport).await);
+            results.push(client.call((), &mut transport).await);
+            clock.advance(Duration::from_secs(10));
+            failing.store(false, Ordering::SeqCst);
+            results.push(client.call((), &mut transport).await);
+            results.push(client.call((), &mut transport).await);
+            (results, open_sent)
+        };
+
+        let ((), (results, open_sent)) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert!(matches!(results[0], Err(RpcError::TransportError(_))));
+        assert!(matches!(results[1], Err(RpcError::TransportError(_))));
+        match &results[2] {
+            Err(RpcError::CircuitOpen(_, retry_after)) => {
+                assert_eq!(Duration::from_secs(10), *retry_after)
+            }
+            other => panic!("Expected CircuitOpen, got {:?}", other),
+        }
+        // Failed fast, without being sent
+        assert_eq!(2, open_sent);
+        assert!(matches!(results[3], Err(RpcError::TransportError(_))));
+        assert!(matches!(results[4], Err(RpcError::CircuitOpen(_, _))));
+        assert_eq!(3, *results[5].as_ref().unwrap());
+        assert_eq!(3, *results[6].as_ref().unwrap());
+        assert_eq!(CircuitState::Closed, breaker.state());
+        assert_eq!(5, sent.load(Ordering::SeqCst));
+    }
+}
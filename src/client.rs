@@ -1,8 +1,19 @@
-use crate::core::{Rpc, RpcName, RpcType};
+use std::time::Duration;
+
+use crate::core::{
+    BidiStreamRpc, ClientStreamRpc, NotificationRpc, Rpc, RpcName, RpcType, StreamRpc,
+};
 use crate::error::{into_rpc_result_transport, RpcError, RpcResult};
+use crate::idempotency::{new_idempotency_key, IDEMPOTENCY_KEY};
+use crate::outbox::Outbox;
+use crate::trace::CallSpan;
 use crate::transport::{
-    InternalTransport, TcpTransport, Transport, TransportConfig, TransportError,
+    connect_tcp, InternalTransport, TcpTransport, Transport, TransportConfig, TransportWireConfig,
 };
+use crate::{Bytes, OwnedBytes};
+use futures_util::{Stream, StreamExt};
+use log::warn;
+use serde::Deserialize;
 
 /// An [RpcClient] encapsulates an Rpc and allows it to be called, providing a [Transport]
 /// a convenience function, [call_client] is provided which wraps this type and uses the
@@ -11,7 +22,7 @@ pub struct RpcClient<Name: RpcName, Q: RpcType, R: RpcType> {
     rpc: Rpc<Name, Q, R>,
 }
 
-impl<'de, Name: RpcName, Q: RpcType, R: RpcType> RpcClient<Name, Q, R> {
+impl<Name: RpcName, Q: RpcType, R: RpcType> RpcClient<Name, Q, R> {
     pub fn new(rpc: Rpc<Name, Q, R>) -> Self {
         Self { rpc }
     }
@@ -22,11 +33,556 @@ impl<'de, Name: RpcName, Q: RpcType, R: RpcType> RpcClient<Name, Q, R> {
         query: Q,
         transport: &mut Transport<impl InternalTransport, Name>,
     ) -> RpcResult<R> {
-        let query_bytes = transport.config.wire_config.serialize(&query)?;
-        let result_bytes = transport.send_query(&query_bytes, &self.rpc.name).await?;
-        let result = transport.config.wire_config.deserialize(&result_bytes);
+        let timeout = transport.config.rcv_timeout;
+        self.call_with_timeout(query, transport, timeout).await
+    }
+
+    /// As [call], but giving up after [timeout] rather than [TransportConfig::rcv_timeout].
+    /// The server is told the deadline too, see [Transport::send_query_with_timeout].
+    /// Idempotent rpcs are retried per [TransportConfig::retry_policy], each attempt with the
+    /// full [timeout]
+    pub async fn call_with_timeout(
+        &self,
+        query: Q,
+        transport: &mut Transport<impl InternalTransport, Name>,
+        timeout: Duration,
+    ) -> RpcResult<R> {
+        let response = self
+            .call_raw_with_timeout(query, transport, timeout)
+            .await?;
+        let result = response.deserialize();
+        transport.config.give_back_buffer(response.bytes);
+        result
+    }
+
+    /// As [call], but sent with [idempotency_key] for a server deduplicating calls, see
+    /// [crate::Deduplication], to answer a repeat of one it's already run with the response it
+    /// gave rather than running it again. That makes it safe to retry per
+    /// [TransportConfig::retry_policy] even if the rpc isn't idempotent. Use a new key for each
+    /// call meant to run, e.g. from [crate::new_idempotency_key]
+    pub async fn call_with_idempotency_key(
+        &self,
+        query: Q,
+        idempotency_key: &str,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<R> {
+        let timeout = transport.config.rcv_timeout;
+        let response = self
+            .call_raw_keyed(query, transport, timeout, Some(idempotency_key), None)
+            .await?;
+        let result = response.deserialize();
+        transport.config.give_back_buffer(response.bytes);
+        result
+    }
+
+    /// As [call_with_idempotency_key], with a new key, but written to [outbox] before it's sent
+    /// and only removed once the server's answered it. If it fails such that it might not have
+    /// run, see [RpcError::is_retryable], it's left there for [Outbox::replay] to send again
+    pub async fn call_durable(
+        &self,
+        query: Q,
+        outbox: &Outbox,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<R> {
+        let timeout = transport.config.rcv_timeout;
+        let idempotency_key = new_idempotency_key();
+        let response = self
+            .call_raw_keyed(
+                query,
+                transport,
+                timeout,
+                Some(&idempotency_key),
+                Some(outbox),
+            )
+            .await?;
+        let result = response.deserialize();
+        transport.config.give_back_buffer(response.bytes);
+        result
+    }
+
+    /// As [call], but leaving the response as received, to deserialise borrowing from with
+    /// [RawResponse::deserialize]. Its fields can then be `&str`s, or `Cow`s marked
+    /// `#[serde(borrow)]`, pointing into the response rather than each copied into its own
+    /// allocation, for large responses that are only read. Whether they can borrow is up to the
+    /// wire format, see [crate::TransportWireConfig]
+    pub async fn call_raw(
+        &self,
+        query: Q,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<RawResponse<Name>> {
+        let timeout = transport.config.rcv_timeout;
+        self.call_raw_with_timeout(query, transport, timeout).await
+    }
+
+    /// As [call_raw], but giving up after [timeout], see [call_with_timeout]
+    pub async fn call_raw_with_timeout(
+        &self,
+        query: Q,
+        transport: &mut Transport<impl InternalTransport, Name>,
+        timeout: Duration,
+    ) -> RpcResult<RawResponse<Name>> {
+        self.call_raw_keyed(query, transport, timeout, None, None)
+            .await
+    }
+
+    async fn call_raw_keyed(
+        &self,
+        query: Q,
+        transport: &mut Transport<impl InternalTransport, Name>,
+        timeout: Duration,
+        idempotency_key: Option<&str>,
+        outbox: Option<&Outbox>,
+    ) -> RpcResult<RawResponse<Name>> {
+        let mut query_bytes = transport.config.take_buffer(0);
+        transport
+            .config
+            .wire_config
+            .serialize_into(&query, &mut query_bytes)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", self.rpc.name)))?;
+        let outbox_entry = match outbox {
+            Some(outbox) => Some(
+                outbox
+                    .add(
+                        &self.rpc.name,
+                        idempotency_key.unwrap_or_default(),
+                        &query_bytes,
+                    )
+                    .await?,
+            ),
+            None => None,
+        };
+        let span = CallSpan::client(&self.rpc.name, query_bytes.len());
+        let started = transport.config.clock.now();
+        let result_bytes = span
+            .instrument(self.send_with_retries(&query_bytes, transport, timeout, idempotency_key))
+            .await;
+        transport.config.give_back_buffer(query_bytes);
+        if let Some(outbox_entry) = outbox_entry {
+            if !matches!(&result_bytes, Err(e) if e.is_retryable()) {
+                outbox_entry.delivered().await;
+            }
+        }
+        span.record_result(result_bytes.as_ref().map(Vec::len));
+        span.record_latency(transport.config.clock.now().duration_since(started));
+        Ok(RawResponse {
+            rpc_name: self.rpc.name.clone(),
+            bytes: result_bytes?,
+            wire_config: transport.config.wire_config.clone(),
+        })
+    }
+
+    /// Send [query_bytes], retrying per [TransportConfig::retry_policy] if the rpc is idempotent
+    /// or the call has an [idempotency_key]
+    async fn send_with_retries(
+        &self,
+        query_bytes: Bytes<'_>,
+        transport: &mut Transport<impl InternalTransport, Name>,
+        timeout: Duration,
+        idempotency_key: Option<&str>,
+    ) -> RpcResult<OwnedBytes> {
+        let keyed_metadata = idempotency_key.map(|idempotency_key| {
+            let mut metadata = transport.config.metadata.clone();
+            metadata.insert(String::from(IDEMPOTENCY_KEY), idempotency_key.to_string());
+            metadata
+        });
+        let mut failed_attempts = 0;
+        loop {
+            let rpc_name = &self.rpc.name;
+            let result = match &keyed_metadata {
+                Some(metadata) => {
+                    transport
+                        .send_query_with_metadata(query_bytes, rpc_name, timeout, metadata.clone())
+                        .await
+                }
+                None => {
+                    transport
+                        .send_query_with_timeout(query_bytes, rpc_name, timeout)
+                        .await
+                }
+            };
+            let error = match result {
+                Ok(result_bytes) => return Ok(result_bytes),
+                Err(error) => error,
+            };
+            failed_attempts += 1;
+            let retry_after = match &transport.config.retry_policy {
+                Some(policy) if self.rpc.idempotent || idempotency_key.is_some() => {
+                    policy.retry_after(&error, failed_attempts)
+                }
+                _ => None,
+            };
+            match retry_after {
+                Some(retry_after) => {
+                    warn!(
+                        "Rpc {} failed, retrying in {:?}: {}",
+                        self.rpc.name, retry_after, error
+                    );
+                    tokio::time::sleep(retry_after).await;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+}
+
+/// A response to a call of the rpc [Name], as received and not yet deserialised, see
+/// [RpcClient::call_raw]
+pub struct RawResponse<Name> {
+    rpc_name: Name,
+    bytes: OwnedBytes,
+    wire_config: TransportWireConfig,
+}
+
+impl<Name: RpcName> RawResponse<Name> {
+    /// Deserialise the response as [T], which needn't be the rpc's response type, only one it
+    /// deserialises as, and can borrow from the response for as long as it's kept
+    pub fn deserialize<'a, T: Deserialize<'a>>(&'a self) -> RpcResult<T> {
+        let result = self
+            .wire_config
+            .deserialize(&self.bytes)
+            .map_err(|e| e.in_step(format_args!("response for rpc {}", self.rpc_name)));
         into_rpc_result_transport(result)
     }
+
+    /// The response in the call's wire format
+    pub fn as_bytes(&self) -> Bytes<'_> {
+        &self.bytes
+    }
+}
+
+/// Sends queries to a [NotificationRpc], without waiting for the server
+pub struct NotificationClient<Name: RpcName, Q: RpcType> {
+    rpc: NotificationRpc<Name, Q>,
+}
+
+impl<Name: RpcName, Q: RpcType> NotificationClient<Name, Q> {
+    pub fn new(rpc: NotificationRpc<Name, Q>) -> Self {
+        Self { rpc }
+    }
+
+    /// Send [query], returning once it's written to [transport], or held back to go with
+    /// others, see [crate::TransportConfig::coalesce_notifications]. Errors are only those
+    /// sending it, as the server never answers
+    pub async fn notify(
+        &self,
+        query: Q,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<()> {
+        let query_bytes = transport
+            .config
+            .wire_config
+            .serialize(&query)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", self.rpc.name)))?;
+        transport
+            .send_notification(&query_bytes, &self.rpc.name)
+            .await
+    }
+}
+
+/// A [TypedRpc] fixes an rpc's name along with its query and response types once, giving a
+/// type-safe client stub without needing the rpc definition or macro to hand. Call sites then
+/// only provide the [Transport] and the query
+pub struct TypedRpc<Name: RpcName, Q: RpcType, R: RpcType> {
+    client: RpcClient<Name, Q, R>,
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> TypedRpc<Name, Q, R> {
+    pub fn new(name: Name) -> Self {
+        Self {
+            client: RpcClient::new(Rpc::new(name)),
+        }
+    }
+
+    pub async fn call(
+        &self,
+        transport: &mut Transport<impl InternalTransport, Name>,
+        query: Q,
+    ) -> RpcResult<R> {
+        self.client.call(query, transport).await
+    }
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> From<Rpc<Name, Q, R>> for TypedRpc<Name, Q, R> {
+    fn from(rpc: Rpc<Name, Q, R>) -> Self {
+        Self {
+            client: RpcClient::new(rpc),
+        }
+    }
+}
+
+/// Build a typed client over a [Transport] from a set of rpcs, with a method per rpc taking
+/// just its query, so call sites don't pass rpc names or spell out query types. Each rpc is
+/// given as its method's signature and then its name, any expression giving the name will do.
+/// For a whole service defined in one place, see the `#[pirates::service]` macro instead
+///
+/// ```rust,ignore
+/// pirates::typed_client! {
+///     /// Calls the name server
+///     pub struct NamesClient for RpcId {
+///         call_add_name(String) -> () = RpcId::AddName,
+///         call_get_names(()) -> Vec<String> = RpcId::GetNames,
+///     }
+/// }
+///
+/// let mut client = NamesClient::new(transport);
+/// client.call_add_name(String::from("Gaspode the wonder dog")).await?;
+/// ```
+#[macro_export]
+macro_rules! typed_client {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $client:ident for $name:ty {
+            $($method:ident($query:ty) -> $response:ty = $rpc_name:expr),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $client<I> {
+            pub transport: $crate::Transport<I, $name>,
+        }
+
+        impl<I: $crate::InternalTransport> $client<I> {
+            $vis fn new(transport: $crate::Transport<I, $name>) -> Self {
+                Self { transport }
+            }
+
+            $(
+                $vis async fn $method(
+                    &mut self,
+                    query: $query,
+                ) -> $crate::error::RpcResult<$response> {
+                    $crate::TypedRpc::<$name, $query, $response>::new($rpc_name)
+                        .call(&mut self.transport, query)
+                        .await
+                }
+            )*
+        }
+    };
+}
+
+/// A [StreamRpcClient] calls a [StreamRpc], yielding its responses as a [Stream] as the server
+/// sends them. The [Transport] is borrowed until the stream ends, after which it can be used for
+/// other calls. Dropping the stream early leaves the call running, so follow up with
+/// [Transport::cancel_stream] before reusing the transport
+pub struct StreamRpcClient<Name: RpcName, Q: RpcType, R: RpcType> {
+    rpc: StreamRpc<Name, Q, R>,
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> StreamRpcClient<Name, Q, R> {
+    pub fn new(rpc: StreamRpc<Name, Q, R>) -> Self {
+        Self { rpc }
+    }
+
+    /// Call the rpc over [transport]. The stream ends after the last response, or after the
+    /// first error
+    pub fn call<'a, I: InternalTransport>(
+        &'a self,
+        query: Q,
+        transport: &'a mut Transport<I, Name>,
+    ) -> impl Stream<Item = RpcResult<R>> + 'a {
+        futures_util::stream::unfold(
+            Some((transport, Some(query))),
+            move |call_state| async move {
+                let (transport, query) = call_state?;
+                if let Some(query) = query {
+                    if let Err(e) = self.send(query, transport).await {
+                        return Some((Err(e), None));
+                    }
+                }
+                match transport.receive_stream_item(&self.rpc.name).await {
+                    Ok(Some(response_bytes)) => {
+                        let response = transport
+                            .config
+                            .wire_config
+                            .deserialize(&response_bytes)
+                            .map_err(|e| {
+                                e.in_step(format_args!("response for rpc {}", self.rpc.name))
+                            });
+                        Some((into_rpc_result_transport(response), Some((transport, None))))
+                    }
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e), None)),
+                }
+            },
+        )
+    }
+
+    async fn send(
+        &self,
+        query: Q,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<()> {
+        let query_bytes = transport
+            .config
+            .wire_config
+            .serialize(&query)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", self.rpc.name)))?;
+        transport
+            .send_stream_query(&query_bytes, &self.rpc.name)
+            .await
+    }
+}
+
+/// A [ClientStreamRpcClient] calls a [ClientStreamRpc], sending each query from a [Stream] and
+/// then waiting for the server's one response
+pub struct ClientStreamRpcClient<Name: RpcName, Q: RpcType, R: RpcType> {
+    rpc: ClientStreamRpc<Name, Q, R>,
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> ClientStreamRpcClient<Name, Q, R> {
+    pub fn new(rpc: ClientStreamRpc<Name, Q, R>) -> Self {
+        Self { rpc }
+    }
+
+    /// Call the rpc over [transport], sending every query from [queries] before waiting for the
+    /// response
+    pub async fn call(
+        &self,
+        queries: impl Stream<Item = Q>,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<R> {
+        transport.send_stream_query(&[], &self.rpc.name).await?;
+        futures_util::pin_mut!(queries);
+        while let Some(query) = queries.next().await {
+            send_stream_item(query, &self.rpc.name, transport).await?;
+        }
+        transport.end_request_stream(&self.rpc.name).await?;
+        let response_bytes = transport
+            .receive_stream_item(&self.rpc.name)
+            .await?
+            .ok_or_else(|| {
+                RpcError::Custom(format!("Rpc {} ended without a response", self.rpc.name))
+            })?;
+        // Read the end of the stream too, leaving the connection ready for the next call
+        if transport
+            .receive_stream_item(&self.rpc.name)
+            .await?
+            .is_some()
+        {
+            return Err(RpcError::Custom(format!(
+                "Rpc {} sent more than one response",
+                self.rpc.name
+            )));
+        }
+        let response = transport
+            .config
+            .wire_config
+            .deserialize(&response_bytes)
+            .map_err(|e| e.in_step(format_args!("response for rpc {}", self.rpc.name)));
+        into_rpc_result_transport(response)
+    }
+}
+
+/// A [BidiStreamRpcClient] calls a [BidiStreamRpc], sending each query from a [Stream] while
+/// yielding the responses as a [Stream] as the server sends them. Queries are only sent while
+/// the response stream is being polled. As with [StreamRpcClient], the [Transport] is borrowed
+/// until the response stream ends
+pub struct BidiStreamRpcClient<Name: RpcName, Q: RpcType, R: RpcType> {
+    rpc: BidiStreamRpc<Name, Q, R>,
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> BidiStreamRpcClient<Name, Q, R> {
+    pub fn new(rpc: BidiStreamRpc<Name, Q, R>) -> Self {
+        Self { rpc }
+    }
+
+    /// Call the rpc over [transport]. The response stream ends after the last response, or
+    /// after the first error
+    pub fn call<'a, I: InternalTransport>(
+        &'a self,
+        queries: impl Stream<Item = Q> + 'a,
+        transport: &'a mut Transport<I, Name>,
+    ) -> impl Stream<Item = RpcResult<R>> + 'a {
+        let queries = Some(Box::pin(queries));
+        futures_util::stream::unfold(
+            Some((transport, queries, false)),
+            move |call_state| async move {
+                let (transport, mut queries, opened) = call_state?;
+                if !opened {
+                    if let Err(e) = transport.send_stream_query(&[], &self.rpc.name).await {
+                        return Some((Err(e), None));
+                    }
+                }
+                let response_bytes = loop {
+                    let Some(pending_queries) = queries.as_mut() else {
+                        break transport.receive_stream_bytes(&self.rpc.name).await;
+                    };
+                    // Receiving is cancelled whenever a query is ready first, so must be
+                    // cancel safe
+                    let sent = tokio::select! {
+                        query = pending_queries.next() => match query {
+                            Some(query) => send_stream_item(query, &self.rpc.name, transport).await,
+                            None => {
+                                queries = None;
+                                transport.end_request_stream(&self.rpc.name).await
+                            }
+                        },
+                        response_bytes = transport.receive_stream_bytes(&self.rpc.name) => {
+                            break response_bytes
+                        }
+                    };
+                    if let Err(e) = sent {
+                        return Some((Err(e), None));
+                    }
+                };
+                let response = match response_bytes {
+                    Ok(response_bytes) => {
+                        transport
+                            .decode_stream_item(response_bytes, &self.rpc.name)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match response {
+                    Ok(Some(response_bytes)) => {
+                        let response = transport
+                            .config
+                            .wire_config
+                            .deserialize(&response_bytes)
+                            .map_err(|e| {
+                                e.in_step(format_args!("response for rpc {}", self.rpc.name))
+                            });
+                        Some((
+                            into_rpc_result_transport(response),
+                            Some((transport, queries, true)),
+                        ))
+                    }
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e), None)),
+                }
+            },
+        )
+    }
+}
+
+async fn send_stream_item<Name: RpcName, Q: RpcType>(
+    query: Q,
+    rpc_name: &Name,
+    transport: &mut Transport<impl InternalTransport, Name>,
+) -> RpcResult<()> {
+    let query_bytes = transport
+        .config
+        .wire_config
+        .serialize(&query)
+        .map_err(|e| e.in_step(format_args!("query for rpc {}", rpc_name)))?;
+    transport.send_stream_item(&query_bytes, rpc_name).await
+}
+
+/// Connect a [TcpTransport] to [addr] and wrap it in a [Transport] with the given config,
+/// handshaking and checking its [TransportConfig::schema] if it asks for them. Connecting
+/// fails after [TransportConfig::connect_timeout]
+pub async fn connect_tcp_transport<Name: RpcName>(
+    addr: &str,
+    transport_config: TransportConfig,
+) -> RpcResult<Transport<TcpTransport, Name>> {
+    let client_stream = connect_tcp(addr, transport_config.connect_timeout).await?;
+    let mut transport = Transport::new(TcpTransport::new(client_stream), transport_config);
+    if transport.config.handshake {
+        transport.handshake().await?;
+    }
+    if let Some(schema) = transport.config.schema.clone() {
+        transport.verify_schema(&schema).await?;
+    }
+    Ok(transport)
 }
 
 /// Basic client call function using the [TpcTransport] internal transport with [TransportConfig::Pickle]
@@ -35,16 +591,7 @@ pub async fn call_client<Name: RpcName, Q: RpcType, R: RpcType>(
     q: Q,
     rpc: Rpc<Name, Q, R>,
 ) -> RpcResult<R> {
-    let mut transport = {
-        match tokio::net::TcpStream::connect(addr).await {
-            Ok(client_stream) => {
-                let tcp_transport = TcpTransport::new(client_stream);
-                Ok(Transport::new(tcp_transport, TransportConfig::default()))
-            }
-            Err(e) => Err(e),
-        }
-    }
-    .map_err(|e| RpcError::TransportError(TransportError::ConnectError(format!("{}", e))))?;
+    let mut transport = connect_tcp_transport(addr, TransportConfig::default()).await?;
 
     let rpc_client = RpcClient::new(rpc);
 
@@ -54,8 +601,74 @@ pub async fn call_client<Name: RpcName, Q: RpcType, R: RpcType>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::make_hello_world_rpc;
-    use crate::transport::CannedTestingTransport;
+    use crate::core::RpcImpl;
+    use crate::server::RpcServer;
+    use crate::tests::{make_hello_world_rpc, HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, CannedTestingTransport};
+    use serde::Serialize;
+    use std::borrow::Cow;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct Manifest {
+        ship: String,
+        cargo: Vec<String>,
+    }
+
+    /// Reads a [Manifest] without copying out its ship, where the wire format allows. Serde
+    /// only borrows a `Cow` field itself, not those in a `Vec`, so the cargo is still copied
+    #[derive(Deserialize)]
+    struct ManifestView<'a> {
+        #[serde(borrow)]
+        ship: Cow<'a, str>,
+        cargo: Vec<Cow<'a, str>>,
+    }
+
+    /// The manifest the server sends, as read by a client borrowing from the response
+    async fn read_manifest_view(wire_config: TransportWireConfig, read: impl FnOnce(ManifestView)) {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let config = TransportConfig {
+            wire_config,
+            ..Default::default()
+        };
+        let mut server = RpcServer::new(state, config.clone());
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, ()| {
+                Ok(Manifest {
+                    ship: String::from("Black Pearl"),
+                    cargo: vec![String::from("rum"), String::from("parrots")],
+                })
+            }),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            let manifest = Rpc::<_, (), Manifest>::new(HelloWorldRpcName::HelloWorld);
+            let response = RpcClient::new(manifest)
+                .call_raw((), &mut transport)
+                .await
+                .unwrap();
+            read(response.deserialize().unwrap());
+        };
+
+        tokio::join!(server.serve_channel(listener), client_calls);
+    }
+
+    #[tokio::test]
+    async fn raw_response_deserialised_as_view() {
+        read_manifest_view(TransportWireConfig::pickle(), |view| {
+            assert_eq!("Black Pearl", view.ship);
+            assert_eq!(vec!["rum", "parrots"], view.cargo);
+        })
+        .await;
+        // Pickle copies strings out, but postcard can leave them where they are
+        #[cfg(feature = "transport_postcard")]
+        read_manifest_view(TransportWireConfig::Postcard, |view| {
+            assert!(matches!(view.ship, Cow::Borrowed("Black Pearl")));
+        })
+        .await;
+    }
 
     #[tokio::test]
     async fn client_test() {
@@ -73,4 +686,55 @@ mod tests {
 
         assert_eq!(String::from("Foo-Bar"), result);
     }
+
+    #[tokio::test]
+    async fn typed_rpc_test() {
+        let internal_transport = CannedTestingTransport {
+            always_respond_with: "Foo-Bar".to_string(),
+            receive_times: 0,
+        };
+        let mut transport = Transport::new(internal_transport, Default::default());
+
+        let hello_world: TypedRpc<_, String, String> = TypedRpc::new(HelloWorldRpcName::HelloWorld);
+
+        let result = hello_world
+            .call(&mut transport, "Foo".into())
+            .await
+            .unwrap();
+
+        assert_eq!(String::from("Foo-Bar"), result);
+    }
+
+    crate::typed_client! {
+        struct HelloWorldClient for HelloWorldRpcName {
+            call_hello_world(String) -> String = HelloWorldRpcName::HelloWorld,
+            call_get_i(()) -> usize = HelloWorldRpcName::GetI,
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_client_test() {
+        use crate::server::RpcServer;
+        use crate::tests::{make_get_i_rpc_impl, make_hello_world_rpc_impl, HelloWorldState};
+        use crate::transport::channel_listener;
+        use std::sync::{Arc, Mutex};
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let mut client = HelloWorldClient::new(transport);
+            let hello = client.call_hello_world("Foo".into()).await.unwrap();
+            (hello, client.call_get_i(()).await.unwrap())
+        };
+
+        let ((), (hello, i)) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!("Hello world: 3:\"Foo\"", hello);
+        assert_eq!(3, i);
+    }
 }
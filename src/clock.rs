@@ -0,0 +1,59 @@
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of the current time for anything that measures durations, like slow handler
+/// detection and timeout reporting. Swap in a [MockClock] to drive these with virtual time in
+/// tests or replays, instead of real delays
+pub trait Clock: Send + Sync + Debug {
+    fn now(&self) -> Instant;
+    fn system_time(&self) -> SystemTime;
+}
+
+/// The real clock, what everything uses by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A [Clock] that starts at the real time when created, and then only moves when [advance]d
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<(Instant, SystemTime)>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new((Instant::now(), SystemTime::now())),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+    fn system_time(&self) -> SystemTime {
+        self.now.lock().unwrap().1
+    }
+}
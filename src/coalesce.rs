@@ -0,0 +1,234 @@
+use std::time::{Duration, Instant};
+
+use crate::OwnedBytes;
+
+/// Holds notifications back to write a burst of them together, see
+/// [crate::TransportConfig::coalesce_notifications], as each written on its own costs a
+/// syscall and, over TCP, likely a packet. Those held back are sent once they add up to
+/// [max_bytes], once the first has waited [linger], before anything else is sent on the
+/// transport, or when [crate::Transport::flush] is called. [linger] is checked as each
+/// notification is made, and by [crate::Transport::linger], which a client should `select!` on
+/// so the end of a burst isn't held back until the next send.
+/// Coalescing so adds up to [linger] of latency to each notification. They still arrive in
+/// the order they were made, but any held back when the transport's dropped are lost, so
+/// flush it first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoalesceConfig {
+    pub max_bytes: usize,
+    pub linger: Duration,
+}
+
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 16 * 1024,
+            linger: Duration::from_millis(5),
+        }
+    }
+}
+
+/// Notification packages a [crate::Transport] is holding back, see [CoalesceConfig]
+#[derive(Default)]
+pub(crate) struct CoalescedSends {
+    packages: Vec<OwnedBytes>,
+    num_bytes: usize,
+    /// When the first of [packages] was held back
+    since: Option<Instant>,
+}
+
+impl CoalescedSends {
+    pub(crate) fn push(&mut self, package_bytes: OwnedBytes, now: Instant) {
+        self.num_bytes += package_bytes.len();
+        self.packages.push(package_bytes);
+        self.since.get_or_insert(now);
+    }
+
+    /// Whether those held back should be sent now, as they've filled [config]'s
+    /// [CoalesceConfig::max_bytes] or waited its [CoalesceConfig::linger]
+    pub(crate) fn is_due(&self, config: &CoalesceConfig, now: Instant) -> bool {
+        self.num_bytes >= config.max_bytes
+            || self
+                .since
+                .is_some_and(|since| now.saturating_duration_since(since) >= config.linger)
+    }
+
+    pub(crate) fn since(&self) -> Option<Instant> {
+        self.since
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    pub(crate) fn take(&mut self) -> Vec<OwnedBytes> {
+        self.num_bytes = 0;
+        self.since = None;
+        std::mem::take(&mut self.packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{NotificationClient, RpcClient};
+    use crate::core::{NotificationRpc, NotificationRpcImpl};
+    use crate::keepalive::{PING, PONG};
+    use crate::listener::TcpListener;
+    use crate::metadata::NO_METADATA;
+    use crate::server::RpcServer;
+    use crate::tests::{make_get_i_rpc, make_get_i_rpc_impl, HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{PackageKind, QueuedTestingTransport, Transport, TransportConfig};
+    use std::sync::{Arc, Mutex};
+
+    fn coalescing(config: CoalesceConfig) -> TransportConfig {
+        TransportConfig {
+            coalesce_notifications: Some(config),
+            ..Default::default()
+        }
+    }
+
+    fn held_back_for_an_hour() -> TransportConfig {
+        coalescing(CoalesceConfig {
+            max_bytes: usize::MAX,
+            linger: Duration::from_secs(3600),
+        })
+    }
+
+    fn incr_i() -> NotificationClient<HelloWorldRpcName, usize> {
+        NotificationClient::new(NotificationRpc::new(HelloWorldRpcName::IncrI))
+    }
+
+    #[tokio::test]
+    async fn ten_quick_notifications_sent_together_in_order() {
+        let mut client: Transport<_, HelloWorldRpcName> =
+            Transport::new(QueuedTestingTransport::default(), held_back_for_an_hour());
+        for i in 0..10 {
+            incr_i().notify(i, &mut client).await.unwrap();
+        }
+        assert!(client.internal_transport().sent.is_empty());
+        client.flush().await.unwrap();
+        assert_eq!(1, client.internal_transport().sends);
+
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: client.internal_transport().sent.clone().into(),
+                ..Default::default()
+            },
+            TransportConfig::default(),
+        );
+        for i in 0..10 {
+            let received = server.receive_query().await.unwrap();
+            assert_eq!(HelloWorldRpcName::IncrI, received.name);
+            let query: usize = server
+                .config
+                .wire_config
+                .deserialize(&received.query_bytes)
+                .unwrap();
+            assert_eq!(i, query);
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_sent_once_max_bytes_held_back() {
+        let package_len = TransportConfig::default()
+            .encode_package_parts(
+                &HelloWorldRpcName::IncrI,
+                PackageKind::Notification,
+                &TransportConfig::default()
+                    .wire_config
+                    .serialize(&1usize)
+                    .unwrap(),
+                0,
+                None,
+                NO_METADATA,
+            )
+            .unwrap()
+            .len();
+        let config = coalescing(CoalesceConfig {
+            max_bytes: 3 * package_len,
+            linger: Duration::from_secs(3600),
+        });
+        let mut client: Transport<_, HelloWorldRpcName> =
+            Transport::new(QueuedTestingTransport::default(), config);
+        for _ in 0..10 {
+            incr_i().notify(1, &mut client).await.unwrap();
+        }
+        assert_eq!(3, client.internal_transport().sends);
+        assert_eq!(9, client.internal_transport().sent.len());
+    }
+
+    #[tokio::test]
+    async fn notifications_sent_after_linger() {
+        let config = coalescing(CoalesceConfig {
+            max_bytes: usize::MAX,
+            linger: Duration::from_millis(20),
+        });
+        let mut client: Transport<_, HelloWorldRpcName> =
+            Transport::new(QueuedTestingTransport::default(), config);
+        incr_i().notify(1, &mut client).await.unwrap();
+        assert!(client.internal_transport().sent.is_empty());
+        tokio::time::timeout(Duration::from_secs(1), client.linger())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, client.internal_transport().sent.len());
+        // With nothing held back, there's nothing to wait for
+        let waited = tokio::time::timeout(Duration::from_millis(50), client.linger()).await;
+        assert!(waited.is_err());
+    }
+
+    #[tokio::test]
+    async fn notifications_held_back_sent_before_anything_else() {
+        let mut client: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![PONG.to_vec()].into(),
+                ..Default::default()
+            },
+            held_back_for_an_hour(),
+        );
+        incr_i().notify(1, &mut client).await.unwrap();
+        incr_i().notify(2, &mut client).await.unwrap();
+        client.ping().await.unwrap();
+        let sent = &client.internal_transport().sent;
+        assert_eq!(3, sent.len());
+        assert_eq!(PING, sent[2]);
+    }
+
+    #[tokio::test]
+    async fn coalesced_notifications_handled_in_order_over_tcp() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 0 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        // Appends each digit, so they must be handled in the order sent to add up
+        server.add_rpc(Box::new(NotificationRpcImpl::new(
+            HelloWorldRpcName::IncrI,
+            Box::new(|state: &mut HelloWorldState, digit: usize| {
+                state.i = state.i * 10 + digit;
+                Ok(())
+            }),
+        )));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                tokio::net::TcpStream::connect(addr)
+                    .await
+                    .map(crate::transport::TcpTransport::new)
+                    .unwrap(),
+                held_back_for_an_hour(),
+            );
+            for digit in 1..=9 {
+                incr_i().notify(digit, &mut transport).await.unwrap();
+            }
+            let i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            shutdown.shutdown();
+            i
+        };
+
+        let ((), i) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert_eq!(123456789, i.unwrap());
+    }
+}
@@ -0,0 +1,145 @@
+use crate::transport::TransportError;
+use crate::{Bytes, OwnedBytes};
+
+/// Hands the codec's deserialiser over, for [WireCodec::deserialize] to call once it's made one
+pub type DeserializeSeed<'s, 'a> =
+    dyn FnMut(&mut dyn erased_serde::Deserializer<'a>) -> Result<(), TransportError> + 's;
+
+/// A wire format of your own, for when none of the built in [crate::TransportWireConfig]
+/// codecs suit. Plug it in with [crate::TransportWireConfig::Custom].
+/// Values are passed through [erased_serde] so the trait can be used as a `dyn WireCodec`, but
+/// any serde format crate can be wrapped:
+///
+/// ```rust,ignore
+/// #[derive(Debug)]
+/// struct Json;
+/// impl WireCodec for Json {
+///     fn name(&self) -> &'static str {
+///         "my_json"
+///     }
+///     fn serialize(
+///         &self,
+///         val: &dyn erased_serde::Serialize,
+///     ) -> Result<OwnedBytes, TransportError> {
+///         serde_json::to_vec(val).map_err(|e| TransportError::SerialiseError(format!("{}", e)))
+///     }
+///     fn deserialize<'a>(
+///         &self,
+///         bytes: Bytes<'a>,
+///         seed: &mut DeserializeSeed<'_, 'a>,
+///     ) -> Result<(), TransportError> {
+///         let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+///         seed(&mut <dyn erased_serde::Deserializer>::erase(&mut deserializer))?;
+///         deserializer.end().map_err(|e| TransportError::DeserialiseError(format!("{}", e)))
+///     }
+/// }
+/// ```
+pub trait WireCodec: Send + Sync + std::fmt::Debug {
+    /// Short name of the codec, see [crate::TransportWireConfig::wire_config_name]
+    fn name(&self) -> &'static str;
+
+    fn serialize(&self, val: &dyn erased_serde::Serialize) -> Result<OwnedBytes, TransportError>;
+
+    /// Make a deserialiser reading [bytes] and pass it to [seed], which deserialises the value
+    /// wanted from it. Check for trailing bytes after, if the format can
+    fn deserialize<'a>(
+        &self,
+        bytes: Bytes<'a>,
+        seed: &mut DeserializeSeed<'_, 'a>,
+    ) -> Result<(), TransportError>;
+
+    /// Whether the deserialiser can hand out byte fields borrowing straight from the input, as
+    /// with the Postcard codec. If so, received queries are decoded without copying the name
+    fn supports_borrowed_bytes(&self) -> bool {
+        false
+    }
+
+    /// Byte identifying packages in this codec, for [crate::TransportConfig::tag_wire_format].
+    /// The built in codecs use tags below 128, so pick one from 128 up. Without a tag the
+    /// codec can't be used with tagging on
+    fn tag(&self) -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::server::RpcServer;
+    use crate::tests::{make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig, TransportWireConfig};
+    use std::sync::{Arc, Mutex};
+
+    /// Pickle behind a magic number, standing in for an in-house format
+    #[derive(Debug)]
+    struct MagicPickle;
+
+    const MAGIC: &[u8] = b"ARR!";
+
+    impl WireCodec for MagicPickle {
+        fn name(&self) -> &'static str {
+            "magic_pickle"
+        }
+
+        fn serialize(
+            &self,
+            val: &dyn erased_serde::Serialize,
+        ) -> Result<OwnedBytes, TransportError> {
+            let mut bytes = MAGIC.to_vec();
+            serde_pickle::to_writer(&mut bytes, &val, serde_pickle::SerOptions::new())
+                .map_err(|e| TransportError::SerialiseError(format!("{}", e)))?;
+            Ok(bytes)
+        }
+
+        fn deserialize<'a>(
+            &self,
+            bytes: Bytes<'a>,
+            seed: &mut DeserializeSeed<'_, 'a>,
+        ) -> Result<(), TransportError> {
+            let pickled = bytes.strip_prefix(MAGIC).ok_or_else(|| {
+                TransportError::DeserialiseError(String::from("missing magic number"))
+            })?;
+            let mut deserializer =
+                serde_pickle::Deserializer::new(pickled, serde_pickle::DeOptions::new());
+            seed(&mut <dyn erased_serde::Deserializer>::erase(
+                &mut deserializer,
+            ))?;
+            deserializer
+                .end()
+                .map_err(|e| TransportError::DeserialiseError(format!("{}", e)))
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_codec_server_round_trip() {
+        let wire_config = TransportWireConfig::Custom(Arc::new(MagicPickle));
+        assert_eq!("magic_pickle", wire_config.wire_config_name());
+        let query_bytes = wire_config.serialize(&String::from("Foo")).unwrap();
+        assert!(query_bytes.starts_with(MAGIC));
+        let query: String = wire_config.deserialize(&query_bytes).unwrap();
+        assert_eq!("Foo", query);
+        assert!(matches!(
+            wire_config.deserialize::<String>(&query_bytes[1..]),
+            Err(TransportError::DeserialiseError(_))
+        ));
+
+        let config = TransportConfig {
+            wire_config,
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, config.clone());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let client_call = async move {
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            RpcClient::new(make_hello_world_rpc())
+                .call("custom".into(), &mut transport)
+                .await
+        };
+
+        let ((), hello) = tokio::join!(server.serve_channel(listener), client_call);
+        assert_eq!("Hello world: 3:\"custom\"", hello.unwrap());
+    }
+}
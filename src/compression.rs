@@ -0,0 +1,252 @@
+use std::borrow::Cow;
+
+use crate::transport::TransportError;
+use crate::{Bytes, OwnedBytes};
+
+/// Flag byte ahead of each package, saying how the rest is compressed
+const UNCOMPRESSED: u8 = 0;
+const GZIP: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// Algorithms available for [CompressionConfig], each enabled by its own feature
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    #[cfg(feature = "compression_gzip")]
+    Gzip,
+    #[cfg(feature = "compression_zstd")]
+    Zstd,
+}
+
+/// CompressionConfig turns on compression of packages in both directions, see
+/// [crate::TransportConfig::compression]. Each package is sent with a leading flag byte saying
+/// how it's compressed, so client and server must both have compression configured, though
+/// each can pick its own [algorithm] so long as the other has that algorithm's feature enabled.
+/// Packages of fewer than [threshold] bytes are sent uncompressed, as compressing small
+/// packages costs more time than it saves in bandwidth
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    pub algorithm: CompressionAlgorithm,
+    pub threshold: usize,
+}
+
+impl CompressionConfig {
+    pub fn new(algorithm: CompressionAlgorithm) -> Self {
+        Self {
+            algorithm,
+            threshold: 1024,
+        }
+    }
+}
+
+#[cfg(any(feature = "compression_gzip", feature = "compression_zstd"))]
+fn compress_error(e: std::io::Error) -> TransportError {
+    TransportError::SerialiseError(format!("compression: {}", e))
+}
+
+#[cfg(any(feature = "compression_gzip", feature = "compression_zstd"))]
+fn decompress_error(e: std::io::Error) -> TransportError {
+    TransportError::DeserialiseError(format!("decompression: {}", e))
+}
+
+/// Read all of [decoder], failing with [TransportError::MessageTooLarge] once it's given more
+/// than [max_size], rather than reading on however much a small package inflates to
+#[cfg(any(feature = "compression_gzip", feature = "compression_zstd"))]
+fn read_limited(
+    decoder: impl std::io::Read,
+    max_size: Option<usize>,
+) -> Result<OwnedBytes, TransportError> {
+    use std::io::Read;
+    let mut decompressed = Vec::new();
+    match max_size {
+        Some(max_size) => {
+            decoder
+                .take(max_size as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(decompress_error)?;
+            if decompressed.len() > max_size {
+                return Err(TransportError::MessageTooLarge(
+                    decompressed.len(),
+                    max_size,
+                ));
+            }
+        }
+        None => {
+            let mut decoder = decoder;
+            decoder
+                .read_to_end(&mut decompressed)
+                .map_err(decompress_error)?;
+        }
+    }
+    Ok(decompressed)
+}
+
+#[cfg(not(all(feature = "compression_gzip", feature = "compression_zstd")))]
+fn not_enabled(algorithm: &str) -> TransportError {
+    TransportError::DeserialiseError(format!(
+        "package compressed with {}, which needs the compression_{} feature",
+        algorithm, algorithm
+    ))
+}
+
+/// Compress [package_bytes] per [config], behind the flag byte. Nothing is added without a config
+pub(crate) fn compress(
+    config: Option<&CompressionConfig>,
+    mut package_bytes: OwnedBytes,
+) -> Result<OwnedBytes, TransportError> {
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(package_bytes),
+    };
+    if package_bytes.len() < config.threshold {
+        package_bytes.insert(0, UNCOMPRESSED);
+        return Ok(package_bytes);
+    }
+    match config.algorithm {
+        #[cfg(feature = "compression_gzip")]
+        CompressionAlgorithm::Gzip => {
+            use std::io::Write;
+            let mut encoder =
+                flate2::write::GzEncoder::new(vec![GZIP], flate2::Compression::default());
+            encoder.write_all(&package_bytes).map_err(compress_error)?;
+            encoder.finish().map_err(compress_error)
+        }
+        #[cfg(feature = "compression_zstd")]
+        CompressionAlgorithm::Zstd => {
+            let mut compressed = vec![ZSTD];
+            zstd::stream::copy_encode(&package_bytes[..], &mut compressed, 0)
+                .map_err(compress_error)?;
+            Ok(compressed)
+        }
+    }
+}
+
+/// Undo [compress], borrowing the package from [bytes] if it wasn't compressed
+pub(crate) fn decompress<'a>(
+    config: Option<&CompressionConfig>,
+    bytes: Bytes<'a>,
+    #[cfg_attr(
+        not(any(feature = "compression_gzip", feature = "compression_zstd")),
+        allow(unused_variables)
+    )]
+    max_size: Option<usize>,
+) -> Result<Cow<'a, [u8]>, TransportError> {
+    if config.is_none() {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    let (flag, compressed) = bytes.split_first().ok_or_else(|| {
+        TransportError::DeserialiseError(String::from("missing compression flag"))
+    })?;
+    match *flag {
+        UNCOMPRESSED => Ok(Cow::Borrowed(compressed)),
+        #[cfg(feature = "compression_gzip")]
+        GZIP => read_limited(flate2::read::GzDecoder::new(compressed), max_size).map(Cow::Owned),
+        #[cfg(feature = "compression_zstd")]
+        ZSTD => {
+            let decoder = zstd::stream::read::Decoder::new(compressed).map_err(decompress_error)?;
+            read_limited(decoder, max_size).map(Cow::Owned)
+        }
+        #[cfg(not(feature = "compression_gzip"))]
+        GZIP => Err(not_enabled("gzip")),
+        #[cfg(not(feature = "compression_zstd"))]
+        ZSTD => Err(not_enabled("zstd")),
+        flag => Err(TransportError::DeserialiseError(format!(
+            "unknown compression flag {}",
+            flag
+        ))),
+    }
+}
+
+/// As [decompress], but for an owned package
+pub(crate) fn decompress_owned(
+    config: Option<&CompressionConfig>,
+    mut bytes: OwnedBytes,
+    max_size: Option<usize>,
+) -> Result<OwnedBytes, TransportError> {
+    match bytes.first() {
+        Some(&UNCOMPRESSED) if config.is_some() => {
+            bytes.remove(0);
+            Ok(bytes)
+        }
+        _ => decompress(config, &bytes, max_size).map(Cow::into_owned),
+    }
+}
+
+#[cfg(all(test, any(feature = "compression_gzip", feature = "compression_zstd")))]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::server::RpcServer;
+    use crate::tests::{make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::{Arc, Mutex};
+
+    fn algorithms() -> Vec<CompressionAlgorithm> {
+        vec![
+            #[cfg(feature = "compression_gzip")]
+            CompressionAlgorithm::Gzip,
+            #[cfg(feature = "compression_zstd")]
+            CompressionAlgorithm::Zstd,
+        ]
+    }
+
+    #[test]
+    fn compressed_above_threshold() {
+        for algorithm in algorithms() {
+            let config = CompressionConfig::new(algorithm);
+            let small = b"Arr".to_vec();
+            let compressed_small = compress(Some(&config), small.clone()).unwrap();
+            assert_eq!(UNCOMPRESSED, compressed_small[0]);
+            assert_eq!(
+                small,
+                decompress_owned(Some(&config), compressed_small, None).unwrap()
+            );
+
+            let large = "Arr".repeat(10_000).into_bytes();
+            let compressed_large = compress(Some(&config), large.clone()).unwrap();
+            assert!(compressed_large.len() < large.len() / 10, "{:?}", algorithm);
+            assert_eq!(
+                large,
+                decompress(Some(&config), &compressed_large, Some(large.len()))
+                    .unwrap()
+                    .as_ref()
+            );
+            // However small it is compressed, it can't inflate past the limit
+            assert!(matches!(
+                decompress(Some(&config), &compressed_large, Some(1000)),
+                Err(TransportError::MessageTooLarge(1001, 1000))
+            ));
+        }
+        assert!(matches!(
+            decompress(
+                Some(&CompressionConfig::new(algorithms()[0])),
+                &[9, 1, 2],
+                None
+            ),
+            Err(TransportError::DeserialiseError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn large_query_round_trip_compressed() {
+        let config = TransportConfig {
+            compression: Some(CompressionConfig::new(algorithms()[0])),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, config.clone());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let query = "Arr".repeat(10_000);
+        let expected = format!("Hello world: 3:{:?}", query);
+        let client_call = async move {
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            RpcClient::new(make_hello_world_rpc())
+                .call(query, &mut transport)
+                .await
+        };
+
+        let ((), hello) = tokio::join!(server.serve_channel(listener), client_call);
+        assert_eq!(expected, hello.unwrap());
+    }
+}
@@ -0,0 +1,156 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use log::debug;
+
+use crate::transport::{connect_tcp, TcpTransport, TransportError};
+
+/// How [TcpTransport::connect_with] tries the addresses a host name resolves to. They're
+/// tried in turn, alternating IPv6 and IPv4, with each attempt given [attempt_delay] before
+/// the next starts alongside it, so an address that's unreachable, e.g. IPv6 on a network
+/// without it, holds up connecting by no more than that (Happy Eyeballs, RFC 8305). The first
+/// to connect wins and the rest are dropped. An attempt failing starts the next straight
+/// away, and one taking over [attempt_timeout] fails
+#[derive(Clone, Debug)]
+pub struct TcpConnectConfig {
+    pub attempt_delay: Duration,
+    pub attempt_timeout: Option<Duration>,
+}
+
+impl Default for TcpConnectConfig {
+    fn default() -> Self {
+        Self {
+            attempt_delay: Duration::from_millis(250),
+            attempt_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl TcpTransport {
+    /// Resolve [host], a name or IP address, and connect to it on [port], trying each of its
+    /// addresses per [TcpConnectConfig::default]
+    pub async fn connect(host: &str, port: u16) -> Result<Self, TransportError> {
+        Self::connect_with(host, port, &TcpConnectConfig::default()).await
+    }
+
+    /// As [connect], trying addresses per [config]
+    pub async fn connect_with(
+        host: &str,
+        port: u16,
+        config: &TcpConnectConfig,
+    ) -> Result<Self, TransportError> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| TransportError::ConnectError(format!("Resolving {}: {}", host, e)))?
+            .collect();
+        connect_to_any(addrs, config)
+            .await
+            .map(TcpTransport::new)
+            .map_err(|e| TransportError::ConnectError(format!("Connecting to {}: {}", host, e)))
+    }
+}
+
+/// Connect to whichever of [addrs] connects first, per [config]
+pub(crate) async fn connect_to_any(
+    addrs: Vec<SocketAddr>,
+    config: &TcpConnectConfig,
+) -> Result<tokio::net::TcpStream, TransportError> {
+    let mut addrs = interleave_families(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if let Some(addr) = addrs.next() {
+            debug!("Connecting to {}", addr);
+            attempts.push(async move {
+                connect_tcp(addr, config.attempt_timeout)
+                    .await
+                    .map_err(|e| (addr, e))
+            });
+        }
+        let attempted = if addrs.len() > 0 {
+            match tokio::time::timeout(config.attempt_delay, attempts.next()).await {
+                Ok(attempted) => attempted,
+                // Still going, so start the next alongside it
+                Err(_) => continue,
+            }
+        } else {
+            attempts.next().await
+        };
+        match attempted {
+            Some(Ok(stream)) => return Ok(stream),
+            Some(Err((addr, e))) => {
+                debug!("Connecting to {} failed: {}", addr, e);
+                last_error = Some(e);
+            }
+            None => {
+                return Err(last_error.unwrap_or_else(|| {
+                    TransportError::ConnectError(String::from("No addresses to connect to"))
+                }))
+            }
+        }
+    }
+}
+
+/// [addrs] alternating between families, starting with that of the first, and otherwise in
+/// the order given
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::InternalTransport;
+
+    #[test]
+    fn families_interleaved() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let interleaved: Vec<String> = interleave_families(addrs)
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        assert_eq!(
+            vec!["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"],
+            interleaved
+        );
+    }
+
+    #[tokio::test]
+    async fn connects_to_address_that_answers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        let dead = {
+            let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            closed.local_addr().unwrap()
+        };
+        let config = TcpConnectConfig::default();
+        let stream = connect_to_any(vec![dead, live], &config).await.unwrap();
+        assert_eq!(live, stream.peer_addr().unwrap());
+        match connect_to_any(vec![dead], &config).await {
+            Err(TransportError::ConnectError(_)) => (),
+            other => panic!("Expected ConnectError, got {:?}", other),
+        }
+
+        // Whichever addresses localhost has, one of them answers
+        let transport = TcpTransport::connect("localhost", live.port())
+            .await
+            .unwrap();
+        assert_eq!(Some(live), transport.peer_addr());
+    }
+}
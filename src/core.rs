@@ -1,28 +1,59 @@
 use std::any::Any;
 
-use crate::error::RpcResult;
+use crate::call_context::{call_context, CallContext};
+use crate::error::{RpcError, RpcResult};
+use crate::extensions::{call_extension, Extension};
+use crate::priority::Priority;
+use crate::reflection::{RpcKind, RpcTypes};
+use crate::schema::DeclaredRpc;
 use crate::transport::TransportWireConfig;
 use crate::{Bytes, OwnedBytes};
+use futures_util::future::LocalBoxFuture;
+use futures_util::{Stream, StreamExt};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
+use std::future::Future;
 use std::hash::Hash;
 use std::marker::PhantomData;
-
-pub trait ToFromBytes {
-    fn to_bytes(&self) -> RpcResult<OwnedBytes>;
-    fn of_bytes(b: Bytes) -> RpcResult<Self>
-    where
-        Self: Sized;
-}
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
 
 pub trait RpcType: Any + Serialize + for<'de> Deserialize<'de> + Clone {}
 
-pub trait RpcName: PartialEq + Eq + Hash + Serialize + DeserializeOwned + Display + Clone {}
+pub trait RpcName:
+    PartialEq + Eq + Hash + Serialize + DeserializeOwned + Display + Clone + Send + Sync
+{
+    /// Compact 2 byte id for this rpc, sent instead of the serialised name with
+    /// [crate::NameEncoding::Opcode]. Must be unique per rpc and round-trip through [from_opcode].
+    /// Rpcs without one can't be sent with that encoding
+    fn opcode(&self) -> Option<u16> {
+        None
+    }
+    fn from_opcode(_opcode: u16) -> Option<Self> {
+        None
+    }
+    /// Parse the name back from its [Display] form, as sent with
+    /// [crate::NameEncoding::Utf8String]. If the type implements [std::str::FromStr] to match
+    /// [Display] this is just `name.parse().ok()`
+    fn from_utf8_name(_name: &str) -> Option<Self> {
+        None
+    }
+}
 
+/// A unary rpc, answering one query with one response. [idempotent] says calling it more than
+/// once with the same query has the same effect as once, so it's safe to retry, see
+/// [crate::RetryPolicy]. [priority] says how urgent its calls are, see [Priority].
+/// [cache_ttl] is how long its responses may be kept by a [crate::ResponseCache]
 #[derive(Clone)]
 pub struct Rpc<Name, Q: RpcType, R: RpcType> {
     pub name: Name,
+    pub idempotent: bool,
+    pub priority: Priority,
+    pub version: u32,
+    pub cache_ttl: Option<Duration>,
     _query_phantom: PhantomData<Q>,
     _response_phantom: PhantomData<R>,
 }
@@ -31,13 +62,44 @@ impl<Name: RpcName, Q: RpcType, R: RpcType> Rpc<Name, Q, R> {
     pub fn new(name: Name) -> Self {
         Self {
             name,
+            idempotent: false,
+            priority: Priority::default(),
+            version: 0,
+            cache_ttl: None,
             _query_phantom: PhantomData,
             _response_phantom: PhantomData,
         }
     }
+
+    /// Mark the rpc [idempotent]
+    pub fn idempotent(mut self) -> Self {
+        self.idempotent = true;
+        self
+    }
+
+    /// Give the rpc's calls [priority]
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Declare the rpc's [version], see [crate::Schema]
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Let a [crate::ResponseCache] keep the rpc's responses for [ttl]
+    pub fn cache_for(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = Some(ttl);
+        self
+    }
 }
 
 type Implementation<State, Q, R> = Box<dyn Fn(&mut State, Q) -> RpcResult<R>>;
+type ExtensionImplementation<State, T, Q, R> =
+    Box<dyn Fn(&mut State, Extension<T>, Q) -> RpcResult<R>>;
+type ContextImplementation<State, Q, R> = Box<dyn Fn(&mut State, CallContext, Q) -> RpcResult<R>>;
 
 pub struct RpcImpl<Name: RpcName, State, Q: RpcType, R: RpcType> {
     pub rpc: Rpc<Name, Q, R>,
@@ -52,19 +114,43 @@ impl<Name: RpcName, State, Q: RpcType, R: RpcType> RpcImpl<Name, State, Q, R> {
         }
     }
 
-    /*
-    fn query_of_bytes(&self, b: Bytes) -> RpcResult<Q> {
-        Q::of_bytes(b)
-    }
-     */
     fn call(&self, state: &mut State, q: Q) -> RpcResult<R> {
         (self.call)(state, q)
     }
-    /*
-    fn result_to_bytes(&self, r: R) -> RpcResult<OwnedBytes> {
-        R::to_bytes(&r)
+}
+
+impl<Name: RpcName, State: 'static, Q: RpcType, R: RpcType> RpcImpl<Name, State, Q, R> {
+    /// As [new], but handing [call] the server's extension of type [T] too, see
+    /// [crate::RpcServer::add_extension]. The call fails if the server has none
+    pub fn with_extension<T: Send + Sync + 'static>(
+        name: Name,
+        call: ExtensionImplementation<State, T, Q, R>,
+    ) -> Self {
+        Self::new(
+            name,
+            Box::new(move |state, query| {
+                let extension = call_extension::<T>().ok_or_else(|| {
+                    RpcError::Custom(format!(
+                        "Server has no extension {}",
+                        std::any::type_name::<T>()
+                    ))
+                })?;
+                call(state, Extension(extension), query)
+            }),
+        )
+    }
+
+    /// As [new], but handing [call] the [CallContext] of each call too, saying who made it
+    pub fn with_context(name: Name, call: ContextImplementation<State, Q, R>) -> Self {
+        Self::new(
+            name,
+            Box::new(move |state, query| {
+                let context = call_context()
+                    .ok_or_else(|| RpcError::Custom(String::from("Called outside of a server")))?;
+                call(state, context, query)
+            }),
+        )
     }
-     */
 }
 
 pub trait StoredRpc<State, Name: RpcName> {
@@ -75,6 +161,13 @@ pub trait StoredRpc<State, Name: RpcName> {
         state: &mut State,
     ) -> RpcResult<OwnedBytes>;
     fn rpc_name(&self) -> Name;
+    /// The query and response types, for [crate::ServerDescription]s
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        None
+    }
+    fn rpc_kind(&self) -> RpcKind {
+        RpcKind::Unary
+    }
 }
 
 impl<Name: RpcName, State, Q: RpcType, R: RpcType> StoredRpc<State, Name>
@@ -86,13 +179,508 @@ impl<Name: RpcName, State, Q: RpcType, R: RpcType> StoredRpc<State, Name>
         transport_config: &TransportWireConfig,
         state: &mut State,
     ) -> RpcResult<OwnedBytes> {
-        let query = transport_config.deserialize(input_bytes)?;
+        let query = transport_config
+            .deserialize(input_bytes)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", self.rpc.name)))?;
         let result = self.call(state, query)?;
-        let result_bytes = transport_config.serialize(&result)?;
+        let result_bytes = transport_config
+            .serialize(&result)
+            .map_err(|e| e.in_step(format_args!("response for rpc {}", self.rpc.name)))?;
         Ok(result_bytes)
     }
 
     fn rpc_name(&self) -> Name {
         self.rpc.name.clone()
     }
+
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        Some(self.rpc.declared_types())
+    }
+}
+
+/// A one-way rpc, taking queries and sending nothing back, e.g. for telemetry or cache
+/// invalidation the caller needn't wait on. Call it with [crate::NotificationClient], which
+/// returns once the query is sent, so the caller doesn't hear whether it was handled, or if
+/// it failed
+#[derive(Clone)]
+pub struct NotificationRpc<Name, Q: RpcType> {
+    pub name: Name,
+    pub version: u32,
+    _query_phantom: PhantomData<Q>,
+}
+
+impl<Name: RpcName, Q: RpcType> NotificationRpc<Name, Q> {
+    pub fn new(name: Name) -> Self {
+        Self {
+            name,
+            version: 0,
+            _query_phantom: PhantomData,
+        }
+    }
+
+    /// Declare the rpc's [version], see [crate::Schema]
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+/// The server side of a [NotificationRpc]. Errors the handler gives are only logged
+pub struct NotificationRpcImpl<Name: RpcName, State, Q: RpcType> {
+    pub rpc: NotificationRpc<Name, Q>,
+    call: Implementation<State, Q, ()>,
+}
+
+impl<Name: RpcName, State, Q: RpcType> NotificationRpcImpl<Name, State, Q> {
+    pub fn new(name: Name, call: Implementation<State, Q, ()>) -> Self {
+        Self {
+            rpc: NotificationRpc::new(name),
+            call,
+        }
+    }
+}
+
+impl<Name: RpcName, State, Q: RpcType> StoredRpc<State, Name>
+    for NotificationRpcImpl<Name, State, Q>
+{
+    fn call_of_bytes(
+        &self,
+        input_bytes: Bytes,
+        transport_config: &TransportWireConfig,
+        state: &mut State,
+    ) -> RpcResult<OwnedBytes> {
+        let query = transport_config
+            .deserialize(input_bytes)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", self.rpc.name)))?;
+        (self.call)(state, query)?;
+        // Never sent, see [crate::PackageKind::Notification]
+        Ok(OwnedBytes::new())
+    }
+
+    fn rpc_name(&self) -> Name {
+        self.rpc.name.clone()
+    }
+
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        Some(self.rpc.declared_types())
+    }
+
+    fn rpc_kind(&self) -> RpcKind {
+        RpcKind::Notification
+    }
+}
+
+/// Handler of an [AsyncRpcImpl] only reading the context
+pub type ReadImplementation<C, Q, R> =
+    Box<dyn for<'c> Fn(&'c C, Q) -> LocalBoxFuture<'c, RpcResult<R>>>;
+/// Handler of an [AsyncRpcImpl] that can update the context
+pub type WriteImplementation<C, Q, R> =
+    Box<dyn for<'c> Fn(&'c mut C, Q) -> LocalBoxFuture<'c, RpcResult<R>>>;
+
+enum AsyncImplementation<C, Q, R> {
+    Read(ReadImplementation<C, Q, R>),
+    Write(WriteImplementation<C, Q, R>),
+}
+
+/// The server side of an [Rpc] with an async handler, which can await while borrowing
+/// [context] for the whole call, e.g. to query a database with a pool kept there. Rather than
+/// the server state, which is behind a blocking mutex and can't be held across an await, the
+/// handler gets a context of its own, shared with other async rpcs by cloning the [Arc].
+/// Calls [reading] it hold a read lock so can run at once, while calls [writing] it wait for
+/// the others to finish and hold it to themselves. As the handler's future borrows the
+/// context, write it as e.g. `Box::new(|names, query| Box::pin(async move { ... }))`
+pub struct AsyncRpcImpl<Name: RpcName, C, Q: RpcType, R: RpcType> {
+    pub rpc: Rpc<Name, Q, R>,
+    context: Arc<RwLock<C>>,
+    call: AsyncImplementation<C, Q, R>,
+}
+
+impl<Name: RpcName, C, Q: RpcType, R: RpcType> AsyncRpcImpl<Name, C, Q, R> {
+    pub fn reading(name: Name, context: Arc<RwLock<C>>, call: ReadImplementation<C, Q, R>) -> Self {
+        Self {
+            rpc: Rpc::new(name),
+            context,
+            call: AsyncImplementation::Read(call),
+        }
+    }
+
+    pub fn writing(
+        name: Name,
+        context: Arc<RwLock<C>>,
+        call: WriteImplementation<C, Q, R>,
+    ) -> Self {
+        Self {
+            rpc: Rpc::new(name),
+            context,
+            call: AsyncImplementation::Write(call),
+        }
+    }
+
+    async fn call(&self, query: Q) -> RpcResult<R> {
+        match &self.call {
+            AsyncImplementation::Read(call) => call(&*self.context.read().await, query).await,
+            AsyncImplementation::Write(call) => call(&mut *self.context.write().await, query).await,
+        }
+    }
+}
+
+pub trait StoredAsyncRpc<Name: RpcName> {
+    /// Deserialise the query from [bytes] and start the call, giving the future of the
+    /// serialised response
+    fn call_of_bytes<'a>(
+        &'a self,
+        bytes: Bytes,
+        wire_config: &TransportWireConfig,
+    ) -> RpcResult<LocalBoxFuture<'a, RpcResult<OwnedBytes>>>;
+    fn rpc_name(&self) -> Name;
+    /// See [StoredRpc::rpc_types]
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        None
+    }
+}
+
+impl<Name: RpcName, C, Q: RpcType, R: RpcType> StoredAsyncRpc<Name>
+    for AsyncRpcImpl<Name, C, Q, R>
+{
+    fn call_of_bytes<'a>(
+        &'a self,
+        bytes: Bytes,
+        wire_config: &TransportWireConfig,
+    ) -> RpcResult<LocalBoxFuture<'a, RpcResult<OwnedBytes>>> {
+        let query = wire_config
+            .deserialize(bytes)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", self.rpc.name)))?;
+        let wire_config = wire_config.clone();
+        Ok(Box::pin(async move {
+            let result = self.call(query).await?;
+            let result_bytes = wire_config
+                .serialize(&result)
+                .map_err(|e| e.in_step(format_args!("response for rpc {}", self.rpc.name)))?;
+            Ok(result_bytes)
+        }))
+    }
+
+    fn rpc_name(&self) -> Name {
+        self.rpc.name.clone()
+    }
+
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        Some(self.rpc.declared_types())
+    }
+}
+
+/// A server streaming rpc, answering one query with any number of responses, e.g. for tailing
+/// a log or reporting progress. Call it with [crate::StreamRpcClient]
+#[derive(Clone)]
+pub struct StreamRpc<Name, Q: RpcType, R: RpcType> {
+    pub name: Name,
+    pub version: u32,
+    _query_phantom: PhantomData<Q>,
+    _response_phantom: PhantomData<R>,
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> StreamRpc<Name, Q, R> {
+    pub fn new(name: Name) -> Self {
+        Self {
+            name,
+            version: 0,
+            _query_phantom: PhantomData,
+            _response_phantom: PhantomData,
+        }
+    }
+
+    /// Declare the rpc's [version], see [crate::Schema]
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+/// The responses of a [StreamRpcImpl] or [BidiStreamRpcImpl]. An error is sent to the client
+/// in place of a response and ends the stream
+pub type ResponseStream<R> = Pin<Box<dyn Stream<Item = RpcResult<R>> + Send>>;
+
+/// The queries a client streams to a [ClientStreamRpcImpl] or [BidiStreamRpcImpl], ending when
+/// the client ends its side of the call. A query that can't be deserialised is an error item
+pub type RequestStream<Q> = Pin<Box<dyn Stream<Item = RpcResult<Q>> + Send>>;
+
+/// The eventual single response of a [ClientStreamRpcImpl]
+pub type ResponseFuture<R> = Pin<Box<dyn Future<Output = RpcResult<R>> + Send>>;
+
+type StreamImplementation<State, Q, R> = Box<dyn Fn(&mut State, Q) -> RpcResult<ResponseStream<R>>>;
+
+/// The server side of a [StreamRpc]. The implementation only holds the state while it sets up
+/// the stream, so the stream must take anything it needs from the state with it, e.g. a
+/// receiver for new log lines
+pub struct StreamRpcImpl<Name: RpcName, State, Q: RpcType, R: RpcType> {
+    pub rpc: StreamRpc<Name, Q, R>,
+    call: StreamImplementation<State, Q, R>,
+}
+
+impl<Name: RpcName, State, Q: RpcType, R: RpcType> StreamRpcImpl<Name, State, Q, R> {
+    pub fn new(name: Name, call: StreamImplementation<State, Q, R>) -> Self {
+        Self {
+            rpc: StreamRpc::new(name),
+            call,
+        }
+    }
+
+    fn call(&self, state: &mut State, q: Q) -> RpcResult<ResponseStream<R>> {
+        (self.call)(state, q)
+    }
+}
+
+/// A client streaming rpc, where the client sends any number of queries, e.g. uploading a file
+/// in chunks, and the server answers once they're all in. Call it with
+/// [crate::ClientStreamRpcClient]
+#[derive(Clone)]
+pub struct ClientStreamRpc<Name, Q: RpcType, R: RpcType> {
+    pub name: Name,
+    pub version: u32,
+    _query_phantom: PhantomData<Q>,
+    _response_phantom: PhantomData<R>,
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> ClientStreamRpc<Name, Q, R> {
+    pub fn new(name: Name) -> Self {
+        Self {
+            name,
+            version: 0,
+            _query_phantom: PhantomData,
+            _response_phantom: PhantomData,
+        }
+    }
+
+    /// Declare the rpc's [version], see [crate::Schema]
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+type ClientStreamImplementation<State, Q, R> =
+    Box<dyn Fn(&mut State, RequestStream<Q>) -> RpcResult<ResponseFuture<R>>>;
+
+/// The server side of a [ClientStreamRpc]. As with [StreamRpcImpl] the state is only held
+/// while setting up, here the future consuming the queries
+pub struct ClientStreamRpcImpl<Name: RpcName, State, Q: RpcType, R: RpcType> {
+    pub rpc: ClientStreamRpc<Name, Q, R>,
+    call: ClientStreamImplementation<State, Q, R>,
+}
+
+impl<Name: RpcName, State, Q: RpcType, R: RpcType> ClientStreamRpcImpl<Name, State, Q, R> {
+    pub fn new(name: Name, call: ClientStreamImplementation<State, Q, R>) -> Self {
+        Self {
+            rpc: ClientStreamRpc::new(name),
+            call,
+        }
+    }
+
+    fn call(&self, state: &mut State, queries: RequestStream<Q>) -> RpcResult<ResponseFuture<R>> {
+        (self.call)(state, queries)
+    }
+}
+
+/// A bidirectional streaming rpc, where the client streams queries and the server streams
+/// responses back at the same time, e.g. for a chat session. Call it with
+/// [crate::BidiStreamRpcClient]
+#[derive(Clone)]
+pub struct BidiStreamRpc<Name, Q: RpcType, R: RpcType> {
+    pub name: Name,
+    pub version: u32,
+    _query_phantom: PhantomData<Q>,
+    _response_phantom: PhantomData<R>,
+}
+
+impl<Name: RpcName, Q: RpcType, R: RpcType> BidiStreamRpc<Name, Q, R> {
+    pub fn new(name: Name) -> Self {
+        Self {
+            name,
+            version: 0,
+            _query_phantom: PhantomData,
+            _response_phantom: PhantomData,
+        }
+    }
+
+    /// Declare the rpc's [version], see [crate::Schema]
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+type BidiStreamImplementation<State, Q, R> =
+    Box<dyn Fn(&mut State, RequestStream<Q>) -> RpcResult<ResponseStream<R>>>;
+
+/// The server side of a [BidiStreamRpc]. As with [StreamRpcImpl] the state is only held while
+/// setting up the response stream
+pub struct BidiStreamRpcImpl<Name: RpcName, State, Q: RpcType, R: RpcType> {
+    pub rpc: BidiStreamRpc<Name, Q, R>,
+    call: BidiStreamImplementation<State, Q, R>,
+}
+
+impl<Name: RpcName, State, Q: RpcType, R: RpcType> BidiStreamRpcImpl<Name, State, Q, R> {
+    pub fn new(name: Name, call: BidiStreamImplementation<State, Q, R>) -> Self {
+        Self {
+            rpc: BidiStreamRpc::new(name),
+            call,
+        }
+    }
+
+    fn call(&self, state: &mut State, queries: RequestStream<Q>) -> RpcResult<ResponseStream<R>> {
+        (self.call)(state, queries)
+    }
+}
+
+/// Type erased [StreamRpcImpl], [ClientStreamRpcImpl] or [BidiStreamRpcImpl], for the server to
+/// hold alongside [StoredRpc]s
+pub trait StoredStreamRpc<State, Name: RpcName> {
+    /// Set up the call from its opening [query_bytes], along with the [requests] the client
+    /// streams after it if [streams_requests]
+    fn stream_of_bytes(
+        &self,
+        query_bytes: Bytes,
+        requests: RequestStream<OwnedBytes>,
+        transport_config: &TransportWireConfig,
+        state: &mut State,
+    ) -> RpcResult<ResponseStream<OwnedBytes>>;
+    /// Whether the client streams queries after opening the call, rather than sending only the
+    /// opening one
+    fn streams_requests(&self) -> bool;
+    fn rpc_name(&self) -> Name;
+    /// See [StoredRpc::rpc_types]
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        None
+    }
+    fn rpc_kind(&self) -> RpcKind {
+        if self.streams_requests() {
+            RpcKind::BidiStream
+        } else {
+            RpcKind::ServerStream
+        }
+    }
+}
+
+fn decode_requests<Q: RpcType>(
+    requests: RequestStream<OwnedBytes>,
+    transport_config: &TransportWireConfig,
+    rpc_name: String,
+) -> RequestStream<Q> {
+    let transport_config = transport_config.clone();
+    Box::pin(requests.map(move |query_bytes| {
+        transport_config
+            .deserialize(&query_bytes?)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", rpc_name)).into())
+    }))
+}
+
+fn encode_responses<R: RpcType>(
+    responses: ResponseStream<R>,
+    transport_config: &TransportWireConfig,
+    rpc_name: String,
+) -> ResponseStream<OwnedBytes> {
+    let transport_config = transport_config.clone();
+    Box::pin(responses.map(move |response| {
+        transport_config.serialize(&response?).map_err(|e| {
+            e.in_step(format_args!("response for rpc {}", rpc_name))
+                .into()
+        })
+    }))
+}
+
+impl<Name: RpcName, State, Q: RpcType, R: RpcType> StoredStreamRpc<State, Name>
+    for StreamRpcImpl<Name, State, Q, R>
+{
+    fn stream_of_bytes(
+        &self,
+        query_bytes: Bytes,
+        _requests: RequestStream<OwnedBytes>,
+        transport_config: &TransportWireConfig,
+        state: &mut State,
+    ) -> RpcResult<ResponseStream<OwnedBytes>> {
+        let query = transport_config
+            .deserialize(query_bytes)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", self.rpc.name)))?;
+        let responses = self.call(state, query)?;
+        Ok(encode_responses(
+            responses,
+            transport_config,
+            format!("{}", self.rpc.name),
+        ))
+    }
+
+    fn streams_requests(&self) -> bool {
+        false
+    }
+
+    fn rpc_name(&self) -> Name {
+        self.rpc.name.clone()
+    }
+
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        Some(self.rpc.declared_types())
+    }
+}
+
+impl<Name: RpcName, State, Q: RpcType, R: RpcType> StoredStreamRpc<State, Name>
+    for ClientStreamRpcImpl<Name, State, Q, R>
+{
+    fn stream_of_bytes(
+        &self,
+        _query_bytes: Bytes,
+        requests: RequestStream<OwnedBytes>,
+        transport_config: &TransportWireConfig,
+        state: &mut State,
+    ) -> RpcResult<ResponseStream<OwnedBytes>> {
+        let rpc_name = format!("{}", self.rpc.name);
+        let queries = decode_requests(requests, transport_config, rpc_name.clone());
+        let response = self.call(state, queries)?;
+        let responses = Box::pin(futures_util::stream::once(response));
+        Ok(encode_responses(responses, transport_config, rpc_name))
+    }
+
+    fn streams_requests(&self) -> bool {
+        true
+    }
+
+    fn rpc_name(&self) -> Name {
+        self.rpc.name.clone()
+    }
+
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        Some(self.rpc.declared_types())
+    }
+
+    fn rpc_kind(&self) -> RpcKind {
+        RpcKind::ClientStream
+    }
+}
+
+impl<Name: RpcName, State, Q: RpcType, R: RpcType> StoredStreamRpc<State, Name>
+    for BidiStreamRpcImpl<Name, State, Q, R>
+{
+    fn stream_of_bytes(
+        &self,
+        _query_bytes: Bytes,
+        requests: RequestStream<OwnedBytes>,
+        transport_config: &TransportWireConfig,
+        state: &mut State,
+    ) -> RpcResult<ResponseStream<OwnedBytes>> {
+        let rpc_name = format!("{}", self.rpc.name);
+        let queries = decode_requests(requests, transport_config, rpc_name.clone());
+        let responses = self.call(state, queries)?;
+        Ok(encode_responses(responses, transport_config, rpc_name))
+    }
+
+    fn streams_requests(&self) -> bool {
+        true
+    }
+
+    fn rpc_name(&self) -> Name {
+        self.rpc.name.clone()
+    }
+
+    fn rpc_types(&self) -> Option<RpcTypes> {
+        Some(self.rpc.declared_types())
+    }
 }
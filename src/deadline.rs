@@ -0,0 +1,40 @@
+use std::time::Instant;
+
+use crate::call_context::read_call_context;
+
+/// When the caller of the rpc being handled will give up waiting for its response, if it said.
+/// A handler doing a lot of work can check this between steps and bail out early, rather than
+/// finish work nobody will see. It's measured by the server's [crate::TransportConfig::clock].
+/// For a streaming call it's when the caller gives up waiting for the first response, see
+/// [crate::TransportConfig::stream_item_timeout]. This is only set while the handler itself
+/// runs, so a streaming handler should read it up front for its stream to use
+pub fn call_deadline() -> Option<Instant> {
+    read_call_context(|context| context.deadline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::call_context::{with_call_context, CallContext};
+    use std::time::Duration;
+
+    #[test]
+    fn deadline_only_set_within_call() {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let context = CallContext {
+            deadline: Some(deadline),
+            ..Default::default()
+        };
+        assert_eq!(None, call_deadline());
+        let seen = with_call_context(&context, || {
+            let inner = with_call_context(&CallContext::default(), call_deadline);
+            (call_deadline(), inner)
+        });
+        assert_eq!((Some(deadline), None), seen);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            with_call_context(&context, || panic!("Handler failed"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(None, call_deadline());
+    }
+}
@@ -0,0 +1,78 @@
+use async_trait::async_trait;
+
+use crate::transport::TransportError;
+
+/// Where a [crate::BalancedClient] finds the servers to call, for deployments where they come
+/// and go, e.g. behind an orchestrator, so clients needn't be restarted when they move. The
+/// client resolves again every [crate::BalanceConfig::resolve_interval], or at once when
+/// [crate::BalancedClient::refresh]ed, e.g. by a resolver that's told of changes. See
+/// [StaticResolver] and [SrvResolver], or implement it for any other registry
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// The addresses of the servers now, as `host:port`, in order of preference. An error
+    /// leaves the client calling those it last knew of
+    async fn resolve(&self) -> Result<Vec<String>, TransportError>;
+}
+
+/// [Resolver] for servers that don't move
+#[derive(Clone, Debug)]
+pub struct StaticResolver {
+    addrs: Vec<String>,
+}
+
+impl StaticResolver {
+    pub fn new(addrs: &[&str]) -> Self {
+        Self {
+            addrs: addrs.iter().map(|addr| addr.to_string()).collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self) -> Result<Vec<String>, TransportError> {
+        Ok(self.addrs.clone())
+    }
+}
+
+/// [Resolver] looking up the DNS SRV records of [name], e.g. `_pirates._tcp.example.com`,
+/// with the system's DNS config. Servers are given in order of priority, then of weight
+#[cfg(feature = "discovery_dns")]
+pub struct SrvResolver {
+    name: String,
+    resolver: hickory_resolver::TokioResolver,
+}
+
+#[cfg(feature = "discovery_dns")]
+impl SrvResolver {
+    pub fn new(name: &str) -> Result<Self, TransportError> {
+        let resolver = hickory_resolver::TokioResolver::builder_tokio()
+            .map_err(|e| TransportError::ConnectError(format!("Reading DNS config: {}", e)))?
+            .build();
+        Ok(Self {
+            name: name.to_string(),
+            resolver,
+        })
+    }
+}
+
+#[cfg(feature = "discovery_dns")]
+#[async_trait]
+impl Resolver for SrvResolver {
+    async fn resolve(&self) -> Result<Vec<String>, TransportError> {
+        let lookup = self
+            .resolver
+            .srv_lookup(self.name.as_str())
+            .await
+            .map_err(|e| TransportError::ConnectError(format!("Resolving {}: {}", self.name, e)))?;
+        let mut records: Vec<_> = lookup.iter().collect();
+        records.sort_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())));
+        Ok(records
+            .iter()
+            .map(|srv| {
+                let target = srv.target().to_utf8();
+                format!("{}:{}", target.trim_end_matches('.'), srv.port())
+            })
+            .collect())
+    }
+}
@@ -1,12 +1,109 @@
 use crate::transport::TransportError;
+use crate::OwnedBytes;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Broad class of an [RpcError] or [TransportError], for deciding what to do about it without
+/// matching on every variant or on error text
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The peer couldn't be reached, or the connection to it broke
+    Unavailable,
+    /// No response came in time
+    DeadlineExceeded,
+    /// The caller gave up on the call
+    Cancelled,
+    /// The server doesn't serve the rpc
+    Unimplemented,
+    /// Bytes that couldn't be framed, serialised or deserialised
+    InvalidData,
+    /// A limit was hit, like a message size or rate limit
+    ResourceExhausted,
+    /// The peers disagree on protocol version or wire format
+    Incompatible,
+    Unauthenticated,
+    PermissionDenied,
+    /// The handler panicked or failed in some way it didn't describe with a type
+    Internal,
+    /// The handler failed with an [RpcErrorPayload]
+    Application,
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Whether trying the same call again might work. Only connection failures and timeouts are,
+    /// as the rest would fail the same way again. That doesn't make the call safe to repeat:
+    /// a timed out call may still have run on the server
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Unavailable | Self::DeadlineExceeded)
+    }
+}
+
+/// An application error from a handler, sent to the client intact so it can match on it, see
+/// [RpcError::application]. [message] is the error's [Display] form, for logging, and
+/// [details] the error itself, always pickled so it reads the same whatever the connection's
+/// wire format
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcErrorPayload {
+    pub message: String,
+    pub details: OwnedBytes,
+}
+
+impl RpcErrorPayload {
+    pub fn new<E: Serialize + Display>(error: &E) -> Result<Self, serde_pickle::Error> {
+        Ok(Self {
+            message: format!("{}", error),
+            details: serde_pickle::to_vec(error, serde_pickle::SerOptions::new())?,
+        })
+    }
+
+    /// The error sent, if it's an [E]
+    pub fn details<E: DeserializeOwned>(&self) -> Result<E, serde_pickle::Error> {
+        serde_pickle::from_slice(&self.details, serde_pickle::DeOptions::new())
+    }
+}
 
 #[derive(Debug)]
 pub enum RpcError {
     ParseError(serde_pickle::error::Error),
     TransportError(TransportError),
+    UnknownRpc(String),
+    /// The named rpc got no response within the receive timeout, after the given [Duration]
+    RpcTimeout(String, Duration),
+    /// The rpc handler panicked, with the panic message if there was one
+    HandlerPanic(String),
+    /// The server failed to handle the query for some other reason, as described by it
+    Remote(String),
+    /// The named rpc was cancelled by the caller before it finished
+    Cancelled(String),
+    /// The server refused the query as the caller didn't prove who they are, see
+    /// [crate::Authentication]
+    Unauthenticated(String),
+    /// The server refused the query as the caller isn't allowed to call the rpc, see
+    /// [crate::Authorization]
+    PermissionDenied(String),
+    /// A typed error returned by the handler, see [RpcError::application]
+    Application(RpcErrorPayload),
     Custom(String),
+    /// The server refused the named rpc as the caller is over its rate limit, see
+    /// [crate::RateLimiter], with how long until it would be let through
+    RateLimited(String, Duration),
+    /// The named rpc's handler ran past the server's [crate::ServerConfig::handler_timeout],
+    /// the given [Duration]
+    HandlerTimeout(String, Duration),
+    /// The named rpc wasn't sent as the server looks down, see [crate::CircuitBreaker], with
+    /// how long until calls will be let through again
+    CircuitOpen(String, Duration),
+    /// The server refused the named rpc as too many queries were waiting to be handled, see
+    /// [crate::OverloadPolicy]
+    Overloaded(String),
+    /// The server doesn't serve rpcs as the client's [crate::Schema] expects, with how they
+    /// differ
+    SchemaMismatch(String),
 }
 
 impl Display for RpcError {
@@ -14,13 +111,105 @@ impl Display for RpcError {
         match self {
             Self::ParseError(pickle) => write!(f, "{}", pickle),
             Self::TransportError(transport_error) => write!(f, "{}", transport_error),
+            Self::UnknownRpc(s) => write!(f, "Rpc not found: {}", s),
+            Self::RpcTimeout(rpc_name, elapsed) => {
+                write!(f, "Rpc {} timed out after {:?}", rpc_name, elapsed)
+            }
+            Self::HandlerPanic(s) => write!(f, "Handler panicked: {}", s),
+            Self::Remote(s) => write!(f, "Server error: {}", s),
+            Self::Cancelled(rpc_name) => write!(f, "Rpc {} cancelled", rpc_name),
+            Self::Unauthenticated(s) => write!(f, "Unauthenticated: {}", s),
+            Self::PermissionDenied(s) => write!(f, "Permission denied: {}", s),
+            Self::Application(payload) => write!(f, "{}", payload.message),
             Self::Custom(s) => write!(f, "{}", s),
+            Self::RateLimited(rpc_name, retry_after) => {
+                write!(
+                    f,
+                    "Rpc {} rate limited, retry after {:?}",
+                    rpc_name, retry_after
+                )
+            }
+            Self::HandlerTimeout(rpc_name, timeout) => {
+                write!(f, "Rpc {} handler timed out after {:?}", rpc_name, timeout)
+            }
+            Self::CircuitOpen(rpc_name, retry_after) => {
+                write!(
+                    f,
+                    "Rpc {} not sent as the circuit is open, retry after {:?}",
+                    rpc_name, retry_after
+                )
+            }
+            Self::Overloaded(rpc_name) => {
+                write!(f, "Rpc {} refused as the server is overloaded", rpc_name)
+            }
+            Self::SchemaMismatch(mismatches) => write!(f, "Schema mismatch: {}", mismatches),
         }
     }
 }
 
 impl Error for RpcError {}
 
+impl RpcError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::ParseError(_) => ErrorCode::InvalidData,
+            Self::TransportError(transport_error) => transport_error.code(),
+            Self::UnknownRpc(_) => ErrorCode::Unimplemented,
+            Self::RpcTimeout(_, _) => ErrorCode::DeadlineExceeded,
+            Self::HandlerPanic(_) | Self::Remote(_) => ErrorCode::Internal,
+            Self::Cancelled(_) => ErrorCode::Cancelled,
+            Self::Unauthenticated(_) => ErrorCode::Unauthenticated,
+            Self::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            Self::Application(_) => ErrorCode::Application,
+            Self::Custom(_) => ErrorCode::Unknown,
+            Self::RateLimited(_, _) => ErrorCode::ResourceExhausted,
+            Self::HandlerTimeout(_, _) => ErrorCode::DeadlineExceeded,
+            Self::CircuitOpen(_, _) | Self::Overloaded(_) => ErrorCode::Unavailable,
+            Self::SchemaMismatch(_) => ErrorCode::InvalidData,
+        }
+    }
+
+    /// How long to wait before trying again, if the server or a [crate::CircuitBreaker] said
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited(_, retry_after) | Self::CircuitOpen(_, retry_after) => {
+                Some(*retry_after)
+            }
+            _ => None,
+        }
+    }
+
+    /// See [ErrorCode::is_retryable]
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_retryable()
+    }
+
+    /// Fail a handler with [error], which the client gets back as itself from
+    /// [RpcError::application_error] rather than as a string
+    pub fn application<E: Serialize + Display>(error: &E) -> Self {
+        match RpcErrorPayload::new(error) {
+            Ok(payload) => Self::Application(payload),
+            Err(e) => Self::Custom(format!("{} (unable to serialise error: {})", error, e)),
+        }
+    }
+
+    /// The application error a handler failed with, if it's an [E]
+    pub fn application_error<E: DeserializeOwned>(&self) -> Option<E> {
+        match self {
+            Self::Application(payload) => payload.details().ok(),
+            _ => None,
+        }
+    }
+
+    /// See [TransportError::is_connection_reset]
+    pub fn is_connection_reset(&self) -> bool {
+        match self {
+            Self::TransportError(transport_error) => transport_error.is_connection_reset(),
+            _ => false,
+        }
+    }
+}
+
 impl From<serde_pickle::Error> for RpcError {
     fn from(e: serde_pickle::Error) -> Self {
         Self::ParseError(e)
@@ -41,3 +230,78 @@ pub fn into_rpc_result_transport<T>(result: Result<T, TransportError>) -> RpcRes
         Err(e) => Err(RpcError::TransportError(e)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum PlunderError {
+        NotEnoughGold { short_by: usize },
+    }
+
+    impl Display for PlunderError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::NotEnoughGold { short_by } => write!(f, "{} gold short", short_by),
+            }
+        }
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let reset = RpcError::from(TransportError::ConnectionReset(String::from("reset")));
+        assert_eq!(ErrorCode::Unavailable, reset.code());
+        assert!(reset.is_retryable());
+        let timeout = RpcError::RpcTimeout(String::from("GetI"), Duration::from_secs(1));
+        assert!(timeout.is_retryable());
+        let garbled = RpcError::from(TransportError::DeserialiseError(String::from("garbled")));
+        assert_eq!(ErrorCode::InvalidData, garbled.code());
+        assert!(!garbled.is_retryable());
+        assert!(!RpcError::UnknownRpc(String::from("GetI")).is_retryable());
+        assert!(!RpcError::Remote(String::from("failed")).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn application_error_round_trip() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::GetI,
+            Box::new(|state: &mut HelloWorldState, wanted: usize| {
+                match wanted.checked_sub(state.i) {
+                    Some(short_by) if short_by > 0 => {
+                        Err(RpcError::application(&PlunderError::NotEnoughGold {
+                            short_by,
+                        }))
+                    }
+                    _ => Ok(wanted),
+                }
+            }),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_call = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let plunder = RpcClient::new(Rpc::<_, usize, usize>::new(HelloWorldRpcName::GetI));
+            plunder.call(10, &mut transport).await
+        };
+
+        let ((), plundered) = tokio::join!(server.serve_channel(listener), client_call);
+        let error = plundered.unwrap_err();
+        assert_eq!("7 gold short", format!("{}", error));
+        assert_eq!(
+            Some(PlunderError::NotEnoughGold { short_by: 7 }),
+            error.application_error()
+        );
+        assert_eq!(None, error.application_error::<String>());
+    }
+}
@@ -0,0 +1,103 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::call_context::read_call_context;
+
+/// Shared values of any type handlers can be given alongside the server state, one per type,
+/// like database pools or caches, see [crate::RpcServer::add_extension]. Each is shared behind
+/// an [Arc] rather than the state's mutex, so any mutability is up to the value itself
+#[derive(Clone, Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: Arc<T>) {
+        self.values.insert(TypeId::of::<T>(), value);
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self.values.get(&TypeId::of::<T>())?.clone();
+        value.downcast().ok()
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Extensions({} values)", self.values.len())
+    }
+}
+
+/// An extension handed to a handler by [crate::RpcImpl::with_extension], to destructure in its
+/// arguments as `Extension(db): Extension<Db>`
+pub struct Extension<T>(pub Arc<T>);
+
+/// The server's extension of type [T], if it has one, from the
+/// [crate::CallContext::extensions] of [crate::call_context]
+pub fn call_extension<T: Send + Sync + 'static>() -> Option<Arc<T>> {
+    read_call_context(|context| context.extensions.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::error::RpcError;
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// Stands in for a connection pool, counting what it's asked
+    struct Lookouts {
+        sightings: AtomicUsize,
+    }
+
+    #[tokio::test]
+    async fn extension_given_to_handler() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        let lookouts = Arc::new(Lookouts {
+            sightings: AtomicUsize::new(0),
+        });
+        server.add_extension(lookouts.clone());
+        server.add_rpc(Box::new(RpcImpl::with_extension(
+            HelloWorldRpcName::GetI,
+            Box::new(
+                |state: &mut HelloWorldState, Extension(lookouts): Extension<Lookouts>, ()| {
+                    Ok(state.i + lookouts.sightings.fetch_add(1, Ordering::Relaxed))
+                },
+            ),
+        )));
+        // The server has no extension of this type
+        server.add_rpc(Box::new(RpcImpl::with_extension(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, Extension(name): Extension<String>, ()| Ok(name.to_string())),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let get_i = RpcClient::new(Rpc::<_, (), usize>::new(HelloWorldRpcName::GetI));
+            let first = get_i.call((), &mut transport).await;
+            let second = get_i.call((), &mut transport).await;
+            let missing = RpcClient::new(Rpc::<_, (), String>::new(HelloWorldRpcName::HelloWorld))
+                .call((), &mut transport)
+                .await;
+            (first, second, missing)
+        };
+
+        let ((), (first, second, missing)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(3, first.unwrap());
+        assert_eq!(4, second.unwrap());
+        assert!(matches!(missing, Err(RpcError::Remote(_))));
+        assert_eq!(2, lookouts.sightings.load(Ordering::Relaxed));
+        assert!(call_extension::<Lookouts>().is_none());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::transport::{InternalTransport, TransportConfig, TransportError};
+use crate::{Bytes, OwnedBytes};
+use log::{debug, warn};
+
+/// Version of the pirates wire protocol, bumped whenever packages change in a way older peers
+/// can't read. Exchanged in the handshake, see [crate::TransportConfig::handshake]
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Opens every hello, so a peer that isn't handshaking is told apart from one that is but
+/// disagrees
+const MAGIC: &[u8] = b"PIRATES";
+
+/// What each side tells the other when a connection opens. The layout is fixed rather than
+/// using a [crate::TransportWireConfig], as agreeing on that is the point:
+/// the magic bytes, the version as a big-endian u16, then a count byte and each wire format
+/// name as a length byte then its UTF-8 bytes
+#[derive(Debug, PartialEq, Eq)]
+struct Hello {
+    protocol_version: u16,
+    wire_formats: Vec<String>,
+}
+
+impl Hello {
+    /// Our hello, listing [TransportConfig::wire_config] first as the one we send in
+    fn ours(config: &TransportConfig) -> Self {
+        let wire_formats = config.receivable_wire_configs().into_iter();
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            wire_formats: wire_formats
+                .map(|wire_config| wire_config.wire_config_name().to_string())
+                .collect(),
+        }
+    }
+
+    fn encode(&self) -> OwnedBytes {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.protocol_version.to_be_bytes());
+        bytes.push(self.wire_formats.len() as u8);
+        for wire_format in &self.wire_formats {
+            bytes.push(wire_format.len() as u8);
+            bytes.extend_from_slice(wire_format.as_bytes());
+        }
+        bytes
+    }
+
+    fn decode(bytes: Bytes) -> Result<Self, TransportError> {
+        let not_hello = || {
+            TransportError::IncompatibleVersion(String::from(
+                "peer didn't send a handshake, it may not have handshakes enabled",
+            ))
+        };
+        let rest = bytes.strip_prefix(MAGIC).ok_or_else(not_hello)?;
+        let mut rest = rest.iter().copied();
+        let mut next = || rest.next().ok_or_else(not_hello);
+        let protocol_version = u16::from_be_bytes([next()?, next()?]);
+        let num_wire_formats = next()?;
+        let mut wire_formats = Vec::with_capacity(num_wire_formats as usize);
+        for _ in 0..num_wire_formats {
+            let len = next()?;
+            let name = (0..len).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+            wire_formats.push(String::from_utf8_lossy(&name).into_owned());
+        }
+        Ok(Self {
+            protocol_version,
+            wire_formats,
+        })
+    }
+
+    /// Why a client sending [self] can't talk to a server that sent [server], if it can't
+    fn incompatibility(&self, server: &Hello) -> Option<String> {
+        if self.protocol_version != server.protocol_version {
+            return Some(format!(
+                "client speaks protocol version {} but server speaks {}",
+                self.protocol_version, server.protocol_version
+            ));
+        }
+        let wire_format = self.wire_formats.first()?;
+        if !server.wire_formats.contains(wire_format) {
+            return Some(format!(
+                "client uses wire format {} but server only accepts {}",
+                wire_format,
+                server.wire_formats.join(", ")
+            ));
+        }
+        None
+    }
+}
+
+/// Send our hello and check the server's reply
+pub(crate) async fn client_handshake(
+    internal_transport: &mut impl InternalTransport,
+    config: &TransportConfig,
+) -> Result<(), TransportError> {
+    let ours = Hello::ours(config);
+    let reply = internal_transport
+        .send_and_wait_for_response(&ours.encode(), config.rcv_timeout)
+        .await?;
+    let server = Hello::decode(&reply)?;
+    match ours.incompatibility(&server) {
+        Some(reason) => Err(TransportError::IncompatibleVersion(reason)),
+        None => Ok(()),
+    }
+}
+
+/// Wait for the client's hello and reply with ours. The reply is sent even if the client is
+/// incompatible, so it can report why before the connection is dropped
+pub(crate) async fn server_handshake(
+    internal_transport: &mut impl InternalTransport,
+    config: &TransportConfig,
+) -> Result<(), TransportError> {
+    let hello_bytes = internal_transport.receive(Some(config.rcv_timeout)).await?;
+    let client = Hello::decode(&hello_bytes)?;
+    let ours = Hello::ours(config);
+    internal_transport.send(&ours.encode()).await?;
+    match client.incompatibility(&ours) {
+        Some(reason) => {
+            warn!("Refusing incompatible client: {}", reason);
+            Err(TransportError::IncompatibleVersion(reason))
+        }
+        None => {
+            debug!("Handshake done with client {:?}", client);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ChannelTransport;
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello {
+            protocol_version: 7,
+            wire_formats: vec![String::from("pickle"), String::from("json")],
+        };
+        assert_eq!(hello, Hello::decode(&hello.encode()).unwrap());
+        assert!(matches!(
+            Hello::decode(b"(dp0\n."),
+            Err(TransportError::IncompatibleVersion(_))
+        ));
+        assert!(matches!(
+            Hello::decode(&hello.encode()[..10]),
+            Err(TransportError::IncompatibleVersion(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn incompatible_version_fails_both_sides() {
+        let (mut client, mut server) = ChannelTransport::pair(1);
+        let config = TransportConfig::default();
+        let old_client = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::ours(&config)
+        };
+        let hello_bytes = old_client.encode();
+        let (client_result, server_result) = tokio::join!(
+            client.send_and_wait_for_response(&hello_bytes, config.rcv_timeout),
+            server_handshake(&mut server, &config),
+        );
+        let server_hello = Hello::decode(&client_result.unwrap()).unwrap();
+        let reason = old_client.incompatibility(&server_hello).unwrap();
+        assert!(reason.contains("protocol version"), "{}", reason);
+        assert!(matches!(
+            server_result,
+            Err(TransportError::IncompatibleVersion(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn compatible_handshake() {
+        let (mut client, mut server) = ChannelTransport::pair(1);
+        let config = TransportConfig::default();
+        let (client_result, server_result) = tokio::join!(
+            client_handshake(&mut client, &config),
+            server_handshake(&mut server, &config),
+        );
+        client_result.unwrap();
+        server_result.unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Sent by a client in place of a query to ask for the server's [HealthReport], followed by
+/// any [crate::Metadata] for the server's [crate::Interceptor]s, see [crate::ServerRequest].
+/// Every [crate::RpcServer] answers it without it being registered, and as it's outside any
+/// [crate::RpcName] type it can't clash with the server's own rpcs
+pub(crate) const HEALTH_CHECK: &[u8] = b"PIRATES-HEALTH";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HealthStatus {
+    Serving,
+    /// Marked not serving with [HealthHandle::set_serving], e.g. while a dependency is down
+    NotServing,
+    /// Shut down with a [crate::ShutdownHandle] and draining the calls in flight. Only seen
+    /// from [crate::RpcServer::health_report], e.g. to serve it over HTTP, as a server shutting
+    /// down takes no more queries, health checks included
+    ShuttingDown,
+}
+
+/// What a server says about itself when probed, e.g. by an orchestrator's liveness and
+/// readiness checks, see [crate::Transport::check_health]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// Time since the server was made
+    pub uptime: Duration,
+    /// Every rpc the server serves, by name, and whether it's ready
+    pub rpcs: BTreeMap<String, bool>,
+}
+
+impl HealthReport {
+    /// Whether the server is serving and [rpc_name] is ready
+    pub fn is_ready(&self, rpc_name: impl Display) -> bool {
+        self.status == HealthStatus::Serving
+            && self.rpcs.get(&format!("{}", rpc_name)) == Some(&true)
+    }
+}
+
+#[derive(Debug)]
+struct Health {
+    serving: bool,
+    not_ready: BTreeSet<String>,
+}
+
+/// Sets what the [RpcServer] it came from reports in its [HealthReport]s, see
+/// [crate::RpcServer::health_handle]. Rpcs are ready, and the server serving, until said
+/// otherwise. Clone it to hand to whatever knows, e.g. a task watching a database connection
+#[derive(Clone, Debug)]
+pub struct HealthHandle(Arc<Mutex<Health>>);
+
+impl HealthHandle {
+    pub(crate) fn new() -> Self {
+        Self(Arc::new(Mutex::new(Health {
+            serving: true,
+            not_ready: BTreeSet::new(),
+        })))
+    }
+
+    pub fn set_serving(&self, serving: bool) {
+        self.0.lock().unwrap().serving = serving;
+    }
+
+    pub fn set_ready(&self, rpc_name: impl Display, ready: bool) {
+        let rpc_name = format!("{}", rpc_name);
+        let mut health = self.0.lock().unwrap();
+        if ready {
+            health.not_ready.remove(&rpc_name);
+        } else {
+            health.not_ready.insert(rpc_name);
+        }
+    }
+
+    /// The report for a server serving [rpc_names], up for [uptime]
+    pub(crate) fn report(
+        &self,
+        shutting_down: bool,
+        uptime: Duration,
+        rpc_names: impl Iterator<Item = String>,
+    ) -> HealthReport {
+        let health = self.0.lock().unwrap();
+        let status = match (shutting_down, health.serving) {
+            (true, _) => HealthStatus::ShuttingDown,
+            (false, true) => HealthStatus::Serving,
+            (false, false) => HealthStatus::NotServing,
+        };
+        let rpcs = rpc_names
+            .map(|rpc_name| {
+                let ready = !health.not_ready.contains(&rpc_name);
+                (rpc_name, ready)
+            })
+            .collect();
+        HealthReport {
+            status,
+            uptime,
+            rpcs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::server::{RpcServer, ServerConfig};
+    use crate::tests::{
+        make_get_i_rpc_impl, make_hello_world_rpc_impl, HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+
+    #[tokio::test]
+    async fn health_reported_without_registering() {
+        let clock = Arc::new(MockClock::new());
+        let server_config = ServerConfig {
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let health = server.health_handle();
+        health.set_ready(HelloWorldRpcName::GetI, false);
+        clock.advance(Duration::from_secs(5));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::<_, HelloWorldRpcName>::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let report = transport.check_health().await;
+            health.set_serving(false);
+            let not_serving = transport.check_health().await;
+            (report, not_serving)
+        };
+
+        let ((), (report, not_serving)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        let report = report.unwrap();
+        assert_eq!(HealthStatus::Serving, report.status);
+        assert_eq!(Duration::from_secs(5), report.uptime);
+        assert_eq!(2, report.rpcs.len());
+        assert!(report.is_ready(HelloWorldRpcName::HelloWorld));
+        assert!(!report.is_ready(HelloWorldRpcName::GetI));
+        let not_serving = not_serving.unwrap();
+        assert_eq!(HealthStatus::NotServing, not_serving.status);
+        assert!(!not_serving.is_ready(HelloWorldRpcName::HelloWorld));
+        server.shutdown_handle().shutdown();
+        assert_eq!(HealthStatus::ShuttingDown, server.health_report().status);
+    }
+
+    #[tokio::test]
+    async fn health_check_authenticated() {
+        use crate::auth::{Authentication, Identity};
+        use crate::error::RpcError;
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.layer(Authentication::new(|token: &str| {
+            (token == "jack").then(|| Identity::new("jack"))
+        }));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut reports = Vec::new();
+            for config in [
+                TransportConfig::default(),
+                TransportConfig::default().with_bearer_token("jack"),
+            ] {
+                let mut transport = Transport::<_, HelloWorldRpcName>::new(
+                    connector.connect().await.unwrap(),
+                    config,
+                );
+                reports.push(transport.check_health().await);
+            }
+            reports
+        };
+
+        let ((), reports) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert!(matches!(reports[0], Err(RpcError::Unauthenticated(_))));
+        assert_eq!(HealthStatus::Serving, reports[1].as_ref().unwrap().status);
+    }
+}
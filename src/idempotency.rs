@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::sync::watch;
+
+use crate::clock::{Clock, SystemClock};
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::middleware::{Interceptor, Next};
+use crate::query_hash::{DefaultQueryHasher, QueryHasher};
+use crate::trace_context::random_id;
+use crate::transport::ReceivedQuery;
+use crate::OwnedBytes;
+
+/// The [crate::Metadata] key a call's idempotency key is sent under, see
+/// [crate::RpcClient::call_with_idempotency_key]
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// A new idempotency key, unique and hard to guess, for
+/// [crate::RpcClient::call_with_idempotency_key]
+pub fn new_idempotency_key() -> String {
+    format!("{:016x}{:016x}", random_id(), random_id())
+}
+
+enum Remembered {
+    /// Being handled, with the response sent once it's done
+    Running(watch::Receiver<Option<OwnedBytes>>),
+    Answered {
+        response: OwnedBytes,
+        expires: Instant,
+    },
+}
+
+struct Call {
+    query_hash: OwnedBytes,
+    remembered: Remembered,
+}
+
+impl Call {
+    fn expired(&self, now: Instant) -> bool {
+        match self.remembered {
+            Remembered::Answered { expires, .. } => expires <= now,
+            Remembered::Running(_) => false,
+        }
+    }
+}
+
+/// The rpc, who called it if they're authenticated, and the idempotency key
+type CallKey<Name> = (Name, Option<String>, String);
+
+type Calls<Name> = HashMap<CallKey<Name>, Call>;
+
+/// Server [Interceptor] running each call sent with an idempotency key, see
+/// [crate::RpcClient::call_with_idempotency_key], only once: a repeat with the same key, e.g.
+/// a retry after a timeout, is answered with the response the first got, for [remember_for]
+/// after. One arriving while the first is still being handled waits for it. Only successful
+/// responses are remembered, so a call that failed runs again when retried.
+/// Keys are per rpc and per authenticated caller, see [crate::Authentication], so one
+/// caller can't be answered with another's response, and reusing one with a different query
+/// is refused. At most [max_keys] are remembered, the soonest to expire making room, so size
+/// it for the calls expected within [remember_for]. Once that many are still running, a new
+/// key is refused with [RpcError::Overloaded]. Layer it after any auth interceptors
+pub struct Deduplication<Name: RpcName> {
+    calls: Arc<Mutex<Calls<Name>>>,
+    remember_for: Duration,
+    max_keys: usize,
+    hasher: Box<dyn QueryHasher>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Forgets a call still running when dropped, e.g. as its handler failed or it was given up
+/// on, so a repeat runs it again rather than waiting on it forever
+struct Running<'a, Name: RpcName> {
+    calls: &'a Mutex<Calls<Name>>,
+    key: Option<CallKey<Name>>,
+    answer: watch::Sender<Option<OwnedBytes>>,
+}
+
+impl<Name: RpcName> Drop for Running<'_, Name> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.calls.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+impl<Name: RpcName> Deduplication<Name> {
+    pub fn new(remember_for: Duration, max_keys: usize) -> Self {
+        Self {
+            calls: Arc::new(Mutex::new(HashMap::new())),
+            remember_for,
+            max_keys,
+            hasher: Box::new(DefaultQueryHasher),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_hasher(mut self, hasher: impl QueryHasher + 'static) -> Self {
+        self.hasher = Box::new(hasher);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Number of keys remembered, whether answered or still running
+    pub fn remembered(&self) -> usize {
+        self.calls.lock().unwrap().len()
+    }
+
+    /// Make room for another key, if there isn't any, giving whether there is. Keys still
+    /// running are kept, so there's none once they fill it
+    fn make_room(&self, calls: &mut Calls<Name>, now: Instant) -> bool {
+        if calls.len() < self.max_keys {
+            return true;
+        }
+        calls.retain(|_, call| !call.expired(now));
+        let soonest = calls
+            .iter()
+            .filter_map(|(key, call)| match call.remembered {
+                Remembered::Answered { expires, .. } => Some((key, expires)),
+                Remembered::Running(_) => None,
+            })
+            .min_by_key(|(_, expires)| *expires)
+            .map(|(key, _)| key.clone());
+        if let Some(soonest) = soonest.filter(|_| calls.len() >= self.max_keys) {
+            calls.remove(&soonest);
+        }
+        calls.len() < self.max_keys
+    }
+}
+
+#[async_trait(?Send)]
+impl<Name: RpcName> Interceptor<Name> for Deduplication<Name> {
+    async fn around(
+        &self,
+        query: &ReceivedQuery<Name>,
+        next: Next<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        let idempotency_key = match query.metadata.get(IDEMPOTENCY_KEY) {
+            Some(idempotency_key) => idempotency_key,
+            None => return next.run(query).await,
+        };
+        let caller = query
+            .identity
+            .as_ref()
+            .map(|identity| identity.subject.clone());
+        let key = (query.name.clone(), caller, idempotency_key.clone());
+        let query_hash = self.hasher.hash_query(&query.query_bytes);
+        let mut running = loop {
+            let mut answer = {
+                let now = self.clock.now();
+                let mut calls = self.calls.lock().unwrap();
+                if calls.get(&key).is_some_and(|call| call.expired(now)) {
+                    calls.remove(&key);
+                }
+                match calls.get(&key) {
+                    Some(call) if call.query_hash != query_hash => {
+                        return Err(RpcError::Custom(format!(
+                            "Idempotency key {} reused for a different query",
+                            idempotency_key
+                        )));
+                    }
+                    Some(Call {
+                        remembered: Remembered::Answered { response, .. },
+                        ..
+                    }) => {
+                        debug!(
+                            "Replaying response to {} with key {}",
+                            query.name, idempotency_key
+                        );
+                        return Ok(response.clone());
+                    }
+                    Some(Call {
+                        remembered: Remembered::Running(answer),
+                        ..
+                    }) => answer.clone(),
+                    None => {
+                        if !self.make_room(&mut calls, now) {
+                            debug!(
+                                "Refusing {} with key {}, as {} calls are running already",
+                                query.name, idempotency_key, self.max_keys
+                            );
+                            return Err(RpcError::Overloaded(format!("{}", query.name)));
+                        }
+                        let (answer, answered) = watch::channel(None);
+                        let call = Call {
+                            query_hash: query_hash.clone(),
+                            remembered: Remembered::Running(answered),
+                        };
+                        calls.insert(key.clone(), call);
+                        break Running {
+                            calls: &self.calls,
+                            key: Some(key.clone()),
+                            answer,
+                        };
+                    }
+                }
+            };
+            // A repeat while the first's running, which answers both if it succeeds
+            let answered = answer
+                .wait_for(Option::is_some)
+                .await
+                .map(|response| response.clone());
+            if let Ok(Some(response)) = answered {
+                return Ok(response);
+            }
+        };
+        let response = next.run(query).await?;
+        if let Some(key) = running.key.take() {
+            let _ = running.answer.send(Some(response.clone()));
+            let call = Call {
+                query_hash,
+                remembered: Remembered::Answered {
+                    response: response.clone(),
+                    expires: self.clock.now() + self.remember_for,
+                },
+            };
+            self.calls.lock().unwrap().insert(key, call);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Authentication, Identity};
+    use crate::client::RpcClient;
+    use crate::clock::MockClock;
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState, IncrIRpc};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use crate::RpcDefinition;
+
+    #[tokio::test]
+    async fn repeats_answered_without_running_again() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let clock = Arc::new(MockClock::new());
+        server.layer(Deduplication::new(Duration::from_secs(60), 16).with_clock(clock.clone()));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let key = new_idempotency_key();
+            for _ in 0..3 {
+                incr_i
+                    .call_with_idempotency_key((), &key, &mut transport)
+                    .await
+                    .unwrap();
+            }
+            let deduplicated = state.lock().unwrap().i;
+            // Without a key, or with a new one, it runs
+            incr_i.call((), &mut transport).await.unwrap();
+            incr_i
+                .call_with_idempotency_key((), &new_idempotency_key(), &mut transport)
+                .await
+                .unwrap();
+            // Forgotten once it's expired
+            clock.advance(Duration::from_secs(60));
+            incr_i
+                .call_with_idempotency_key((), &key, &mut transport)
+                .await
+                .unwrap();
+            (deduplicated, state.lock().unwrap().i)
+        };
+
+        let ((), (deduplicated, after)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(4, deduplicated);
+        assert_eq!(7, after);
+    }
+
+    #[tokio::test]
+    async fn keys_kept_apart_per_caller() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.layer(Authentication::new(|token: &str| {
+            Some(Identity::new(token))
+        }));
+        server.layer(Deduplication::new(Duration::from_secs(60), 16));
+        let (connector, listener) = channel_listener(2);
+        let client_calls = async move {
+            let key = new_idempotency_key();
+            for token in ["jack", "gibbs", "jack"] {
+                let config = TransportConfig::default().with_bearer_token(token);
+                let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+                RpcClient::new(IncrIRpc::client())
+                    .call_with_idempotency_key((), &key, &mut transport)
+                    .await
+                    .unwrap();
+            }
+            state.lock().unwrap().i
+        };
+
+        let ((), i) = tokio::join!(server.serve_channel(listener), client_calls);
+        // Once for each caller
+        assert_eq!(5, i);
+    }
+
+    #[test]
+    fn no_room_once_running_fill_it() {
+        let deduplication = Deduplication::<HelloWorldRpcName>::new(Duration::from_secs(60), 1);
+        let mut calls = deduplication.calls.lock().unwrap();
+        let (_answer, answered) = watch::channel(None);
+        let call = Call {
+            query_hash: vec![],
+            remembered: Remembered::Running(answered),
+        };
+        calls.insert((HelloWorldRpcName::IncrI, None, String::from("a")), call);
+        assert!(!deduplication.make_room(&mut calls, Instant::now()));
+        assert_eq!(1, calls.len());
+    }
+}
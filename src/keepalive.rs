@@ -0,0 +1,141 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::priority::Priority;
+use crate::transport::TransportError;
+use crate::OwnedBytes;
+use log::{debug, warn};
+use tokio::sync::mpsc;
+
+/// Sent by a client to check the connection, and answered by the server with [PONG]. Like the
+/// handshake these are fixed bytes rather than packages, as they're for no rpc and mustn't
+/// depend on the wire format
+pub(crate) const PING: &[u8] = b"PIRATES-PING";
+pub(crate) const PONG: &[u8] = b"PIRATES-PONG";
+
+/// Has clients ping the server over connections that have been quiet for [interval], see
+/// [crate::TransportConfig::keepalive], so NAT boxes and load balancers that drop idle
+/// connections see traffic, and a dead server is noticed within [interval] plus [timeout]
+/// rather than on the next call. A [crate::MultiplexedClient] pings by itself, as does a
+/// [crate::TransportPool] or [crate::ClientPool] over its idle connections, while a
+/// [crate::Transport] does while [crate::Transport::keep_alive] is awaited.
+/// Servers always answer pings. To drop dead clients in bounded time too, give the server an
+/// [crate::ServerConfig::idle_timeout] longer than the clients' [interval]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Ping over a multiplexed connection whenever nothing has been received on it for
+/// [KeepaliveConfig::interval], giving the error to fail it with if nothing arrives within
+/// [KeepaliveConfig::timeout] of a ping. [last_received] is updated by the reader
+pub(crate) async fn keep_alive_multiplexed(
+    config: KeepaliveConfig,
+    outgoing: mpsc::Sender<(Priority, OwnedBytes)>,
+    last_received: Arc<Mutex<Instant>>,
+) -> TransportError {
+    loop {
+        let quiet_for = last_received.lock().unwrap().elapsed();
+        if quiet_for < config.interval {
+            tokio::time::sleep(config.interval - quiet_for).await;
+            continue;
+        }
+        debug!("Pinging connection quiet for {:?}", quiet_for);
+        let pinged = Instant::now();
+        if outgoing
+            .send((Priority::High, PING.to_vec()))
+            .await
+            .is_err()
+        {
+            return TransportError::ConnectionClosed;
+        }
+        tokio::time::sleep(config.timeout).await;
+        if *last_received.lock().unwrap() < pinged {
+            warn!("No pong within {:?}, dropping connection", config.timeout);
+            return TransportError::ConnectionReset(format!(
+                "No pong within {:?} of keepalive ping",
+                config.timeout
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::error::RpcError;
+    use crate::multiplex::MultiplexedClient;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+
+    fn quick_keepalive() -> TransportConfig {
+        TransportConfig {
+            keepalive: Some(KeepaliveConfig {
+                interval: Duration::from_millis(10),
+                timeout: Duration::from_millis(20),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_transport_pinged() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport =
+                Transport::new(connector.connect().await.unwrap(), quick_keepalive());
+            let round_trip = transport.ping().await;
+            // Dropped part way through one of several pings
+            tokio::select! {
+                result = transport.keep_alive() => panic!("Keepalive failed: {:?}", result),
+                () = tokio::time::sleep(Duration::from_millis(55)) => {}
+            }
+            let hello = RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut transport)
+                .await;
+            (round_trip, hello)
+        };
+
+        let ((), (round_trip, hello)) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert!(round_trip.unwrap() < Duration::from_secs(1));
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap());
+    }
+
+    #[tokio::test]
+    async fn dead_server_noticed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // Accepts the connection, then never reads or answers anything
+        let silent_server = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let client: MultiplexedClient<HelloWorldRpcName> =
+            MultiplexedClient::connect(&addr, quick_keepalive())
+                .await
+                .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let hello = client.call("Foo".to_string(), make_hello_world_rpc()).await;
+        match hello {
+            Err(RpcError::TransportError(TransportError::ConnectionReset(s))) => {
+                assert!(s.contains("pong"), "{}", s)
+            }
+            other => panic!("Expected the connection to be dropped, got {:?}", other),
+        }
+        drop(silent_server);
+    }
+}
@@ -5,7 +5,7 @@
 //! the `#[pirates::rpc_definition]` macro can do this for you on an impl that
 //! contains a run and implement function (Enable the "macros" feature)
 //!
-//! ```rust,no_run
+//! ```rust,ignore
 //! # pub struct AddName {}
 //! # pub use pirates_macro_lib::rpc_definition;
 //! #[pirates::rpc_definition]
@@ -19,6 +19,8 @@
 //!     }
 //! }
 //! ```
+//! Use `#[pirates::rpc_definition(idempotent)]` for rpcs that are safe to retry, see
+//! `RetryPolicy`
 //!
 //! There are two core types these are generic over which you need to define:
 //! 1) Rpc Identifier. Create a type which implements RpcName
@@ -39,6 +41,17 @@
 //!     }
 //! }
 //! ```
+//! Or, with the "macros" feature, derive all of that. Each variant is sent and displayed as its
+//! name, or the name given with `#[rpc_name(rename = "...")]` so variants can be renamed without
+//! breaking peers. Its opcode is its position, so add new variants at the end
+//! ```rust,ignore
+//! #[derive(PartialEq, Eq, Hash, Clone, pirates::RpcName)]
+//! enum RpcId {
+//!     AddName,
+//!     #[rpc_name(rename = "get_names")]
+//!     GetNames,
+//! }
+//! ```
 //! 2) Server state. Any type inside an Arc<Mutex<T> that the server can hand to RPCs
 //! ```rust,no_run
 //! struct ServerState {
@@ -63,48 +76,330 @@
 //! let name = String::from("Gaspode the wonder dog");
 //! pirates::call_client(addr, name, rpcs::AddName::client()).await;
 //! ```
+//!
+//! With the "macros" feature, `#[pirates::service]` on a trait does all of the above. Each
+//! method is an rpc, answered by the server state's impl of the trait. Alongside the trait it
+//! generates a `NamesRpcName` enum, a typed `Rpc` per method on it, e.g.
+//! `NamesRpcName::add_name()`, `NamesRpcName::register` to add them all to a server, and a
+//! `NamesClient` with an async method per rpc
+//!
+//! ```rust,ignore
+//! #[pirates::service]
+//! pub trait Names {
+//!     fn add_name(&mut self, name: String) -> RpcResult<()>;
+//!     fn get_names(&mut self) -> RpcResult<Vec<String>>;
+//! }
+//!
+//! impl Names for ServerState { ... }
+//!
+//! let mut server = RpcServer::new(state.clone(), TransportConfig::default());
+//! NamesRpcName::register(&mut server);
+//!
+//! let mut client = NamesClient::new(transport);
+//! client.add_name(String::from("Gaspode the wonder dog")).await?;
+//! ```
+//!
+//! Methods can't be async, as they're called with the server state locked, which is a
+//! compile error pointing at the `async`
+//!
+//! ```rust,compile_fail
+//! use pirates::error::RpcResult;
+//!
+//! #[pirates::service]
+//! pub trait Names {
+//!     async fn get_names(&mut self) -> RpcResult<Vec<String>>;
+//! }
+//! ```
 
+#[macro_use]
+mod logging;
+
+mod access_log;
+mod auth;
+mod balance;
+mod bandwidth;
+mod batch;
+mod buffer_pool;
+mod builder;
+mod cache;
+mod call_context;
+mod circuit_breaker;
 mod client;
+mod clock;
+mod coalesce;
+mod codec;
+mod compression;
+mod connect;
 mod core;
+mod deadline;
+mod discovery;
 pub mod error;
+mod extensions;
+mod handshake;
+mod health;
+mod idempotency;
+mod keepalive;
+mod listener;
+mod metadata;
+mod metrics;
+mod middleware;
+mod multiplex;
+mod outbox;
+mod overload;
+mod pipeline;
+mod pool;
+mod priority;
+mod pubsub;
+mod query_hash;
+#[cfg(feature = "transport_quic")]
+mod quic;
+mod rate_limit;
+mod reconnect;
+mod reflection;
+mod retry;
+mod reverse;
 mod rpc_types;
+mod schema;
 mod server;
+mod session;
+#[cfg(feature = "transport_tls")]
+mod tls;
+mod trace;
+mod trace_context;
 mod transport;
+#[cfg(feature = "transport_websocket")]
+mod websocket;
+
+pub use erased_serde;
+pub use serde;
+
+// Lets the derive macros' `pirates::` paths resolve in this crate's own tests
+#[cfg(test)]
+extern crate self as pirates;
 
 pub type Bytes<'a> = &'a [u8];
 pub type OwnedBytes = Vec<u8>;
 
+pub use crate::access_log::AccessLog;
+pub use crate::access_log::AccessLogEntry;
+pub use crate::access_log::ACCESS_LOG_TARGET;
+pub use crate::auth::call_identity;
+pub use crate::auth::Authentication;
+pub use crate::auth::Authenticator;
+pub use crate::auth::Authorization;
+pub use crate::auth::Authorizer;
+pub use crate::auth::BearerToken;
+pub use crate::auth::Identity;
+pub use crate::auth::RolePolicy;
+pub use crate::auth::AUTHORIZATION;
+pub use crate::balance::BalanceConfig;
+pub use crate::balance::BalanceStrategy;
+pub use crate::balance::BalancedClient;
+pub use crate::bandwidth::BytesPerSecond;
+pub use crate::batch::Batch;
+pub use crate::batch::BatchCall;
+pub use crate::batch::BatchResults;
+pub use crate::buffer_pool::BufferPool;
+pub use crate::buffer_pool::BufferPoolStats;
+pub use crate::buffer_pool::DEFAULT_MAX_POOLED_CAPACITY;
+pub use crate::builder::RpcServerBuilder;
+pub use crate::builder::RunnableServer;
+pub use crate::cache::CacheStats;
+pub use crate::cache::ResponseCache;
+pub use crate::call_context::call_cancellation;
+pub use crate::call_context::call_context;
+pub use crate::call_context::CallContext;
+pub use crate::circuit_breaker::CircuitBreaker;
+pub use crate::circuit_breaker::CircuitBreakerConfig;
+pub use crate::circuit_breaker::CircuitState;
 pub use crate::client::call_client;
+pub use crate::client::connect_tcp_transport;
+pub use crate::client::BidiStreamRpcClient;
+pub use crate::client::ClientStreamRpcClient;
+pub use crate::client::NotificationClient;
+pub use crate::client::RawResponse;
 pub use crate::client::RpcClient;
+pub use crate::client::StreamRpcClient;
+pub use crate::client::TypedRpc;
+pub use crate::clock::Clock;
+pub use crate::clock::MockClock;
+pub use crate::clock::SystemClock;
+pub use crate::coalesce::CoalesceConfig;
+pub use crate::codec::DeserializeSeed;
+pub use crate::codec::WireCodec;
+pub use crate::compression::CompressionAlgorithm;
+pub use crate::compression::CompressionConfig;
+pub use crate::connect::TcpConnectConfig;
+pub use crate::core::AsyncRpcImpl;
+pub use crate::core::BidiStreamRpc;
+pub use crate::core::BidiStreamRpcImpl;
+pub use crate::core::ClientStreamRpc;
+pub use crate::core::ClientStreamRpcImpl;
+pub use crate::core::NotificationRpc;
+pub use crate::core::NotificationRpcImpl;
+pub use crate::core::RequestStream;
+pub use crate::core::ResponseFuture;
+pub use crate::core::ResponseStream;
 pub use crate::core::Rpc;
 pub use crate::core::RpcImpl;
 pub use crate::core::RpcName;
 pub use crate::core::RpcType;
+pub use crate::core::StoredAsyncRpc;
 pub use crate::core::StoredRpc;
+pub use crate::core::StoredStreamRpc;
+pub use crate::core::StreamRpc;
+pub use crate::core::StreamRpcImpl;
+pub use crate::deadline::call_deadline;
+pub use crate::discovery::Resolver;
+#[cfg(feature = "discovery_dns")]
+pub use crate::discovery::SrvResolver;
+pub use crate::discovery::StaticResolver;
+pub use crate::extensions::call_extension;
+pub use crate::extensions::Extension;
+pub use crate::extensions::Extensions;
+pub use crate::handshake::PROTOCOL_VERSION;
+pub use crate::health::HealthHandle;
+pub use crate::health::HealthReport;
+pub use crate::health::HealthStatus;
+pub use crate::idempotency::new_idempotency_key;
+pub use crate::idempotency::Deduplication;
+pub use crate::idempotency::IDEMPOTENCY_KEY;
+pub use crate::keepalive::KeepaliveConfig;
+pub use crate::listener::Accepting;
+pub use crate::listener::Listener;
+pub use crate::listener::TcpListener;
+#[cfg(unix)]
+pub use crate::listener::UnixListener;
+pub use crate::metadata::call_metadata;
+pub use crate::metadata::Metadata;
+pub use crate::metrics::CallRecord;
+pub use crate::metrics::LatencyHistogram;
+pub use crate::metrics::MetricsRecorder;
+pub use crate::metrics::MetricsSnapshot;
+pub use crate::metrics::RpcMetrics;
+pub use crate::metrics::RpcStats;
+pub use crate::metrics::LATENCY_BUCKETS;
+pub use crate::middleware::ClientInterceptor;
+pub use crate::middleware::ClientNext;
+pub use crate::middleware::Interceptor;
+pub use crate::middleware::Next;
+pub use crate::middleware::OutgoingQuery;
+pub use crate::middleware::ServerRequest;
+pub use crate::middleware::ServerRequestKind;
+pub use crate::multiplex::MultiplexedClient;
+pub use crate::outbox::Outbox;
+pub use crate::overload::OverloadPolicy;
+pub use crate::pipeline::Pipeline;
+pub use crate::pipeline::PipelinedCall;
+pub use crate::pool::ClientPool;
+pub use crate::pool::PooledTransport;
+pub use crate::pool::TransportPool;
+pub use crate::priority::Priority;
+pub use crate::priority::PRIORITY;
+pub use crate::pubsub::Published;
+pub use crate::pubsub::Topics;
+pub use crate::pubsub::DEFAULT_SUBSCRIBER_BUFFER;
+pub use crate::query_hash::DefaultQueryHasher;
+pub use crate::query_hash::QueryHasher;
+#[cfg(feature = "transport_quic")]
+pub use crate::quic::{QuicConnection, QuicStreamTransport};
+pub use crate::rate_limit::RateLimit;
+pub use crate::rate_limit::RateLimiter;
+pub use crate::reconnect::ReconnectConfig;
+pub use crate::reconnect::ReconnectingTransport;
+pub use crate::reflection::RpcDescription;
+pub use crate::reflection::RpcKind;
+pub use crate::reflection::RpcTypes;
+pub use crate::reflection::ServerDescription;
+pub use crate::retry::RetryPolicy;
+pub use crate::reverse::ReverseConnections;
+pub use crate::schema::DeclaredRpc;
+pub use crate::schema::Schema;
 pub use crate::server::RpcServer;
+pub use crate::server::ServerConfig;
+pub use crate::server::ShutdownHandle;
+pub use crate::session::call_session;
+pub use crate::session::Session;
+#[cfg(feature = "transport_tls")]
+pub use crate::tls::{
+    TlsClientConfig, TlsClientConfigBuilder, TlsListener, TlsServerConfig, TlsServerConfigBuilder,
+    TlsTcpTransport,
+};
+pub use crate::trace_context::call_trace_context;
+pub use crate::trace_context::TraceContext;
+pub use crate::trace_context::TracePropagation;
+pub use crate::trace_context::TRACEPARENT;
+pub use crate::trace_context::TRACESTATE;
+pub use crate::transport::channel_listener;
+pub use crate::transport::ChannelConnector;
+pub use crate::transport::ChannelListener;
+pub use crate::transport::ChannelTransport;
 pub use crate::transport::InternalTransport;
+pub use crate::transport::NameEncoding;
+pub use crate::transport::PackageKind;
+pub use crate::transport::ReceivedQuery;
+pub use crate::transport::TcpTransport;
 pub use crate::transport::Transport;
 pub use crate::transport::TransportConfig;
+pub use crate::transport::TransportError;
 pub use crate::transport::TransportWireConfig;
+pub use crate::transport::UdpTransport;
+#[cfg(unix)]
+pub use crate::transport::UnixTransport;
+pub use crate::transport::DEFAULT_MAX_DATAGRAM_SIZE;
+pub use crate::transport::DEFAULT_MAX_MESSAGE_SIZE;
+pub use crate::transport::DEFAULT_READ_BUFFER_SIZE;
+#[cfg(feature = "transport_websocket")]
+pub use crate::websocket::{WebSocketListener, WebSocketTransport};
 
 #[cfg(feature = "macros")]
 pub use pirates_macro_lib::rpc_definition;
+#[cfg(feature = "macros")]
+pub use pirates_macro_lib::service;
+#[cfg(feature = "macros")]
+pub use pirates_macro_lib::RpcName;
 
 pub trait RpcDefinition<Name: RpcName, State, Q: RpcType, R: RpcType> {
     fn client() -> Rpc<Name, Q, R>;
     fn server() -> RpcImpl<Name, State, Q, R>;
 }
 
+/// As [RpcDefinition], for a [StreamRpc]
+pub trait StreamRpcDefinition<Name: RpcName, State, Q: RpcType, R: RpcType> {
+    fn client() -> StreamRpc<Name, Q, R>;
+    fn server() -> StreamRpcImpl<Name, State, Q, R>;
+}
+
+/// As [RpcDefinition], for a [ClientStreamRpc]
+pub trait ClientStreamRpcDefinition<Name: RpcName, State, Q: RpcType, R: RpcType> {
+    fn client() -> ClientStreamRpc<Name, Q, R>;
+    fn server() -> ClientStreamRpcImpl<Name, State, Q, R>;
+}
+
+/// As [RpcDefinition], for a [BidiStreamRpc]
+pub trait BidiStreamRpcDefinition<Name: RpcName, State, Q: RpcType, R: RpcType> {
+    fn client() -> BidiStreamRpc<Name, Q, R>;
+    fn server() -> BidiStreamRpcImpl<Name, State, Q, R>;
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::client::call_client;
-    use crate::core::{Rpc, RpcImpl, RpcName};
-    use crate::error::RpcResult;
-    use crate::server::RpcServer;
-    use crate::transport::{TransportConfig, TransportWireConfig};
-    use crate::RpcDefinition;
+    use crate::client::{call_client, connect_tcp_transport, RpcClient};
+    use crate::clock::MockClock;
+    use crate::core::{
+        BidiStreamRpc, BidiStreamRpcImpl, ClientStreamRpc, ClientStreamRpcImpl, RequestStream,
+        ResponseFuture, ResponseStream, Rpc, RpcImpl, RpcName, StreamRpc, StreamRpcImpl,
+    };
+    use crate::error::{RpcError, RpcResult};
+    use crate::server::{RpcServer, ServerConfig};
+    use crate::transport::{ReceivedQuery, Transport, TransportConfig, TransportWireConfig};
+    use crate::{
+        BidiStreamRpcDefinition, ClientStreamRpcDefinition, RpcDefinition, StreamRpcDefinition,
+    };
+    use futures_util::StreamExt;
     use serde::{Deserialize, Serialize};
     use std::fmt::{Display, Formatter};
+    use std::str::FromStr;
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
@@ -119,13 +414,55 @@ mod tests {
         IncrI,
         MassiveRpc,
         PreciseRpc,
+        PanicRpc,
+        CountTo,
+        Sum,
+        Double,
     }
     impl Display for HelloWorldRpcName {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
             write!(f, "{:?}", self)
         }
     }
-    impl RpcName for HelloWorldRpcName {}
+    impl RpcName for HelloWorldRpcName {
+        fn opcode(&self) -> Option<u16> {
+            Some(self.clone() as u16)
+        }
+        fn from_opcode(opcode: u16) -> Option<Self> {
+            match opcode {
+                0 => Some(Self::HelloWorld),
+                1 => Some(Self::GetI),
+                2 => Some(Self::IncrI),
+                3 => Some(Self::MassiveRpc),
+                4 => Some(Self::PreciseRpc),
+                5 => Some(Self::PanicRpc),
+                6 => Some(Self::CountTo),
+                7 => Some(Self::Sum),
+                8 => Some(Self::Double),
+                _ => None,
+            }
+        }
+        fn from_utf8_name(name: &str) -> Option<Self> {
+            name.parse().ok()
+        }
+    }
+    impl FromStr for HelloWorldRpcName {
+        type Err = ();
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "HelloWorld" => Ok(Self::HelloWorld),
+                "GetI" => Ok(Self::GetI),
+                "IncrI" => Ok(Self::IncrI),
+                "MassiveRpc" => Ok(Self::MassiveRpc),
+                "PreciseRpc" => Ok(Self::PreciseRpc),
+                "PanicRpc" => Ok(Self::PanicRpc),
+                "CountTo" => Ok(Self::CountTo),
+                "Sum" => Ok(Self::Sum),
+                "Double" => Ok(Self::Double),
+                _ => Err(()),
+            }
+        }
+    }
 
     pub fn make_hello_world_rpc() -> Rpc<HelloWorldRpcName, String, String> {
         Rpc::new(HelloWorldRpcName::HelloWorld)
@@ -229,6 +566,92 @@ mod tests {
         }
     }
 
+    pub struct PanicRpc {}
+    impl PanicRpc {
+        fn implement(_state: &mut HelloWorldState, query: String) -> RpcResult<()> {
+            panic!("Arr, {}", query)
+        }
+    }
+    impl RpcDefinition<HelloWorldRpcName, HelloWorldState, String, ()> for PanicRpc {
+        fn client() -> Rpc<HelloWorldRpcName, String, ()> {
+            Rpc::new(HelloWorldRpcName::PanicRpc)
+        }
+
+        fn server() -> RpcImpl<HelloWorldRpcName, HelloWorldState, String, ()> {
+            RpcImpl::new(HelloWorldRpcName::PanicRpc, Box::new(Self::implement))
+        }
+    }
+
+    /// Counts up from i to the query, but refuses to go past 10
+    pub struct CountToRpc {}
+    impl CountToRpc {
+        fn implement(
+            state: &mut HelloWorldState,
+            query: usize,
+        ) -> RpcResult<ResponseStream<usize>> {
+            let counts = (state.i..=query).map(|i| match i {
+                i if i > 10 => Err(RpcError::Custom(String::from("Can't count past 10"))),
+                i => Ok(i),
+            });
+            Ok(Box::pin(futures_util::stream::iter(counts)))
+        }
+    }
+    impl StreamRpcDefinition<HelloWorldRpcName, HelloWorldState, usize, usize> for CountToRpc {
+        fn client() -> StreamRpc<HelloWorldRpcName, usize, usize> {
+            StreamRpc::new(HelloWorldRpcName::CountTo)
+        }
+
+        fn server() -> StreamRpcImpl<HelloWorldRpcName, HelloWorldState, usize, usize> {
+            StreamRpcImpl::new(HelloWorldRpcName::CountTo, Box::new(Self::implement))
+        }
+    }
+
+    /// Adds up the queries, starting from i
+    pub struct SumRpc {}
+    impl SumRpc {
+        fn implement(
+            state: &mut HelloWorldState,
+            mut queries: RequestStream<usize>,
+        ) -> RpcResult<ResponseFuture<usize>> {
+            let mut total = state.i;
+            Ok(Box::pin(async move {
+                while let Some(query) = queries.next().await {
+                    total += query?;
+                }
+                Ok(total)
+            }))
+        }
+    }
+    impl ClientStreamRpcDefinition<HelloWorldRpcName, HelloWorldState, usize, usize> for SumRpc {
+        fn client() -> ClientStreamRpc<HelloWorldRpcName, usize, usize> {
+            ClientStreamRpc::new(HelloWorldRpcName::Sum)
+        }
+
+        fn server() -> ClientStreamRpcImpl<HelloWorldRpcName, HelloWorldState, usize, usize> {
+            ClientStreamRpcImpl::new(HelloWorldRpcName::Sum, Box::new(Self::implement))
+        }
+    }
+
+    /// Answers each query with double it
+    pub struct DoubleRpc {}
+    impl DoubleRpc {
+        fn implement(
+            _state: &mut HelloWorldState,
+            queries: RequestStream<usize>,
+        ) -> RpcResult<ResponseStream<usize>> {
+            Ok(Box::pin(queries.map(|query| Ok(query? * 2))))
+        }
+    }
+    impl BidiStreamRpcDefinition<HelloWorldRpcName, HelloWorldState, usize, usize> for DoubleRpc {
+        fn client() -> BidiStreamRpc<HelloWorldRpcName, usize, usize> {
+            BidiStreamRpc::new(HelloWorldRpcName::Double)
+        }
+
+        fn server() -> BidiStreamRpcImpl<HelloWorldRpcName, HelloWorldState, usize, usize> {
+            BidiStreamRpcImpl::new(HelloWorldRpcName::Double, Box::new(Self::implement))
+        }
+    }
+
     #[test]
     fn just_server_test() {
         let state = HelloWorldState { i: 3 };
@@ -238,6 +661,7 @@ mod tests {
                 serde_pickle::DeOptions::new(),
                 serde_pickle::SerOptions::new(),
             ),
+            ..Default::default()
         };
         let mut server = RpcServer::new(Arc::new(Mutex::new(state)), transport_config);
         server.add_rpc(Box::new(make_hello_world_rpc_impl()));
@@ -245,10 +669,16 @@ mod tests {
         let incoming_bytes =
             serde_pickle::ser::to_vec(&"Foo", serde_pickle::SerOptions::new()).unwrap();
         server
-            .call(&incoming_bytes, &HelloWorldRpcName::HelloWorld)
+            .call(
+                &ReceivedQuery::query(HelloWorldRpcName::HelloWorld, incoming_bytes.clone()),
+                &TransportWireConfig::pickle(),
+            )
             .unwrap();
         server
-            .call(&incoming_bytes, &HelloWorldRpcName::HelloWorld)
+            .call(
+                &ReceivedQuery::query(HelloWorldRpcName::HelloWorld, incoming_bytes.clone()),
+                &TransportWireConfig::pickle(),
+            )
             .unwrap();
     }
 
@@ -343,4 +773,904 @@ mod tests {
         assert_eq!(slightly_smaller_len, num_bulk);
         // which returns 1286 bytes = 1024 + 262 overhead
     }
+
+    #[tokio::test]
+    async fn handler_panic_server() {
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(PanicRpc::server()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let addr = "127.0.0.1:5558";
+
+        let panic_rpc = PanicRpc::client();
+        let get_i_rpc = make_get_i_rpc();
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            // Both over the one connection, which survives the panic
+            let mut transport: Transport<_, HelloWorldRpcName> =
+                connect_tcp_transport(addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            let panic_result = RpcClient::new(panic_rpc)
+                .call("me hearties".into(), &mut transport)
+                .await;
+            let get_i_result = RpcClient::new(get_i_rpc).call((), &mut transport).await;
+            (panic_result, get_i_result)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (panic_result, get_i_result) = rpc_results.unwrap().unwrap();
+        match panic_result {
+            Err(RpcError::HandlerPanic(message)) => assert_eq!("Arr, me hearties", message),
+            other => panic!("Expected HandlerPanic, got {:?}", other),
+        }
+        assert_eq!(3usize, get_i_result.unwrap());
+    }
+
+    #[tokio::test]
+    async fn idle_connection_dropped() {
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let server_config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let mut server =
+            RpcServer::with_config(state_ref, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let addr = "127.0.0.1:5559";
+
+        let get_i_rpc = make_get_i_rpc();
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            use tokio::io::AsyncReadExt;
+            let mut silent_client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let get_i_result = call_client(addr, (), get_i_rpc).await;
+            let mut buf = [0u8; 8];
+            let silent_read =
+                tokio::time::timeout(Duration::from_secs(1), silent_client.read(&mut buf)).await;
+            (get_i_result, silent_read)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (get_i_result, silent_read) = rpc_results.unwrap().unwrap();
+        assert_eq!(3usize, get_i_result.unwrap());
+        // Server closed the silent connection, so the read sees EOF rather than timing out
+        assert_eq!(0, silent_read.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn channel_server() {
+        use crate::client::RpcClient;
+        use crate::transport::{channel_listener, Transport};
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let (connector, listener) = channel_listener(1);
+
+        let client_calls = async move {
+            let connect = || async {
+                Transport::new(
+                    connector.connect().await.unwrap(),
+                    TransportConfig::default(),
+                )
+            };
+            RpcClient::new(IncrIRpc::client())
+                .call((), &mut connect().await)
+                .await
+                .unwrap();
+            RpcClient::new(make_get_i_rpc())
+                .call((), &mut connect().await)
+                .await
+        };
+
+        // Serving ends when the client calls finish and drop the connector
+        let ((), get_i) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(4usize, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn udp_server() {
+        use crate::client::RpcClient;
+        use crate::transport::{Transport, TransportError, UdpTransport};
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let addr = "127.0.0.1:5563";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let connect = || async {
+                let udp_transport = UdpTransport::connect(addr.parse().unwrap()).await.unwrap();
+                Transport::new(udp_transport, TransportConfig::default())
+            };
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut connect().await)
+                .await;
+            let too_large = RpcClient::new(make_hello_world_rpc())
+                .call("a".repeat(2000), &mut connect().await)
+                .await;
+            (get_i, too_large)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve_udp(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (get_i, too_large) = rpc_results.unwrap().unwrap();
+        assert_eq!(3usize, get_i.unwrap());
+        match too_large {
+            Err(RpcError::TransportError(TransportError::PayloadTooLarge(size, 1200))) => {
+                assert!(size > 2000)
+            }
+            other => panic!("Expected PayloadTooLarge, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_server() {
+        use crate::client::RpcClient;
+        use crate::transport::{Transport, UnixTransport};
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let path = std::env::temp_dir().join(format!("pirates-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut rpc_results = None;
+        let client_path = path.clone();
+        let mut client_call_task = tokio::spawn(async move {
+            let connect = || async {
+                let unix_transport = UnixTransport::connect(&client_path).await.unwrap();
+                Transport::new(unix_transport, TransportConfig::default())
+            };
+            let hello = RpcClient::new(make_hello_world_rpc())
+                .call("Unix".into(), &mut connect().await)
+                .await;
+            RpcClient::new(IncrIRpc::client())
+                .call((), &mut connect().await)
+                .await
+                .unwrap();
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut connect().await)
+                .await;
+            (hello, get_i)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve_unix(&path) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let (hello, get_i) = rpc_results.unwrap().unwrap();
+        assert_eq!("Hello world: 3:\"Unix\"", hello.unwrap());
+        assert_eq!(4usize, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stream_rpc_server() {
+        use crate::client::{connect_tcp_transport, RpcClient, StreamRpcClient};
+        use futures_util::StreamExt;
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_stream_rpc(Box::new(CountToRpc::server()));
+        let addr = "127.0.0.1:5566";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let mut transport = connect_tcp_transport(addr, TransportConfig::default())
+                .await
+                .unwrap();
+            let count_to = StreamRpcClient::new(CountToRpc::client());
+            let counted: Vec<_> = count_to.call(6, &mut transport).collect().await;
+            let counted_too_far: Vec<_> = count_to.call(12, &mut transport).collect().await;
+            // The connection is left ready for other calls once each stream ends
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            (counted, counted_too_far, get_i)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (counted, counted_too_far, get_i) = rpc_results.unwrap().unwrap();
+        let counted: Vec<usize> = counted.into_iter().map(Result::unwrap).collect();
+        assert_eq!(vec![3, 4, 5, 6], counted);
+        assert_eq!(9, counted_too_far.len());
+        match counted_too_far.last() {
+            Some(Err(RpcError::Remote(message))) => assert_eq!("Can't count past 10", message),
+            other => panic!("Expected Remote error, got {:?}", other),
+        }
+        assert_eq!(3usize, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn client_and_bidi_stream_rpc_server() {
+        use crate::client::{
+            connect_tcp_transport, BidiStreamRpcClient, ClientStreamRpcClient, RpcClient,
+        };
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_stream_rpc(Box::new(SumRpc::server()));
+        server.add_stream_rpc(Box::new(DoubleRpc::server()));
+        let addr = "127.0.0.1:5567";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let mut transport = connect_tcp_transport(addr, TransportConfig::default())
+                .await
+                .unwrap();
+            let sum = ClientStreamRpcClient::new(SumRpc::client())
+                .call(futures_util::stream::iter(1..=4), &mut transport)
+                .await;
+            // Each query is answered as it arrives, before the next is sent
+            let (query_sender, query_receiver) = tokio::sync::mpsc::unbounded_channel();
+            query_sender.send(1).unwrap();
+            let mut query_sender = Some(query_sender);
+            let queries = futures_util::stream::unfold(query_receiver, |mut receiver| async {
+                let query = receiver.recv().await?;
+                Some((query, receiver))
+            });
+            let double = BidiStreamRpcClient::new(DoubleRpc::client());
+            let mut doubled = Vec::new();
+            {
+                let responses = double.call(queries, &mut transport);
+                futures_util::pin_mut!(responses);
+                while let Some(response) = responses.next().await {
+                    let response: usize = response.unwrap();
+                    doubled.push(response);
+                    match &query_sender {
+                        Some(sender) if response < 8 => sender.send(response).unwrap(),
+                        // Ending the queries ends the responses once they're all answered
+                        _ => query_sender = None,
+                    }
+                }
+            }
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            (sum, doubled, get_i)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (sum, doubled, get_i) = rpc_results.unwrap().unwrap();
+        assert_eq!(13usize, sum.unwrap());
+        assert_eq!(vec![2, 4, 8], doubled);
+        assert_eq!(3usize, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn cancelled_stream_rpc_frees_connection() {
+        use crate::client::{connect_tcp_transport, BidiStreamRpcClient, RpcClient};
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_stream_rpc(Box::new(DoubleRpc::server()));
+        let addr = "127.0.0.1:5568";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let mut transport = connect_tcp_transport(addr, TransportConfig::default())
+                .await
+                .unwrap();
+            // The queries never end, so the handler would run until the connection closed
+            let queries = futures_util::stream::iter([1]).chain(futures_util::stream::pending());
+            let double = BidiStreamRpcClient::new(DoubleRpc::client());
+            let first = {
+                let responses = double.call(queries, &mut transport);
+                futures_util::pin_mut!(responses);
+                responses.next().await
+            };
+            let cancelled = transport.cancel_stream(&HelloWorldRpcName::Double).await;
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            (first, cancelled, get_i)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (first, cancelled, get_i) = rpc_results.unwrap().unwrap();
+        assert_eq!(2usize, first.unwrap().unwrap());
+        cancelled.unwrap();
+        assert_eq!(3usize, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn deadline_sent_to_server() {
+        use crate::client::{connect_tcp_transport, RpcClient, StreamRpcClient};
+        use std::time::Instant;
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let time_left = || {
+            crate::call_deadline().map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as u64
+            })
+        };
+        server.add_stream_rpc(Box::new(StreamRpcImpl::new(
+            HelloWorldRpcName::CountTo,
+            Box::new(move |_state, _query: usize| {
+                let time_left = Ok(time_left().unwrap_or_default() as usize);
+                Ok(Box::pin(futures_util::stream::iter([time_left])) as ResponseStream<usize>)
+            }),
+        )));
+        // Answers with the time the handler has left, in millis
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(move |_state, _query: String| Ok(time_left())),
+        )));
+        let addr = "127.0.0.1:5569";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let mut transport = connect_tcp_transport(addr, TransportConfig::default())
+                .await
+                .unwrap();
+            let time_left = Rpc::<_, String, Option<u64>>::new(HelloWorldRpcName::HelloWorld);
+            let time_left = RpcClient::new(time_left);
+            let remaining = time_left
+                .call_with_timeout(String::new(), &mut transport, Duration::from_secs(2))
+                .await;
+            // Already expired when the server gets it, so it's skipped rather than answered
+            let expired = time_left
+                .call_with_timeout(String::new(), &mut transport, Duration::ZERO)
+                .await;
+            // Which leaves nothing stale on the connection to be taken as this response
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            transport.config.stream_item_timeout = Some(Duration::from_secs(2));
+            let stream_remaining: Vec<_> = StreamRpcClient::new(CountToRpc::client())
+                .call(1, &mut transport)
+                .collect()
+                .await;
+            (remaining, expired, get_i, stream_remaining)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (remaining, expired, get_i, stream_remaining) = rpc_results.unwrap().unwrap();
+        let remaining = remaining.unwrap().unwrap();
+        assert!(remaining > 1000 && remaining <= 2000, "{}", remaining);
+        let stream_remaining = *stream_remaining[0].as_ref().unwrap();
+        assert!(stream_remaining > 1000 && stream_remaining <= 2000);
+        assert!(matches!(expired, Err(RpcError::RpcTimeout(_, _))));
+        assert_eq!(3usize, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn handshake_before_queries() {
+        use crate::client::{connect_tcp_transport, RpcClient};
+        let config = TransportConfig {
+            handshake: true,
+            ..Default::default()
+        };
+        let state = HelloWorldState { i: 3 };
+        let state_ref = Arc::new(Mutex::new(state));
+        let mut server = RpcServer::new(state_ref, config.clone());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let addr = "127.0.0.1:5570";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let mut transport = connect_tcp_transport(addr, config).await.unwrap();
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            // A client without the handshake has its query refused and the connection dropped
+            let mut transport = connect_tcp_transport(addr, TransportConfig::default())
+                .await
+                .unwrap();
+            let no_handshake = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            (get_i, no_handshake)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (get_i, no_handshake) = rpc_results.unwrap().unwrap();
+        assert_eq!(3usize, get_i.unwrap());
+        assert!(no_handshake.is_err());
+    }
+
+    #[test]
+    fn slow_handler_flagged_with_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let server_config = ServerConfig {
+            slow_handler_threshold: Some(Duration::from_secs(1)),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        let handler_clock = clock.clone();
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::GetI,
+            Box::new(move |state: &mut HelloWorldState, _query: ()| {
+                handler_clock.advance(Duration::from_secs(state.i as u64));
+                Ok(state.i)
+            }),
+        )));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let query_bytes = serde_pickle::ser::to_vec(&(), serde_pickle::SerOptions::new()).unwrap();
+
+        server
+            .call(
+                &ReceivedQuery::query(HelloWorldRpcName::IncrI, query_bytes.clone()),
+                &TransportWireConfig::pickle(),
+            )
+            .unwrap();
+        assert_eq!(0, server.slow_handler_count());
+        server
+            .call(
+                &ReceivedQuery::query(HelloWorldRpcName::GetI, query_bytes.clone()),
+                &TransportWireConfig::pickle(),
+            )
+            .unwrap();
+        assert_eq!(1, server.slow_handler_count());
+    }
+
+    #[tokio::test]
+    async fn overrunning_handlers_time_out() {
+        use crate::client::RpcClient;
+        use crate::core::AsyncRpcImpl;
+        use crate::transport::{channel_listener, Transport};
+        let clock = Arc::new(MockClock::new());
+        let server_config = ServerConfig {
+            handler_timeout: Some(Duration::from_millis(50)),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        let handler_clock = clock.clone();
+        // Takes as many seconds as i, as far as the server can tell
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::GetI,
+            Box::new(move |state: &mut HelloWorldState, _query: ()| {
+                handler_clock.advance(Duration::from_secs(state.i as u64));
+                Ok(state.i)
+            }),
+        )));
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        // Never answers
+        server.add_async_rpc(Box::new(AsyncRpcImpl::reading(
+            HelloWorldRpcName::IncrI,
+            Arc::new(tokio::sync::RwLock::new(())),
+            Box::new(|_context: &(), _query: ()| Box::pin(std::future::pending::<RpcResult<()>>())),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let sync_overran = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            let async_hung = RpcClient::new(Rpc::<_, (), ()>::new(HelloWorldRpcName::IncrI))
+                .call((), &mut transport)
+                .await;
+            let hello = RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut transport)
+                .await;
+            (sync_overran, async_hung, hello)
+        };
+
+        let ((), (sync_overran, async_hung, hello)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        match sync_overran {
+            Err(RpcError::HandlerTimeout(rpc_name, timeout)) => {
+                assert_eq!("GetI", rpc_name);
+                assert_eq!(Duration::from_millis(50), timeout);
+            }
+            other => panic!("Expected GetI to time out, got {:?}", other),
+        }
+        assert!(matches!(async_hung, Err(RpcError::HandlerTimeout(_, _))));
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap());
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn derived_rpc_name() {
+        #[derive(Clone, Hash, Eq, PartialEq, Debug, crate::RpcName)]
+        enum DerivedRpcName {
+            AddName,
+            #[rpc_name(rename = "get_names")]
+            GetNames,
+        }
+        assert_eq!("AddName", DerivedRpcName::AddName.to_string());
+        assert_eq!("get_names", DerivedRpcName::GetNames.to_string());
+        assert_eq!(Some(1), DerivedRpcName::GetNames.opcode());
+        assert_eq!(
+            Some(DerivedRpcName::GetNames),
+            DerivedRpcName::from_opcode(1)
+        );
+        assert_eq!(None, DerivedRpcName::from_opcode(2));
+        assert_eq!(
+            Some(DerivedRpcName::GetNames),
+            DerivedRpcName::from_utf8_name("get_names")
+        );
+        assert_eq!(None, DerivedRpcName::from_utf8_name("GetNames"));
+
+        let wire_config = TransportWireConfig::pickle();
+        let name_bytes = wire_config.serialize(&DerivedRpcName::GetNames).unwrap();
+        assert_eq!(
+            "get_names",
+            wire_config.deserialize::<String>(&name_bytes).unwrap()
+        );
+        let name: DerivedRpcName = wire_config.deserialize(&name_bytes).unwrap();
+        assert_eq!(DerivedRpcName::GetNames, name);
+        let unknown = wire_config.serialize(&"Plunder").unwrap();
+        assert!(wire_config.deserialize::<DerivedRpcName>(&unknown).is_err());
+    }
+
+    #[cfg(feature = "macros")]
+    #[tokio::test]
+    async fn service_macro_round_trip() {
+        use crate::error::RpcResult;
+
+        #[crate::service]
+        trait Names {
+            fn add_name(&mut self, name: String) -> RpcResult<()>;
+            fn add_names(&mut self, first: String, second: String) -> RpcResult<usize>;
+            fn get_names(&mut self) -> RpcResult<Vec<String>>;
+        }
+
+        struct NamesState(Vec<String>);
+        impl Names for NamesState {
+            fn add_name(&mut self, name: String) -> RpcResult<()> {
+                self.0.push(name);
+                Ok(())
+            }
+            fn add_names(&mut self, first: String, second: String) -> RpcResult<usize> {
+                self.0.extend([first, second]);
+                Ok(self.0.len())
+            }
+            fn get_names(&mut self) -> RpcResult<Vec<String>> {
+                Ok(self.0.clone())
+            }
+        }
+
+        assert_eq!("add_name", NamesRpcName::AddName.to_string());
+        let mut server = RpcServer::new(
+            Arc::new(Mutex::new(NamesState(Vec::new()))),
+            TransportConfig::default(),
+        );
+        NamesRpcName::register(&mut server);
+        let (connector, listener) = crate::channel_listener(1);
+        let client_calls = async move {
+            let transport = crate::Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let mut client = NamesClient::new(transport);
+            client.add_name(String::from("Gaspode")).await.unwrap();
+            let num_names = client
+                .add_names(String::from("Angua"), String::from("Carrot"))
+                .await
+                .unwrap();
+            (num_names, client.get_names().await.unwrap())
+        };
+
+        let ((), (num_names, names)) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(3, num_names);
+        assert_eq!(vec!["Gaspode", "Angua", "Carrot"], names);
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_drains_calls() {
+        use crate::client::{RpcClient, StreamRpcClient};
+        use crate::transport::{channel_listener, Transport};
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            drain_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        // Never finishes counting, to still be in flight when shut down
+        server.add_stream_rpc(Box::new(StreamRpcImpl::new(
+            HelloWorldRpcName::CountTo,
+            Box::new(|_state, _query: usize| {
+                let counts = futures_util::stream::pending::<RpcResult<usize>>();
+                Ok(Box::pin(counts) as ResponseStream<usize>)
+            }),
+        )));
+        let shutdown = server.shutdown_handle();
+        let (connector, listener) = channel_listener(2);
+        let client_calls = async move {
+            let mut idle = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let hello = RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut idle)
+                .await;
+            let mut busy = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let count_to = StreamRpcClient::new(CountToRpc::client());
+            let mut counts = Box::pin(count_to.call(5, &mut busy));
+            let before_shutdown =
+                tokio::time::timeout(Duration::from_millis(20), counts.next()).await;
+            assert!(before_shutdown.is_err());
+            shutdown.shutdown();
+            let after_drain = counts.next().await;
+            drop(counts);
+            let after_shutdown = RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut idle)
+                .await;
+            // Still connected, but the server must go regardless
+            (hello, after_drain, after_shutdown, connector)
+        };
+
+        let ((), (hello, after_drain, after_shutdown, _connector)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap());
+        assert!(matches!(after_drain, None | Some(Err(_))));
+        assert!(after_shutdown.is_err());
+    }
+
+    #[tokio::test]
+    async fn max_connections_served_at_once() {
+        use crate::client::RpcClient;
+        use crate::transport::{channel_listener, Transport};
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(2);
+        let client_calls = async move {
+            let hello = RpcClient::new(make_hello_world_rpc());
+            let mut first = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let first_hello = hello.call("Foo".into(), &mut first).await;
+            let config = TransportConfig {
+                rcv_timeout: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let mut second = Transport::new(connector.connect().await.unwrap(), config);
+            let waiting_hello = hello.call("Bar".into(), &mut second).await;
+            // Makes room for the next connection, once the second is served and closed
+            drop(first);
+            drop(second);
+            let mut third = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let third_hello = hello.call("Baz".into(), &mut third).await;
+            (first_hello, waiting_hello, third_hello)
+        };
+
+        let ((), (first_hello, waiting_hello, third_hello)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!("Hello world: 3:\"Foo\"", first_hello.unwrap());
+        assert!(matches!(waiting_hello, Err(RpcError::RpcTimeout(_, _))));
+        assert_eq!("Hello world: 3:\"Baz\"", third_hello.unwrap());
+    }
+
+    #[tokio::test]
+    async fn message_size_limits() {
+        use crate::client::{connect_tcp_transport, RpcClient};
+        use crate::transport::TransportError;
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let transport_config = TransportConfig {
+            max_inbound_message_size: Some(512),
+            max_outbound_message_size: Some(1024),
+            ..Default::default()
+        };
+        let mut server = RpcServer::new(state, transport_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(MassiveRpc::server()));
+        let addr = "127.0.0.1:5573";
+        let mut client_calls = tokio::spawn(async move {
+            let mut transport = connect_tcp_transport(addr, TransportConfig::default())
+                .await
+                .unwrap();
+            let response_too_large = RpcClient::new(MassiveRpc::client())
+                .call(2000, &mut transport)
+                .await;
+            let query_too_large = RpcClient::new(make_hello_world_rpc())
+                .call("x".repeat(1000), &mut transport)
+                .await;
+            let config = TransportConfig {
+                max_outbound_message_size: Some(100),
+                ..Default::default()
+            };
+            let mut transport = connect_tcp_transport(addr, config).await.unwrap();
+            let not_sent = RpcClient::new(make_hello_world_rpc())
+                .call("x".repeat(200), &mut transport)
+                .await;
+            (response_too_large, query_too_large, not_sent)
+        });
+
+        let results = loop {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                results = &mut client_calls => break results.unwrap(),
+            }
+        };
+        let (response_too_large, query_too_large, not_sent) = results;
+        match response_too_large {
+            Err(RpcError::Remote(s)) => assert!(s.starts_with("MessageTooLarge("), "{}", s),
+            other => panic!("Expected the response to be too large, got {:?}", other),
+        }
+        // The server drops the connection rather than read the query
+        assert!(matches!(
+            query_too_large,
+            Err(RpcError::TransportError(
+                TransportError::ConnectionClosed | TransportError::ConnectionReset(_)
+            ))
+        ));
+        assert!(matches!(
+            not_sent,
+            Err(RpcError::TransportError(TransportError::MessageTooLarge(
+                _,
+                100
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn async_handlers_share_context() {
+        use crate::client::RpcClient;
+        use crate::core::AsyncRpcImpl;
+        use crate::metadata::{call_metadata, Metadata};
+        use crate::transport::{channel_listener, Transport};
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let names = Arc::new(tokio::sync::RwLock::new(Vec::<String>::new()));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        // Adds a name, answering with the caller's tenant, read after awaiting
+        server.add_async_rpc(Box::new(AsyncRpcImpl::writing(
+            HelloWorldRpcName::HelloWorld,
+            names.clone(),
+            Box::new(|names: &mut Vec<String>, name: String| {
+                Box::pin(async move {
+                    tokio::task::yield_now().await;
+                    names.push(name);
+                    Ok(call_metadata("tenant"))
+                })
+            }),
+        )));
+        server.add_async_rpc(Box::new(AsyncRpcImpl::reading(
+            HelloWorldRpcName::GetI,
+            names.clone(),
+            Box::new(|names: &Vec<String>, ()| Box::pin(async move { Ok(names.len()) })),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let config = TransportConfig {
+                metadata: Metadata::from([(String::from("tenant"), String::from("ankh"))]),
+                ..Default::default()
+            };
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            let add_name = RpcClient::new(Rpc::<_, String, Option<String>>::new(
+                HelloWorldRpcName::HelloWorld,
+            ));
+            let tenant = add_name.call("Foo".into(), &mut transport).await;
+            add_name.call("Bar".into(), &mut transport).await.unwrap();
+            let num_names = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            (tenant, num_names)
+        };
+
+        let ((), (tenant, num_names)) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(Some(String::from("ankh")), tenant.unwrap());
+        assert_eq!(2, num_names.unwrap());
+        assert_eq!(vec!["Foo", "Bar"], *names.read().await);
+    }
+
+    #[tokio::test]
+    async fn notifications_never_answered() {
+        use crate::client::{NotificationClient, RpcClient};
+        use crate::core::{NotificationRpc, NotificationRpcImpl};
+        use crate::transport::{channel_listener, Transport};
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(NotificationRpcImpl::new(
+            HelloWorldRpcName::IncrI,
+            Box::new(|state: &mut HelloWorldState, by: usize| {
+                if by == 0 {
+                    return Err(RpcError::Custom(String::from("Nothing to add")));
+                }
+                state.i += by;
+                Ok(())
+            }),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let incr_i = NotificationClient::new(NotificationRpc::new(HelloWorldRpcName::IncrI));
+            incr_i.notify(2, &mut transport).await.unwrap();
+            incr_i.notify(0, &mut transport).await.unwrap();
+            incr_i.notify(5, &mut transport).await.unwrap();
+            // Any answer to the notifications would be taken as this call's response
+            RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await
+        };
+
+        let ((), get_i) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(10, get_i.unwrap());
+        let incr_i = server.describe().rpc("IncrI").unwrap().kind;
+        assert_eq!(crate::reflection::RpcKind::Notification, incr_i);
+    }
 }
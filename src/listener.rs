@@ -0,0 +1,182 @@
+use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
+use log::debug;
+
+#[cfg(unix)]
+use crate::transport::UnixTransport;
+use crate::transport::{
+    ChannelListener, ChannelTransport, InternalTransport, TcpTransport, TransportError,
+};
+
+/// A connection a [Listener] has accepted, finishing any handshake it needs, e.g. TLS, once
+/// awaited. Handshakes are awaited alongside the connections being served, so a slow client
+/// can't hold up accepting others
+pub type Accepting<T> = LocalBoxFuture<'static, Result<T, TransportError>>;
+
+/// A source of connections for a server to serve with [crate::RpcServer::serve_listener],
+/// whatever they're carried over, so one accept loop serves them all. See [TcpListener],
+/// [UnixListener], [crate::TlsListener], [crate::WebSocketListener] and [ChannelListener], or
+/// implement it for any other transport
+#[async_trait(?Send)]
+pub trait Listener {
+    type Transport: InternalTransport + 'static;
+
+    /// Wait for the next connection, or [None] once there will be no more. An error is for
+    /// that connection alone, so the server logs it and carries on accepting
+    async fn accept(&mut self) -> Option<Result<Accepting<Self::Transport>, TransportError>>;
+}
+
+/// Already connected, with no handshake to do
+pub(crate) fn accepted<T: 'static>(transport: T) -> Accepting<T> {
+    Box::pin(std::future::ready(Ok(transport)))
+}
+
+pub(crate) fn accept_error(e: std::io::Error) -> TransportError {
+    TransportError::ConnectError(format!("Accept failed: {}", e))
+}
+
+/// [Listener] for [TcpTransport]s
+pub struct TcpListener {
+    listener: tokio::net::TcpListener,
+}
+
+impl TcpListener {
+    pub async fn bind(addr: impl tokio::net::ToSocketAddrs) -> Result<Self, TransportError> {
+        tokio::net::TcpListener::bind(addr)
+            .await
+            .map(Self::from)
+            .map_err(|e| TransportError::ConnectError(format!("Bind failed: {}", e)))
+    }
+
+    pub fn local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.listener.local_addr().ok()
+    }
+
+    /// The next TCP connection, before it's made into a transport
+    pub(crate) async fn accept_tcp(&self) -> Result<tokio::net::TcpStream, TransportError> {
+        let (tcp_stream, _from) = self.listener.accept().await.map_err(accept_error)?;
+        debug!("Handling connection: {:?}", tcp_stream);
+        Ok(tcp_stream)
+    }
+}
+
+impl From<tokio::net::TcpListener> for TcpListener {
+    fn from(listener: tokio::net::TcpListener) -> Self {
+        Self { listener }
+    }
+}
+
+#[async_trait(?Send)]
+impl Listener for TcpListener {
+    type Transport = TcpTransport;
+
+    async fn accept(&mut self) -> Option<Result<Accepting<TcpTransport>, TransportError>> {
+        let tcp_stream = self.accept_tcp().await;
+        Some(tcp_stream.map(|tcp_stream| accepted(TcpTransport::new(tcp_stream))))
+    }
+}
+
+/// [Listener] for [UnixTransport]s
+#[cfg(unix)]
+pub struct UnixListener {
+    listener: tokio::net::UnixListener,
+}
+
+#[cfg(unix)]
+impl UnixListener {
+    /// Listen on a unix domain socket at [path]. Fails if a file already exists at [path],
+    /// e.g. left over from a previous run
+    pub fn bind(path: impl AsRef<std::path::Path>) -> Result<Self, TransportError> {
+        tokio::net::UnixListener::bind(path)
+            .map(Self::from)
+            .map_err(|e| TransportError::ConnectError(format!("Bind failed: {}", e)))
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::UnixListener> for UnixListener {
+    fn from(listener: tokio::net::UnixListener) -> Self {
+        Self { listener }
+    }
+}
+
+#[cfg(unix)]
+#[async_trait(?Send)]
+impl Listener for UnixListener {
+    type Transport = UnixTransport;
+
+    async fn accept(&mut self) -> Option<Result<Accepting<UnixTransport>, TransportError>> {
+        let accepted_stream = self.listener.accept().await.map_err(accept_error);
+        Some(accepted_stream.map(|(unix_stream, _from)| {
+            debug!("Handling connection: {:?}", unix_stream);
+            accepted(UnixTransport::new(unix_stream))
+        }))
+    }
+}
+
+/// Ends once every [crate::ChannelConnector] is dropped
+#[async_trait(?Send)]
+impl Listener for ChannelListener {
+    type Transport = ChannelTransport;
+
+    async fn accept(&mut self) -> Option<Result<Accepting<ChannelTransport>, TransportError>> {
+        let channel_transport = ChannelListener::accept(self).await?;
+        Some(Ok(accepted(channel_transport)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::server::RpcServer;
+    use crate::tests::{make_get_i_rpc, make_get_i_rpc_impl, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::{Arc, Mutex};
+
+    /// Fails to accept its first connection, then hands over those of [listener]
+    struct FlakyListener {
+        listener: ChannelListener,
+        failed: bool,
+    }
+
+    #[async_trait(?Send)]
+    impl Listener for FlakyListener {
+        type Transport = ChannelTransport;
+
+        async fn accept(&mut self) -> Option<Result<Accepting<ChannelTransport>, TransportError>> {
+            if !self.failed {
+                self.failed = true;
+                return Some(Err(accept_error(
+                    std::io::ErrorKind::ConnectionAborted.into(),
+                )));
+            }
+            Listener::accept(&mut self.listener).await
+        }
+    }
+
+    #[tokio::test]
+    async fn any_listener_served() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let listener = FlakyListener {
+            listener,
+            failed: false,
+        };
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await
+        };
+
+        // The failed accept is logged, and the server carries on accepting
+        let ((), get_i) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert_eq!(3, get_i.unwrap());
+    }
+}
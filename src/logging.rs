@@ -0,0 +1,18 @@
+//! Internal logging macros for the transport hot path.
+//! These go to [log] by default, or to `defmt` with the "defmt" feature enabled, for embedded
+//! targets where `log`'s string formatting is too heavy. Arguments must implement both
+//! [std::fmt::Debug]/[std::fmt::Display] and `defmt::Format` for whichever hint is used.
+
+#[cfg(not(feature = "defmt"))]
+macro_rules! debug_log {
+    ($($arg:tt)+) => {
+        log::debug!($($arg)+)
+    };
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! debug_log {
+    ($($arg:tt)+) => {
+        defmt::debug!($($arg)+)
+    };
+}
@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+
+use crate::call_context::read_call_context;
+
+/// Key/value pairs sent with a query alongside its name and query bytes, for things every rpc
+/// might need like auth tokens, trace ids or tenancy, without adding them to each query type.
+/// Clients send [crate::TransportConfig::metadata] with every query, which
+/// [crate::ClientInterceptor]s can add to per call. Handlers read it with [call_metadata], and
+/// server [crate::Interceptor]s from [crate::ReceivedQuery::metadata]
+pub type Metadata = BTreeMap<String, String>;
+
+/// Sent with packages other than queries
+pub(crate) const NO_METADATA: &Metadata = &BTreeMap::new();
+
+/// The value the caller of the rpc being handled sent for [key] in its [Metadata], if any.
+/// From the [crate::CallContext::metadata] of [crate::call_context]
+pub fn call_metadata(key: &str) -> Option<String> {
+    read_call_context(|context| context.metadata.get(key).cloned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn metadata_sent_to_handler() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        // Answers with the tenant the caller said it's calling for
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, _query: ()| Ok(call_metadata("tenant"))),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_call = async move {
+            let config = TransportConfig {
+                metadata: Metadata::from([(String::from("tenant"), String::from("ankh"))]),
+                ..Default::default()
+            };
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            let tenant = Rpc::<_, (), Option<String>>::new(HelloWorldRpcName::HelloWorld);
+            let tenant = RpcClient::new(tenant);
+            let with_tenant = tenant.call((), &mut transport).await;
+            transport.config.metadata.clear();
+            let without_tenant = tenant.call((), &mut transport).await;
+            (with_tenant, without_tenant)
+        };
+
+        let ((), (with_tenant, without_tenant)) =
+            tokio::join!(server.serve_channel(listener), client_call);
+        assert_eq!(Some(String::from("ankh")), with_tenant.unwrap());
+        assert_eq!(None, without_tenant.unwrap());
+        assert_eq!(None, call_metadata("tenant"));
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::ErrorCode;
+
+/// Upper bounds of the [LatencyHistogram] buckets, as in Prometheus' default buckets
+pub const LATENCY_BUCKETS: [Duration; 11] = [
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// One call handled by the server, as given to its [MetricsRecorder]. A query refused by an
+/// [crate::Interceptor] counts as a call that failed straight away. For stream rpcs,
+/// [bytes_in] is the opening query's alone, and [bytes_out] and errors sent part way through
+/// the stream aren't counted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallRecord {
+    pub rpc_name: String,
+    /// The error the call failed with, if it did
+    pub error: Option<ErrorCode>,
+    pub latency: Duration,
+    /// Size of the query, as serialised
+    pub bytes_in: usize,
+    /// Size of the response, as serialised
+    pub bytes_out: usize,
+}
+
+/// Told about every call an [crate::RpcServer] handles, see [crate::RpcServer::record_metrics].
+/// Implement it to pass the calls on to a metrics library such as `metrics` or `prometheus`,
+/// or use [RpcMetrics] to keep them in memory. Any `Fn(&CallRecord)` will do
+pub trait MetricsRecorder: Send + Sync {
+    fn record_call(&self, call: &CallRecord);
+}
+
+impl<F: Fn(&CallRecord) + Send + Sync> MetricsRecorder for F {
+    fn record_call(&self, call: &CallRecord) {
+        self(call)
+    }
+}
+
+/// Call latencies, counted into [LATENCY_BUCKETS]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// How many calls took up to each of [LATENCY_BUCKETS], not counting those in earlier
+    /// buckets, then how many took longer than all of them
+    pub counts: [u64; LATENCY_BUCKETS.len() + 1],
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let bucket = LATENCY_BUCKETS.partition_point(|bound| *bound < latency);
+        self.counts[bucket] += 1;
+        self.sum += latency;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// Everything recorded for one rpc
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RpcStats {
+    pub requests: u64,
+    pub errors: HashMap<ErrorCode, u64>,
+    pub latency: LatencyHistogram,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// [RpcStats] for every rpc called, by name, as taken by [RpcMetrics::snapshot]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub rpcs: BTreeMap<String, RpcStats>,
+}
+
+impl MetricsSnapshot {
+    /// The snapshot in the Prometheus text exposition format, for servers serving their
+    /// metrics from an endpoint of their own
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, line: &dyn Fn(&mut String)| {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            line(&mut text);
+        };
+        family(
+            "pirates_requests_total",
+            "counter",
+            "Rpc calls handled",
+            &|text| {
+                for (rpc, stats) in &self.rpcs {
+                    let _ = writeln!(
+                        text,
+                        "pirates_requests_total{{rpc=\"{}\"}} {}",
+                        rpc, stats.requests
+                    );
+                }
+            },
+        );
+        family(
+            "pirates_errors_total",
+            "counter",
+            "Rpc calls failed, by error code",
+            &|text| {
+                for (rpc, stats) in &self.rpcs {
+                    let mut errors: Vec<_> = stats.errors.iter().collect();
+                    errors.sort_by_key(|(code, _)| format!("{:?}", code));
+                    for (code, count) in errors {
+                        let _ = writeln!(
+                            text,
+                            "pirates_errors_total{{rpc=\"{}\",code=\"{:?}\"}} {}",
+                            rpc, code, count
+                        );
+                    }
+                }
+            },
+        );
+        family(
+            "pirates_request_duration_seconds",
+            "histogram",
+            "Time taken to handle rpc calls",
+            &|text| {
+                for (rpc, stats) in &self.rpcs {
+                    let mut cumulative = 0;
+                    for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.latency.counts) {
+                        cumulative += count;
+                        let _ = writeln!(
+                            text,
+                            "pirates_request_duration_seconds_bucket{{rpc=\"{}\",le=\"{}\"}} {}",
+                            rpc,
+                            bound.as_secs_f64(),
+                            cumulative
+                        );
+                    }
+                    let _ = writeln!(
+                        text,
+                        "pirates_request_duration_seconds_bucket{{rpc=\"{}\",le=\"+Inf\"}} {}",
+                        rpc,
+                        stats.latency.count()
+                    );
+                    let _ = writeln!(
+                        text,
+                        "pirates_request_duration_seconds_sum{{rpc=\"{}\"}} {}",
+                        rpc,
+                        stats.latency.sum.as_secs_f64()
+                    );
+                    let _ = writeln!(
+                        text,
+                        "pirates_request_duration_seconds_count{{rpc=\"{}\"}} {}",
+                        rpc,
+                        stats.latency.count()
+                    );
+                }
+            },
+        );
+        for (name, help, bytes) in [
+            (
+                "pirates_received_bytes_total",
+                "Serialised query bytes received",
+                (|stats: &RpcStats| stats.bytes_in) as fn(&RpcStats) -> u64,
+            ),
+            (
+                "pirates_sent_bytes_total",
+                "Serialised response bytes sent",
+                |stats: &RpcStats| stats.bytes_out,
+            ),
+        ] {
+            family(name, "counter", help, &|text| {
+                for (rpc, stats) in &self.rpcs {
+                    let _ = writeln!(text, "{}{{rpc=\"{}\"}} {}", name, rpc, bytes(stats));
+                }
+            });
+        }
+        text
+    }
+}
+
+/// [MetricsRecorder] keeping [RpcStats] per rpc in memory, to [snapshot] whenever wanted.
+/// Clone it to keep a handle after giving it to the server
+#[derive(Clone, Debug, Default)]
+pub struct RpcMetrics {
+    rpcs: Arc<Mutex<BTreeMap<String, RpcStats>>>,
+}
+
+impl RpcMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            rpcs: self.rpcs.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl MetricsRecorder for RpcMetrics {
+    fn record_call(&self, call: &CallRecord) {
+        let mut rpcs = self.rpcs.lock().unwrap();
+        let stats = match rpcs.get_mut(&call.rpc_name) {
+            Some(stats) => stats,
+            None => rpcs.entry(call.rpc_name.clone()).or_default(),
+        };
+        stats.requests += 1;
+        if let Some(code) = call.error {
+            *stats.errors.entry(code).or_insert(0) += 1;
+        }
+        stats.latency.record(call.latency);
+        stats.bytes_in += call.bytes_in as u64;
+        stats.bytes_out += call.bytes_out as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::Rpc;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, make_hello_world_rpc_impl,
+        HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+
+    #[test]
+    fn latency_bucketed_by_upper_bound() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_millis(6));
+        histogram.record(Duration::from_secs(11));
+        assert_eq!(1, histogram.counts[0]);
+        assert_eq!(1, histogram.counts[1]);
+        assert_eq!(1, histogram.counts[LATENCY_BUCKETS.len()]);
+        assert_eq!(3, histogram.count());
+        assert_eq!(Duration::from_millis(11_011), histogram.sum);
+    }
+
+    #[tokio::test]
+    async fn calls_recorded_per_rpc() {
+        let metrics = RpcMetrics::new();
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.record_metrics(metrics.clone());
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let hello = RpcClient::new(make_hello_world_rpc());
+            hello.call("Foo".into(), &mut transport).await.unwrap();
+            hello.call("Bar".into(), &mut transport).await.unwrap();
+            RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await
+                .unwrap();
+            let not_served = Rpc::<_, (), ()>::new(HelloWorldRpcName::MassiveRpc);
+            RpcClient::new(not_served).call((), &mut transport).await
+        };
+
+        let ((), not_served) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert!(not_served.is_err());
+        let snapshot = metrics.snapshot();
+        let names: Vec<&str> = snapshot.rpcs.keys().map(String::as_str).collect();
+        assert_eq!(vec!["GetI", "HelloWorld", "MassiveRpc"], names);
+        let hello = &snapshot.rpcs["HelloWorld"];
+        assert_eq!(2, hello.requests);
+        assert!(hello.errors.is_empty());
+        assert_eq!(2, hello.latency.count());
+        assert!(hello.bytes_in > 0 && hello.bytes_out > 0);
+        let not_served = &snapshot.rpcs["MassiveRpc"];
+        assert_eq!(1, not_served.requests);
+        assert_eq!(Some(&1), not_served.errors.get(&ErrorCode::Unimplemented));
+        assert_eq!(0, not_served.bytes_out);
+        let text = snapshot.to_prometheus();
+        assert!(
+            text.contains("pirates_requests_total{rpc=\"HelloWorld\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("pirates_errors_total{rpc=\"MassiveRpc\",code=\"Unimplemented\"} 1\n")
+        );
+        assert!(
+            text.contains("pirates_request_duration_seconds_bucket{rpc=\"GetI\",le=\"+Inf\"} 1\n")
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
+
+use crate::auth::Identity;
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::metadata::Metadata;
+use crate::transport::ReceivedQuery;
+use crate::OwnedBytes;
+
+/// An Interceptor sees every query a [crate::RpcServer] receives, added with
+/// [crate::RpcServer::layer], for concerns like auth, logging, rate limiting and metrics that
+/// would otherwise be repeated in each handler.
+/// [on_query] can inspect or modify each query before it's handled, or refuse it by returning
+/// an error, which is sent as the response in place of calling the handler. It sees the
+/// opening query of stream rpcs too.
+/// [around] wraps handling of each unary query, calling [Next::run] to carry on down the chain
+/// to the handler. It can time it, or replace or rewrite the result. Calls on a connection
+/// are run concurrently on one task, so anything awaited here lets the others carry on.
+/// [on_refused] is told of queries any interceptor's [on_query] refused, which [around]
+/// never sees.
+/// [on_server_request] is [on_query] for the requests the server answers itself rather than
+/// with an rpc, see [ServerRequest], refusing one by returning an error. Everything's let
+/// through by default
+#[async_trait(?Send)]
+pub trait Interceptor<Name: RpcName> {
+    async fn on_query(&self, _query: &mut ReceivedQuery<Name>) -> RpcResult<()> {
+        Ok(())
+    }
+
+    async fn on_server_request(&self, _request: &mut ServerRequest) -> RpcResult<()> {
+        Ok(())
+    }
+
+    fn on_refused(&self, _query: &ReceivedQuery<Name>, _error: &RpcError) {}
+
+    async fn around(
+        &self,
+        query: &ReceivedQuery<Name>,
+        next: Next<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        next.run(query).await
+    }
+}
+
+/// What a [ServerRequest] asks of the server
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerRequestKind {
+    /// Take the connection to call the client's rpcs over, as the client with this key, see
+    /// [crate::ReverseConnections]
+    Reverse(String),
+    /// Send its [crate::HealthReport], see [crate::Transport::check_health]
+    HealthCheck,
+    /// Describe what it serves, see [crate::Transport::reflect]
+    Reflect,
+}
+
+/// A request the server answers itself rather than with an rpc, for [Interceptor]s to check
+/// with [Interceptor::on_server_request] before it's acted on. It comes with the client's
+/// [crate::TransportConfig::metadata], which [crate::ClientInterceptor]s don't add to, while
+/// [identity], [connection_id], [peer_addr] and [peer_certificate] are as for a
+/// [ReceivedQuery]
+#[derive(Clone, Debug)]
+pub struct ServerRequest {
+    pub kind: ServerRequestKind,
+    pub metadata: Metadata,
+    pub identity: Option<Identity>,
+    pub connection_id: u64,
+    pub peer_addr: Option<std::net::SocketAddr>,
+    pub peer_certificate: Option<Arc<OwnedBytes>>,
+}
+
+/// Starts the handler for a query, sync or async, at the end of the interceptor chain
+pub(crate) type Handler<'a, Name> =
+    dyn Fn(&ReceivedQuery<Name>) -> LocalBoxFuture<'a, RpcResult<OwnedBytes>> + 'a;
+
+/// The rest of the interceptor chain, ending in the rpc's handler
+pub struct Next<'a, Name: RpcName> {
+    interceptors: &'a [Box<dyn Interceptor<Name>>],
+    handler: &'a Handler<'a, Name>,
+}
+
+impl<'a, Name: RpcName> Next<'a, Name> {
+    pub(crate) fn new(
+        interceptors: &'a [Box<dyn Interceptor<Name>>],
+        handler: &'a Handler<'a, Name>,
+    ) -> Self {
+        Self {
+            interceptors,
+            handler,
+        }
+    }
+
+    /// Pass [query] on to the next interceptor, or the handler after the last one
+    pub async fn run(self, query: &ReceivedQuery<Name>) -> RpcResult<OwnedBytes> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = Next::new(interceptors, self.handler);
+                interceptor.around(query, next).await
+            }
+            None => (self.handler)(query).await,
+        }
+    }
+}
+
+/// A query on its way out of a [crate::Transport], as seen by [ClientInterceptor]s. Any of it
+/// can be rewritten before passing it on
+#[derive(Clone, Debug)]
+pub struct OutgoingQuery<Name: RpcName> {
+    pub rpc_name: Name,
+    pub query_bytes: OwnedBytes,
+    pub timeout: Duration,
+    pub metadata: Metadata,
+}
+
+/// The client's counterpart to [Interceptor], added to a [crate::Transport] with
+/// [crate::Transport::layer]. [around] wraps each unary query sent, calling [ClientNext::run]
+/// to carry on down the chain and send it. It can rewrite the query first, time the call, or
+/// call [ClientNext::run] again to retry on errors it knows are safe to retry.
+/// Interceptors run in the order they're added, so the first added is outermost
+#[async_trait]
+pub trait ClientInterceptor<Name: RpcName>: Send + Sync {
+    async fn around(
+        &self,
+        query: OutgoingQuery<Name>,
+        next: ClientNext<'_, Name>,
+    ) -> RpcResult<OwnedBytes>;
+}
+
+/// Sends a query past all the [ClientInterceptor]s, i.e. the end of the chain
+#[async_trait]
+pub(crate) trait QuerySender<Name: RpcName>: Send {
+    async fn send_intercepted_query(
+        &mut self,
+        query: &OutgoingQuery<Name>,
+    ) -> RpcResult<OwnedBytes>;
+}
+
+/// The rest of the client interceptor chain, ending in sending the query
+pub struct ClientNext<'a, Name: RpcName> {
+    interceptors: &'a [Arc<dyn ClientInterceptor<Name>>],
+    sender: &'a mut dyn QuerySender<Name>,
+}
+
+impl<'a, Name: RpcName> ClientNext<'a, Name> {
+    pub(crate) fn new(
+        interceptors: &'a [Arc<dyn ClientInterceptor<Name>>],
+        sender: &'a mut dyn QuerySender<Name>,
+    ) -> Self {
+        Self {
+            interceptors,
+            sender,
+        }
+    }
+
+    /// Pass [query] on to the next interceptor, or send it after the last one, returning the
+    /// response bytes. Can be called more than once, e.g. to retry
+    pub async fn run(&mut self, query: OutgoingQuery<Name>) -> RpcResult<OwnedBytes> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = ClientNext::new(interceptors, &mut *self.sender);
+                interceptor.around(query, next).await
+            }
+            None => self.sender.send_intercepted_query(&query).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::error::RpcError;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, make_hello_world_rpc_impl,
+        HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig, TransportWireConfig};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Refuses GetI, and records the order the chain runs in
+    struct Guard(Rc<RefCell<Vec<&'static str>>>);
+
+    #[async_trait(?Send)]
+    impl Interceptor<HelloWorldRpcName> for Guard {
+        async fn on_query(&self, query: &mut ReceivedQuery<HelloWorldRpcName>) -> RpcResult<()> {
+            self.0.borrow_mut().push("guard on_query");
+            match query.name {
+                HelloWorldRpcName::GetI => Err(RpcError::Custom(String::from("No GetI here"))),
+                _ => Ok(()),
+            }
+        }
+
+        async fn around(
+            &self,
+            query: &ReceivedQuery<HelloWorldRpcName>,
+            next: Next<'_, HelloWorldRpcName>,
+        ) -> RpcResult<OwnedBytes> {
+            self.0.borrow_mut().push("guard before");
+            let result = next.run(query).await;
+            self.0.borrow_mut().push("guard after");
+            result
+        }
+    }
+
+    /// Swaps every hello world query for its own, and shouts the response
+    struct Shout(Rc<RefCell<Vec<&'static str>>>);
+
+    #[async_trait(?Send)]
+    impl Interceptor<HelloWorldRpcName> for Shout {
+        async fn on_query(&self, query: &mut ReceivedQuery<HelloWorldRpcName>) -> RpcResult<()> {
+            self.0.borrow_mut().push("shout on_query");
+            query.query_bytes = TransportWireConfig::pickle().serialize(&"ahoy")?;
+            Ok(())
+        }
+
+        async fn around(
+            &self,
+            query: &ReceivedQuery<HelloWorldRpcName>,
+            next: Next<'_, HelloWorldRpcName>,
+        ) -> RpcResult<OwnedBytes> {
+            self.0.borrow_mut().push("shout");
+            let wire_config = TransportWireConfig::pickle();
+            let hello: String = wire_config.deserialize(&next.run(query).await?)?;
+            Ok(wire_config.serialize(&hello.to_uppercase())?)
+        }
+    }
+
+    #[tokio::test]
+    async fn interceptors_wrap_handlers() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.layer(Guard(calls.clone()));
+        server.layer(Shout(calls.clone()));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            let hello = RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut transport)
+                .await;
+            (get_i, hello)
+        };
+
+        let ((), (get_i, hello)) = tokio::join!(server.serve_channel(listener), client_calls);
+        match get_i {
+            Err(RpcError::Remote(s)) => assert_eq!("No GetI here", s),
+            other => panic!("Expected GetI to be refused, got {:?}", other),
+        }
+        assert_eq!("HELLO WORLD: 3:\"AHOY\"", hello.unwrap());
+        assert_eq!(
+            vec![
+                "guard on_query",
+                "guard on_query",
+                "shout on_query",
+                "guard before",
+                "shout",
+                "guard after"
+            ],
+            *calls.borrow()
+        );
+    }
+
+    /// Retries queries for rpcs the server doesn't know as hello world instead, counting tries
+    struct FallBackToHelloWorld(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl ClientInterceptor<HelloWorldRpcName> for FallBackToHelloWorld {
+        async fn around(
+            &self,
+            query: OutgoingQuery<HelloWorldRpcName>,
+            mut next: ClientNext<'_, HelloWorldRpcName>,
+        ) -> RpcResult<OwnedBytes> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            match next.run(query.clone()).await {
+                Err(RpcError::UnknownRpc(_)) => {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                    let query = OutgoingQuery {
+                        rpc_name: HelloWorldRpcName::HelloWorld,
+                        ..query
+                    };
+                    next.run(query).await
+                }
+                result => result,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn client_interceptor_retries_renamed() {
+        let tries = Arc::new(AtomicUsize::new(0));
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let client_tries = tries.clone();
+        let client_call = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            transport.layer(FallBackToHelloWorld(client_tries));
+            let not_served = crate::Rpc::new(HelloWorldRpcName::MassiveRpc);
+            RpcClient::<_, String, String>::new(not_served)
+                .call("Foo".into(), &mut transport)
+                .await
+        };
+
+        let ((), hello) = tokio::join!(server.serve_channel(listener), client_call);
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap());
+        assert_eq!(2, tries.load(Ordering::Relaxed));
+    }
+}
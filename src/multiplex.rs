@@ -0,0 +1,1066 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::{into_rpc_result_transport, RpcError, RpcResult};
+use crate::keepalive::{keep_alive_multiplexed, PONG};
+use crate::metadata::NO_METADATA;
+use crate::priority::{Priority, PriorityQueue};
+use crate::server::turned_away;
+use crate::trace::CallSpan;
+use crate::transport::{
+    connect_tcp, within_send_timeout, write_frame, FrameReader, PackageKind, TcpTransport,
+    Transport, TransportConfig, TransportError, TransportResponseOwned,
+};
+use crate::{Bytes, OwnedBytes};
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// Calls waiting on a response, by correlation id
+#[derive(Default)]
+struct PendingCalls {
+    calls: HashMap<u64, oneshot::Sender<RpcResult<OwnedBytes>>>,
+    /// Set once the connection has failed, failing any later calls straight away
+    closed: Option<TransportError>,
+    /// Responses received with no call waiting on them, see [MultiplexedClient::orphan_responses]
+    orphaned: u64,
+}
+
+impl PendingCalls {
+    fn fail_all(&mut self, error: TransportError) {
+        for (_, call) in self.calls.drain() {
+            let _ = call.send(Err(RpcError::TransportError(error.clone())));
+        }
+        self.closed = Some(error);
+    }
+}
+
+struct Shared {
+    outgoing: mpsc::Sender<(Priority, OwnedBytes)>,
+    pending: Arc<Mutex<PendingCalls>>,
+    next_correlation_id: AtomicU64,
+    /// Slots for calls in flight, one taken by each until it's answered, see
+    /// [TransportConfig::max_in_flight]
+    in_flight_slots: Option<Semaphore>,
+    config: TransportConfig,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // The writer ends by itself once [outgoing] is dropped
+        self.reader.abort();
+    }
+}
+
+/// A [MultiplexedClient] lets many concurrent calls share one connection, rather than each
+/// needing its own [crate::Transport] and waiting its turn. Each query is tagged with a
+/// correlation id that the server echoes back on its response, so responses can be routed to
+/// the right caller whatever order they arrive in.
+/// Cloning is cheap and shares the connection, so hand a clone to each task making calls.
+/// The connection is closed once every clone is dropped.
+/// It works over any stream using the same length-prefixed framing as [crate::TcpTransport],
+/// so can talk to [crate::RpcServer::serve], [crate::RpcServer::serve_unix] and similar.
+/// Queries waiting to be sent go in order of [crate::Rpc::priority], which is sent along for
+/// the server to handle them in that order too
+pub struct MultiplexedClient<Name: RpcName> {
+    shared: Arc<Shared>,
+    name: PhantomData<Name>,
+}
+
+/// Held while a call waits for its query to be queued and then on its response. If the wait
+/// ends any other way, because it timed out or the caller dropped it, the call's forgotten,
+/// and once its query was queued the server's told to cancel the call so it can stop working
+/// on it
+struct CancelOnDrop<'a, Name: RpcName> {
+    shared: &'a Shared,
+    rpc_name: &'a Name,
+    correlation_id: u64,
+    armed: bool,
+    queued: bool,
+}
+
+impl<Name: RpcName> Drop for CancelOnDrop<'_, Name> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        self.shared
+            .pending
+            .lock()
+            .unwrap()
+            .calls
+            .remove(&self.correlation_id);
+        if !self.queued {
+            return;
+        }
+        let cancel = self.shared.config.encode_package(
+            self.rpc_name,
+            PackageKind::Cancel,
+            &[],
+            self.correlation_id,
+            None,
+            NO_METADATA,
+        );
+        if let Ok(package_bytes) = cancel {
+            debug!("Cancelling call {}", self.correlation_id);
+            // Nothing to do if the connection has already gone, while with no room to queue
+            // it, the server's left to finish the call
+            if let Err(e) = self
+                .shared
+                .outgoing
+                .try_send((Priority::High, package_bytes))
+            {
+                debug!("Unable to cancel call {}: {}", self.correlation_id, e);
+            }
+        }
+    }
+}
+
+impl<Name: RpcName> Clone for MultiplexedClient<Name> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            name: PhantomData,
+        }
+    }
+}
+
+impl<Name: RpcName> MultiplexedClient<Name> {
+    /// Start multiplexing calls over [stream]. Reading and writing happen on their own tasks,
+    /// so this must be called within a tokio runtime
+    pub fn new<S>(stream: S, config: TransportConfig) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+        let pending = Arc::new(Mutex::new(PendingCalls::default()));
+        let (outgoing, outgoing_receiver) = mpsc::channel(config.max_queued_sends.max(1));
+        tokio::spawn(write_packages(
+            writer,
+            outgoing_receiver,
+            pending.clone(),
+            config.send_timeout,
+            config.starvation_limit,
+        ));
+        let reader = tokio::spawn(read_responses(
+            reader,
+            config.clone(),
+            pending.clone(),
+            outgoing.clone(),
+        ));
+        Self {
+            shared: Arc::new(Shared {
+                outgoing,
+                pending,
+                // 0 is left for calls made one at a time
+                next_correlation_id: AtomicU64::new(1),
+                in_flight_slots: config.max_in_flight.map(|max| Semaphore::new(max.max(1))),
+                config,
+                reader,
+            }),
+            name: PhantomData,
+        }
+    }
+
+    /// Connect over TCP to [addr], handshaking and checking the schema first if [config] asks
+    /// for them, see [TransportConfig::handshake] and [TransportConfig::schema]. Connecting
+    /// fails after [TransportConfig::connect_timeout]
+    pub async fn connect(addr: &str, config: TransportConfig) -> RpcResult<Self> {
+        let tcp_stream = connect_tcp(addr, config.connect_timeout).await?;
+        if config.handshake || config.schema.is_some() {
+            let mut transport: Transport<_, Name> =
+                Transport::new(TcpTransport::new(tcp_stream), config.clone());
+            if config.handshake {
+                transport.handshake().await?;
+            }
+            if let Some(schema) = &config.schema {
+                transport.verify_schema(schema).await?;
+            }
+            return Ok(Self::new(
+                transport.into_internal_transport().into_stream(),
+                config,
+            ));
+        }
+        Ok(Self::new(tcp_stream, config))
+    }
+
+    pub async fn call<Q: RpcType, R: RpcType>(
+        &self,
+        query: Q,
+        rpc: Rpc<Name, Q, R>,
+    ) -> RpcResult<R> {
+        let config = &self.shared.config;
+        let query_bytes = config
+            .wire_config
+            .serialize(&query)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", rpc.name)))?;
+        let span = CallSpan::client(&rpc.name, query_bytes.len());
+        let started = config.clock.now();
+        let result_bytes = span
+            .instrument(self.send_prioritised(&query_bytes, &rpc.name, rpc.priority))
+            .await;
+        span.record_result(result_bytes.as_ref().map(Vec::len));
+        span.record_latency(config.clock.now().duration_since(started));
+        let result = config
+            .wire_config
+            .deserialize(&result_bytes?)
+            .map_err(|e| e.in_step(format_args!("response for rpc {}", rpc.name)));
+        into_rpc_result_transport(result)
+    }
+
+    /// As [call], but giving up with [RpcError::Cancelled] as soon as [cancellation] is
+    /// cancelled, telling the server to stop work on the call
+    pub async fn call_with_cancellation<Q: RpcType, R: RpcType>(
+        &self,
+        query: Q,
+        rpc: Rpc<Name, Q, R>,
+        cancellation: &CancellationToken,
+    ) -> RpcResult<R> {
+        let rpc_name = format!("{}", rpc.name);
+        tokio::select! {
+            result = self.call(query, rpc) => result,
+            _ = cancellation.cancelled() => Err(RpcError::Cancelled(rpc_name)),
+        }
+    }
+
+    /// As [crate::Transport::send_query], but without waiting for other calls to finish first.
+    /// Dropping the returned future before it completes cancels the call on the server
+    pub async fn send_query(
+        &self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+    ) -> RpcResult<OwnedBytes> {
+        self.send_prioritised(query_bytes, rpc_name, Priority::Normal)
+            .await
+    }
+
+    async fn send_prioritised(
+        &self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        priority: Priority,
+    ) -> RpcResult<OwnedBytes> {
+        let config = &self.shared.config;
+        let mut metadata = Cow::Borrowed(&config.metadata);
+        if priority != Priority::Normal {
+            priority.add_to(metadata.to_mut());
+        }
+        let start = config.clock.now();
+        let deadline = tokio::time::Instant::now() + config.rcv_timeout;
+        let timed_out = || {
+            let elapsed = config.clock.now().duration_since(start);
+            RpcError::RpcTimeout(format!("{}", rpc_name), elapsed)
+        };
+        // Held until the call's over, whichever way it ends
+        let _slot = match &self.shared.in_flight_slots {
+            Some(slots) => match tokio::time::timeout_at(deadline, slots.acquire()).await {
+                Ok(slot) => Some(slot.expect("In flight slots are never closed")),
+                Err(_) => return Err(timed_out()),
+            },
+            None => None,
+        };
+        let correlation_id = self
+            .shared
+            .next_correlation_id
+            .fetch_add(1, Ordering::Relaxed);
+        let package_bytes = config.encode_package(
+            rpc_name,
+            PackageKind::Query,
+            query_bytes,
+            correlation_id,
+            Some(config.rcv_timeout),
+            &metadata,
+        )?;
+        let (call, response) = oneshot::channel();
+        {
+            let mut pending = self.shared.pending.lock().unwrap();
+            if let Some(error) = &pending.closed {
+                return Err(RpcError::TransportError(error.clone()));
+            }
+            pending.calls.insert(correlation_id, call);
+        }
+        let mut cancel_on_drop = CancelOnDrop {
+            shared: &self.shared,
+            rpc_name,
+            correlation_id,
+            armed: true,
+            queued: false,
+        };
+        // Waiting for room to queue the query, if too many are waiting to be written already
+        let queue = self.shared.outgoing.send((priority, package_bytes));
+        match tokio::time::timeout_at(deadline, queue).await {
+            Ok(Ok(())) => cancel_on_drop.queued = true,
+            Ok(Err(_)) => {
+                return Err(RpcError::TransportError(TransportError::ConnectionClosed));
+            }
+            Err(_) => return Err(timed_out()),
+        }
+        let result = tokio::time::timeout_at(deadline, response).await;
+        // Timing out leaves the guard armed, so the server stops work on the call too
+        cancel_on_drop.armed = result.is_err();
+        drop(cancel_on_drop);
+        match result {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(RpcError::TransportError(TransportError::ConnectionClosed)),
+            Err(_) => Err(timed_out()),
+        }
+    }
+
+    /// Number of responses received that no call was waiting on, and so were dropped: those
+    /// to calls that had already timed out or been cancelled, but also any for a correlation
+    /// id never sent or already answered, which a healthy server never sends
+    pub fn orphan_responses(&self) -> u64 {
+        self.shared.pending.lock().unwrap().orphaned
+    }
+
+    /// Number of calls sent and still waiting on a response, which
+    /// [TransportConfig::max_in_flight] caps. Those waiting for a slot aren't counted
+    pub fn in_flight(&self) -> usize {
+        self.shared.pending.lock().unwrap().calls.len()
+    }
+}
+
+/// Write each package as a whole frame, so a caller giving up part way can't leave half a
+/// frame on the connection. One not written within [send_timeout] fails every pending call,
+/// as the connection can't be used after. Those queued up while another's written go by
+/// [Priority], with none overtaken more than [starvation_limit] times
+async fn write_packages(
+    mut writer: impl AsyncWrite + Unpin,
+    mut outgoing: mpsc::Receiver<(Priority, OwnedBytes)>,
+    pending: Arc<Mutex<PendingCalls>>,
+    send_timeout: Option<Duration>,
+    starvation_limit: usize,
+) {
+    let mut queued = PriorityQueue::new(starvation_limit);
+    loop {
+        while let Ok((priority, package_bytes)) = outgoing.try_recv() {
+            queued.push(priority, package_bytes);
+        }
+        let package_bytes = match queued.pop() {
+            Some(package_bytes) => package_bytes,
+            None => match outgoing.recv().await {
+                Some((_, package_bytes)) => package_bytes,
+                None => return,
+            },
+        };
+        let send = write_frame(&mut writer, &package_bytes);
+        if let Err(e) = within_send_timeout(send_timeout, send).await {
+            warn!("Multiplexed connection failed sending: {}", e);
+            pending.lock().unwrap().fail_all(e);
+            return;
+        }
+    }
+}
+
+/// Route each response to the call waiting on its correlation id, until the connection fails.
+/// Chunks of a response, see [crate::ServerConfig::response_chunk_size], are joined up by
+/// correlation id until the rest of it arrives, no more than
+/// [TransportConfig::max_inbound_message_size] of each. Keepalive pings, see
+/// [crate::KeepaliveConfig], are sent from here too, as they're answered with a pong to read
+async fn read_responses(
+    mut reader: impl AsyncRead + Unpin,
+    config: TransportConfig,
+    pending: Arc<Mutex<PendingCalls>>,
+    outgoing: mpsc::Sender<(Priority, OwnedBytes)>,
+) {
+    let mut frames = FrameReader::with_config(&config);
+    let last_received = Arc::new(Mutex::new(Instant::now()));
+    let keep_alive = async {
+        match config.keepalive {
+            Some(keepalive) => {
+                keep_alive_multiplexed(keepalive, outgoing, last_received.clone()).await
+            }
+            None => std::future::pending().await,
+        }
+    };
+    futures_util::pin_mut!(keep_alive);
+    let mut partial: HashMap<u64, OwnedBytes> = HashMap::new();
+    let error = loop {
+        let response_bytes = tokio::select! {
+            read = frames.read_frame(&mut reader, None) => match read {
+                Ok(response_bytes) => response_bytes,
+                Err(e) => break e,
+            },
+            e = &mut keep_alive => break e,
+        };
+        *last_received.lock().unwrap() = Instant::now();
+        if response_bytes == PONG {
+            continue;
+        }
+        if let Some(correlation_id) = turned_away(&response_bytes) {
+            // Those in flight are still answered, but nothing sent after will be
+            debug!("Multiplexed connection closing, as the server's going away");
+            let mut pending = pending.lock().unwrap();
+            pending.closed = Some(TransportError::ConnectionClosed);
+            if let Some(call) = pending.calls.remove(&correlation_id) {
+                let closed = TransportError::ConnectionClosed;
+                let _ = call.send(Err(RpcError::TransportError(closed)));
+            }
+            continue;
+        }
+        let envelope = match config.decode_response(&response_bytes, "multiplexed call") {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Dropping response that couldn't be decoded: {}", e);
+                continue;
+            }
+        };
+        let correlation_id = envelope.correlation_id;
+        let mut pending_calls = pending.lock().unwrap();
+        let response = match envelope.response {
+            TransportResponseOwned::Chunk(chunk) => {
+                if !pending_calls.calls.contains_key(&correlation_id) {
+                    partial.remove(&correlation_id);
+                    continue;
+                }
+                let joined = partial.entry(correlation_id).or_default();
+                joined.extend_from_slice(&chunk);
+                match config.max_inbound_message_size {
+                    Some(max) if joined.len() > max => {
+                        let too_large = TransportError::MessageTooLarge(joined.len(), max);
+                        partial.remove(&correlation_id);
+                        if let Some(call) = pending_calls.calls.remove(&correlation_id) {
+                            let _ = call.send(Err(RpcError::TransportError(too_large)));
+                        }
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+            response => response,
+        };
+        let joined = partial.remove(&correlation_id);
+        // Removed once answered, so a second response to the same call is dropped like any
+        // other with no call waiting on it
+        match pending_calls.calls.remove(&correlation_id) {
+            Some(call) => {
+                let result = match (joined, response.into_result()) {
+                    (Some(mut joined), Ok(rest)) => {
+                        joined.extend_from_slice(&rest);
+                        Ok(joined)
+                    }
+                    (_, result) => result,
+                };
+                let _ = call.send(result);
+            }
+            None => {
+                pending_calls.orphaned += 1;
+                debug!(
+                    "Dropping response {} with no call waiting on it, which may have timed out",
+                    correlation_id
+                );
+            }
+        }
+    };
+    debug!("Multiplexed connection closed: {}", error);
+    pending.lock().unwrap().fail_all(error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::{Interceptor, Next};
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, make_hello_world_rpc_impl,
+        HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{ReceivedQuery, TcpTransport, Transport};
+    use async_trait::async_trait;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn responses_routed_out_of_order() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport: Transport<_, HelloWorldRpcName> =
+                Transport::new(TcpTransport::new(stream), TransportConfig::default());
+            let first = transport.receive_query().await.unwrap();
+            let second = transport.receive_query().await.unwrap();
+            // Answer in reverse, echoing each query back
+            for query in [second, first] {
+                transport
+                    .respond(query.correlation_id, &query.query_bytes)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client: MultiplexedClient<HelloWorldRpcName> =
+            MultiplexedClient::connect(&addr, TransportConfig::default())
+                .await
+                .unwrap();
+        let other_client = client.clone();
+        let (first, second) = tokio::join!(
+            client.call("first".to_string(), make_hello_world_rpc()),
+            other_client.call("second".to_string(), make_hello_world_rpc()),
+        );
+        assert_eq!("first", first.unwrap());
+        assert_eq!("second", second.unwrap());
+        assert_eq!(0, client.in_flight());
+        server.await.unwrap();
+
+        // The server has hung up, which fails later calls rather than leaving them waiting
+        match client
+            .call("third".to_string(), make_hello_world_rpc())
+            .await
+        {
+            Err(RpcError::TransportError(TransportError::ConnectionClosed)) => (),
+            other => panic!("Expected ConnectionClosed, got {:?}", other),
+        }
+    }
+
+    /// Serve one connection from [listener] with [answer], then echo back one last query, to
+    /// show the client's still working
+    async fn answer_then_echo<F, Fut>(listener: tokio::net::TcpListener, answer: F)
+    where
+        F: FnOnce(Transport<TcpTransport, HelloWorldRpcName>) -> Fut,
+        Fut: std::future::Future<Output = Transport<TcpTransport, HelloWorldRpcName>>,
+    {
+        let (stream, _) = listener.accept().await.unwrap();
+        let transport = Transport::new(TcpTransport::new(stream), TransportConfig::default());
+        let mut transport = answer(transport).await;
+        let last = transport.receive_query().await.unwrap();
+        transport
+            .respond(last.correlation_id, &last.query_bytes)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn response_for_unknown_call_dropped() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(answer_then_echo(listener, |mut transport| async {
+            let query = transport.receive_query().await.unwrap();
+            let unknown = query.correlation_id + 100;
+            let stray = b"stray".to_vec();
+            transport.respond(unknown, &stray).await.unwrap();
+            transport
+                .respond(query.correlation_id, &query.query_bytes)
+                .await
+                .unwrap();
+            transport
+        }));
+
+        let client: MultiplexedClient<HelloWorldRpcName> =
+            MultiplexedClient::connect(&addr, TransportConfig::default())
+                .await
+                .unwrap();
+        let first = client
+            .call("first".to_string(), make_hello_world_rpc())
+            .await;
+        assert_eq!("first", first.unwrap());
+        assert_eq!(1, client.orphan_responses());
+        let last = client
+            .call("last".to_string(), make_hello_world_rpc())
+            .await;
+        assert_eq!("last", last.unwrap());
+        assert_eq!(0, client.in_flight());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn second_response_to_call_dropped() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(answer_then_echo(listener, |mut transport| async {
+            let first = transport.receive_query().await.unwrap();
+            let second = transport.receive_query().await.unwrap();
+            // Twice, the second time while the second call's still waiting on its own
+            for _ in 0..2 {
+                transport
+                    .respond(first.correlation_id, &first.query_bytes)
+                    .await
+                    .unwrap();
+            }
+            transport
+                .respond(second.correlation_id, &second.query_bytes)
+                .await
+                .unwrap();
+            transport
+        }));
+
+        let client: MultiplexedClient<HelloWorldRpcName> =
+            MultiplexedClient::connect(&addr, TransportConfig::default())
+                .await
+                .unwrap();
+        let (first, second) = tokio::join!(
+            client.call("first".to_string(), make_hello_world_rpc()),
+            client.call("second".to_string(), make_hello_world_rpc()),
+        );
+        assert_eq!("first", first.unwrap());
+        assert_eq!("second", second.unwrap());
+        assert_eq!(1, client.orphan_responses());
+        let last = client
+            .call("last".to_string(), make_hello_world_rpc())
+            .await;
+        assert_eq!("last", last.unwrap());
+        assert_eq!(0, client.in_flight());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_call_tells_server() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport: Transport<_, HelloWorldRpcName> =
+                Transport::new(TcpTransport::new(stream), TransportConfig::default());
+            // Never answered, just followed by the cancel
+            let query = transport.receive_query().await.unwrap();
+            let cancel = transport.receive_query().await.unwrap();
+            (query, cancel)
+        });
+
+        let client: MultiplexedClient<HelloWorldRpcName> =
+            MultiplexedClient::connect(&addr, TransportConfig::default())
+                .await
+                .unwrap();
+        let cancellation = CancellationToken::new();
+        let cancel_soon = cancellation.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            cancel_soon.cancel();
+        });
+        let result = client
+            .call_with_cancellation("hello".to_string(), make_hello_world_rpc(), &cancellation)
+            .await;
+        assert!(matches!(result, Err(RpcError::Cancelled(_))));
+        assert_eq!(0, client.in_flight());
+
+        let (query, cancel) = server.await.unwrap();
+        assert_eq!(PackageKind::Query, query.kind);
+        assert_eq!(PackageKind::Cancel, cancel.kind);
+        assert_eq!(query.correlation_id, cancel.correlation_id);
+        assert_eq!(HelloWorldRpcName::HelloWorld, cancel.name);
+    }
+
+    #[tokio::test]
+    async fn calls_past_max_queued_sends_wait_for_room() {
+        // Never read, so the writer's stuck on the first query and the rest queue behind it
+        let (stream, _unread) = tokio::io::duplex(64);
+        let config = TransportConfig {
+            max_queued_sends: 1,
+            rcv_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client: MultiplexedClient<HelloWorldRpcName> = MultiplexedClient::new(stream, config);
+        let calls = (0..4).map(|_| client.call("hello".repeat(100), make_hello_world_rpc()));
+        for result in futures_util::future::join_all(calls).await {
+            assert!(matches!(result, Err(RpcError::RpcTimeout(_, _))));
+        }
+        assert_eq!(0, client.in_flight());
+    }
+
+    #[tokio::test]
+    async fn calls_past_max_in_flight_wait_for_one_to_finish() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport: Transport<_, HelloWorldRpcName> =
+                Transport::new(TcpTransport::new(stream), TransportConfig::default());
+            let first = transport.receive_query().await.unwrap();
+            let second = transport.receive_query().await.unwrap();
+            let third = tokio::time::timeout(Duration::from_millis(100), transport.receive_query());
+            let third_sent_early = third.await.is_ok();
+            // Echoing each query back, which frees a slot for the third
+            transport
+                .respond(first.correlation_id, &first.query_bytes)
+                .await
+                .unwrap();
+            let third = transport.receive_query().await.unwrap();
+            for query in [second, third] {
+                transport
+                    .respond(query.correlation_id, &query.query_bytes)
+                    .await
+                    .unwrap();
+            }
+            third_sent_early
+        });
+
+        let config = TransportConfig {
+            max_in_flight: Some(2),
+            ..Default::default()
+        };
+        let client: MultiplexedClient<HelloWorldRpcName> =
+            MultiplexedClient::connect(&addr, config).await.unwrap();
+        let in_flight_while_waiting = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.in_flight()
+        };
+        let (first, second, third, in_flight_while_waiting) = tokio::join!(
+            client.call("first".to_string(), make_hello_world_rpc()),
+            client.call("second".to_string(), make_hello_world_rpc()),
+            client.call("third".to_string(), make_hello_world_rpc()),
+            in_flight_while_waiting,
+        );
+        assert_eq!("first", first.unwrap());
+        assert_eq!("second", second.unwrap());
+        assert_eq!("third", third.unwrap());
+        assert_eq!(2, in_flight_while_waiting);
+        assert_eq!(0, client.in_flight());
+        assert!(!server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn cancelled_call_stops_handler() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        let handed_off = Arc::new(tokio::sync::RwLock::new(None));
+        server.add_async_rpc(Box::new(crate::core::AsyncRpcImpl::writing(
+            HelloWorldRpcName::HelloWorld,
+            handed_off.clone(),
+            Box::new(
+                |handed_off: &mut Option<CancellationToken>, _query: String| {
+                    Box::pin(async move {
+                        *handed_off = crate::call_cancellation();
+                        std::future::pending::<RpcResult<String>>().await
+                    })
+                },
+            ),
+        )));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let listener = crate::listener::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let client: MultiplexedClient<HelloWorldRpcName> =
+                MultiplexedClient::connect(&addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            let cancellation = CancellationToken::new();
+            let cancelled = client.call_with_cancellation(
+                "hello".to_string(),
+                make_hello_world_rpc(),
+                &cancellation,
+            );
+            let cancel_soon = async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                cancellation.cancel();
+            };
+            let (cancelled, ()) = tokio::join!(cancelled, cancel_soon);
+            // Dropping the handler frees its context for the next call
+            let handed_off = tokio::time::timeout(Duration::from_secs(1), handed_off.read())
+                .await
+                .unwrap()
+                .clone();
+            let i = client.call((), make_get_i_rpc()).await;
+            shutdown.shutdown();
+            (cancelled, handed_off, i)
+        };
+
+        let ((), (cancelled, handed_off, i)) =
+            tokio::join!(server.serve_listener(listener), client_calls);
+        assert!(matches!(cancelled, Err(RpcError::Cancelled(_))));
+        assert!(handed_off.unwrap().is_cancelled());
+        assert_eq!(3, i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_connection() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let addr = "127.0.0.1:5565";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let client: MultiplexedClient<HelloWorldRpcName> =
+                MultiplexedClient::connect(addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            let calls: Vec<_> = (0..10)
+                .map(|i| {
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        client.call(format!("{}", i), make_hello_world_rpc()).await
+                    })
+                })
+                .collect();
+            let mut results = Vec::new();
+            for call in calls {
+                results.push(call.await.unwrap());
+            }
+            results
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        for (i, result) in rpc_results.unwrap().unwrap().into_iter().enumerate() {
+            assert_eq!(format!("Hello world: 3:\"{}\"", i), result.unwrap());
+        }
+    }
+
+    /// Takes its time over GetI before handing it on
+    struct SlowGetI;
+
+    #[async_trait(?Send)]
+    impl Interceptor<HelloWorldRpcName> for SlowGetI {
+        async fn around(
+            &self,
+            query: &ReceivedQuery<HelloWorldRpcName>,
+            next: Next<'_, HelloWorldRpcName>,
+        ) -> RpcResult<OwnedBytes> {
+            if query.name == HelloWorldRpcName::GetI {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            next.run(query).await
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_call_doesnt_hold_up_connection() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.layer(SlowGetI);
+        let addr = "127.0.0.1:5571";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let client: MultiplexedClient<HelloWorldRpcName> =
+                MultiplexedClient::connect(addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            let slow_client = client.clone();
+            let slow = tokio::spawn(async move { slow_client.call((), make_get_i_rpc()).await });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let hello = client.call("Foo".to_string(), make_hello_world_rpc()).await;
+            let slow_finished_first = slow.is_finished();
+            (hello, slow_finished_first, slow.await.unwrap())
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (hello, slow_finished_first, get_i) = rpc_results.unwrap().unwrap();
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap());
+        assert!(!slow_finished_first);
+        assert_eq!(3, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn call_turned_away_fails_without_waiting_for_close() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = crate::ServerConfig {
+            max_requests_per_connection: Some(1),
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.layer(SlowGetI);
+        let listener = crate::listener::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let client: MultiplexedClient<HelloWorldRpcName> =
+                MultiplexedClient::connect(&addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            let slow_client = client.clone();
+            let slow = tokio::spawn(async move { slow_client.call((), make_get_i_rpc()).await });
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // Past the limit, while the slow call keeps the connection open
+            let turned_away = client.call("Foo".to_string(), make_hello_world_rpc()).await;
+            let slow_finished_first = slow.is_finished();
+            let after = client.call("Bar".to_string(), make_hello_world_rpc()).await;
+            let slow = slow.await.unwrap();
+            shutdown.shutdown();
+            (turned_away, slow_finished_first, after, slow)
+        };
+
+        let ((), (turned_away, slow_finished_first, after, slow)) =
+            tokio::join!(server.serve_listener(listener), client_calls);
+        match turned_away {
+            Err(e @ RpcError::TransportError(TransportError::ConnectionClosed)) => {
+                assert!(e.is_retryable())
+            }
+            other => panic!("Expected ConnectionClosed, got {:?}", other),
+        }
+        assert!(!slow_finished_first);
+        assert!(matches!(
+            after,
+            Err(RpcError::TransportError(TransportError::ConnectionClosed))
+        ));
+        assert_eq!(3, slow.unwrap());
+    }
+
+    /// Takes its time admitting HelloWorld
+    struct SlowToAdmitHelloWorld;
+
+    #[async_trait(?Send)]
+    impl Interceptor<HelloWorldRpcName> for SlowToAdmitHelloWorld {
+        async fn on_query(&self, query: &mut ReceivedQuery<HelloWorldRpcName>) -> RpcResult<()> {
+            if query.name == HelloWorldRpcName::HelloWorld {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_sent_while_admitting_doesnt_lose_query() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.layer(SlowGetI);
+        server.layer(SlowToAdmitHelloWorld);
+        let listener = crate::listener::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let client: MultiplexedClient<HelloWorldRpcName> =
+                MultiplexedClient::connect(&addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            // GetI's answered 200ms in, while HelloWorld's being admitted from 150ms to 250ms
+            let get_i = client.call((), make_get_i_rpc());
+            let hello = async {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                let hello = client.call("Foo".to_string(), make_hello_world_rpc());
+                tokio::time::timeout(Duration::from_secs(2), hello).await
+            };
+            let (get_i, hello) = tokio::join!(get_i, hello);
+            shutdown.shutdown();
+            (get_i, hello)
+        };
+
+        let ((), (get_i, hello)) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert_eq!(3, get_i.unwrap());
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn queued_calls_handled_by_priority() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = crate::ServerConfig {
+            max_concurrent_queries: 2,
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let handled_by_server = handled.clone();
+        server.add_rpc(Box::new(crate::core::RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(move |_state, query: String| {
+                handled_by_server.lock().unwrap().push(query);
+                Ok(())
+            }),
+        )));
+        server.layer(SlowGetI);
+        let listener = crate::listener::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let client: MultiplexedClient<HelloWorldRpcName> =
+                MultiplexedClient::connect(&addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            let hello = Rpc::<_, String, ()>::new(HelloWorldRpcName::HelloWorld);
+            // Taking up both places, so the rest wait their turn
+            let slow = async {
+                tokio::join!(
+                    client.call((), make_get_i_rpc()),
+                    client.call((), make_get_i_rpc())
+                )
+            };
+            let queued = async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                let low = client.call("low".to_string(), hello.clone().priority(Priority::Low));
+                let high = async {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    client
+                        .call("high".to_string(), hello.clone().priority(Priority::High))
+                        .await
+                };
+                tokio::join!(low, high)
+            };
+            let ((slow_a, slow_b), (low, high)) = tokio::join!(slow, queued);
+            shutdown.shutdown();
+            (slow_a, slow_b, low, high)
+        };
+
+        let ((), (slow_a, slow_b, low, high)) =
+            tokio::join!(server.serve_listener(listener), client_calls);
+        assert_eq!(3, slow_a.unwrap());
+        assert_eq!(3, slow_b.unwrap());
+        low.unwrap();
+        high.unwrap();
+        assert_eq!(vec!["high", "low"], *handled.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn small_response_sent_between_chunks_of_large_one() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = crate::ServerConfig {
+            response_chunk_size: Some(1024),
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(crate::core::RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, len: usize| Ok("x".repeat(len))),
+        )));
+        let listener = crate::listener::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let client: MultiplexedClient<HelloWorldRpcName> =
+                MultiplexedClient::connect(&addr, TransportConfig::default())
+                    .await
+                    .unwrap();
+            let large_rpc = Rpc::<_, usize, String>::new(HelloWorldRpcName::HelloWorld);
+            let large = client.call(4 * 1024 * 1024, large_rpc);
+            futures_util::pin_mut!(large);
+            // Sent first, so the small one's only answered first by going between its chunks
+            let i = tokio::select! {
+                biased;
+                large = &mut large => panic!("Large response finished first: {:?}", large.err()),
+                i = client.call((), make_get_i_rpc()) => i,
+            };
+            let large = large.await;
+            shutdown.shutdown();
+            (i, large)
+        };
+
+        let ((), (i, large)) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert_eq!(3, i.unwrap());
+        assert_eq!("x".repeat(4 * 1024 * 1024), large.unwrap());
+    }
+}
@@ -0,0 +1,339 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use log::{info, warn};
+
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::idempotency::IDEMPOTENCY_KEY;
+use crate::transport::{InternalTransport, Transport};
+use crate::Bytes;
+
+const CALL_EXTENSION: &str = "call";
+const CORRUPT_EXTENSION: &str = "corrupt";
+
+/// A durable call, as written to its file: the pickled rpc name and the idempotency key, each
+/// length prefixed, then the query as serialised for the wire
+struct StoredCall<Name> {
+    rpc_name: Name,
+    idempotency_key: String,
+    query_bytes: Vec<u8>,
+}
+
+impl<Name: RpcName> StoredCall<Name> {
+    fn encode(
+        rpc_name: &Name,
+        idempotency_key: &str,
+        query_bytes: Bytes<'_>,
+    ) -> io::Result<Vec<u8>> {
+        let name_bytes = serde_pickle::to_vec(rpc_name, serde_pickle::SerOptions::new())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut encoded = Vec::with_capacity(8 + name_bytes.len() + idempotency_key.len());
+        for field in [&name_bytes[..], idempotency_key.as_bytes()] {
+            encoded.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            encoded.extend_from_slice(field);
+        }
+        encoded.extend_from_slice(query_bytes);
+        Ok(encoded)
+    }
+
+    fn decode(mut encoded: Bytes<'_>) -> io::Result<Self> {
+        let mut field = || {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Truncated outbox call");
+            let (len, rest) = encoded.split_first_chunk::<4>().ok_or_else(invalid)?;
+            let len = u32::from_be_bytes(*len) as usize;
+            let (field, rest) = rest.split_at_checked(len).ok_or_else(invalid)?;
+            encoded = rest;
+            Ok::<_, io::Error>(field)
+        };
+        let rpc_name = serde_pickle::from_slice(field()?, serde_pickle::DeOptions::new())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let idempotency_key = String::from_utf8(field()?.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            rpc_name,
+            idempotency_key,
+            query_bytes: encoded.to_vec(),
+        })
+    }
+}
+
+/// A directory of calls made with [crate::RpcClient::call_durable], each written to disk before
+/// it's sent and removed once the server's answered it, so none is lost to the connection or
+/// the client going down: those left are sent again by [Outbox::replay], e.g. when the client
+/// restarts or reconnects. Each is sent with an idempotency key, so a server layered with
+/// [crate::Deduplication] runs it once however many times it arrives, so long as each repeat
+/// arrives within its remember_for and before max_keys newer keys push it out.
+/// Queries are kept as serialised, so must be replayed over a transport with the same
+/// [crate::TransportWireConfig]. Files are written and read on tokio's blocking threads.
+/// Clones share the directory, which only one [Outbox] should have open at a time
+#[derive(Clone)]
+pub struct Outbox {
+    dir: Arc<PathBuf>,
+    next_seq: Arc<AtomicU64>,
+}
+
+/// A durable call written to the [Outbox], until it's [delivered](OutboxEntry::delivered)
+pub(crate) struct OutboxEntry {
+    path: PathBuf,
+}
+
+impl OutboxEntry {
+    /// Remove the call, as the server's answered it
+    pub(crate) async fn delivered(self) {
+        let path = self.path.clone();
+        if let Err(e) = blocking(move || fs::remove_file(path)).await {
+            warn!(
+                "Unable to remove delivered call {:?} from outbox, it'll be sent again: {}",
+                self.path, e
+            );
+        }
+    }
+}
+
+impl Outbox {
+    /// Open the outbox in [dir], creating it if needed, with any calls left from before. This
+    /// blocks on the filesystem, so open it before serving or calling
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let next_seq = Self::pending_paths(&dir)?
+            .last()
+            .and_then(|path| Self::seq_of(path))
+            .map_or(0, |seq| seq + 1);
+        Ok(Self {
+            dir: Arc::new(dir),
+            next_seq: Arc::new(AtomicU64::new(next_seq)),
+        })
+    }
+
+    /// Number of calls not yet answered
+    pub async fn pending(&self) -> io::Result<usize> {
+        let dir = self.dir.clone();
+        Ok(blocking(move || Self::pending_paths(&dir)).await?.len())
+    }
+
+    /// Send each call not yet answered, oldest first, removing them as they're answered. That
+    /// they were answered is all that's kept, whether with a response or an error, as there's
+    /// no caller left to give it to. Stops at the first that fails such that it might not have
+    /// run, see [RpcError::is_retryable], with that error, leaving the rest for next time.
+    /// A call that can't be read back is renamed to end `.corrupt`, to look at by hand, and
+    /// skipped. Gives how many were answered
+    pub async fn replay<Name: RpcName>(
+        &self,
+        transport: &mut Transport<impl InternalTransport, Name>,
+    ) -> RpcResult<usize> {
+        let mut answered = 0;
+        let dir = self.dir.clone();
+        let paths = blocking(move || Self::pending_paths(&dir))
+            .await
+            .map_err(Self::outbox_error)?;
+        for path in paths {
+            let read_from = path.clone();
+            let call: StoredCall<Name> = match blocking(move || fs::read(read_from)).await {
+                Ok(encoded) => match StoredCall::decode(&encoded) {
+                    Ok(call) => call,
+                    Err(e) => {
+                        Self::quarantine(path, e).await;
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    Self::quarantine(path, e).await;
+                    continue;
+                }
+            };
+            let mut metadata = transport.config.metadata.clone();
+            metadata.insert(String::from(IDEMPOTENCY_KEY), call.idempotency_key);
+            let timeout = transport.config.rcv_timeout;
+            let result = transport
+                .send_query_with_metadata(&call.query_bytes, &call.rpc_name, timeout, metadata)
+                .await;
+            match result {
+                Err(e) if e.is_retryable() => return Err(e),
+                Err(e) => warn!("Replayed call to {} failed: {}", call.rpc_name, e),
+                Ok(_) => (),
+            }
+            OutboxEntry { path }.delivered().await;
+            answered += 1;
+        }
+        if answered > 0 {
+            info!("Replayed {} calls from outbox", answered);
+        }
+        Ok(answered)
+    }
+
+    /// Write a call to disk, completely or not at all, before it's sent
+    pub(crate) async fn add<Name: RpcName>(
+        &self,
+        rpc_name: &Name,
+        idempotency_key: &str,
+        query_bytes: Bytes<'_>,
+    ) -> RpcResult<OutboxEntry> {
+        let encoded = StoredCall::encode(rpc_name, idempotency_key, query_bytes)
+            .map_err(Self::outbox_error)?;
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{:020}.{}", seq, CALL_EXTENSION));
+        let partial = path.with_extension("partial");
+        let dir = self.dir.clone();
+        let written = path.clone();
+        let write = move || {
+            let mut file = fs::File::create(&partial)?;
+            io::Write::write_all(&mut file, &encoded)?;
+            file.sync_all()?;
+            fs::rename(&partial, &written)?;
+            // So the rename itself survives the machine going down
+            sync_dir(&dir)
+        };
+        blocking(write).await.map_err(Self::outbox_error)?;
+        Ok(OutboxEntry { path })
+    }
+
+    /// Set aside a call that can't be read back, so it neither stops the rest being replayed
+    /// nor is tried again
+    async fn quarantine(path: PathBuf, e: io::Error) {
+        warn!(
+            "Unable to read call {:?} from outbox, setting it aside: {}",
+            path, e
+        );
+        let corrupt = path.with_extension(CORRUPT_EXTENSION);
+        if let Err(e) = blocking(move || fs::rename(&path, corrupt)).await {
+            warn!("Unable to set aside unreadable call: {}", e);
+        }
+    }
+
+    /// Calls not yet answered, oldest first
+    fn pending_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == CALL_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        // Zero padded, so in order by name
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn seq_of(path: &Path) -> Option<u64> {
+        path.file_stem()?.to_str()?.parse().ok()
+    }
+
+    fn outbox_error(e: io::Error) -> RpcError {
+        RpcError::Custom(format!("Outbox error: {}", e))
+    }
+}
+
+/// Run [f] on tokio's blocking threads, as it waits on the filesystem
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened to sync elsewhere
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::error::RpcResult;
+    use crate::idempotency::Deduplication;
+    use crate::middleware::{ClientInterceptor, ClientNext, OutgoingQuery};
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState, IncrIRpc};
+    use crate::transport::{channel_listener, TransportConfig, TransportError};
+    use crate::{OwnedBytes, RpcDefinition};
+    use async_trait::async_trait;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Sends queries, then fails as if the connection broke before the response, while [failing]
+    struct LosesResponses {
+        failing: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl ClientInterceptor<HelloWorldRpcName> for LosesResponses {
+        async fn around(
+            &self,
+            query: OutgoingQuery<HelloWorldRpcName>,
+            mut next: ClientNext<'_, HelloWorldRpcName>,
+        ) -> RpcResult<OwnedBytes> {
+            let response = next.run(query).await;
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(RpcError::TransportError(TransportError::ConnectionClosed));
+            }
+            response
+        }
+    }
+
+    #[tokio::test]
+    async fn unanswered_calls_replayed_once_reopened() {
+        let dir = std::env::temp_dir().join(format!("pirates-outbox-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state.clone(), TransportConfig::default());
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        server.layer(Deduplication::new(Duration::from_secs(60), 16));
+        let (connector, listener) = channel_listener(1);
+        let failing = Arc::new(AtomicBool::new(true));
+        let client_calls = async {
+            let connector = connector;
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            transport.layer(LosesResponses {
+                failing: failing.clone(),
+            });
+            let outbox = Outbox::open(&dir).unwrap();
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            for _ in 0..2 {
+                let result = incr_i.call_durable((), &outbox, &mut transport).await;
+                assert!(matches!(result, Err(RpcError::TransportError(_))));
+            }
+            assert!(outbox.replay(&mut transport).await.is_err());
+            let pending = outbox.pending().await.unwrap();
+            drop(outbox);
+            // Set aside rather than stopping the rest being replayed
+            fs::write(dir.join("99.call"), b"garbage").unwrap();
+
+            failing.store(false, Ordering::SeqCst);
+            let outbox = Outbox::open(&dir).unwrap();
+            let replayed = outbox.replay(&mut transport).await.unwrap();
+            incr_i
+                .call_durable((), &outbox, &mut transport)
+                .await
+                .unwrap();
+            (pending, replayed, outbox.pending().await.unwrap())
+        };
+
+        let ((), (pending, replayed, pending_after)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        let corrupt = dir.join("99.corrupt").exists();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(corrupt);
+        assert_eq!(2, pending);
+        assert_eq!(2, replayed);
+        assert_eq!(0, pending_after);
+        // Each run once, despite the first two being sent twice
+        assert_eq!(6, state.lock().unwrap().i);
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::Notify;
+
+use crate::priority::{Priority, PriorityQueue};
+
+/// What a server does with a query arriving once queries waiting to be handled are at
+/// [crate::ServerConfig::max_queued_queries] on its connection, or
+/// [crate::ServerConfig::max_queued_queries_total] across them all
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Stop reading from the connection until there's room, leaving the client's sends to
+    /// back up behind it
+    #[default]
+    Block,
+    /// Answer it straight away with [crate::error::RpcError::Overloaded], which the client
+    /// can retry elsewhere or later, as the handler never ran
+    Reject,
+}
+
+/// Queries waiting across every connection, with a wake-up for connections blocked on there
+/// being room
+#[derive(Default)]
+pub(crate) struct QueuedTotal {
+    queued: AtomicUsize,
+    freed: Notify,
+}
+
+impl QueuedTotal {
+    pub(crate) fn get(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once a query's left any connection's queue since it was made
+    pub(crate) fn freed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.freed.notified()
+    }
+}
+
+/// One connection's queries waiting to be handled, by [Priority], counted in [QueuedTotal]
+/// while they're waiting, including until dropped should the connection fail first
+pub(crate) struct QueryQueue<'a, T> {
+    queue: PriorityQueue<T>,
+    total: &'a QueuedTotal,
+    max_queued: usize,
+    max_queued_total: Option<usize>,
+}
+
+impl<'a, T> QueryQueue<'a, T> {
+    pub(crate) fn new(
+        starvation_limit: usize,
+        total: &'a QueuedTotal,
+        max_queued: usize,
+        max_queued_total: Option<usize>,
+    ) -> Self {
+        Self {
+            queue: PriorityQueue::new(starvation_limit),
+            total,
+            max_queued,
+            max_queued_total,
+        }
+    }
+
+    /// Whether another query can wait, on this connection and across them all
+    pub(crate) fn has_room(&self) -> bool {
+        self.queue.len() < self.max_queued
+            && self
+                .max_queued_total
+                .is_none_or(|max_queued_total| self.total.get() < max_queued_total)
+    }
+
+    pub(crate) fn push(&mut self, priority: Priority, item: T) {
+        self.total.queued.fetch_add(1, Ordering::Relaxed);
+        self.queue.push(priority, item);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let item = self.queue.pop()?;
+        self.total.queued.fetch_sub(1, Ordering::Relaxed);
+        self.total.freed.notify_waiters();
+        Some(item)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl<T> Drop for QueryQueue<'_, T> {
+    fn drop(&mut self) {
+        let left = self.queue.len();
+        if left > 0 {
+            self.total.queued.fetch_sub(left, Ordering::Relaxed);
+            self.total.freed.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::AsyncRpcImpl;
+    use crate::error::RpcError;
+    use crate::listener::TcpListener;
+    use crate::multiplex::MultiplexedClient;
+    use crate::server::{RpcServer, ServerConfig};
+    use crate::tests::{make_get_i_rpc, HelloWorldRpcName, HelloWorldState};
+    use crate::transport::TransportConfig;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn queries_past_queue_limits_rejected() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            max_concurrent_queries: 1,
+            max_queued_queries: 1,
+            max_queued_queries_total: Some(1),
+            overload_policy: OverloadPolicy::Reject,
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_async_rpc(Box::new(AsyncRpcImpl::reading(
+            HelloWorldRpcName::GetI,
+            Arc::new(tokio::sync::RwLock::new(3)),
+            Box::new(|i: &usize, _query: ()| {
+                let i = *i;
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    Ok(i)
+                })
+            }),
+        )));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let connect = || MultiplexedClient::connect(&addr, TransportConfig::default());
+            let (client_a, client_b) = (connect().await.unwrap(), connect().await.unwrap());
+            // One handled and one waiting on the first connection, filling the total, so the
+            // second connection can't queue any either
+            let calls_a =
+                futures_util::future::join_all((0..3).map(|_| client_a.call((), make_get_i_rpc())));
+            let calls_b = async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                futures_util::future::join_all((0..2).map(|_| client_b.call((), make_get_i_rpc())))
+                    .await
+            };
+            let results = tokio::join!(calls_a, calls_b);
+            shutdown.shutdown();
+            results
+        };
+
+        let ((), (results_a, results_b)) =
+            tokio::join!(server.serve_listener(listener), client_calls);
+        let overloaded = |results: &[Result<usize, RpcError>]| {
+            results
+                .iter()
+                .filter(|result| matches!(result, Err(RpcError::Overloaded(_))))
+                .count()
+        };
+        assert_eq!(1, overloaded(&results_a));
+        assert_eq!(1, overloaded(&results_b));
+        let answered: Vec<_> = results_a.iter().chain(&results_b).flatten().collect();
+        assert_eq!(vec![&3, &3, &3], answered);
+    }
+
+    #[tokio::test]
+    async fn queries_past_deadline_while_waiting_dropped() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            max_concurrent_queries: 1,
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        let calls_run = Arc::new(tokio::sync::RwLock::new(0));
+        server.add_async_rpc(Box::new(AsyncRpcImpl::writing(
+            HelloWorldRpcName::GetI,
+            calls_run.clone(),
+            Box::new(|calls_run: &mut usize, _query: ()| {
+                *calls_run += 1;
+                let i = *calls_run;
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    Ok(i)
+                })
+            }),
+        )));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let config = TransportConfig {
+                rcv_timeout: Duration::from_millis(100),
+                ..Default::default()
+            };
+            let client = MultiplexedClient::connect(&addr, config).await.unwrap();
+            // The second waits for the first, and is given up on meanwhile
+            let calls =
+                futures_util::future::join_all((0..2).map(|_| client.call((), make_get_i_rpc())))
+                    .await;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            shutdown.shutdown();
+            calls
+        };
+
+        let ((), calls) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert!(calls
+            .iter()
+            .all(|call| matches!(call, Err(RpcError::RpcTimeout(_, _)))));
+        assert_eq!(1, *calls_run.read().await);
+    }
+}
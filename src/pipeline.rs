@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::{into_rpc_result_transport, RpcResult};
+use crate::transport::{InternalTransport, Transport, TransportResponseOwned};
+
+/// Unary calls sent back to back over one [Transport] without waiting for each response
+/// before sending the next, so a high latency link costs one round trip rather than one per
+/// call, see [Transport::pipeline]:
+///
+/// ```rust,ignore
+/// let mut pipeline = transport.pipeline();
+/// let hello = pipeline.send(&make_hello_world_rpc(), "Foo".into()).await?;
+/// let get_i = pipeline.send(&make_get_i_rpc(), ()).await?;
+/// let i = pipeline.receive(get_i).await?;
+/// let hello = pipeline.receive(hello).await?;
+/// ```
+///
+/// A server handles up to [crate::ServerConfig::max_concurrent_queries] of the calls at once,
+/// answering them as they finish, so responses are matched to calls by correlation id and
+/// held until [receive]d, in whatever order. Receive every call sent before using the
+/// transport for anything else, or the responses left will be taken for those of later calls.
+/// [crate::ClientInterceptor]s and retries don't apply. For calls from many tasks at once, see
+/// [crate::MultiplexedClient]
+pub struct Pipeline<'a, I, Name: RpcName> {
+    transport: &'a mut Transport<I, Name>,
+    next_correlation_id: u64,
+    in_flight: HashSet<u64>,
+    arrived: HashMap<u64, TransportResponseOwned>,
+}
+
+/// A call sent down a [Pipeline], to [Pipeline::receive] its response with
+pub struct PipelinedCall<Name, R> {
+    correlation_id: u64,
+    rpc_name: Name,
+    _response_phantom: PhantomData<R>,
+}
+
+impl<'a, I: InternalTransport, Name: RpcName> Pipeline<'a, I, Name> {
+    pub(crate) fn new(transport: &'a mut Transport<I, Name>) -> Self {
+        Self {
+            transport,
+            next_correlation_id: 1,
+            in_flight: HashSet::new(),
+            arrived: HashMap::new(),
+        }
+    }
+
+    /// Send a call of [rpc] with [query], returning once it's sent
+    pub async fn send<Q: RpcType, R: RpcType>(
+        &mut self,
+        rpc: &Rpc<Name, Q, R>,
+        query: Q,
+    ) -> RpcResult<PipelinedCall<Name, R>> {
+        let query_bytes = self
+            .transport
+            .config
+            .wire_config
+            .serialize(&query)
+            .map_err(|e| e.in_step(format_args!("query for rpc {}", rpc.name)))?;
+        let correlation_id = self.next_correlation_id;
+        self.transport
+            .send_pipelined_query(&query_bytes, &rpc.name, correlation_id)
+            .await?;
+        self.next_correlation_id += 1;
+        self.in_flight.insert(correlation_id);
+        Ok(PipelinedCall {
+            correlation_id,
+            rpc_name: rpc.name.clone(),
+            _response_phantom: PhantomData,
+        })
+    }
+
+    /// Wait for the response to [call], holding on to responses to other calls that arrive
+    /// first. Each wait for a response is up to [crate::TransportConfig::rcv_timeout]
+    pub async fn receive<R: RpcType>(&mut self, call: PipelinedCall<Name, R>) -> RpcResult<R> {
+        let response = loop {
+            if let Some(response) = self.arrived.remove(&call.correlation_id) {
+                break response;
+            }
+            let envelope = self.transport.receive_response(&call.rpc_name).await?;
+            if self.in_flight.remove(&envelope.correlation_id) {
+                self.arrived
+                    .insert(envelope.correlation_id, envelope.response);
+            }
+        };
+        let result_bytes = response.into_result()?;
+        let result = self
+            .transport
+            .config
+            .wire_config
+            .deserialize(&result_bytes)
+            .map_err(|e| e.in_step(format_args!("response for rpc {}", call.rpc_name)));
+        into_rpc_result_transport(result)
+    }
+
+    /// Number of calls sent whose responses haven't arrived yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::AsyncRpcImpl;
+    use crate::server::RpcServer;
+    use crate::tests::{make_get_i_rpc, make_get_i_rpc_impl, HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, TransportConfig};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn responses_matched_to_calls() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        // Answers with the query after sleeping that many milliseconds
+        server.add_async_rpc(Box::new(AsyncRpcImpl::reading(
+            HelloWorldRpcName::HelloWorld,
+            Arc::new(tokio::sync::RwLock::new(())),
+            Box::new(|_: &(), millis: u64| {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(millis)).await;
+                    Ok(millis)
+                })
+            }),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let sleep = Rpc::<_, u64, u64>::new(HelloWorldRpcName::HelloWorld);
+            let mut pipeline = transport.pipeline();
+            let slow = pipeline.send(&sleep, 30).await.unwrap();
+            let quick = pipeline.send(&sleep, 1).await.unwrap();
+            let get_i = pipeline.send(&make_get_i_rpc(), ()).await.unwrap();
+            let sent = pipeline.in_flight();
+            // The slow call is answered last, so the others are held until asked for
+            let slow = pipeline.receive(slow).await;
+            let in_flight = pipeline.in_flight();
+            let quick = pipeline.receive(quick).await;
+            let get_i = pipeline.receive(get_i).await;
+            let after = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            (sent, in_flight, slow, quick, get_i, after)
+        };
+
+        let ((), (sent, in_flight, slow, quick, get_i, after)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(3, sent);
+        assert_eq!(0, in_flight);
+        assert_eq!(30, slow.unwrap());
+        assert_eq!(1, quick.unwrap());
+        assert_eq!(3, get_i.unwrap());
+        assert_eq!(3, after.unwrap());
+    }
+}
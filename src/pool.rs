@@ -0,0 +1,429 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::client::{connect_tcp_transport, RpcClient};
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::{RpcError, RpcResult};
+use crate::keepalive::KeepaliveConfig;
+use crate::transport::{
+    InternalTransport, TcpTransport, Transport, TransportConfig, TransportError,
+};
+use log::debug;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// A [TransportPool] keeps up to [max_size] live [TcpTransport] connections to one address,
+/// for clients issuing many RPCs in parallel to the same server.
+/// [acquire] hands out an idle connection if there is one, connects a new one if the pool is
+/// below [max_size], and otherwise waits for one to be returned. Connections go back to the pool
+/// when the [PooledTransport] guard is dropped, unless they no longer look healthy.
+/// With [TransportConfig::keepalive] set, a task pings the idle connections every
+/// [KeepaliveConfig::interval], dropping any that don't answer, so they aren't closed as idle
+/// by the server or anything in between. That task is spawned by [new], so it must then be
+/// called within a tokio runtime
+pub struct TransportPool<Name: RpcName> {
+    addr: String,
+    transport_config: TransportConfig,
+    idle: Arc<Mutex<Vec<Transport<TcpTransport, Name>>>>,
+    permits: Arc<Semaphore>,
+}
+
+impl<Name: RpcName + 'static> TransportPool<Name> {
+    pub fn new(addr: &str, max_size: usize, transport_config: TransportConfig) -> Self {
+        let idle = Arc::new(Mutex::new(Vec::new()));
+        let permits = Arc::new(Semaphore::new(max_size));
+        if let Some(keepalive) = transport_config.keepalive {
+            tokio::spawn(keep_idle_alive(
+                keepalive,
+                Arc::downgrade(&idle),
+                Arc::downgrade(&permits),
+            ));
+        }
+        Self {
+            addr: addr.to_string(),
+            transport_config,
+            idle,
+            permits,
+        }
+    }
+}
+
+impl<Name: RpcName> TransportPool<Name> {
+    pub async fn acquire(&self) -> RpcResult<PooledTransport<'_, Name>> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("Pool semaphore is never closed");
+        let (transport, reused) = match self.take_idle() {
+            Some(transport) => (transport, true),
+            None => (
+                connect_tcp_transport(&self.addr, self.transport_config.clone()).await?,
+                false,
+            ),
+        };
+        Ok(PooledTransport {
+            pool: self,
+            transport: Some(transport),
+            reused,
+            mid_call: false,
+            _permit: permit,
+        })
+    }
+
+    /// Number of connections sitting idle in the pool
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    fn take_idle(&self) -> Option<Transport<TcpTransport, Name>> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(transport) = idle.pop() {
+            if transport.internal_transport().is_healthy() {
+                return Some(transport);
+            }
+            debug!("Discarding broken pooled connection to {}", self.addr);
+        }
+        None
+    }
+
+    fn give_back(&self, transport: Transport<TcpTransport, Name>) {
+        if transport.internal_transport().is_healthy() {
+            self.idle.lock().unwrap().push(transport);
+        } else {
+            debug!("Not returning broken connection to {} to pool", self.addr);
+        }
+    }
+}
+
+/// Ping each idle connection every [KeepaliveConfig::interval], until the pool's dropped.
+/// Each is checked out while it's pinged, so it's counted against the pool's size
+async fn keep_idle_alive<Name: RpcName>(
+    keepalive: KeepaliveConfig,
+    idle: Weak<Mutex<Vec<Transport<TcpTransport, Name>>>>,
+    permits: Weak<Semaphore>,
+) {
+    loop {
+        tokio::time::sleep(keepalive.interval).await;
+        let (Some(idle), Some(permits)) = (idle.upgrade(), permits.upgrade()) else {
+            return;
+        };
+        let num_idle = idle.lock().unwrap().len();
+        for _ in 0..num_idle {
+            let Ok(_permit) = permits.try_acquire() else {
+                break;
+            };
+            let Some(mut transport) = idle.lock().unwrap().pop() else {
+                break;
+            };
+            match transport.ping().await {
+                // To the back, as connections are reused from the front
+                Ok(_) => idle.lock().unwrap().insert(0, transport),
+                Err(e) => debug!("Dropping pooled connection failing keepalive: {}", e),
+            }
+        }
+    }
+}
+
+/// A [Transport] checked out of a [TransportPool], returned to the pool on drop
+pub struct PooledTransport<'a, Name: RpcName> {
+    pool: &'a TransportPool<Name>,
+    transport: Option<Transport<TcpTransport, Name>>,
+    reused: bool,
+    /// Set while a [ClientPool] call is in flight, so a call given up on part way, e.g. by
+    /// being dropped, doesn't leave its response to be read by the next call
+    mid_call: bool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<'a, Name: RpcName> PooledTransport<'a, Name> {
+    /// Whether this connection was used before being returned to the pool, rather than freshly
+    /// connected. A reused connection may have been closed by the server since
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    /// Drop the connection rather than returning it to the pool, e.g. after an error left it in
+    /// an unknown state
+    pub fn discard(mut self) {
+        self.transport = None;
+    }
+}
+
+impl<'a, Name: RpcName> Deref for PooledTransport<'a, Name> {
+    type Target = Transport<TcpTransport, Name>;
+
+    fn deref(&self) -> &Self::Target {
+        self.transport.as_ref().unwrap()
+    }
+}
+
+impl<'a, Name: RpcName> DerefMut for PooledTransport<'a, Name> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.transport.as_mut().unwrap()
+    }
+}
+
+impl<'a, Name: RpcName> Drop for PooledTransport<'a, Name> {
+    fn drop(&mut self) {
+        if let Some(transport) = self.transport.take() {
+            if !self.mid_call {
+                self.pool.give_back(transport);
+            }
+        }
+    }
+}
+
+/// A [ClientPool] makes rpc calls to one server over a [TransportPool] of up to [max_size]
+/// connections, checking one out per call so many calls can be in flight at once.
+/// A connection is dropped rather than returned to the pool after a transport error or
+/// timeout, and replaced lazily by the next call needing one. If a reused connection turns out
+/// to have been closed by the server, the call is retried on another when that's safe: when
+/// the rpc is [Rpc::idempotent], or the query wasn't completely written so the server can't
+/// have run it. Otherwise the server may have run it before closing, so the error's returned,
+/// as it is for a freshly connected connection failing
+pub struct ClientPool<Name: RpcName> {
+    transports: TransportPool<Name>,
+}
+
+impl<Name: RpcName + 'static> ClientPool<Name> {
+    /// Needs a tokio runtime with [TransportConfig::keepalive] set, see [TransportPool]
+    pub fn new(addr: &str, max_size: usize, transport_config: TransportConfig) -> Self {
+        Self {
+            transports: TransportPool::new(addr, max_size, transport_config),
+        }
+    }
+}
+
+impl<Name: RpcName> ClientPool<Name> {
+    pub async fn call<Q: RpcType, R: RpcType>(
+        &self,
+        query: Q,
+        rpc: Rpc<Name, Q, R>,
+    ) -> RpcResult<R> {
+        let idempotent = rpc.idempotent;
+        let rpc_client = RpcClient::new(rpc);
+        loop {
+            let mut transport = self.transports.acquire().await?;
+            transport.mid_call = true;
+            let result = rpc_client.call(query.clone(), &mut transport).await;
+            transport.mid_call = false;
+            match result {
+                Err(e)
+                    if transport.is_reused()
+                        && (idempotent && is_closed_connection(&e) || never_written(&e)) =>
+                {
+                    debug!("Pooled connection was closed, retrying on a new one: {}", e);
+                    transport.discard();
+                }
+                Err(e) => {
+                    if leaves_connection_unusable(&e) {
+                        transport.discard();
+                    }
+                    return Err(e);
+                }
+                Ok(response) => return Ok(response),
+            }
+        }
+    }
+
+    /// Number of connections sitting idle in the pool
+    pub fn idle_count(&self) -> usize {
+        self.transports.idle_count()
+    }
+}
+
+fn is_closed_connection(e: &RpcError) -> bool {
+    matches!(
+        e,
+        RpcError::TransportError(TransportError::ConnectionClosed)
+            | RpcError::TransportError(TransportError::ConnectionReset(_))
+    )
+}
+
+/// The query was only partly written, if at all, so the server can't have read it whole
+fn never_written(e: &RpcError) -> bool {
+    matches!(e, RpcError::TransportError(TransportError::SendError(_)))
+}
+
+/// After these the connection may be broken, or a late response may still arrive on it
+pub(crate) fn leaves_connection_unusable(e: &RpcError) -> bool {
+    matches!(e, RpcError::TransportError(_) | RpcError::RpcTimeout(_, _))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{RpcServer, ServerConfig};
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, HelloWorldRpcName,
+        HelloWorldState, IncrIRpc,
+    };
+    use crate::RpcDefinition;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn local_addr(transport: &PooledTransport<HelloWorldRpcName>) -> std::net::SocketAddr {
+        transport.internal_transport().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn acquire_up_to_cap_then_reuse() {
+        let addr = "127.0.0.1:5557";
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        // Hold on to accepted streams so the connections stay open
+        let accept_task = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                streams.push(stream);
+            }
+        });
+
+        let pool: TransportPool<HelloWorldRpcName> =
+            TransportPool::new(addr, 2, TransportConfig::default());
+        let (first, second) = tokio::join!(pool.acquire(), pool.acquire());
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_ne!(local_addr(&first), local_addr(&second));
+
+        let third = pool.acquire();
+        tokio::pin!(third);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut third)
+            .await
+            .is_err());
+
+        let first_addr = local_addr(&first);
+        drop(first);
+        let third = third.await.unwrap();
+        assert_eq!(first_addr, local_addr(&third));
+        assert_eq!(0, pool.idle_count());
+
+        drop(third);
+        drop(second);
+        assert_eq!(2, pool.idle_count());
+        accept_task.abort();
+    }
+
+    #[tokio::test]
+    async fn client_pool_reconnects_closed_connections() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let addr = "127.0.0.1:5564";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let pool: ClientPool<HelloWorldRpcName> =
+                ClientPool::new(addr, 2, TransportConfig::default());
+            // The server closes each connection after one query, so every call after the first
+            // finds a closed connection, whether or not it's noticed before reuse. Only retried
+            // if it's not noticed as it's marked idempotent, which holds as the server only
+            // closes connections once they've answered
+            for _ in 0..3 {
+                pool.call((), IncrIRpc::client().idempotent())
+                    .await
+                    .unwrap();
+            }
+            let (a, b) = tokio::join!(
+                pool.call((), make_get_i_rpc()),
+                pool.call((), make_get_i_rpc())
+            );
+            (a, b)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (a, b) = rpc_results.unwrap().unwrap();
+        assert_eq!(6usize, a.unwrap());
+        assert_eq!(6usize, b.unwrap());
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_calls_retried_once_written() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // Answers the first query on each connection, then closes it on reading the second
+        let server = tokio::spawn(async move {
+            let mut queries = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut transport: Transport<_, HelloWorldRpcName> =
+                    Transport::new(TcpTransport::new(stream), TransportConfig::default());
+                let query = transport.receive_query().await.unwrap();
+                transport
+                    .respond(query.correlation_id, &query.query_bytes)
+                    .await
+                    .unwrap();
+                queries += 1;
+                if queries == 5 {
+                    break;
+                }
+                if transport.receive_query().await.is_ok() {
+                    queries += 1;
+                }
+            }
+            queries
+        });
+
+        let pool: ClientPool<HelloWorldRpcName> =
+            ClientPool::new(&addr, 1, TransportConfig::default());
+        let hello = |query: &str, rpc| pool.call(String::from(query), rpc);
+        let first = hello("first", make_hello_world_rpc()).await;
+        // The server may have run it before closing, so it's not sent again
+        let not_retried = hello("second", make_hello_world_rpc()).await;
+        let fresh = hello("third", make_hello_world_rpc().idempotent()).await;
+        let retried = hello("fourth", make_hello_world_rpc().idempotent()).await;
+        assert_eq!("first", first.unwrap());
+        assert!(matches!(
+            not_retried,
+            Err(RpcError::TransportError(TransportError::ConnectionClosed))
+        ));
+        assert_eq!("third", fresh.unwrap());
+        assert_eq!("fourth", retried.unwrap());
+        assert_eq!(5, server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn idle_connections_kept_alive() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let listener = crate::listener::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let config = TransportConfig {
+                keepalive: Some(KeepaliveConfig {
+                    interval: Duration::from_millis(10),
+                    timeout: Duration::from_millis(100),
+                }),
+                ..Default::default()
+            };
+            let pool: TransportPool<HelloWorldRpcName> = TransportPool::new(&addr, 1, config);
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let mut transport = pool.acquire().await.unwrap();
+            get_i.call((), &mut transport).await.unwrap();
+            drop(transport);
+            // Long past the server's idle timeout
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let mut transport = pool.acquire().await.unwrap();
+            let i = get_i.call((), &mut transport).await;
+            shutdown.shutdown();
+            (transport.is_reused(), i)
+        };
+
+        let ((), (reused, i)) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert!(reused);
+        assert_eq!(3, i.unwrap());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::VecDeque;
+
+use crate::metadata::Metadata;
+
+/// The [Metadata] key a call's [Priority] is sent under, when it's not [Priority::Normal]
+pub const PRIORITY: &str = "priority";
+
+/// How urgent a call is, set per rpc with [crate::Rpc::priority]. Where calls queue up, a
+/// [crate::MultiplexedClient] waiting to send them and a server waiting to handle those sharing
+/// a connection, see [crate::ServerConfig::max_concurrent_queries], higher priorities go
+/// first, e.g. so control calls aren't held up behind bulk data ones. Calls of the same
+/// priority keep their order, and none waits forever, see
+/// [crate::TransportConfig::starvation_limit]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    /// The priority [metadata] was sent with, [Priority::Normal] if none or one not known
+    pub(crate) fn of(metadata: &Metadata) -> Self {
+        metadata
+            .get(PRIORITY)
+            .and_then(|sent| Self::ALL.into_iter().find(|p| p.as_str() == sent))
+            .unwrap_or_default()
+    }
+
+    /// Add this to [metadata] to send, leaving it out for [Priority::Normal]
+    pub(crate) fn add_to(&self, metadata: &mut Metadata) {
+        if *self != Priority::Normal {
+            metadata.insert(String::from(PRIORITY), String::from(self.as_str()));
+        }
+    }
+}
+
+struct Queued<T> {
+    item: T,
+    /// [PriorityQueue::popped] when it was pushed
+    pushed_at: u64,
+}
+
+/// Items by [Priority], highest first and in order within each, except that one having seen
+/// [starvation_limit] others popped ahead of it goes next whatever its priority
+pub(crate) struct PriorityQueue<T> {
+    /// By [Priority], lowest first
+    queues: [VecDeque<Queued<T>>; 3],
+    popped: u64,
+    starvation_limit: usize,
+}
+
+impl<T> PriorityQueue<T> {
+    pub(crate) fn new(starvation_limit: usize) -> Self {
+        Self {
+            queues: Default::default(),
+            popped: 0,
+            starvation_limit,
+        }
+    }
+
+    pub(crate) fn push(&mut self, priority: Priority, item: T) {
+        self.queues[priority as usize].push_back(Queued {
+            item,
+            pushed_at: self.popped,
+        });
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let starved = self
+            .queues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, queue)| Some((i, queue.front()?.pushed_at)))
+            .min_by_key(|(_, pushed_at)| *pushed_at)
+            .filter(|(_, pushed_at)| self.popped - pushed_at >= self.starvation_limit as u64);
+        let queue = match starved {
+            Some((i, _)) => &mut self.queues[i],
+            None => self
+                .queues
+                .iter_mut()
+                .rev()
+                .find(|queue| !queue.is_empty())?,
+        };
+        let queued = queue.pop_front()?;
+        self.popped += 1;
+        Some(queued.item)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn higher_priorities_first_until_one_starves() {
+        let mut queue = PriorityQueue::new(3);
+        queue.push(Priority::Low, "low");
+        queue.push(Priority::Normal, "normal");
+        for high in ["h0", "h1", "h2", "h3"] {
+            queue.push(Priority::High, high);
+        }
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        // The low one's seen three go ahead, so goes next, then the normal one has too
+        assert_eq!(vec!["h0", "h1", "h2", "low", "normal", "h3"], popped);
+        assert!(queue.is_empty());
+
+        let mut metadata = Metadata::new();
+        Priority::Normal.add_to(&mut metadata);
+        assert!(metadata.is_empty());
+        Priority::High.add_to(&mut metadata);
+        assert_eq!(Priority::High, Priority::of(&metadata));
+        metadata.insert(String::from(PRIORITY), String::from("urgent"));
+        assert_eq!(Priority::Normal, Priority::of(&metadata));
+    }
+}
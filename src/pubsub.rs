@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::core::{ResponseStream, RpcName, RpcType, StreamRpc, StreamRpcImpl};
+
+/// How many messages a subscriber can fall behind by before more are dropped for it
+pub const DEFAULT_SUBSCRIBER_BUFFER: usize = 256;
+
+/// A message published to [topic], as pushed to its subscribers. [missed] is how many were
+/// dropped for the subscriber just before this one as it had fallen behind, see [Topics]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Published<M> {
+    pub topic: String,
+    pub message: M,
+    #[serde(default)]
+    pub missed: u64,
+}
+
+struct Subscriber<M> {
+    sender: mpsc::Sender<Published<M>>,
+    /// Dropped since the last message pushed, shared by each topic subscribed to at once
+    missed: Arc<AtomicU64>,
+}
+
+type Subscribers<M> = HashMap<String, Vec<Subscriber<M>>>;
+
+/// Registry of the subscribers to each topic, for a server to push messages of type [M] to
+/// clients as they're published. Clients subscribe with the [StreamRpc] from [subscribe_rpc],
+/// served by [subscribe_rpc_impl], which streams them what's published to the topics they
+/// named for as long as they stay subscribed. The pushes come over the connection the client
+/// subscribed on, which is then given over to them, so subscribe on a connection of its own.
+/// Anything with a clone can [publish], e.g. the handlers of other rpcs, or a task watching a
+/// database. A subscriber more than [DEFAULT_SUBSCRIBER_BUFFER] messages behind, see
+/// [with_buffer], misses those published until it catches up, so a slow client can't have the
+/// server buffer without limit. It's told how many it missed with the next it's pushed, see
+/// [Published::missed], and [dropped] counts them across all subscribers
+pub struct Topics<M> {
+    subscribers: Arc<Mutex<Subscribers<M>>>,
+    buffer: usize,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<M> Clone for Topics<M> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: self.subscribers.clone(),
+            buffer: self.buffer,
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl<M: RpcType + Send> Default for Topics<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: RpcType + Send> Topics<M> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            buffer: DEFAULT_SUBSCRIBER_BUFFER,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Let each subscriber fall [buffer] messages behind rather than
+    /// [DEFAULT_SUBSCRIBER_BUFFER]
+    pub fn with_buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer.max(1);
+        self
+    }
+
+    /// Push [message] to every subscriber to [topic], giving how many it was pushed to
+    pub fn publish(&self, topic: &str, message: M) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(topic_subscribers) = subscribers.get_mut(topic) else {
+            return 0;
+        };
+        topic_subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+        let mut pushed = 0;
+        for subscriber in topic_subscribers.iter() {
+            let missed = subscriber.missed.swap(0, Ordering::Relaxed);
+            let published = Published {
+                topic: String::from(topic),
+                message: message.clone(),
+                missed,
+            };
+            match subscriber.sender.try_send(published) {
+                Ok(()) => pushed += 1,
+                Err(_) => {
+                    warn!("Subscriber to {} fell behind, dropping message", topic);
+                    subscriber.missed.fetch_add(missed + 1, Ordering::Relaxed);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if topic_subscribers.is_empty() {
+            subscribers.remove(topic);
+        }
+        pushed
+    }
+
+    /// Number of subscribers to [topic] still connected
+    pub fn subscribers(&self, topic: &str) -> usize {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .get(topic)
+            .map_or(0, |subscribers| {
+                subscribers
+                    .iter()
+                    .filter(|subscriber| !subscriber.sender.is_closed())
+                    .count()
+            })
+    }
+
+    /// Number of messages dropped for subscribers that fell behind, since these were made
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// What's published to any of [topics] from now on, until the stream is dropped
+    pub fn subscribe(&self, topics: Vec<String>) -> ResponseStream<Published<M>> {
+        let (sender, receiver) = mpsc::channel(self.buffer);
+        let missed = Arc::new(AtomicU64::new(0));
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for topic in topics {
+            let subscriber = Subscriber {
+                sender: sender.clone(),
+                missed: missed.clone(),
+            };
+            subscribers.entry(topic).or_default().push(subscriber);
+        }
+        Box::pin(futures_util::stream::unfold(
+            receiver,
+            |mut receiver| async move {
+                let published = receiver.recv().await?;
+                Some((Ok(published), receiver))
+            },
+        ))
+    }
+
+    /// The client side of [subscribe_rpc_impl], taking the topics to subscribe to
+    pub fn subscribe_rpc<Name: RpcName>(name: Name) -> StreamRpc<Name, Vec<String>, Published<M>> {
+        StreamRpc::new(name)
+    }
+
+    /// Serve subscriptions to these topics as the stream rpc [name], see [subscribe_rpc]
+    pub fn subscribe_rpc_impl<Name: RpcName, State>(
+        &self,
+        name: Name,
+    ) -> StreamRpcImpl<Name, State, Vec<String>, Published<M>> {
+        let topics = self.clone();
+        StreamRpcImpl::new(
+            name,
+            Box::new(move |_state, subscribe_to| Ok(topics.subscribe(subscribe_to))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::StreamRpcClient;
+    use crate::error::RpcResult;
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, ChannelConnector, Transport, TransportConfig};
+    use futures_util::StreamExt;
+    use std::time::Duration;
+
+    /// The first [num_messages] published to [subscribe_to] once subscribed
+    async fn subscribe(
+        connector: &ChannelConnector,
+        subscribe_to: Vec<&str>,
+        num_messages: usize,
+    ) -> Vec<RpcResult<Published<String>>> {
+        let mut transport = Transport::new(
+            connector.connect().await.unwrap(),
+            TransportConfig::default(),
+        );
+        let subscribe_to = subscribe_to.into_iter().map(String::from).collect();
+        let client = StreamRpcClient::new(Topics::subscribe_rpc(HelloWorldRpcName::CountTo));
+        let messages = client.call(subscribe_to, &mut transport);
+        messages.take(num_messages).collect().await
+    }
+
+    #[tokio::test]
+    async fn published_messages_pushed_to_subscribers() {
+        let topics = Topics::<String>::new();
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_stream_rpc(Box::new(
+            topics.subscribe_rpc_impl(HelloWorldRpcName::CountTo),
+        ));
+        let (connector, listener) = channel_listener(2);
+        let subscribers = async move {
+            let news = subscribe(&connector, vec!["news"], 1);
+            let everything = subscribe(&connector, vec!["news", "weather"], 2);
+            tokio::join!(news, everything)
+        };
+        let publisher = topics.clone();
+        let publish = async move {
+            while publisher.subscribers("news") < 2 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            let weather = publisher.publish("weather", String::from("Squalls"));
+            let news = publisher.publish("news", String::from("Kraken sighted"));
+            let nobody = publisher.publish("gossip", String::from("Rum's gone"));
+            (weather, news, nobody)
+        };
+
+        let ((), (news, everything), published) =
+            tokio::join!(server.serve_channel(listener), subscribers, publish);
+        assert_eq!((1, 2, 0), published);
+        let kraken = Published {
+            topic: String::from("news"),
+            message: String::from("Kraken sighted"),
+            missed: 0,
+        };
+        let news: Vec<_> = news.into_iter().map(Result::unwrap).collect();
+        assert_eq!(vec![kraken.clone()], news);
+        let everything: Vec<_> = everything.into_iter().map(Result::unwrap).collect();
+        assert_eq!("Squalls", everything[0].message);
+        assert_eq!(kraken, everything[1]);
+        // Subscribers are gone with their connections
+        assert_eq!(0, topics.subscribers("news"));
+        assert_eq!(0, topics.publish("news", String::from("Anyone?")));
+    }
+
+    #[tokio::test]
+    async fn subscriber_told_how_many_it_missed() {
+        let topics = Topics::<u32>::new().with_buffer(1);
+        let mut subscription = topics.subscribe(vec![String::from("news")]);
+        let published: Vec<_> = (0..3).map(|i| topics.publish("news", i)).collect();
+        assert_eq!(vec![1, 0, 0], published);
+        let first = subscription.next().await.unwrap().unwrap();
+        topics.publish("news", 3);
+        let next = subscription.next().await.unwrap().unwrap();
+        assert_eq!((0, 0), (first.message, first.missed));
+        assert_eq!((3, 2), (next.message, next.missed));
+        assert_eq!(2, topics.dropped());
+    }
+}
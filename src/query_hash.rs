@@ -0,0 +1,41 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+use crate::{Bytes, OwnedBytes};
+
+/// Hashes serialised query bytes into a key, for response caching, see [crate::ResponseCache],
+/// and idempotency.
+/// Implement this to trade speed against collision resistance, or when keys must stay stable
+/// across processes and restarts (e.g. a shared cache), where something like SHA-256 fits
+pub trait QueryHasher: Send + Sync {
+    fn hash_query(&self, query_bytes: Bytes) -> OwnedBytes;
+}
+
+/// Fast 8 byte hash using std's [DefaultHasher]. Deterministic within a process, but not
+/// guaranteed to be stable across Rust versions, so don't persist its output
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultQueryHasher;
+
+impl QueryHasher for DefaultQueryHasher {
+    fn hash_query(&self, query_bytes: Bytes) -> OwnedBytes {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(query_bytes);
+        hasher.finish().to_be_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_hasher_equal_and_different() {
+        let hasher = DefaultQueryHasher;
+        let query = b"Gaspode the wonder dog".to_vec();
+        let same_query = query.clone();
+        let other_query = b"Gaspode the wonder cat".to_vec();
+
+        assert_eq!(hasher.hash_query(&query), hasher.hash_query(&same_query));
+        assert_ne!(hasher.hash_query(&query), hasher.hash_query(&other_query));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::tls::{TlsClientConfig, TlsServerConfig};
+use crate::transport::{
+    InternalTransport, TransportConfig, TransportError, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn quic_error(context: &str, e: impl std::fmt::Display) -> TransportError {
+    TransportError::ConnectError(format!("QUIC {}: {}", context, e))
+}
+
+/// A QUIC connection to a server, which opens a new stream for each rpc call with
+/// [open_transport]. Calls on separate streams don't block each other, unlike calls sharing
+/// one TCP connection. Cloning shares the underlying connection
+#[derive(Clone)]
+pub struct QuicConnection {
+    // Kept so the client endpoint lives as long as any handle to the connection
+    _endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+}
+
+impl QuicConnection {
+    /// Connect to [addr], checking the server's certificate is valid for [server_name] per
+    /// [tls_config]
+    pub async fn connect(
+        addr: SocketAddr,
+        server_name: &str,
+        tls_config: &TlsClientConfig,
+    ) -> Result<Self, TransportError> {
+        let crypto = QuicClientConfig::try_from(tls_config.config.clone())
+            .map_err(|e| quic_error("client config", e))?;
+        let bind_addr: SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let endpoint = quinn::Endpoint::client(bind_addr)
+            .map_err(|e| TransportError::ConnectError(format!("{}", e)))?;
+        let connection = endpoint
+            .connect_with(
+                quinn::ClientConfig::new(Arc::new(crypto)),
+                addr,
+                server_name,
+            )
+            .map_err(|e| quic_error("connect", e))?
+            .await
+            .map_err(|e| quic_error("handshake", e))?;
+        Ok(Self {
+            _endpoint: endpoint,
+            connection,
+        })
+    }
+
+    /// Open a new stream, to be wrapped in a [crate::Transport] for a single rpc call
+    pub async fn open_transport(&self) -> Result<QuicStreamTransport, TransportError> {
+        let (send, recv) = self
+            .connection
+            .open_bi()
+            .await
+            .map_err(|e| quic_error("open stream", e))?;
+        Ok(QuicStreamTransport::new(send, recv))
+    }
+}
+
+/// Implementation of [InternalTransport] over one bidirectional QUIC stream, carrying one query
+/// and its response. Each side sends its message and then finishes its half of the stream,
+/// so no extra framing is needed
+pub struct QuicStreamTransport {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    received: bool,
+    max_receive_size: Option<usize>,
+}
+
+impl QuicStreamTransport {
+    fn new(send: quinn::SendStream, recv: quinn::RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            received: false,
+            // As TransportConfig's default, until it's configured
+            max_receive_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+        }
+    }
+
+    async fn receive_to_end(&mut self) -> Result<OwnedBytes, TransportError> {
+        // The peer finished its half of the stream after its one message
+        if self.received {
+            return Err(TransportError::ConnectionClosed);
+        }
+        // No limit configured reads however much the peer sends
+        let max_size = self.max_receive_size.unwrap_or(usize::MAX);
+        match self.recv.read_to_end(max_size).await {
+            Ok(bytes) if bytes.is_empty() => Err(TransportError::ConnectionClosed),
+            Ok(bytes) => {
+                self.received = true;
+                Ok(bytes)
+            }
+            Err(quinn::ReadToEndError::Read(quinn::ReadError::ConnectionLost(e))) => {
+                Err(TransportError::ConnectionReset(format!("{}", e)))
+            }
+            Err(quinn::ReadToEndError::TooLong) => Err(TransportError::MessageTooLarge(
+                max_size.saturating_add(1),
+                max_size,
+            )),
+            Err(e) => Err(TransportError::ReceiveError(format!("{}", e))),
+        }
+    }
+}
+
+#[async_trait]
+impl InternalTransport for QuicStreamTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.send.write_all(b).await.map_err(|e| match e {
+            quinn::WriteError::ConnectionLost(e) => {
+                TransportError::ConnectionReset(format!("{}", e))
+            }
+            e => TransportError::SendError(format!("{}", e)),
+        })?;
+        self.send
+            .finish()
+            .map_err(|e| TransportError::SendError(format!("{}", e)))
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        match timeout {
+            Some(timeout_) => match tokio::time::timeout(timeout_, self.receive_to_end()).await {
+                Ok(r) => r,
+                Err(_) => Err(TransportError::ReceiveTimeout(timeout_)),
+            },
+            None => self.receive_to_end().await,
+        }
+    }
+
+    /// QUIC streams aren't framed, so a message is refused once more than
+    /// [TransportConfig::max_inbound_message_size] bytes of it have been read
+    fn configure(&mut self, config: &TransportConfig) {
+        self.max_receive_size = config.max_inbound_message_size;
+    }
+}
+
+/// Bind a QUIC server endpoint on [addr] with the certificate from [tls_config]
+pub(crate) fn server_endpoint(
+    addr: SocketAddr,
+    tls_config: &TlsServerConfig,
+) -> Result<quinn::Endpoint, TransportError> {
+    let crypto = QuicServerConfig::try_from(tls_config.config.clone())
+        .map_err(|e| quic_error("server config", e))?;
+    quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)
+        .map_err(|e| TransportError::ConnectError(format!("{}", e)))
+}
+
+/// Accept streams on [connection] until it closes, passing each on to [streams]
+pub(crate) async fn forward_streams(
+    connection: quinn::Connection,
+    streams: tokio::sync::mpsc::UnboundedSender<QuicStreamTransport>,
+) {
+    while let Ok((send, recv)) = connection.accept_bi().await {
+        if streams.send(QuicStreamTransport::new(send, recv)).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::server::RpcServer;
+    use crate::tests::{make_get_i_rpc, make_get_i_rpc_impl, HelloWorldState, IncrIRpc};
+    use crate::transport::{Transport, TransportConfig};
+    use crate::RpcDefinition;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn quic_stream_per_call() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert = certified.cert.der().to_vec();
+        let server_tls = TlsServerConfig::builder()
+            .certificate_chain(vec![cert.clone()])
+            .private_key(certified.signing_key.serialize_der())
+            .build()
+            .unwrap();
+        let client_tls = TlsClientConfig::builder()
+            .add_root_certificate(cert)
+            .build()
+            .unwrap();
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let addr: SocketAddr = "127.0.0.1:5562".parse().unwrap();
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let connection = QuicConnection::connect(addr, "localhost", &client_tls)
+                .await
+                .unwrap();
+            let call_transport = || async {
+                Transport::new(
+                    connection.open_transport().await.unwrap(),
+                    TransportConfig::default(),
+                )
+            };
+            RpcClient::new(IncrIRpc::client())
+                .call((), &mut call_transport().await)
+                .await
+                .unwrap();
+            RpcClient::new(make_get_i_rpc())
+                .call((), &mut call_transport().await)
+                .await
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve_quic(addr, &server_tls) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        assert_eq!(4usize, rpc_results.unwrap().unwrap().unwrap());
+    }
+}
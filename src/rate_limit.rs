@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::clock::{Clock, SystemClock};
+use crate::core::RpcName;
+use crate::error::{RpcError, RpcResult};
+use crate::middleware::Interceptor;
+use crate::transport::ReceivedQuery;
+
+/// Connection buckets are only cleared out of a [RateLimiter] once there are this many
+const PRUNE_CONNECTIONS_AT: usize = 1024;
+
+/// How many queries are let through: [per_second] on average, in bursts of up to [burst]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// [queries] a second, all of which can come at once
+    pub fn per_second(queries: u32) -> Self {
+        Self {
+            per_second: queries as f64,
+            burst: queries,
+        }
+    }
+
+    pub fn with_burst(self, burst: u32) -> Self {
+        Self { burst, ..self }
+    }
+}
+
+/// Token bucket over queries, starting full. Unlike [crate::BytesPerSecond]'s, it never goes
+/// into debt, as queries over the limit are refused rather than delayed
+struct QueryBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl QueryBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    /// How long until there's a token to take, if there isn't one now
+    fn wait(&self, limit: &RateLimit) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / limit.per_second))
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= limit.burst as f64
+    }
+}
+
+struct Buckets<Name> {
+    connections: HashMap<u64, QueryBucket>,
+    rpcs: HashMap<Name, QueryBucket>,
+    prune_connections_at: usize,
+}
+
+/// Server [Interceptor] refusing queries over its [RateLimit]s with [RpcError::RateLimited],
+/// before they reach any handler, telling the caller how long to wait. There can be a limit
+/// for each connection, and for each rpc across all connections, e.g. for expensive ones; a
+/// query must be within both to be let through.
+/// A client retrying with [crate::RetryPolicy] waits at least as long as it's told
+pub struct RateLimiter<Name: RpcName> {
+    per_connection: Option<RateLimit>,
+    per_rpc: HashMap<Name, RateLimit>,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<Buckets<Name>>,
+}
+
+impl<Name: RpcName> RateLimiter<Name> {
+    pub fn new() -> Self {
+        Self {
+            per_connection: None,
+            per_rpc: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            buckets: Mutex::new(Buckets {
+                connections: HashMap::new(),
+                rpcs: HashMap::new(),
+                prune_connections_at: PRUNE_CONNECTIONS_AT,
+            }),
+        }
+    }
+
+    /// Limit queries on each connection to [limit], whatever rpc they're for
+    pub fn per_connection(mut self, limit: RateLimit) -> Self {
+        self.per_connection = Some(limit);
+        self
+    }
+
+    /// Limit queries for [rpc_name] to [limit], shared by every connection
+    pub fn per_rpc(mut self, rpc_name: Name, limit: RateLimit) -> Self {
+        self.per_rpc.insert(rpc_name, limit);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Take a token for [query] from each bucket it's limited by, or none if any is empty,
+    /// giving the longest wait until all have one
+    fn take(&self, query: &ReceivedQuery<Name>) -> Result<(), Duration> {
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+        if buckets.connections.len() >= buckets.prune_connections_at {
+            // Full buckets are as good as new ones, so there's no need to keep them for
+            // connections that may have closed
+            if let Some(limit) = &self.per_connection {
+                buckets.connections.retain(|_, bucket| {
+                    bucket.refill(limit, now);
+                    !bucket.is_full(limit)
+                });
+            }
+            buckets.prune_connections_at = PRUNE_CONNECTIONS_AT.max(2 * buckets.connections.len());
+        }
+        let mut limited = Vec::with_capacity(2);
+        if let Some(limit) = &self.per_connection {
+            let bucket = buckets
+                .connections
+                .entry(query.connection_id)
+                .or_insert_with(|| QueryBucket::new(limit, now));
+            limited.push((bucket, limit));
+        }
+        if let Some(limit) = self.per_rpc.get(&query.name) {
+            let bucket = buckets
+                .rpcs
+                .entry(query.name.clone())
+                .or_insert_with(|| QueryBucket::new(limit, now));
+            limited.push((bucket, limit));
+        }
+        let mut wait = None;
+        for (bucket, limit) in limited.iter_mut() {
+            bucket.refill(limit, now);
+            wait = wait.max(bucket.wait(limit));
+        }
+        match wait {
+            Some(wait) => Err(wait),
+            None => {
+                for (bucket, _) in limited {
+                    bucket.tokens -= 1.0;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<Name: RpcName> Default for RateLimiter<Name> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait(?Send)]
+impl<Name: RpcName> Interceptor<Name> for RateLimiter<Name> {
+    async fn on_query(&self, query: &mut ReceivedQuery<Name>) -> RpcResult<()> {
+        self.take(query)
+            .map_err(|wait| RpcError::RateLimited(format!("{}", query.name), wait))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::clock::MockClock;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, make_hello_world_rpc_impl,
+        HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+
+    #[tokio::test]
+    async fn queries_over_limit_refused() {
+        let clock = Arc::new(MockClock::new());
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        server.layer(
+            RateLimiter::new()
+                .per_connection(RateLimit::per_second(2))
+                .per_rpc(HelloWorldRpcName::HelloWorld, RateLimit::per_second(1))
+                .with_clock(clock.clone()),
+        );
+        let (connector, listener) = channel_listener(2);
+        let client_calls = async move {
+            let get_i = RpcClient::new(make_get_i_rpc());
+            let hello = RpcClient::new(make_hello_world_rpc());
+            let mut first = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let mut second = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let first_hello = hello.call("Foo".into(), &mut first).await;
+            let first_get_i = get_i.call((), &mut first).await;
+            let first_over_limit = get_i.call((), &mut first).await;
+            // Has its own connection limit, but shares the limit on hello world
+            let second_get_i = get_i.call((), &mut second).await;
+            let second_hello = hello.call("Bar".into(), &mut second).await;
+            clock.advance(Duration::from_millis(500));
+            let first_refilled = get_i.call((), &mut first).await;
+            (
+                (first_hello, first_get_i, first_over_limit),
+                (second_get_i, second_hello, first_refilled),
+            )
+        };
+
+        let ((), (first, second)) = tokio::join!(server.serve_channel(listener), client_calls);
+        let (first_hello, first_get_i, first_over_limit) = first;
+        let (second_get_i, second_hello, first_refilled) = second;
+        assert_eq!("Hello world: 3:\"Foo\"", first_hello.unwrap());
+        assert_eq!(3, first_get_i.unwrap());
+        let first_over_limit = first_over_limit.unwrap_err();
+        assert!(matches!(first_over_limit, RpcError::RateLimited(_, _)));
+        assert_eq!(
+            Some(Duration::from_millis(500)),
+            first_over_limit.retry_after()
+        );
+        assert_eq!(3, second_get_i.unwrap());
+        assert_eq!(
+            Some(Duration::from_secs(1)),
+            second_hello.unwrap_err().retry_after()
+        );
+        assert_eq!(3, first_refilled.unwrap());
+    }
+}
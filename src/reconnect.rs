@@ -0,0 +1,460 @@
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::time::Duration;
+
+use crate::server::turned_away;
+use crate::transport::{
+    connect_tcp, within_connect_timeout, InternalTransport, TcpTransport, TransportConfig,
+    TransportError,
+};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use log::{debug, warn};
+
+/// ReconnectConfig defines how a [ReconnectingTransport] retries connecting
+/// Each failed attempt waits [initial_backoff], doubling per attempt up to [max_backoff],
+/// before the next. [jitter] is the fraction, from 0 to 1, of each wait that's randomised so
+/// many clients don't all reconnect in lockstep after a server restart
+/// [max_attempts] attempts are made before giving up with a [TransportError::ConnectError].
+/// Each attempt is bounded by the transport's [TransportConfig::connect_timeout], so one to a
+/// peer that's gone quiet is retried rather than left to the OS's timeout
+#[derive(Clone, Debug)]
+pub struct ReconnectConfig {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: usize,
+    pub jitter: f64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: 10,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Wait before the attempt after [failed_attempts] failures, without jitter
+    fn backoff(&self, failed_attempts: usize) -> Duration {
+        backoff(self.initial_backoff, self.max_backoff, failed_attempts)
+    }
+
+    fn jittered(&self, backoff: Duration) -> Duration {
+        jittered(self.jitter, backoff)
+    }
+}
+
+/// [initial_backoff] doubled per failed attempt after the first, up to [max_backoff]
+pub(crate) fn backoff(
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    failed_attempts: usize,
+) -> Duration {
+    let doublings = (failed_attempts.saturating_sub(1)).min(31) as u32;
+    initial_backoff
+        .saturating_mul(2u32.pow(doublings))
+        .min(max_backoff)
+}
+
+/// [backoff] less a random part of up to [jitter] of it
+pub(crate) fn jittered(jitter: f64, backoff: Duration) -> Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    backoff.mul_f64(1.0 - jitter * random_fraction())
+}
+
+/// A random number in [0, 1), good enough for spreading out retries
+fn random_fraction() -> f64 {
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+type ConnectFn<I> =
+    Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<I, TransportError>> + Send>> + Send + Sync>;
+
+/// An [InternalTransport] that connects lazily with [connect], and connects again when the
+/// connection breaks, so long-lived clients survive server restarts.
+/// A connection is dropped when a send or receive finds it closed or reset, when a send fails,
+/// e.g. on [crate::TransportConfig::send_timeout], or when it no longer looks healthy before
+/// the next send. That call still fails, as it's not known whether the server handled the
+/// query, but the next call reconnects, backing off per [ReconnectConfig] while the server is
+/// unreachable. A query the server turned away unhandled, as the connection had served its
+/// [crate::ServerConfig::max_requests_per_connection], is sent again on a new connection
+pub struct ReconnectingTransport<I> {
+    connect: ConnectFn<I>,
+    config: ReconnectConfig,
+    connection: Option<I>,
+    connect_count: usize,
+    /// Given to each connection made, see [InternalTransport::configure]
+    transport_config: Option<TransportConfig>,
+}
+
+impl<I: InternalTransport + Send> ReconnectingTransport<I> {
+    pub fn new<F, Fut>(connect: F, config: ReconnectConfig) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<I, TransportError>> + Send + 'static,
+    {
+        Self {
+            connect: Box::new(move || Box::pin(connect())),
+            config,
+            connection: None,
+            connect_count: 0,
+            transport_config: None,
+        }
+    }
+
+    /// Number of times a connection has been made, including the first
+    pub fn connect_count(&self) -> usize {
+        self.connect_count
+    }
+
+    async fn connection(&mut self) -> Result<&mut I, TransportError> {
+        if let Some(connection) = &self.connection {
+            if !connection.is_healthy() {
+                debug!("Dropping unhealthy connection before reuse");
+                self.connection = None;
+            }
+        }
+        if self.connection.is_none() {
+            let connect_timeout = self
+                .transport_config
+                .as_ref()
+                .and_then(|transport_config| transport_config.connect_timeout);
+            let mut connection =
+                connect_with_backoff(&self.connect, &self.config, connect_timeout).await?;
+            if let Some(transport_config) = &self.transport_config {
+                connection.configure(transport_config);
+            }
+            self.connection = Some(connection);
+            self.connect_count += 1;
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    /// Drop the connection if [result] shows it's broken
+    fn check<T>(&mut self, result: Result<T, TransportError>) -> Result<T, TransportError> {
+        if let Err(
+            TransportError::ConnectionClosed
+            | TransportError::ConnectionReset(_)
+            | TransportError::ZeroLengthFrame
+            | TransportError::TruncatedHeader { .. }
+            | TransportError::TruncatedFrame { .. }
+            | TransportError::SendError(_)
+            | TransportError::MessageTooLarge(_, _),
+        ) = &result
+        {
+            debug!("Connection broken, will reconnect on next use");
+            self.connection = None;
+        }
+        result
+    }
+
+    /// Drop the connection if the server answered with [crate::server::GO_AWAY] rather than
+    /// handling the query, giving whether it did
+    fn went_away(&mut self, result: &Result<OwnedBytes, TransportError>) -> bool {
+        let went_away = matches!(result, Ok(response) if turned_away(response).is_some());
+        if went_away {
+            debug!("Server closing the connection, reconnecting");
+            self.connection = None;
+        }
+        went_away
+    }
+}
+
+// Takes just the fields it needs, as borrowing the whole transport across an await would need
+// the connection to be Sync
+async fn connect_with_backoff<I>(
+    connect: &ConnectFn<I>,
+    config: &ReconnectConfig,
+    connect_timeout: Option<Duration>,
+) -> Result<I, TransportError> {
+    let mut failed_attempts = 0;
+    loop {
+        match within_connect_timeout(connect_timeout, connect()).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) => {
+                failed_attempts += 1;
+                if failed_attempts >= config.max_attempts {
+                    return Err(TransportError::ConnectError(format!(
+                        "Gave up after {} attempts: {}",
+                        failed_attempts, e
+                    )));
+                }
+                let backoff = config.jittered(config.backoff(failed_attempts));
+                warn!(
+                    "Connect attempt {} failed, retrying in {:?}: {}",
+                    failed_attempts, backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+impl ReconnectingTransport<TcpTransport> {
+    /// A [ReconnectingTransport] over TCP to [addr]
+    pub fn tcp(addr: &str, config: ReconnectConfig) -> Self {
+        let addr = addr.to_string();
+        Self::new(
+            move || {
+                let addr = addr.clone();
+                // Each attempt's timed out by the transport's connect_timeout around this
+                async move { connect_tcp(addr, None).await.map(TcpTransport::new) }
+            },
+            config,
+        )
+    }
+}
+
+#[async_trait]
+impl<I: InternalTransport + Send> InternalTransport for ReconnectingTransport<I> {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        let result = self.connection().await?.send(b).await;
+        self.check(result)
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        let result = self
+            .connection()
+            .await?
+            .send_and_wait_for_response(b, timeout)
+            .await;
+        if !self.went_away(&result) {
+            return self.check(result);
+        }
+        // It wasn't handled, so can be sent again whatever it is
+        let result = self
+            .connection()
+            .await?
+            .send_and_wait_for_response(b, timeout)
+            .await;
+        self.check(result)
+    }
+
+    async fn send_vectored(&mut self, parts: &[Bytes<'_>]) -> Result<(), TransportError> {
+        let result = self.connection().await?.send_vectored(parts).await;
+        self.check(result)
+    }
+
+    async fn send_many(&mut self, messages: &[Bytes<'_>]) -> Result<(), TransportError> {
+        let result = self.connection().await?.send_many(messages).await;
+        self.check(result)
+    }
+
+    async fn send_vectored_and_wait_for_response(
+        &mut self,
+        parts: &[Bytes<'_>],
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        let result = self
+            .connection()
+            .await?
+            .send_vectored_and_wait_for_response(parts, timeout)
+            .await;
+        if !self.went_away(&result) {
+            return self.check(result);
+        }
+        let result = self
+            .connection()
+            .await?
+            .send_vectored_and_wait_for_response(parts, timeout)
+            .await;
+        self.check(result)
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        let result = self.connection().await?.receive(timeout).await;
+        self.went_away(&result);
+        self.check(result)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_none_or(|connection| connection.is_healthy())
+    }
+
+    fn configure(&mut self, config: &TransportConfig) {
+        self.transport_config = Some(config.clone());
+        if let Some(connection) = &mut self.connection {
+            connection.configure(config);
+        }
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.connection.as_ref()?.peer_addr()
+    }
+
+    fn peer_certificate(&self) -> Option<OwnedBytes> {
+        self.connection.as_ref()?.peer_certificate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::listener::TcpListener;
+    use crate::server::{RpcServer, ServerConfig};
+    use crate::tests::{HelloWorldRpcName, HelloWorldState, IncrIRpc};
+    use crate::transport::{ChannelTransport, Transport};
+    use crate::RpcDefinition;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn quick_config(max_attempts: usize) -> ReconnectConfig {
+        ReconnectConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            max_attempts,
+            jitter: 0.5,
+        }
+    }
+
+    /// Connects to a fresh echo server after failing the first [failures] attempts
+    fn flaky_echo_connect(
+        failures: usize,
+        attempts: Arc<AtomicUsize>,
+    ) -> impl Fn() -> Pin<Box<dyn Future<Output = Result<ChannelTransport, TransportError>> + Send>>
+    {
+        move || {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                if attempt < failures {
+                    return Err(TransportError::ConnectError("Refused".to_string()));
+                }
+                let (client, mut server) = ChannelTransport::pair(1);
+                tokio::spawn(async move {
+                    // Echo one message, then hang up
+                    let bytes = server.receive(None).await.unwrap();
+                    server.send(&bytes).await.unwrap();
+                });
+                Ok(client)
+            })
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let backoffs: Vec<_> = (1..=5).map(|n| config.backoff(n).as_millis()).collect();
+        assert_eq!(vec![100, 200, 400, 500, 500], backoffs);
+        for _ in 0..100 {
+            let jittered = config.jittered(Duration::from_millis(100));
+            assert!(jittered > Duration::from_millis(50) && jittered <= Duration::from_millis(100));
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_backoff_after_hang_up() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut transport =
+            ReconnectingTransport::new(flaky_echo_connect(2, attempts.clone()), quick_config(5));
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            b"one".to_vec(),
+            transport
+                .send_and_wait_for_response(b"one", timeout)
+                .await
+                .unwrap()
+        );
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+
+        // The echo server hung up, which the next call finds out and reports
+        match transport.send_and_wait_for_response(b"two", timeout).await {
+            Err(TransportError::ConnectionReset(_) | TransportError::ConnectionClosed) => (),
+            other => panic!("Expected a broken connection, got {:?}", other),
+        }
+        assert_eq!(
+            b"three".to_vec(),
+            transport
+                .send_and_wait_for_response(b"three", timeout)
+                .await
+                .unwrap()
+        );
+        assert_eq!(2, transport.connect_count());
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut transport = ReconnectingTransport::new(
+            flaky_echo_connect(usize::MAX, attempts.clone()),
+            quick_config(3),
+        );
+        match transport.send(b"hello").await {
+            Err(TransportError::ConnectError(s)) => assert!(s.contains("3 attempts"), "{}", s),
+            other => panic!("Expected ConnectError, got {:?}", other),
+        }
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn attempts_fail_connect_timeout() {
+        let mut transport = ReconnectingTransport::<ChannelTransport>::new(
+            std::future::pending::<Result<ChannelTransport, TransportError>>,
+            quick_config(2),
+        );
+        transport.configure(&TransportConfig {
+            connect_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        });
+        match transport.send(b"hello").await {
+            Err(TransportError::ConnectError(s)) => {
+                assert!(s.contains("2 attempts") && s.contains("Timed out"), "{}", s)
+            }
+            other => panic!("Expected ConnectError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_server_goes_away() {
+        let state = Arc::new(std::sync::Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            max_requests_per_connection: Some(2),
+            ..Default::default()
+        };
+        let mut server =
+            RpcServer::with_config(state.clone(), TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(IncrIRpc::server()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let client_calls = async {
+            let reconnecting = ReconnectingTransport::tcp(&addr, quick_config(3));
+            let mut transport: Transport<_, HelloWorldRpcName> =
+                Transport::new(reconnecting, TransportConfig::default());
+            let incr_i = RpcClient::new(IncrIRpc::client());
+            let mut results = Vec::new();
+            for _ in 0..5 {
+                results.push(incr_i.call((), &mut transport).await);
+            }
+            shutdown.shutdown();
+            (results, transport.internal_transport().connect_count())
+        };
+
+        let ((), (results, connect_count)) =
+            tokio::join!(server.serve_listener(listener), client_calls);
+        for result in results {
+            result.unwrap();
+        }
+        // Turned away from the third and fifth calls, each run once on the next connection
+        assert_eq!(3, connect_count);
+        assert_eq!(8, state.lock().unwrap().i);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::RpcType;
+
+/// Sent by a client in place of a query to ask for the server's [ServerDescription], which a
+/// server with [crate::ServerConfig::reflection] on answers. Like [crate::HealthReport]s, it's
+/// followed by any [crate::Metadata] for the server's [crate::Interceptor]s, and outside any
+/// [crate::RpcName] type so can't clash with the server's own rpcs
+pub(crate) const REFLECT: &[u8] = b"PIRATES-REFLECT";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcKind {
+    /// One query, one response. Both sync and async handlers, see [crate::AsyncRpcImpl]
+    Unary,
+    /// See [crate::StreamRpc]
+    ServerStream,
+    /// See [crate::ClientStreamRpc]
+    ClientStream,
+    /// See [crate::BidiStreamRpc]
+    BidiStream,
+    /// See [crate::NotificationRpc]
+    Notification,
+}
+
+/// The query and response types of an rpc, as Rust names them, and the version it declares,
+/// see [crate::Rpc::version]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcTypes {
+    pub query: String,
+    pub response: String,
+    /// Absent from servers that predate versions, so read as 0 for them
+    #[serde(default)]
+    pub version: u32,
+    /// Hash of both type names and the version in hex, for a quick check that client and
+    /// server agree. It only sees the names, so misses a field changed in place unless the
+    /// version's bumped with it, and like [crate::DefaultQueryHasher]'s isn't stable across
+    /// Rust versions
+    pub schema_hash: String,
+}
+
+impl RpcTypes {
+    pub fn of<Q: RpcType, R: RpcType>() -> Self {
+        Self::versioned::<Q, R>(0)
+    }
+
+    pub fn versioned<Q: RpcType, R: RpcType>(version: u32) -> Self {
+        let query = String::from(std::any::type_name::<Q>());
+        let response = String::from(std::any::type_name::<R>());
+        let mut hasher = DefaultHasher::new();
+        (&query, &response).hash(&mut hasher);
+        if version != 0 {
+            version.hash(&mut hasher);
+        }
+        Self {
+            query,
+            response,
+            version,
+            schema_hash: format!("{:016x}", hasher.finish()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcDescription {
+    pub name: String,
+    pub kind: RpcKind,
+    /// None for rpcs stored by a type that doesn't say, see [crate::StoredRpc::rpc_types]
+    pub types: Option<RpcTypes>,
+}
+
+/// What a server says it serves and how, for generic tooling like CLIs and fuzzers to work
+/// against any server. See [crate::Transport::reflect]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerDescription {
+    /// Every rpc the server serves, sorted by name
+    pub rpcs: Vec<RpcDescription>,
+    /// Name of the codec the server answers in, see [crate::TransportWireConfig::wire_config_name]
+    pub wire_config: String,
+    /// Names of the codecs queries are taken in, if [tag_wire_format] is on
+    pub accepted_wire_configs: Vec<String>,
+    /// See [crate::TransportConfig::tag_wire_format]
+    pub tag_wire_format: bool,
+}
+
+impl ServerDescription {
+    pub fn rpc(&self, rpc_name: impl Display) -> Option<&RpcDescription> {
+        let rpc_name = format!("{}", rpc_name);
+        self.rpcs.iter().find(|rpc| rpc.name == rpc_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RpcError;
+    use crate::server::{RpcServer, ServerConfig};
+    use crate::tests::{
+        make_hello_world_rpc_impl, CountToRpc, HelloWorldRpcName, HelloWorldState, SumRpc,
+    };
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use crate::{ClientStreamRpcDefinition, StreamRpcDefinition};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn server_described_if_reflection_on() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            reflection: true,
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.add_stream_rpc(Box::new(CountToRpc::server()));
+        server.add_stream_rpc(Box::new(SumRpc::server()));
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut quiet_server = RpcServer::new(state, TransportConfig::default());
+        quiet_server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let (quiet_connector, quiet_listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::<_, HelloWorldRpcName>::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let mut quiet_transport = Transport::<_, HelloWorldRpcName>::new(
+                quiet_connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            (transport.reflect().await, quiet_transport.reflect().await)
+        };
+
+        let ((), (), (description, quiet)) = tokio::join!(
+            server.serve_channel(listener),
+            quiet_server.serve_channel(quiet_listener),
+            client_calls
+        );
+        let description = description.unwrap();
+        assert_eq!(server.describe(), description);
+        let names: Vec<&str> = description
+            .rpcs
+            .iter()
+            .map(|rpc| rpc.name.as_str())
+            .collect();
+        assert_eq!(vec!["CountTo", "HelloWorld", "Sum"], names);
+        let hello = description.rpc(HelloWorldRpcName::HelloWorld).unwrap();
+        assert_eq!(RpcKind::Unary, hello.kind);
+        assert_eq!(Some(RpcTypes::of::<String, String>()), hello.types);
+        let count_to = description.rpc(HelloWorldRpcName::CountTo).unwrap();
+        assert_eq!(RpcKind::ServerStream, count_to.kind);
+        let sum = description.rpc(HelloWorldRpcName::Sum).unwrap();
+        assert_eq!(RpcKind::ClientStream, sum.kind);
+        assert_ne!(
+            hello.types.as_ref().unwrap().schema_hash,
+            sum.types.as_ref().unwrap().schema_hash
+        );
+        assert_eq!("pickle", description.wire_config);
+        assert!(matches!(quiet, Err(RpcError::UnknownRpc(_))));
+    }
+
+    #[tokio::test]
+    async fn reflection_authenticated() {
+        use crate::auth::{Authentication, Identity};
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            reflection: true,
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        server.layer(Authentication::new(|token: &str| {
+            (token == "jack").then(|| Identity::new("jack"))
+        }));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut descriptions = Vec::new();
+            for config in [
+                TransportConfig::default(),
+                TransportConfig::default().with_bearer_token("jack"),
+            ] {
+                let mut transport = Transport::<_, HelloWorldRpcName>::new(
+                    connector.connect().await.unwrap(),
+                    config,
+                );
+                descriptions.push(transport.reflect().await);
+            }
+            descriptions
+        };
+
+        let ((), descriptions) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert!(matches!(descriptions[0], Err(RpcError::Unauthenticated(_))));
+        assert_eq!(1, descriptions[1].as_ref().unwrap().rpcs.len());
+    }
+}
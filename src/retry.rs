@@ -0,0 +1,145 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::RpcError;
+use crate::reconnect::{backoff, jittered};
+
+/// RetryPolicy has [crate::RpcClient] calls that fail try again, set with
+/// [crate::TransportConfig::retry_policy]. Only rpcs marked [crate::Rpc::idempotent] are
+/// retried, as a call that failed may still have run on the server, and running others twice
+/// could e.g. pay out twice.
+/// Up to [max_attempts] attempts are made in all, while [retry_on] says the error is worth
+/// retrying, by default when [RpcError::is_retryable] or the server said when to try again,
+/// see [RpcError::retry_after], as with [RpcError::RateLimited]. Each retry waits
+/// [initial_backoff], doubling per attempt up to [max_backoff], less up to [jitter] of it at
+/// random, as with [crate::ReconnectConfig], and no sooner than the server said
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: f64,
+    pub retry_on: Arc<dyn Fn(&RpcError) -> bool + Send + Sync>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            jitter: 0.5,
+            retry_on: Arc::new(|e: &RpcError| e.is_retryable() || e.retry_after().is_some()),
+        }
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retrying after the [failed_attempts]th attempt failed with
+    /// [error], or None to give up
+    pub(crate) fn retry_after(&self, error: &RpcError, failed_attempts: usize) -> Option<Duration> {
+        if failed_attempts >= self.max_attempts || !(self.retry_on)(error) {
+            return None;
+        }
+        let backoff = backoff(self.initial_backoff, self.max_backoff, failed_attempts);
+        // No sooner than the server asked, if it did
+        let retry_after = error.retry_after().unwrap_or_default();
+        Some(jittered(self.jitter, backoff).max(retry_after))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn only_idempotent_rpcs_retried() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 0 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        // Fails until it's been called three times
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::GetI,
+            Box::new(|state: &mut HelloWorldState, _query: ()| {
+                state.i += 1;
+                match state.i {
+                    i if i < 3 => Err(RpcError::Custom(String::from("Rough seas"))),
+                    i => Ok(i),
+                }
+            }),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let config = TransportConfig {
+                retry_policy: Some(RetryPolicy {
+                    retry_on: Arc::new(|e| matches!(e, RpcError::Remote(_))),
+                    initial_backoff: Duration::from_millis(1),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            let get_i = Rpc::<_, (), usize>::new(HelloWorldRpcName::GetI);
+            let not_retried = RpcClient::new(get_i.clone()).call((), &mut transport).await;
+            let retried = RpcClient::new(get_i.idempotent())
+                .call((), &mut transport)
+                .await;
+            (not_retried, retried)
+        };
+
+        let ((), (not_retried, retried)) =
+            tokio::join!(server.serve_channel(listener), client_calls);
+        assert!(matches!(not_retried, Err(RpcError::Remote(_))));
+        assert_eq!(3, retried.unwrap());
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let reset = RpcError::from(crate::transport::TransportError::ConnectionClosed);
+        assert_eq!(
+            Some(Duration::from_millis(50)),
+            policy.retry_after(&reset, 1)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            policy.retry_after(&reset, 2)
+        );
+        assert_eq!(None, policy.retry_after(&reset, 3));
+        let unknown = RpcError::UnknownRpc(String::from("GetI"));
+        assert_eq!(None, policy.retry_after(&unknown, 1));
+    }
+
+    #[test]
+    fn rate_limited_retried_no_sooner_than_told() {
+        let policy = RetryPolicy::default();
+        let rate_limited = RpcError::RateLimited(String::from("GetI"), Duration::from_secs(5));
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            policy.retry_after(&rate_limited, 1)
+        );
+        let too_large =
+            RpcError::TransportError(crate::transport::TransportError::MessageTooLarge(2, 1));
+        assert_eq!(None, policy.retry_after(&too_large, 1));
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{info, warn};
+use tokio::sync::Notify;
+
+use crate::auth::Identity;
+use crate::client::RpcClient;
+use crate::core::{Rpc, RpcName, RpcType};
+use crate::error::{RpcError, RpcResult};
+use crate::transport::{InternalTransport, Transport, TransportConfig, TransportError};
+use crate::{Bytes, OwnedBytes};
+
+/// Sent by a client, followed by the UTF-8 key it's to be known by, to have the server call
+/// its rpcs over the connection from then on, see [ReverseConnections]. The server echoes it
+/// back once it's taken the connection
+pub(crate) const REVERSE: &[u8] = b"PIRATES-REVERSE";
+
+/// A connection of any kind, for [ReverseConnections] to hold those accepted by any of the
+/// server's listeners alike
+pub(crate) type BoxedTransport = Box<dyn InternalTransport>;
+
+#[async_trait]
+impl InternalTransport for BoxedTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        (**self).send(b).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        (**self).send_and_wait_for_response(b, timeout).await
+    }
+
+    async fn send_vectored(&mut self, parts: &[Bytes<'_>]) -> Result<(), TransportError> {
+        (**self).send_vectored(parts).await
+    }
+
+    async fn send_many(&mut self, messages: &[Bytes<'_>]) -> Result<(), TransportError> {
+        (**self).send_many(messages).await
+    }
+
+    async fn send_vectored_and_wait_for_response(
+        &mut self,
+        parts: &[Bytes<'_>],
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        (**self)
+            .send_vectored_and_wait_for_response(parts, timeout)
+            .await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        (**self).receive(timeout).await
+    }
+
+    fn is_healthy(&self) -> bool {
+        (**self).is_healthy()
+    }
+
+    fn configure(&mut self, config: &TransportConfig) {
+        (**self).configure(config)
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn peer_certificate(&self) -> Option<OwnedBytes> {
+        (**self).peer_certificate()
+    }
+}
+
+/// Takes the connections handed over to a [crate::RpcServer], whatever the client's rpcs are
+/// named, see [crate::RpcServer::accept_reversed]
+pub(crate) trait AcceptReversed {
+    /// Whether the client with [identity] may hand over a connection as [key], failing with
+    /// [RpcError::PermissionDenied] if it's held by someone else
+    fn check(&self, key: &str, identity: Option<&Identity>) -> RpcResult<()>;
+
+    fn add(&self, key: String, identity: Option<&Identity>, internal_transport: BoxedTransport);
+}
+
+/// A client's connection, and the [Identity::subject] of who handed it over, if known
+struct Reversed<Name> {
+    owner: Option<String>,
+    transport: Arc<tokio::sync::Mutex<Transport<BoxedTransport, Name>>>,
+}
+
+type Connections<Name> = HashMap<String, Reversed<Name>>;
+
+/// Connections clients have handed over for the server to call the client's rpcs, named
+/// [Name], over. For clients behind NAT, which can dial out to the server but can't be
+/// dialled. The client connects as usual, then serves its rpcs over the connection with
+/// [crate::RpcServer::serve_reversed], giving a key to be known by, while the server takes the
+/// connection once it's been given these with [crate::RpcServer::accept_reversed], to [call]
+/// over.
+/// A reversed connection carries calls from the server alone, so a client that calls the
+/// server too does so over another connection. A newer connection with the same key replaces
+/// the older one, so long as it's from the same client: the request to reverse goes past the
+/// server's [crate::Interceptor]s, see [crate::ServerRequest], and with
+/// [crate::Authentication] a key is held by whoever handed over its connection, any other
+/// trying to take it being refused. Without, any client can claim any key not yet taken by
+/// an authenticated one, so use keys that can't be guessed
+pub struct ReverseConnections<Name: RpcName> {
+    connections: Arc<Mutex<Connections<Name>>>,
+    connected: Arc<Notify>,
+    transport_config: TransportConfig,
+}
+
+impl<Name: RpcName> Clone for ReverseConnections<Name> {
+    fn clone(&self) -> Self {
+        Self {
+            connections: self.connections.clone(),
+            connected: self.connected.clone(),
+            transport_config: self.transport_config.clone(),
+        }
+    }
+}
+
+impl<Name: RpcName> ReverseConnections<Name> {
+    /// Calls over the connections use [transport_config], which must agree with the clients'
+    pub fn new(transport_config: TransportConfig) -> Self {
+        Self {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            connected: Arc::new(Notify::new()),
+            transport_config,
+        }
+    }
+
+    /// Keys of the clients connected
+    pub fn clients(&self) -> Vec<String> {
+        let mut clients: Vec<String> = self.connections.lock().unwrap().keys().cloned().collect();
+        clients.sort();
+        clients
+    }
+
+    /// Wait until the client with [key] has connected
+    pub async fn wait_for(&self, key: &str) {
+        loop {
+            let connected = self.connected.notified();
+            if self.connections.lock().unwrap().contains_key(key) {
+                return;
+            }
+            connected.await;
+        }
+    }
+
+    /// Call [rpc] on the client with [key]. Calls to one client are made one at a time.
+    /// Fails with [RpcError::UnknownRpc] if no such client is connected, and drops the
+    /// connection if the call fails on it
+    pub async fn call<Q: RpcType, R: RpcType>(
+        &self,
+        key: &str,
+        rpc: Rpc<Name, Q, R>,
+        query: Q,
+    ) -> RpcResult<R> {
+        let connection = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|reversed| reversed.transport.clone())
+            .ok_or_else(|| RpcError::UnknownRpc(format!("{} on client {}", rpc.name, key)))?;
+        let mut transport = connection.lock().await;
+        let result = RpcClient::new(rpc).call(query, &mut transport).await;
+        if let Err(RpcError::TransportError(_) | RpcError::RpcTimeout(_, _)) = &result {
+            let mut connections = self.connections.lock().unwrap();
+            // Unless already replaced by a newer connection
+            if connections
+                .get(key)
+                .is_some_and(|current| Arc::ptr_eq(&current.transport, &connection))
+            {
+                info!("Dropping reversed connection to client {}", key);
+                connections.remove(key);
+            }
+        }
+        result
+    }
+}
+
+impl<Name: RpcName> AcceptReversed for ReverseConnections<Name> {
+    fn check(&self, key: &str, identity: Option<&Identity>) -> RpcResult<()> {
+        let owner = identity.map(|identity| &identity.subject);
+        match self.connections.lock().unwrap().get(key) {
+            Some(held) if held.owner.as_ref() != owner => Err(RpcError::PermissionDenied(format!(
+                "reversed connection {} is held by another client",
+                key
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn add(&self, key: String, identity: Option<&Identity>, internal_transport: BoxedTransport) {
+        // Checked again, as another could have taken it while this was being accepted
+        if let Err(e) = self.check(&key, identity) {
+            warn!("Dropping reversed connection: {}", e);
+            return;
+        }
+        info!("Client {} reversed its connection", key);
+        let transport = Transport::new(internal_transport, self.transport_config.clone());
+        let reversed = Reversed {
+            owner: identity.map(|identity| identity.subject.clone()),
+            transport: Arc::new(tokio::sync::Mutex::new(transport)),
+        };
+        self.connections.lock().unwrap().insert(key, reversed);
+        self.connected.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::RpcServer;
+    use crate::tests::{
+        make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldRpcName, HelloWorldState,
+    };
+    use crate::transport::channel_listener;
+
+    #[tokio::test]
+    async fn server_calls_client_over_reversed_connection() {
+        let connections = ReverseConnections::<HelloWorldRpcName>::new(TransportConfig::default());
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::<_, HelloWorldRpcName>::new(state, TransportConfig::default());
+        server.accept_reversed(connections.clone());
+        // The client behind NAT, serving its own rpcs
+        let client_state = Arc::new(Mutex::new(HelloWorldState { i: 7 }));
+        let mut client = RpcServer::new(client_state, TransportConfig::default());
+        client.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let client_shutdown = client.shutdown_handle();
+        let (connector, listener) = channel_listener(1);
+        let client_serves = async move {
+            let transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            client.serve_reversed(transport, "black-pearl").await
+        };
+        let server_calls = async {
+            connections.wait_for("black-pearl").await;
+            let hello = connections
+                .call("black-pearl", make_hello_world_rpc(), "Foo".into())
+                .await;
+            let unknown = connections
+                .call("flying-dutchman", make_hello_world_rpc(), "Foo".into())
+                .await;
+            client_shutdown.shutdown();
+            (hello, unknown)
+        };
+
+        let ((), served, (hello, unknown)) =
+            tokio::join!(server.serve_channel(listener), client_serves, server_calls);
+        served.unwrap();
+        assert_eq!("Hello world: 7:\"Foo\"", hello.unwrap());
+        assert!(matches!(unknown, Err(RpcError::UnknownRpc(_))));
+        assert_eq!(vec![String::from("black-pearl")], connections.clients());
+    }
+
+    #[tokio::test]
+    async fn reversed_key_held_by_authenticated_client() {
+        use crate::auth::Authentication;
+        let connections = ReverseConnections::<HelloWorldRpcName>::new(TransportConfig::default());
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::<_, HelloWorldRpcName>::new(state, TransportConfig::default());
+        server.accept_reversed(connections.clone());
+        server.layer(Authentication::new(|token: &str| {
+            ["jack", "davy"]
+                .contains(&token)
+                .then(|| Identity::new(token))
+        }));
+        let client_state = Arc::new(Mutex::new(HelloWorldState { i: 7 }));
+        let mut client = RpcServer::new(client_state, TransportConfig::default());
+        client.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let client_shutdown = client.shutdown_handle();
+        let (connector, listener) = channel_listener(4);
+        let clients = async {
+            // Dropped once done with, so the server stops serving the channel
+            let connector = connector;
+            let reverse_as = |token: Option<&'static str>| {
+                let connector = connector.clone();
+                let client = &client;
+                async move {
+                    let config = match token {
+                        Some(token) => TransportConfig::default().with_bearer_token(token),
+                        None => TransportConfig::default(),
+                    };
+                    let transport = Transport::new(connector.connect().await.unwrap(), config);
+                    client.serve_reversed(transport, "black-pearl").await
+                }
+            };
+            let jack = reverse_as(Some("jack"));
+            let others = async {
+                connections.wait_for("black-pearl").await;
+                let anonymous = reverse_as(None).await;
+                let davy = reverse_as(Some("davy")).await;
+                let hello = connections
+                    .call("black-pearl", make_hello_world_rpc(), "Foo".into())
+                    .await;
+                client_shutdown.shutdown();
+                (anonymous, davy, hello)
+            };
+            let (jack, others) = tokio::join!(jack, others);
+            (jack, others)
+        };
+
+        let ((), (jack, (anonymous, davy, hello))) =
+            tokio::join!(server.serve_channel(listener), clients);
+        jack.unwrap();
+        assert!(matches!(anonymous, Err(RpcError::Unauthenticated(_))));
+        assert!(matches!(davy, Err(RpcError::PermissionDenied(_))));
+        assert_eq!("Hello world: 7:\"Foo\"", hello.unwrap());
+    }
+}
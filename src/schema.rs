@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::{
+    BidiStreamRpc, ClientStreamRpc, NotificationRpc, Rpc, RpcName, RpcType, StreamRpc,
+};
+use crate::reflection::{RpcTypes, ServerDescription};
+
+/// An rpc as declared, for a [Schema]
+pub trait DeclaredRpc {
+    fn declared_name(&self) -> String;
+    fn declared_types(&self) -> RpcTypes;
+}
+
+macro_rules! declared_rpc {
+    ($rpc:ident) => {
+        impl<Name: RpcName, Q: RpcType, R: RpcType> DeclaredRpc for $rpc<Name, Q, R> {
+            fn declared_name(&self) -> String {
+                format!("{}", self.name)
+            }
+
+            fn declared_types(&self) -> RpcTypes {
+                RpcTypes::versioned::<Q, R>(self.version)
+            }
+        }
+    };
+}
+
+declared_rpc!(Rpc);
+declared_rpc!(StreamRpc);
+declared_rpc!(ClientStreamRpc);
+declared_rpc!(BidiStreamRpc);
+
+impl<Name: RpcName, Q: RpcType> DeclaredRpc for NotificationRpc<Name, Q> {
+    fn declared_name(&self) -> String {
+        format!("{}", self.name)
+    }
+
+    fn declared_types(&self) -> RpcTypes {
+        RpcTypes::versioned::<Q, ()>(self.version)
+    }
+}
+
+/// The rpcs one end expects, each with its query and response types and version, by name.
+/// A client builds the schema of the rpcs it calls with [Schema::with], and checks a server
+/// serves each the same with [crate::Transport::verify_schema], or when connecting with
+/// [crate::TransportConfig::schema], so a mismatch fails up front with
+/// [crate::error::RpcError::SchemaMismatch] saying what differs, rather than later as an error
+/// deserialising a query or response, or worse a field silently read wrong. A server exports
+/// its own with [crate::RpcServer::schema], to store or diff against a client's. Types are
+/// compared by name, so bump an rpc's version, see [crate::Rpc::version], when its types
+/// change shape in place. Names are as [std::any::type_name] gives them, which isn't stable
+/// across compilers, so a schema stored from one build may differ by type name alone from
+/// another's; [Schema::version_mismatches] compares only versions, for such a schema
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schema {
+    pub rpcs: BTreeMap<String, RpcTypes>,
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, rpc: &impl DeclaredRpc) -> Self {
+        self.rpcs.insert(rpc.declared_name(), rpc.declared_types());
+        self
+    }
+
+    /// How [server] differs from this, for each rpc here it doesn't serve the same, if any
+    pub fn mismatches(&self, server: &Schema) -> Vec<String> {
+        self.differences(server, true)
+    }
+
+    /// As [Schema::mismatches], but only for rpcs missing or at a different version, ignoring
+    /// type names
+    pub fn version_mismatches(&self, server: &Schema) -> Vec<String> {
+        self.differences(server, false)
+    }
+
+    fn differences(&self, server: &Schema, compare_types: bool) -> Vec<String> {
+        self.rpcs
+            .iter()
+            .filter_map(|(name, ours)| match server.rpcs.get(name) {
+                None => Some(format!("{} isn't served", name)),
+                Some(theirs) if theirs.version != ours.version => Some(format!(
+                    "{} is version {} on the server but {} here",
+                    name, theirs.version, ours.version
+                )),
+                Some(theirs)
+                    if compare_types
+                        && (&theirs.query, &theirs.response) != (&ours.query, &ours.response) =>
+                {
+                    Some(format!(
+                        "{} takes {} giving {} on the server but {} giving {} here",
+                        name, theirs.query, theirs.response, ours.query, ours.response
+                    ))
+                }
+                Some(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl From<&ServerDescription> for Schema {
+    /// The rpcs [description] gives the types of
+    fn from(description: &ServerDescription) -> Self {
+        Self {
+            rpcs: description
+                .rpcs
+                .iter()
+                .filter_map(|rpc| Some((rpc.name.clone(), rpc.types.clone()?)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::connect_tcp_transport;
+    use crate::error::RpcError;
+    use crate::listener::TcpListener;
+    use crate::server::{RpcServer, ServerConfig};
+    use crate::tests::{
+        make_get_i_rpc, make_get_i_rpc_impl, make_hello_world_rpc, HelloWorldRpcName,
+        HelloWorldState,
+    };
+    use crate::transport::{Transport, TransportConfig};
+    use crate::RpcImpl;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn mismatched_schema_fails_on_connect() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let server_config = ServerConfig {
+            reflection: true,
+            ..Default::default()
+        };
+        let mut server = RpcServer::with_config(state, TransportConfig::default(), server_config);
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let incr_i = RpcImpl::new(
+            HelloWorldRpcName::IncrI,
+            Box::new(|state: &mut HelloWorldState, by: usize| {
+                state.i += by;
+                Ok(())
+            }),
+        );
+        server.add_rpc(Box::new(incr_i));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let exported = server.schema();
+        let client_calls = async {
+            let connect = |schema: Schema| {
+                let config = TransportConfig {
+                    schema: Some(schema),
+                    ..Default::default()
+                };
+                connect_tcp_transport::<HelloWorldRpcName>(&addr, config)
+            };
+            let matching = Schema::new().with(&make_get_i_rpc());
+            let mut transport: Transport<_, _> = connect(matching).await.unwrap();
+            let i = crate::RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            let mismatched = Schema::new()
+                .with(&make_get_i_rpc().version(2))
+                .with(&Rpc::<_, (), ()>::new(HelloWorldRpcName::IncrI))
+                .with(&make_hello_world_rpc());
+            let refused = connect(mismatched).await.map(|_| ());
+            shutdown.shutdown();
+            (i, refused)
+        };
+
+        let ((), (i, refused)) = tokio::join!(server.serve_listener(listener), client_calls);
+        assert_eq!(3, i.unwrap());
+        assert_eq!(2, exported.rpcs.len());
+        let mut renamed = exported.clone();
+        for types in renamed.rpcs.values_mut() {
+            types.query = format!("other::{}", types.query);
+        }
+        assert!(renamed.version_mismatches(&exported).is_empty());
+        assert_eq!(2, renamed.mismatches(&exported).len());
+        match refused {
+            Err(RpcError::SchemaMismatch(mismatches)) => {
+                assert_eq!(
+                    "GetI is version 0 on the server but 2 here; \
+                    HelloWorld isn't served; \
+                    IncrI takes usize giving () on the server but () giving () here",
+                    mismatches
+                )
+            }
+            other => panic!("Expected SchemaMismatch, got {:?}", other),
+        }
+    }
+}
@@ -1,11 +1,224 @@
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::core::{RpcName, StoredRpc};
+use crate::auth::Identity;
+use crate::batch::BATCH;
+use crate::call_context::{with_call_context, CallContext};
+use crate::clock::{Clock, SystemClock};
+use crate::core::{RpcName, StoredAsyncRpc, StoredRpc, StoredStreamRpc};
 use crate::error::{RpcError, RpcResult};
-use crate::transport::{TcpTransport, Transport, TransportConfig};
+use crate::extensions::Extensions;
+use crate::health::{HealthHandle, HealthReport, HEALTH_CHECK};
+use crate::keepalive::PING;
+#[cfg(unix)]
+use crate::listener::UnixListener;
+use crate::listener::{Listener, TcpListener};
+use crate::metrics::{CallRecord, MetricsRecorder};
+use crate::middleware::{Interceptor, Next, ServerRequest, ServerRequestKind};
+use crate::overload::{OverloadPolicy, QueryQueue, QueuedTotal};
+use crate::priority::Priority;
+#[cfg(feature = "transport_quic")]
+use crate::quic;
+use crate::reflection::{RpcDescription, RpcKind, ServerDescription, REFLECT};
+use crate::reverse::{AcceptReversed, ReverseConnections, REVERSE};
+use crate::schema::Schema;
+#[cfg(feature = "transport_tls")]
+use crate::tls::{TlsListener, TlsServerConfig};
+use crate::trace::CallSpan;
+use crate::transport::{
+    decode_request_metadata, decode_reverse_request, ChannelListener, InternalTransport,
+    PackageKind, ReceivedQuery, Transport, TransportConfig, TransportError, TransportWireConfig,
+    UdpTransport, MAX_UDP_PAYLOAD,
+};
+#[cfg(feature = "transport_websocket")]
+use crate::websocket::WebSocketListener;
 use crate::OwnedBytes;
+use futures_util::future::LocalBoxFuture;
+use futures_util::stream::FuturesUnordered;
+use futures_util::{Stream, StreamExt};
 use log::{debug, error, info, warn};
+use tokio_util::sync::CancellationToken;
+
+/// ServerConfig defines options for how the [RpcServer] handles queries
+/// [catch_handler_panics] turns a panicking handler into an [RpcError::HandlerPanic] response to
+/// the client, rather than letting the panic take down the server. Disable it to fail fast
+/// [idle_timeout] closes a connection that sends no query within that time, so abandoned
+/// connections don't hold the server and a file descriptor forever. The wait restarts for each
+/// query received
+/// [slow_handler_threshold] logs a warning for any handler call taking longer than it, as
+/// measured by [clock], and counts it in [RpcServer::slow_handler_count]
+/// [drain_timeout] is how long a server that's been shut down waits for calls in flight to
+/// finish, see [ShutdownHandle]
+/// [max_concurrent_queries] is how many unary queries each connection has handled at once,
+/// see [RpcServer::answer_queries]. A [crate::Batch] takes one of them, while having up to
+/// this many of its own calls in flight. Up to [max_queued_queries] more are received to wait
+/// their turn, going by [crate::Priority], and [max_queued_queries_total], if set, caps those
+/// waiting across every connection. Past either, [overload_policy] says whether to stop reading
+/// until there's room or refuse the query, see [OverloadPolicy]
+/// [max_connections] caps how many connections are served at once. Once reached, no more are
+/// accepted until one closes, leaving new ones waiting in the listener's backlog
+/// [handler_timeout] fails a unary call whose handler runs longer than it with
+/// [RpcError::HandlerTimeout], so a hung handler doesn't leave the client waiting. Only async
+/// handlers, see [crate::AsyncRpcImpl], are bounded by it, being dropped at the await they're
+/// stuck on. A sync handler runs on the connection's task holding the state, so can't be
+/// stopped part way or moved off it: one that hangs still holds up its connection and every
+/// other call needing the state. Once one that overran, as measured by [clock], finishes, its
+/// result is thrown away for the error, though anything it did to the state stays done
+/// [reflection] has the server describe the rpcs it serves to any client that asks, see
+/// [RpcServer::describe]. Leave it off where the rpcs on offer are best kept quiet
+/// [response_chunk_size] splits a unary response bigger than it into chunks of that many bytes,
+/// sent in turn with those of other responses, so a small response needn't wait for the whole
+/// of a large one ahead of it. Only a [crate::MultiplexedClient] joins them back together, so
+/// leave it unset for other clients
+/// [max_requests_per_connection] has a connection that's been sent that many queries answer
+/// the next with [GO_AWAY] rather than handling it, then close once those in flight are
+/// answered, so long-lived clients spread out again behind a load balancer. A
+/// [crate::ReconnectingTransport] sends the query again on a new connection. A
+/// [crate::MultiplexedClient] can't reconnect, so fails the call turned away straight away
+/// with [TransportError::ConnectionClosed], which is retryable, and every call after it, leaving
+/// the caller to connect again
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub catch_handler_panics: bool,
+    pub idle_timeout: Option<Duration>,
+    pub slow_handler_threshold: Option<Duration>,
+    pub clock: Arc<dyn Clock>,
+    pub drain_timeout: Duration,
+    pub max_concurrent_queries: usize,
+    pub max_queued_queries: usize,
+    pub max_queued_queries_total: Option<usize>,
+    pub overload_policy: OverloadPolicy,
+    pub max_connections: Option<usize>,
+    pub handler_timeout: Option<Duration>,
+    pub reflection: bool,
+    pub response_chunk_size: Option<usize>,
+    pub max_requests_per_connection: Option<u64>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            catch_handler_panics: true,
+            idle_timeout: None,
+            slow_handler_threshold: None,
+            clock: Arc::new(SystemClock),
+            drain_timeout: Duration::from_secs(30),
+            max_concurrent_queries: 16,
+            max_queued_queries: 16,
+            max_queued_queries_total: None,
+            overload_policy: OverloadPolicy::default(),
+            max_connections: None,
+            handler_timeout: None,
+            reflection: false,
+            response_chunk_size: None,
+            max_requests_per_connection: None,
+        }
+    }
+}
+
+/// Sent by the server in place of a response to a query it won't handle, as the connection's
+/// served [ServerConfig::max_requests_per_connection], before it closes the connection. It's
+/// followed by the query's correlation id, as a big-endian u64. Like [crate::keepalive::PONG]
+/// it's fixed bytes, so is understood whatever the wire format
+pub(crate) const GO_AWAY: &[u8] = b"PIRATES-GOAWAY";
+
+/// The correlation id of the query [response] turned away, if it's a [GO_AWAY]
+pub(crate) fn turned_away(response: &[u8]) -> Option<u64> {
+    let correlation_id = response.strip_prefix(GO_AWAY)?;
+    Some(u64::from_be_bytes(correlation_id.try_into().ok()?))
+}
+
+/// Shuts down the [RpcServer] it came from, see [RpcServer::shutdown_handle]. Clone it to
+/// hand to e.g. a signal handler
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle(CancellationToken);
+
+impl ShutdownHandle {
+    /// Stop accepting connections, close each open one once its call in flight is answered,
+    /// and then have the serve future resolve. Calls still going after
+    /// [ServerConfig::drain_timeout] are dropped
+    pub fn shutdown(&self) {
+        self.0.cancel();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0.is_cancelled()
+    }
+}
+
+/// What came of receiving the next package on a connection
+enum Received<Name: RpcName> {
+    /// The connection closed, went idle or the server is shutting down
+    Closed,
+    /// Nothing's left to do for it, e.g. a query refused by an [Interceptor]
+    Answered,
+    Query(ReceivedQuery<Name>),
+    /// The client asked for the connection to be reversed, as the client with this key, see
+    /// [ReverseConnections], and was let by the [Interceptor]s as who they found it to be
+    Reversed(String, Option<Identity>),
+    /// The client cancelled the call with this correlation id
+    Cancel(u64),
+    /// A [crate::Batch], its queries admitted and ready to call
+    Batch(ReceivedBatch<Name>),
+}
+
+/// A [crate::Batch] received, with each of its queries decoded and admitted
+struct ReceivedBatch<Name: RpcName> {
+    /// A result for every query in the batch, in order, those admitted filled in once called
+    results: Vec<RpcResult<OwnedBytes>>,
+    /// The queries admitted, each with its index in the batch
+    calls: Vec<(usize, ReceivedQuery<Name>)>,
+    concurrent: bool,
+    wire_config: TransportWireConfig,
+}
+
+/// What a call in flight on a connection gives, to send back to the client
+enum Answer<Name: RpcName> {
+    Query(Box<ReceivedQuery<Name>>, RpcResult<OwnedBytes>),
+    /// The result of every query in a batch, in order
+    Batch(Vec<RpcResult<OwnedBytes>>),
+}
+
+/// What [RpcServer::admit_query] made of a query
+enum Admitted<Name: RpcName> {
+    Query(ReceivedQuery<Name>),
+    /// Refused by an [Interceptor], with the error to answer it with
+    Refused(ReceivedQuery<Name>, RpcError),
+    /// Not to be answered at all, and why
+    Dropped(String),
+}
+
+/// A response being sent a chunk at a time, see [ServerConfig::response_chunk_size]
+struct ChunkedResponse {
+    correlation_id: u64,
+    bytes: OwnedBytes,
+    sent: usize,
+    chunk_size: usize,
+}
+
+impl ChunkedResponse {
+    /// Send the next chunk, giving back what's left if it wasn't the last
+    async fn send_next<I: InternalTransport, Name: RpcName>(
+        mut self,
+        transport: &mut Transport<I, Name>,
+    ) -> RpcResult<Option<Self>> {
+        let rest = &self.bytes[self.sent..];
+        if rest.len() > self.chunk_size {
+            let chunk = &rest[..self.chunk_size];
+            transport.respond_chunk(self.correlation_id, chunk).await?;
+            self.sent += self.chunk_size;
+            return Ok(Some(self));
+        }
+        let sent = transport.respond(self.correlation_id, rest).await;
+        transport.config.give_back_buffer(self.bytes);
+        sent.map(|()| None)
+    }
+}
 
 pub struct RpcServer<S, Name>
 where
@@ -13,7 +226,19 @@ where
 {
     state: Arc<Mutex<S>>,
     rpcs: HashMap<Name, Box<dyn StoredRpc<S, Name>>>,
+    async_rpcs: HashMap<Name, Box<dyn StoredAsyncRpc<Name>>>,
+    stream_rpcs: HashMap<Name, Box<dyn StoredStreamRpc<S, Name>>>,
     transport_config: TransportConfig,
+    server_config: ServerConfig,
+    slow_handler_count: AtomicUsize,
+    interceptors: Vec<Box<dyn Interceptor<Name>>>,
+    shutdown: CancellationToken,
+    extensions: Arc<Extensions>,
+    health: HealthHandle,
+    started: Instant,
+    metrics: Option<Box<dyn MetricsRecorder>>,
+    accept_reversed: Option<Box<dyn AcceptReversed>>,
+    queued_total: QueuedTotal,
 }
 
 impl<S, Name> RpcServer<S, Name>
@@ -21,69 +246,1363 @@ where
     Name: RpcName,
 {
     pub fn new(state: Arc<Mutex<S>>, transport_config: TransportConfig) -> Self {
+        Self::with_config(state, transport_config, ServerConfig::default())
+    }
+
+    pub fn with_config(
+        state: Arc<Mutex<S>>,
+        transport_config: TransportConfig,
+        server_config: ServerConfig,
+    ) -> Self {
         Self {
             state,
             rpcs: HashMap::new(),
+            async_rpcs: HashMap::new(),
+            stream_rpcs: HashMap::new(),
             transport_config,
+            slow_handler_count: AtomicUsize::new(0),
+            interceptors: Vec::new(),
+            shutdown: CancellationToken::new(),
+            extensions: Arc::new(Extensions::default()),
+            health: HealthHandle::new(),
+            started: server_config.clock.now(),
+            metrics: None,
+            accept_reversed: None,
+            queued_total: QueuedTotal::default(),
+            server_config,
+        }
+    }
+
+    /// Share [extension] with every handler, as well as the state, see [Extensions]. Handlers
+    /// get it from [crate::call_extension], or as an argument with
+    /// [crate::RpcImpl::with_extension]. Replaces any extension of the same type added before
+    pub fn add_extension<T: Send + Sync + 'static>(&mut self, extension: Arc<T>) {
+        Arc::make_mut(&mut self.extensions).insert(extension);
+    }
+
+    /// A handle to shut the server down gracefully with, rather than aborting the task serving
+    /// it and dropping calls in flight
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// A handle to mark the server or its rpcs not ready with, see [HealthReport]
+    pub fn health_handle(&self) -> HealthHandle {
+        self.health.clone()
+    }
+
+    /// What the server reports when a client checks its health, see
+    /// [crate::Transport::check_health]. Every rpc added is listed
+    pub fn health_report(&self) -> HealthReport {
+        let uptime = self.server_config.clock.now().duration_since(self.started);
+        let rpc_names = self
+            .rpcs
+            .keys()
+            .chain(self.async_rpcs.keys())
+            .chain(self.stream_rpcs.keys())
+            .map(|rpc_name| format!("{}", rpc_name));
+        self.health
+            .report(self.shutdown.is_cancelled(), uptime, rpc_names)
+    }
+
+    /// What the server tells clients it serves, if [ServerConfig::reflection] is on, see
+    /// [crate::Transport::reflect]
+    pub fn describe(&self) -> ServerDescription {
+        let unary = self
+            .rpcs
+            .iter()
+            .map(|(rpc_name, rpc)| (rpc_name, rpc.rpc_kind(), rpc.rpc_types()));
+        let unary_async = self
+            .async_rpcs
+            .iter()
+            .map(|(rpc_name, rpc)| (rpc_name, RpcKind::Unary, rpc.rpc_types()));
+        let streams = self
+            .stream_rpcs
+            .iter()
+            .map(|(rpc_name, rpc)| (rpc_name, rpc.rpc_kind(), rpc.rpc_types()));
+        let mut rpcs: Vec<RpcDescription> = unary
+            .chain(unary_async)
+            .chain(streams)
+            .map(|(rpc_name, kind, types)| RpcDescription {
+                name: format!("{}", rpc_name),
+                kind,
+                types,
+            })
+            .collect();
+        rpcs.sort_by(|a, b| a.name.cmp(&b.name));
+        let config = &self.transport_config;
+        ServerDescription {
+            rpcs,
+            wire_config: String::from(config.wire_config.wire_config_name()),
+            accepted_wire_configs: config
+                .accepted_wire_configs
+                .iter()
+                .map(|wire_config| String::from(wire_config.wire_config_name()))
+                .collect(),
+            tag_wire_format: config.tag_wire_format,
+        }
+    }
+
+    /// The rpcs served, with their types and versions, to export for clients to check against,
+    /// see [Schema]
+    pub fn schema(&self) -> Schema {
+        Schema::from(&self.describe())
+    }
+
+    /// Take connections clients reverse, handing them to [connections] to call the clients
+    /// over, see [ReverseConnections]. Without this, clients asking are refused
+    pub fn accept_reversed<ClientName: RpcName + 'static>(
+        &mut self,
+        connections: ReverseConnections<ClientName>,
+    ) {
+        self.accept_reversed = Some(Box::new(connections));
+    }
+
+    /// Serve this server's rpcs to the server at the other end of [transport], a connection
+    /// this end dialled, as the client known by [key], see [ReverseConnections]. Returns once
+    /// the connection closes or this server shuts down
+    pub async fn serve_reversed(
+        &self,
+        mut transport: Transport<impl InternalTransport + 'static, Name>,
+        key: &str,
+    ) -> RpcResult<()> {
+        transport.request_reverse(key).await?;
+        self.answer_queries(transport).await
+    }
+
+    /// Tell [recorder] about every call handled, see [CallRecord]. Replaces any recorder given
+    /// before
+    pub fn record_metrics(&mut self, recorder: impl MetricsRecorder + 'static) {
+        self.metrics = Some(Box::new(recorder));
+    }
+
+    fn record_call(
+        &self,
+        rpc_name: &Name,
+        bytes_in: usize,
+        error: Option<&RpcError>,
+        latency: Duration,
+        bytes_out: usize,
+    ) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_call(&CallRecord {
+                rpc_name: format!("{}", rpc_name),
+                error: error.map(RpcError::code),
+                latency,
+                bytes_in,
+                bytes_out,
+            });
+        }
+    }
+
+    /// Number of handler calls that took longer than [ServerConfig::slow_handler_threshold]
+    pub fn slow_handler_count(&self) -> usize {
+        self.slow_handler_count.load(Ordering::Relaxed)
+    }
+
+    fn check_slow_handler(&self, rpc_name: &Name, started: Instant) {
+        if let Some(threshold) = self.server_config.slow_handler_threshold {
+            let elapsed = self.server_config.clock.now().duration_since(started);
+            if elapsed > threshold {
+                warn!("Slow handler: rpc {} took {:?}", rpc_name, elapsed);
+                self.slow_handler_count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// [RpcError::HandlerTimeout] if the handler called at [started] has run for longer than
+    /// [ServerConfig::handler_timeout]
+    fn check_handler_timeout(&self, rpc_name: &Name, started: Instant) -> RpcResult<()> {
+        match self.server_config.handler_timeout {
+            Some(timeout) if self.server_config.clock.now().duration_since(started) > timeout => {
+                Err(RpcError::HandlerTimeout(format!("{}", rpc_name), timeout))
+            }
+            _ => Ok(()),
         }
     }
 
+    /// Run every query through [interceptor], see [Interceptor]. Interceptors run in the order
+    /// they're added, so the first added is outermost
+    pub fn layer(&mut self, interceptor: impl Interceptor<Name> + 'static) {
+        self.layer_boxed(Box::new(interceptor));
+    }
+
+    pub(crate) fn layer_boxed(&mut self, interceptor: Box<dyn Interceptor<Name>>) {
+        self.interceptors.push(interceptor);
+    }
+
     pub fn add_rpc(&mut self, stored_rpc: Box<dyn StoredRpc<S, Name>>) {
         let name = stored_rpc.rpc_name();
         self.rpcs.insert(name, stored_rpc);
     }
 
+    /// Serve [async_rpc] as well, see [crate::AsyncRpcImpl]. While it awaits, other calls on
+    /// the connection carry on, up to [ServerConfig::max_concurrent_queries]
+    pub fn add_async_rpc(&mut self, async_rpc: Box<dyn StoredAsyncRpc<Name>>) {
+        let name = async_rpc.rpc_name();
+        self.async_rpcs.insert(name, async_rpc);
+    }
+
+    /// Serve [stream_rpc] as well, see [crate::StreamRpc], [crate::ClientStreamRpc] and
+    /// [crate::BidiStreamRpc]. Its responses are sent as the stream yields them, and no other
+    /// query on the same connection is handled until it ends
+    pub fn add_stream_rpc(&mut self, stream_rpc: Box<dyn StoredStreamRpc<S, Name>>) {
+        let name = stream_rpc.rpc_name();
+        self.stream_rpcs.insert(name, stream_rpc);
+    }
+
+    /// Run a handler for [query] with the state, per [ServerConfig::catch_handler_panics] and
+    /// [ServerConfig::slow_handler_threshold], with its [CallContext] given by
+    /// [crate::call_context]
+    fn with_state<T>(
+        &self,
+        query: &ReceivedQuery<Name>,
+        handler: impl FnOnce(&mut S) -> RpcResult<T>,
+    ) -> RpcResult<T> {
+        let started = self.server_config.clock.now();
+        let result = {
+            let mut state = self.state.lock().unwrap();
+            // The panic is caught before the state guard drops, so the mutex isn't poisoned,
+            // though the handler may have left the state half updated
+            self.in_call_context(&self.call_context(query), || handler(&mut state))
+        };
+        self.check_slow_handler(&query.name, started);
+        self.check_handler_timeout(&query.name, started)?;
+        result
+    }
+
+    /// The [CallContext] handlers of [query] see
+    fn call_context(&self, query: &ReceivedQuery<Name>) -> CallContext {
+        CallContext {
+            extensions: self.extensions.clone(),
+            ..query.context()
+        }
+    }
+
+    /// Run [f] with [context] set for [crate::call_context], catching any panic per
+    /// [ServerConfig::catch_handler_panics]
+    fn in_call_context<T>(
+        &self,
+        context: &CallContext,
+        f: impl FnOnce() -> RpcResult<T>,
+    ) -> RpcResult<T> {
+        let call = || with_call_context(context, f);
+        if self.server_config.catch_handler_panics {
+            std::panic::catch_unwind(AssertUnwindSafe(call))
+                .unwrap_or_else(|panic| Err(RpcError::HandlerPanic(panic_message(panic))))
+        } else {
+            call()
+        }
+    }
+
+    /// Call [async_rpc] for [query], as [with_state] does for sync handlers. The call's context
+    /// is set again each time the handler is polled, so it stays readable across awaits, but
+    /// is never left set for other calls polled in between
+    fn call_async<'a>(
+        &'a self,
+        async_rpc: &'a dyn StoredAsyncRpc<Name>,
+        query: &ReceivedQuery<Name>,
+        wire_config: &TransportWireConfig,
+    ) -> LocalBoxFuture<'a, RpcResult<OwnedBytes>> {
+        debug!("Server called by async rpc {}", query.name);
+        let started = self.server_config.clock.now();
+        let context = self.call_context(query);
+        let rpc_name = query.name.clone();
+        let mut call = match self.in_call_context(&context, || {
+            async_rpc.call_of_bytes(&query.query_bytes, wire_config)
+        }) {
+            Ok(call) => call,
+            Err(e) => return Box::pin(std::future::ready(Err(e))),
+        };
+        Box::pin(async move {
+            let call = futures_util::future::poll_fn(|cx| {
+                let poll = self.in_call_context(&context, || Ok(call.as_mut().poll(cx)));
+                match poll {
+                    Ok(poll) => poll,
+                    Err(e) => std::task::Poll::Ready(Err(e)),
+                }
+            });
+            let result = match self.server_config.handler_timeout {
+                Some(timeout) => {
+                    let elapsed = self.server_config.clock.now().duration_since(started);
+                    tokio::time::timeout(timeout.saturating_sub(elapsed), call)
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(RpcError::HandlerTimeout(format!("{}", rpc_name), timeout))
+                        })
+                }
+                None => call.await,
+            };
+            self.check_slow_handler(&rpc_name, started);
+            self.check_handler_timeout(&rpc_name, started)?;
+            result
+        })
+    }
+
+    /// Call the rpc [query] is for, with query and response in [wire_config], which is that of
+    /// the connection the query came in on
     pub(crate) fn call(
         &self,
-        incoming_bytes: &[u8],
-        incoming_name: &Name,
+        query: &ReceivedQuery<Name>,
+        wire_config: &TransportWireConfig,
     ) -> RpcResult<OwnedBytes> {
-        debug!("Server called by rpc {}", incoming_name);
-        match self.rpcs.get(incoming_name) {
-            Some(rpc_impl) => {
-                let result_bytes = {
-                    let mut state = self.state.lock().unwrap();
-                    rpc_impl.call_of_bytes(
-                        incoming_bytes,
-                        &self.transport_config.wire_config,
-                        &mut state,
-                    )?
+        debug!("Server called by rpc {}", query.name);
+        match self.rpcs.get(&query.name) {
+            Some(rpc_impl) => self.with_state(query, |state| {
+                rpc_impl.call_of_bytes(&query.query_bytes, wire_config, state)
+            }),
+            None => Err(RpcError::UnknownRpc(format!("{}", query.name))),
+        }
+    }
+
+    /// Run a streaming call opened by [opening_query], sending each response as it comes and
+    /// then ending the stream. An error is sent in place of a response and ends the stream early.
+    /// The connection is read at the same time, handing any queries the client streams to the
+    /// handler, and stopping the handler if the client cancels the call or hangs up. Any other
+    /// query arriving meanwhile is answered with an error, as this connection is busy.
+    /// Returns false if the connection closed
+    async fn handle_stream_call<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+        stream_rpc: &dyn StoredStreamRpc<S, Name>,
+        opening_query: ReceivedQuery<Name>,
+    ) -> RpcResult<bool> {
+        let correlation_id = opening_query.correlation_id;
+        let rpc_name = &opening_query.name;
+        debug!("Server called by stream rpc {}", rpc_name);
+        let (request_sender, request_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let requests = Box::pin(futures_util::stream::unfold(
+            request_receiver,
+            |mut request_receiver| async move {
+                let query_bytes = request_receiver.recv().await?;
+                Some((Ok(query_bytes), request_receiver))
+            },
+        ));
+        let responses = self.with_state(&opening_query, |state| {
+            stream_rpc.stream_of_bytes(
+                &opening_query.query_bytes,
+                requests,
+                &transport.config.wire_config,
+                state,
+            )
+        });
+        let mut responses = match responses {
+            Ok(responses) => responses,
+            Err(e) => {
+                warn!("Stream rpc {} failed: {}", rpc_name, e);
+                transport.respond_error(correlation_id, &e).await?;
+                return Ok(true);
+            }
+        };
+        let mut request_sender = stream_rpc.streams_requests().then_some(request_sender);
+        let cancellation = &opening_query.cancellation;
+        loop {
+            // Receiving is cancelled whenever a response is ready first, so must be cancel safe
+            let query_bytes = tokio::select! {
+                biased;
+                _ = cancellation.cancelled() => {
+                    // Dropping the responses stops the handler's work
+                    let cancelled = RpcError::Cancelled(format!("{}", rpc_name));
+                    transport.respond_error(correlation_id, &cancelled).await?;
+                    return Ok(true);
+                }
+                response = responses.next() => {
+                    match response {
+                        Some(Ok(response_bytes)) => {
+                            transport
+                                .respond_stream_item(correlation_id, &response_bytes)
+                                .await?;
+                            continue;
+                        }
+                        Some(Err(e)) => {
+                            warn!("Stream rpc {} failed: {}", rpc_name, e);
+                            transport.respond_error(correlation_id, &e).await?;
+                        }
+                        None => transport.end_stream(correlation_id).await?,
+                    }
+                    return Ok(true);
+                }
+                query_bytes = transport.receive_query_bytes() => query_bytes,
+            };
+            let query = match query_bytes {
+                Ok(query_bytes) => transport.decode_query(query_bytes).await?,
+                Err(RpcError::TransportError(TransportError::ConnectionClosed)) => {
+                    return Ok(false)
+                }
+                Err(e) => return Err(e),
+            };
+            match query.kind {
+                PackageKind::StreamItem if query.correlation_id == correlation_id => {
+                    if let Some(request_sender) = &request_sender {
+                        // The handler may have stopped reading, which is its business
+                        let _ = request_sender.send(query.query_bytes);
+                    }
+                }
+                PackageKind::StreamEnd if query.correlation_id == correlation_id => {
+                    request_sender = None;
+                }
+                PackageKind::Cancel if query.correlation_id == correlation_id => {
+                    debug!("Stream rpc {} cancelled by the caller", rpc_name);
+                    cancellation.cancel();
+                }
+                PackageKind::Query => {
+                    let busy =
+                        RpcError::Custom(format!("Connection busy with stream rpc {}", rpc_name));
+                    transport.respond_error(query.correlation_id, &busy).await?;
+                }
+                kind => debug!(
+                    "Dropping {:?} package for finished call {}",
+                    kind, query.correlation_id
+                ),
+            }
+        }
+    }
+
+    /// Answer queries on a connection until the peer closes it, or it's idle for longer than
+    /// [ServerConfig::idle_timeout]
+    async fn handle_connection(
+        &self,
+        internal_transport: impl InternalTransport + 'static,
+    ) -> RpcResult<()> {
+        let mut transport = Transport::new(internal_transport, self.transport_config.clone());
+        if self.transport_config.handshake {
+            transport.accept_handshake().await?;
+        }
+        self.answer_queries(transport).await
+    }
+
+    /// As [handle_connection], but without the handshake, for QUIC streams which each carry
+    /// just one query.
+    /// Up to [ServerConfig::max_concurrent_queries] unary queries are handled at once, so a
+    /// client multiplexing calls over the connection, see [crate::MultiplexedClient], isn't held
+    /// up by one slow call. They're run concurrently on this task, not in parallel, so this
+    /// helps with calls waiting on async [Interceptor]s, while a handler blocking the thread
+    /// still holds up the rest. Queries received while it's at the limit wait their turn,
+    /// highest [crate::Priority] first. A stream rpc waits for the calls in flight to be
+    /// answered, then has the connection to itself. Responses split into chunks, see
+    /// [ServerConfig::response_chunk_size], are sent a chunk at a time between everything else
+    async fn answer_queries(
+        &self,
+        mut transport: Transport<impl InternalTransport + 'static, Name>,
+    ) -> RpcResult<()> {
+        let max_concurrent_queries = self.server_config.max_concurrent_queries.max(1);
+        let mut in_flight = FuturesUnordered::new();
+        let mut waiting = QueryQueue::new(
+            self.transport_config.starvation_limit,
+            &self.queued_total,
+            self.server_config.max_queued_queries,
+            self.server_config.max_queued_queries_total,
+        );
+        // A batch received while at the limit, which holds up receiving until it's started
+        let mut waiting_batch = None;
+        // Of the calls in flight or waiting, by correlation id, for the client to cancel
+        let mut cancellations: HashMap<u64, CancellationToken> = HashMap::new();
+        let mut chunked = VecDeque::new();
+        // Queries and batches received, for [ServerConfig::max_requests_per_connection]
+        let mut requests = 0;
+        let mut receiving = true;
+        while receiving || !in_flight.is_empty() || !waiting.is_empty() || !chunked.is_empty() {
+            if in_flight.len() < max_concurrent_queries {
+                if let Some(batch) = waiting_batch.take() {
+                    in_flight.push(self.start_batch(batch));
+                }
+            }
+            while in_flight.len() < max_concurrent_queries {
+                match self.next_waiting(&mut waiting, &mut cancellations) {
+                    Some((query, wire_config)) => {
+                        in_flight.push(self.start_call(query, wire_config))
+                    }
+                    None => break,
+                }
+            }
+            let freed = self.queued_total.freed();
+            let can_receive = receiving
+                && waiting_batch.is_none()
+                && match self.server_config.overload_policy {
+                    OverloadPolicy::Block => {
+                        in_flight.len() < max_concurrent_queries || waiting.has_room()
+                    }
+                    OverloadPolicy::Reject => true,
                 };
-                Ok(result_bytes)
+            // Only reading the frame races the calls in flight, as it's safe to drop part way.
+            // Anything taking its turn on the connection after, e.g. admitting the query or
+            // writing a refusal, happens once it's picked, so is never dropped half done
+            tokio::select! {
+                frame = self.receive_frame(&mut transport), if can_receive => {
+                    let received = match frame? {
+                        Some(frame) => self.handle_frame(&mut transport, frame).await?,
+                        None => Received::Closed,
+                    };
+                    // With the correlation id to turn it away with, if it's to be answered
+                    let request = match &received {
+                        Received::Query(query) if query.kind == PackageKind::Query => {
+                            Some(query.correlation_id)
+                        }
+                        Received::Batch(_) => Some(0),
+                        _ => None,
+                    };
+                    if let Some(correlation_id) = request {
+                        let max = self.server_config.max_requests_per_connection;
+                        if max.is_some_and(|max| requests >= max) {
+                            debug!(
+                                "Connection served {} queries, telling client to go away",
+                                requests
+                            );
+                            transport.go_away(correlation_id).await?;
+                            receiving = false;
+                            continue;
+                        }
+                        requests += 1;
+                    }
+                    match received {
+                        Received::Closed => receiving = false,
+                        Received::Answered => {}
+                        Received::Cancel(correlation_id) => {
+                            if let Some(cancellation) = cancellations.remove(&correlation_id) {
+                                debug!("Call {} cancelled by the caller", correlation_id);
+                                cancellation.cancel();
+                            }
+                        }
+                        Received::Reversed(key, identity) => {
+                            let waited = || self.next_waiting(&mut waiting, &mut cancellations);
+                            for (query, wire_config) in std::iter::from_fn(waited) {
+                                in_flight.push(self.start_call(query, wire_config));
+                            }
+                            self.finish_chunked(&mut transport, &mut chunked).await?;
+                            while let Some(answer) = in_flight.next().await {
+                                self.send_answer(&mut transport, answer).await?;
+                            }
+                            return self.reverse(transport, key, identity).await;
+                        }
+                        Received::Query(query) if self.stream_rpcs.contains_key(&query.name) => {
+                            let waited = || self.next_waiting(&mut waiting, &mut cancellations);
+                            for (query, wire_config) in std::iter::from_fn(waited) {
+                                in_flight.push(self.start_call(query, wire_config));
+                            }
+                            self.finish_chunked(&mut transport, &mut chunked).await?;
+                            while let Some(answer) = in_flight.next().await {
+                                self.send_answer(&mut transport, answer).await?;
+                            }
+                            cancellations.clear();
+                            receiving = self.answer_query(&mut transport, query).await?;
+                        }
+                        Received::Query(query) => {
+                            let wire_config = transport.config.wire_config.clone();
+                            cancellations.insert(query.correlation_id, query.cancellation.clone());
+                            if in_flight.len() < max_concurrent_queries && waiting.is_empty() {
+                                in_flight.push(self.start_call(query, wire_config));
+                            } else if waiting.has_room()
+                                || self.server_config.overload_policy == OverloadPolicy::Block
+                            {
+                                // Blocking, it can only be full if other connections filled
+                                // it while this one was receiving, so goes over by one
+                                waiting.push(Priority::of(&query.metadata), (query, wire_config));
+                            } else {
+                                cancellations.remove(&query.correlation_id);
+                                let e = RpcError::Overloaded(format!("{}", query.name));
+                                let bytes_in = query.query_bytes.len();
+                                let refused = Some(&e);
+                                self.record_call(&query.name, bytes_in, refused, Duration::ZERO, 0);
+                                self.respond(&mut transport, query, Err(e)).await?;
+                            }
+                        }
+                        Received::Batch(batch) => {
+                            if in_flight.len() < max_concurrent_queries {
+                                in_flight.push(self.start_batch(batch));
+                            } else if waiting.has_room()
+                                || self.server_config.overload_policy == OverloadPolicy::Block
+                            {
+                                waiting_batch = Some(batch);
+                            } else {
+                                let e = RpcError::Overloaded(String::from("batch"));
+                                transport.respond_error(0, &e).await?;
+                            }
+                        }
+                    }
+                }
+                Some(answer) = in_flight.next() => {
+                    if let Answer::Query(query, _) = &answer {
+                        cancellations.remove(&query.correlation_id);
+                    }
+                    match self.in_chunks(&transport, answer) {
+                        Ok(response) => chunked.push_back(response),
+                        Err(answer) => self.send_answer(&mut transport, answer).await?,
+                    }
+                }
+                _ = std::future::ready(()), if !chunked.is_empty() => {
+                    if let Some(response) = chunked.pop_front() {
+                        if let Some(rest) = response.send_next(&mut transport).await? {
+                            chunked.push_back(rest);
+                        }
+                    }
+                }
+                // Room made in another connection's queue, for one blocked on the total
+                _ = freed, if receiving && !can_receive => {}
             }
-            None => Err(RpcError::Custom(format!(
-                "Rpc not found: {}",
-                incoming_name
-            ))),
         }
+        Ok(())
     }
 
-    async fn handle_connection(&self, tcp_stream: tokio::net::TcpStream) -> RpcResult<()> {
-        debug!("Handling connection: {:?}", tcp_stream);
-        let mut transport = {
-            let async_trans = TcpTransport::new(tcp_stream);
-            Transport::new(async_trans, self.transport_config.clone())
+    /// [call_intercepted], to run among the calls in flight on a connection
+    fn start_call(
+        &self,
+        query: ReceivedQuery<Name>,
+        wire_config: TransportWireConfig,
+    ) -> LocalBoxFuture<'_, Answer<Name>> {
+        Box::pin(async move {
+            let (query, result) = self.call_intercepted(query, wire_config).await;
+            Answer::Query(Box::new(query), result)
+        })
+    }
+
+    /// [call_batch], to run among the calls in flight on a connection
+    fn start_batch(&self, batch: ReceivedBatch<Name>) -> LocalBoxFuture<'_, Answer<Name>> {
+        Box::pin(async move { Answer::Batch(self.call_batch(batch).await) })
+    }
+
+    /// Send what a call in flight gave back to the client
+    async fn send_answer<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+        answer: Answer<Name>,
+    ) -> RpcResult<()> {
+        match answer {
+            Answer::Query(query, result) => self.respond(transport, *query, result).await,
+            Answer::Batch(results) => {
+                let responses = results
+                    .iter()
+                    .enumerate()
+                    .map(|(index, result)| transport.config.encode_result(index as u64, result))
+                    .collect::<RpcResult<_>>()?;
+                transport.respond_batch(responses).await
+            }
+        }
+    }
+
+    /// [answer] as a [ChunkedResponse], if it's a response big enough to split, see
+    /// [ServerConfig::response_chunk_size], otherwise giving it back to send whole
+    fn in_chunks<I: InternalTransport>(
+        &self,
+        transport: &Transport<I, Name>,
+        answer: Answer<Name>,
+    ) -> Result<ChunkedResponse, Answer<Name>> {
+        let chunk_size = match self.server_config.response_chunk_size {
+            Some(chunk_size) => chunk_size.max(1),
+            None => return Err(answer),
         };
-        let received_query = transport.receive_query().await?;
-        let result_bytes = self
-            .call(&received_query.query_bytes, &received_query.name)
-            .unwrap();
-        transport.respond(&result_bytes).await
+        match answer {
+            // One too large to send at all is left to fail as it would whole
+            Answer::Query(query, Ok(bytes))
+                if query.kind == PackageKind::Query
+                    && bytes.len() > chunk_size
+                    && transport.config.check_outbound_size(bytes.len()).is_ok() =>
+            {
+                transport.config.give_back_buffer(query.query_bytes);
+                Ok(ChunkedResponse {
+                    correlation_id: query.correlation_id,
+                    bytes,
+                    sent: 0,
+                    chunk_size,
+                })
+            }
+            answer => Err(answer),
+        }
+    }
+
+    /// Send the rest of every response being sent in chunks
+    async fn finish_chunked<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+        chunked: &mut VecDeque<ChunkedResponse>,
+    ) -> RpcResult<()> {
+        while let Some(response) = chunked.pop_front() {
+            if let Some(rest) = response.send_next(transport).await? {
+                chunked.push_back(rest);
+            }
+        }
+        Ok(())
+    }
+
+    /// The next query waiting its turn that's still wanted, dropping those whose deadline
+    /// passed while they waited
+    fn next_waiting(
+        &self,
+        waiting: &mut QueryQueue<'_, (ReceivedQuery<Name>, TransportWireConfig)>,
+        cancellations: &mut HashMap<u64, CancellationToken>,
+    ) -> Option<(ReceivedQuery<Name>, TransportWireConfig)> {
+        loop {
+            let (query, wire_config) = waiting.pop()?;
+            if !self.past_deadline(&query) {
+                return Some((query, wire_config));
+            }
+            cancellations.remove(&query.correlation_id);
+        }
+    }
+
+    /// Hand [transport] over to the [ReverseConnections] given to [accept_reversed]
+    async fn reverse(
+        &self,
+        mut transport: Transport<impl InternalTransport + 'static, Name>,
+        key: String,
+        identity: Option<Identity>,
+    ) -> RpcResult<()> {
+        transport.accept_reverse().await?;
+        if let Some(accept_reversed) = &self.accept_reversed {
+            let internal_transport = Box::new(transport.into_internal_transport());
+            accept_reversed.add(key, identity.as_ref(), internal_transport);
+        }
+        Ok(())
+    }
+
+    /// Receive and answer one query, returning false if the connection closed, went idle or
+    /// the server shut down rather than it sending one
+    async fn handle_next_query<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+    ) -> RpcResult<bool> {
+        match self.receive_next_query(transport).await? {
+            Received::Closed => Ok(false),
+            // One query at a time, so the call's already over
+            Received::Answered | Received::Cancel(_) => Ok(true),
+            Received::Query(query) => self.answer_query(transport, query).await,
+            Received::Batch(batch) => {
+                let results = self.call_batch(batch).await;
+                self.send_answer(transport, Answer::Batch(results)).await?;
+                Ok(true)
+            }
+            Received::Reversed(_, _) => {
+                let error = RpcError::Custom(String::from("Connection can't be reversed"));
+                transport.respond_error(0, &error).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Receive the next query to answer, checking it's still wanted and running it past the
+    /// [Interceptor]s. Queries refused by them are answered here
+    async fn receive_next_query<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+    ) -> RpcResult<Received<Name>> {
+        match self.receive_frame(transport).await? {
+            Some(frame) => self.handle_frame(transport, frame).await,
+            None => Ok(Received::Closed),
+        }
+    }
+
+    /// Read the next frame off [transport], or None if the connection closed, went idle or
+    /// the server shut down first. Safe to drop part way, as reading the frame is
+    async fn receive_frame<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+    ) -> RpcResult<Option<OwnedBytes>> {
+        let receive_query = async {
+            match self.server_config.idle_timeout {
+                Some(idle_timeout) => {
+                    tokio::time::timeout(idle_timeout, transport.receive_query_bytes())
+                        .await
+                        .ok()
+                }
+                None => Some(transport.receive_query_bytes().await),
+            }
+        };
+        // A query part way received when shutting down is lost with the connection, as the
+        // client couldn't know it would be answered
+        let received_query = tokio::select! {
+            biased;
+            _ = self.shutdown.cancelled() => {
+                debug!("Closing connection as the server is shutting down");
+                return Ok(None);
+            }
+            received_query = receive_query => match received_query {
+                Some(received_query) => received_query,
+                None => {
+                    debug!("Closing idle connection");
+                    return Ok(None);
+                }
+            },
+        };
+        match received_query {
+            Ok(query_bytes) => Ok(Some(query_bytes)),
+            Err(RpcError::TransportError(TransportError::ConnectionClosed)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Make what's to be done of [query_bytes], checking a query's still wanted and running
+    /// it past the [Interceptor]s. Queries refused by them, and requests answered by the
+    /// server itself, e.g. pings, are answered here
+    async fn handle_frame<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+        query_bytes: OwnedBytes,
+    ) -> RpcResult<Received<Name>> {
+        if query_bytes == PING {
+            transport.pong().await?;
+            return Ok(Received::Answered);
+        }
+        if let Some(metadata_bytes) = query_bytes.strip_prefix(HEALTH_CHECK) {
+            let kind = ServerRequestKind::HealthCheck;
+            if let Err(e) = self
+                .admit_fixed_request(transport, kind, metadata_bytes)
+                .await
+            {
+                warn!("Refused health check: {}", e);
+                transport.respond_error(0, &e).await?;
+                return Ok(Received::Answered);
+            }
+            let report_bytes = transport
+                .config
+                .wire_config
+                .serialize(&self.health_report())
+                .map_err(|e| e.in_step("health report"))?;
+            transport.respond(0, &report_bytes).await?;
+            return Ok(Received::Answered);
+        }
+        if let Some(metadata_bytes) = query_bytes.strip_prefix(REFLECT) {
+            if !self.server_config.reflection {
+                let error = RpcError::UnknownRpc(String::from("reflection, which is off"));
+                transport.respond_error(0, &error).await?;
+                return Ok(Received::Answered);
+            }
+            let kind = ServerRequestKind::Reflect;
+            if let Err(e) = self
+                .admit_fixed_request(transport, kind, metadata_bytes)
+                .await
+            {
+                warn!("Refused reflection: {}", e);
+                transport.respond_error(0, &e).await?;
+                return Ok(Received::Answered);
+            }
+            let description_bytes = transport
+                .config
+                .wire_config
+                .serialize(&self.describe())
+                .map_err(|e| e.in_step("server description"))?;
+            transport.respond(0, &description_bytes).await?;
+            return Ok(Received::Answered);
+        }
+        if let Some(request) = query_bytes.strip_prefix(REVERSE) {
+            let Some(accept_reversed) = &self.accept_reversed else {
+                let error = RpcError::UnknownRpc(String::from("reversed connections, not taken"));
+                transport.respond_error(0, &error).await?;
+                return Ok(Received::Answered);
+            };
+            let reversed = decode_reverse_request(request)
+                .map_err(RpcError::TransportError)
+                .map(|(key, metadata)| {
+                    transport.server_request(ServerRequestKind::Reverse(key), metadata)
+                });
+            let reversed = match reversed {
+                Ok(request) => self.admit_server_request(request).await,
+                Err(e) => Err(e),
+            }
+            .and_then(|request| match request.kind {
+                ServerRequestKind::Reverse(key) => {
+                    accept_reversed.check(&key, request.identity.as_ref())?;
+                    Ok(Received::Reversed(key, request.identity))
+                }
+                kind => Err(RpcError::Custom(format!(
+                    "Expected a reverse, not {:?}",
+                    kind
+                ))),
+            });
+            if let Err(e) = &reversed {
+                warn!("Refused to reverse connection: {}", e);
+                transport.respond_error(0, e).await?;
+                return Ok(Received::Answered);
+            }
+            return reversed;
+        }
+        if query_bytes.starts_with(BATCH) {
+            return match self.receive_batch(transport, query_bytes).await {
+                Ok(batch) => Ok(Received::Batch(batch)),
+                Err(e) => {
+                    warn!("Batch failed: {}", e);
+                    transport.respond_error(0, &e).await?;
+                    Ok(Received::Answered)
+                }
+            };
+        }
+        let received_query = transport.decode_query(query_bytes).await?;
+        if received_query.kind == PackageKind::Cancel {
+            return Ok(Received::Cancel(received_query.correlation_id));
+        }
+        match self.admit_query(received_query).await {
+            Admitted::Query(received_query) => Ok(Received::Query(received_query)),
+            Admitted::Refused(received_query, e) => {
+                if received_query.kind == PackageKind::Query {
+                    transport
+                        .respond_error(received_query.correlation_id, &e)
+                        .await?;
+                }
+                Ok(Received::Answered)
+            }
+            Admitted::Dropped(_) => Ok(Received::Answered),
+        }
+    }
+
+    /// Run the [ServerRequest] of [kind] past the [Interceptor]s, with the [crate::Metadata]
+    /// sent after its fixed bytes, [metadata_bytes], failing with the error of the first to
+    /// refuse it
+    async fn admit_fixed_request<I: InternalTransport>(
+        &self,
+        transport: &Transport<I, Name>,
+        kind: ServerRequestKind,
+        metadata_bytes: &[u8],
+    ) -> RpcResult<ServerRequest> {
+        let metadata = decode_request_metadata(metadata_bytes)?;
+        self.admit_server_request(transport.server_request(kind, metadata))
+            .await
+    }
+
+    /// Run [request] past the [Interceptor]s, failing with the error of the first to refuse it
+    async fn admit_server_request(&self, mut request: ServerRequest) -> RpcResult<ServerRequest> {
+        for interceptor in &self.interceptors {
+            interceptor.on_server_request(&mut request).await?;
+        }
+        Ok(request)
+    }
+
+    /// Check [received_query] is still wanted and run it past the [Interceptor]s
+    async fn admit_query(&self, mut received_query: ReceivedQuery<Name>) -> Admitted<Name> {
+        if !matches!(
+            received_query.kind,
+            PackageKind::Query | PackageKind::Notification
+        ) {
+            // The rest of a client stream the handler finished without reading to the end, or a
+            // cancel that arrived after the call finished anyway
+            debug!(
+                "Dropping {:?} package for finished call {}",
+                received_query.kind, received_query.correlation_id
+            );
+            return Admitted::Dropped(format!("{:?} package", received_query.kind));
+        }
+        if received_query.kind == PackageKind::Notification
+            && self.stream_rpcs.contains_key(&received_query.name)
+        {
+            warn!(
+                "Dropping notification for stream rpc {}",
+                received_query.name
+            );
+            return Admitted::Dropped(String::from("notification for stream rpc"));
+        }
+        if self.past_deadline(&received_query) {
+            return Admitted::Dropped(String::from("query past its deadline"));
+        }
+        for interceptor in &self.interceptors {
+            if let Err(e) = interceptor.on_query(&mut received_query).await {
+                warn!("Rpc {} refused: {}", received_query.name, e);
+                let bytes_in = received_query.query_bytes.len();
+                let name = &received_query.name;
+                self.record_call(name, bytes_in, Some(&e), Duration::ZERO, 0);
+                for interceptor in &self.interceptors {
+                    interceptor.on_refused(&received_query, &e);
+                }
+                return Admitted::Refused(received_query, e);
+            }
+        }
+        // Checked again as the interceptors may have taken a while
+        if self.past_deadline(&received_query) {
+            return Admitted::Dropped(String::from("query past its deadline"));
+        }
+        Admitted::Query(received_query)
+    }
+
+    /// Whether the caller's given up on [query], in which case it's skipped: an answer now
+    /// would be taken as the response to its next query on this connection
+    fn past_deadline(&self, query: &ReceivedQuery<Name>) -> bool {
+        let now = self.transport_config.clock.now();
+        match query.deadline {
+            Some(deadline) if now >= deadline => {
+                warn!(
+                    "Skipping rpc {} {:?} past its deadline",
+                    query.name,
+                    now.duration_since(deadline)
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Decode and admit each query of a [crate::Batch], each failing by itself as if sent by
+    /// itself, to then call those admitted with [call_batch]
+    async fn receive_batch<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+        batch_bytes: OwnedBytes,
+    ) -> RpcResult<ReceivedBatch<Name>> {
+        let batch = transport.decode_batch(batch_bytes).await?;
+        let mut results = Vec::with_capacity(batch.packages.len());
+        let mut calls = Vec::new();
+        for (index, package_bytes) in batch.packages.into_iter().enumerate() {
+            let result = match transport.decode_query(package_bytes).await {
+                Ok(query) if self.stream_rpcs.contains_key(&query.name) => Err(RpcError::Custom(
+                    format!("Stream rpc {} can't be batched", query.name),
+                )),
+                Ok(query) if query.kind != PackageKind::Query => Err(RpcError::Custom(format!(
+                    "Only queries can be batched, not {:?}",
+                    query.kind
+                ))),
+                Ok(query) => match self.admit_query(query).await {
+                    Admitted::Query(query) => {
+                        calls.push((index, query));
+                        Ok(OwnedBytes::new())
+                    }
+                    Admitted::Refused(_, e) => Err(e),
+                    Admitted::Dropped(reason) => {
+                        Err(RpcError::Custom(format!("Dropped {}", reason)))
+                    }
+                },
+                Err(e) => Err(e),
+            };
+            results.push(result);
+        }
+        Ok(ReceivedBatch {
+            results,
+            calls,
+            concurrent: batch.concurrent,
+            wire_config: transport.config.wire_config.clone(),
+        })
+    }
+
+    /// Call the queries admitted from a batch, giving a result for every query in it, in
+    /// order. A concurrent batch has up to [ServerConfig::max_concurrent_queries] of its
+    /// calls in flight at once
+    async fn call_batch(&self, batch: ReceivedBatch<Name>) -> Vec<RpcResult<OwnedBytes>> {
+        let ReceivedBatch {
+            mut results,
+            calls,
+            concurrent,
+            wire_config,
+        } = batch;
+        let at_once = match concurrent {
+            true => self.server_config.max_concurrent_queries.max(1),
+            false => 1,
+        };
+        let wire_config = &wire_config;
+        let answered: Vec<_> = futures_util::stream::iter(calls)
+            .map(|(index, query)| async move {
+                (
+                    index,
+                    self.call_intercepted(query, wire_config.clone()).await.1,
+                )
+            })
+            .buffer_unordered(at_once)
+            .collect()
+            .await;
+        for (index, result) in answered {
+            results[index] = result;
+        }
+        results
+    }
+
+    /// Answer [query], returning false if the connection closed during a stream rpc
+    async fn answer_query<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+        query: ReceivedQuery<Name>,
+    ) -> RpcResult<bool> {
+        if let Some(stream_rpc) = self.stream_rpcs.get(&query.name) {
+            let span = CallSpan::server(&query);
+            let started = self.server_config.clock.now();
+            let rpc_name = query.name.clone();
+            let bytes_in = query.query_bytes.len();
+            let handled = span
+                .instrument(self.handle_stream_call(transport, stream_rpc.as_ref(), query))
+                .await;
+            let latency = self.server_config.clock.now().duration_since(started);
+            span.record_latency(latency);
+            self.record_call(&rpc_name, bytes_in, handled.as_ref().err(), latency, 0);
+            return handled;
+        }
+        let wire_config = transport.config.wire_config.clone();
+        let (query, result) = self.call_intercepted(query, wire_config).await;
+        self.respond(transport, query, result).await?;
+        Ok(true)
+    }
+
+    /// Call the unary rpc [query] is for through the [Interceptor]s
+    async fn call_intercepted(
+        &self,
+        query: ReceivedQuery<Name>,
+        wire_config: TransportWireConfig,
+    ) -> (ReceivedQuery<Name>, RpcResult<OwnedBytes>) {
+        let handler = |query: &ReceivedQuery<Name>| -> LocalBoxFuture<'_, _> {
+            match self.async_rpcs.get(&query.name) {
+                Some(async_rpc) => self.call_async(async_rpc.as_ref(), query, &wire_config),
+                None => Box::pin(std::future::ready(self.call(query, &wire_config))),
+            }
+        };
+        let span = CallSpan::server(&query);
+        let started = self.server_config.clock.now();
+        let run = Next::new(&self.interceptors, &handler).run(&query);
+        // Dropping the call stops an async handler at its next await
+        let result = span
+            .instrument(async {
+                tokio::select! {
+                    biased;
+                    _ = query.cancellation.cancelled() => {
+                        Err(RpcError::Cancelled(format!("{}", query.name)))
+                    }
+                    result = run => result,
+                }
+            })
+            .await;
+        span.record_result(result.as_ref().map(Vec::len));
+        let latency = self.server_config.clock.now().duration_since(started);
+        span.record_latency(latency);
+        let bytes_out = result.as_ref().map_or(0, Vec::len);
+        let bytes_in = query.query_bytes.len();
+        self.record_call(
+            &query.name,
+            bytes_in,
+            result.as_ref().err(),
+            latency,
+            bytes_out,
+        );
+        (query, result)
+    }
+
+    async fn respond<I: InternalTransport>(
+        &self,
+        transport: &mut Transport<I, Name>,
+        query: ReceivedQuery<Name>,
+        result: RpcResult<OwnedBytes>,
+    ) -> RpcResult<()> {
+        // Handled, so the query's buffer can take whatever's received next
+        transport.config.give_back_buffer(query.query_bytes);
+        if query.kind == PackageKind::Notification {
+            if let Err(e) = result {
+                warn!("Notification {} failed: {}", query.name, e);
+            }
+            return Ok(());
+        }
+        match result {
+            Ok(result_bytes) => {
+                let sent = match transport.respond(query.correlation_id, &result_bytes).await {
+                    // Nothing was sent, so the client can still be told why
+                    Err(e @ RpcError::TransportError(TransportError::MessageTooLarge(_, _))) => {
+                        warn!("Rpc {} response not sent: {}", query.name, e);
+                        transport.respond_error(query.correlation_id, &e).await
+                    }
+                    sent => sent,
+                };
+                transport.config.give_back_buffer(result_bytes);
+                sent
+            }
+            Err(e) => {
+                warn!("Rpc {} failed: {}", query.name, e);
+                transport.respond_error(query.correlation_id, &e).await
+            }
+        }
+    }
+
+    /// Handle each connection from [accepted] with [handle], concurrently on this task, until
+    /// [accepted] ends or the server is shut down, and the connections still open are done.
+    /// Every connection shares this server's rpcs and state, up to
+    /// [ServerConfig::max_connections] at once.
+    /// After a shutdown, those still open after [ServerConfig::drain_timeout] are dropped
+    async fn serve_connections<C, F>(
+        &self,
+        accepted: impl Stream<Item = C>,
+        handle: impl Fn(C) -> F,
+    ) where
+        F: Future<Output = RpcResult<()>>,
+    {
+        futures_util::pin_mut!(accepted);
+        let drained = async {
+            self.shutdown.cancelled().await;
+            tokio::time::sleep(self.server_config.drain_timeout).await;
+        };
+        futures_util::pin_mut!(drained);
+        let mut connections = FuturesUnordered::new();
+        let mut accepting = true;
+        while accepting || !connections.is_empty() {
+            tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled(), if accepting => {
+                    info!("Shutting down, draining {} connections", connections.len());
+                    accepting = false;
+                }
+                _ = &mut drained => {
+                    warn!(
+                        "Dropping {} connections still busy after {:?}",
+                        connections.len(),
+                        self.server_config.drain_timeout
+                    );
+                    return;
+                }
+                next = accepted.next(), if accepting && self.can_accept(connections.len()) => {
+                    match next {
+                        Some(connection) => connections.push(handle(connection)),
+                        None => accepting = false,
+                    }
+                }
+                Some(result) = connections.next(), if !connections.is_empty() => {
+                    if let Err(e) = result {
+                        warn!("Error handling connection: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether another connection can be served alongside [num_connections], per
+    /// [ServerConfig::max_connections]
+    fn can_accept(&self, num_connections: usize) -> bool {
+        match self.server_config.max_connections {
+            Some(max_connections) => num_connections < max_connections.max(1),
+            None => true,
+        }
+    }
+
+    /// Serve each connection [listener] accepts, until it ends or the server is shut down, see
+    /// [serve_connections]. Connections that fail to be accepted, e.g. a failed TLS handshake,
+    /// are logged and dropped
+    pub async fn serve_listener<L: Listener>(&self, listener: L) {
+        let accepted = futures_util::stream::unfold(listener, |mut listener| async move {
+            let accepting = listener.accept().await?;
+            Some((accepting, listener))
+        });
+        self.serve_connections(accepted, |accepting| async move {
+            let internal_transport = accepting?.await?;
+            self.handle_connection(internal_transport).await
+        })
+        .await
     }
 
     pub async fn serve(&self, listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display) {
         info!("Starting server on {}", listen_on);
-        let listener = tokio::net::TcpListener::bind(listen_on).await.unwrap();
+        let listener = TcpListener::bind(listen_on).await.unwrap();
+        self.serve_listener(listener).await
+    }
+
+    /// As [serve], but completing a TLS handshake per [tls_config] on each connection first.
+    /// A failed handshake is logged and the connection dropped
+    #[cfg(feature = "transport_tls")]
+    pub async fn serve_tls(
+        &self,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+        tls_config: &TlsServerConfig,
+    ) {
+        info!("Starting TLS server on {}", listen_on);
+        let listener = TlsListener::bind(listen_on, tls_config.clone())
+            .await
+            .unwrap();
+        self.serve_listener(listener).await
+    }
+
+    /// As [serve], but accepting QUIC connections secured per [tls_config]. Each stream a
+    /// client opens on a connection carries one query, see [crate::QuicConnection]
+    #[cfg(feature = "transport_quic")]
+    pub async fn serve_quic(&self, listen_on: std::net::SocketAddr, tls_config: &TlsServerConfig) {
+        info!("Starting QUIC server on {}", listen_on);
+        let endpoint = quic::server_endpoint(listen_on, tls_config).unwrap();
+        // Connections are accepted on their own tasks, feeding their streams back here
+        let (stream_sender, streams) = tokio::sync::mpsc::unbounded_channel();
+        let accepted = futures_util::stream::unfold(
+            (endpoint, stream_sender, streams),
+            |(endpoint, stream_sender, mut streams)| async move {
+                loop {
+                    tokio::select! {
+                        incoming = endpoint.accept() => {
+                            let incoming = match incoming {
+                                Some(incoming) => incoming,
+                                None => {
+                                    error!("QUIC endpoint closed");
+                                    return None;
+                                }
+                            };
+                            let stream_sender = stream_sender.clone();
+                            tokio::spawn(async move {
+                                match incoming.await {
+                                    Ok(connection) => {
+                                        quic::forward_streams(connection, stream_sender).await
+                                    }
+                                    Err(e) => warn!("Error accepting QUIC connection: {}", e),
+                                }
+                            });
+                        }
+                        Some(stream) = streams.recv() => {
+                            return Some((stream, (endpoint, stream_sender, streams)));
+                        }
+                    }
+                }
+            },
+        );
+        self.serve_connections(accepted, |stream| {
+            self.answer_queries(Transport::new(stream, self.transport_config.clone()))
+        })
+        .await
+    }
+
+    /// As [serve], but completing a WebSocket handshake on each connection first, so browsers
+    /// and other WS clients can call rpcs
+    #[cfg(feature = "transport_websocket")]
+    pub async fn serve_websocket(
+        &self,
+        listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display,
+    ) {
+        info!("Starting WebSocket server on {}", listen_on);
+        let listener = WebSocketListener::bind(listen_on).await.unwrap();
+        self.serve_listener(listener.with_config(&self.transport_config))
+            .await
+    }
+
+    /// As [serve], but receiving each query as a UDP datagram and replying with one, see
+    /// [UdpTransport]. A response over the default maximum datagram size can't be sent back,
+    /// so is logged and dropped
+    pub async fn serve_udp(&self, listen_on: impl tokio::net::ToSocketAddrs + std::fmt::Display) {
+        info!("Starting UDP server on {}", listen_on);
+        let socket = Arc::new(tokio::net::UdpSocket::bind(listen_on).await.unwrap());
+        let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
         loop {
-            match listener.accept().await {
-                Ok((tcp_stream, _from)) => {
-                    let connection_result = self.handle_connection(tcp_stream).await;
-                    if let Err(e) = connection_result {
-                        warn!("Error handling connection: {}", e);
+            let received = tokio::select! {
+                _ = self.shutdown.cancelled() => return,
+                received = socket.recv_from(&mut buf) => received,
+            };
+            match received {
+                Ok((n, from)) => {
+                    debug!("Handling datagram from {}", from);
+                    let udp_transport =
+                        UdpTransport::for_peer(socket.clone(), from, buf[..n].to_vec());
+                    let mut transport =
+                        Transport::new(udp_transport, self.transport_config.clone());
+                    // There's no connection to keep reading from, just the one datagram
+                    if let Err(e) = self.handle_next_query(&mut transport).await {
+                        warn!("Error handling datagram: {}", e);
                     }
                 }
-                Err(e) => error!("TCP Listener error: {}", e),
+                Err(e) => error!("UDP socket error: {}", e),
             }
         }
     }
+
+    /// As [serve], but accepting in-process connections from [listener]'s
+    /// [crate::ChannelConnector]s. Returns once they've all been dropped and their
+    /// connections closed
+    pub async fn serve_channel(&self, listener: ChannelListener) {
+        self.serve_listener(listener).await
+    }
+
+    /// As [serve], but listening on a unix domain socket at [path].
+    /// Binding fails if a file already exists at [path], e.g. left over from a previous run
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, path: impl AsRef<std::path::Path>) {
+        info!("Starting server on {}", path.as_ref().display());
+        let listener = UnixListener::bind(path).unwrap();
+        self.serve_listener(listener).await
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("<non-string panic payload>")
+    }
 }
@@ -0,0 +1,259 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::ThreadId;
+
+use crate::call_context::read_call_context;
+
+/// One kept value, locked apart from the others so [Session::with] on it holds up nothing else
+struct Slot {
+    value: Mutex<Box<dyn Any + Send>>,
+    /// The thread holding [value], to catch it asking for the value again rather than deadlock
+    held_by: Mutex<Option<ThreadId>>,
+}
+
+impl Slot {
+    fn new(value: Box<dyn Any + Send>) -> Arc<Self> {
+        Arc::new(Self {
+            value: Mutex::new(value),
+            held_by: Mutex::new(None),
+        })
+    }
+
+    fn held_here(&self) -> bool {
+        *self.held_by.lock().unwrap() == Some(std::thread::current().id())
+    }
+
+    fn lock(&self) -> SlotGuard<'_> {
+        if self.held_here() {
+            panic!("Session value used again from within Session::with on it");
+        }
+        let thread = std::thread::current().id();
+        // A handler panicking in [Session::with] fails only its own call, so the value stays usable
+        let value = self.value.lock().unwrap_or_else(PoisonError::into_inner);
+        *self.held_by.lock().unwrap() = Some(thread);
+        SlotGuard { slot: self, value }
+    }
+
+    /// The value, once it's no longer kept, unless it's replaced or removed from within
+    /// [Session::with] on it, leaving it to be dropped once that's done
+    fn take<T: 'static>(self: Arc<Self>) -> Option<T> {
+        let value = match Arc::try_unwrap(self) {
+            Ok(slot) => slot
+                .value
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner),
+            Err(slot) if slot.held_here() => return None,
+            Err(slot) => std::mem::replace(&mut *slot.lock(), Box::new(())),
+        };
+        value.downcast().ok().map(|value| *value)
+    }
+}
+
+struct SlotGuard<'a> {
+    slot: &'a Slot,
+    value: MutexGuard<'a, Box<dyn Any + Send>>,
+}
+
+impl Deref for SlotGuard<'_> {
+    type Target = Box<dyn Any + Send>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl DerefMut for SlotGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        *self.slot.held_by.lock().unwrap() = None;
+    }
+}
+
+type Values = HashMap<TypeId, Arc<Slot>>;
+
+/// Values of any type kept for one connection, one per type, that handlers read and write
+/// through [call_session]. Each call on a connection sees what earlier calls on it left, so a
+/// client can log in once and then make calls as that user, or have a cache of its own, while
+/// calls on other connections see their own. It's dropped along with the connection.
+/// Clones share the values, and calls on a connection can run at once, see
+/// [crate::ServerConfig::max_concurrent_queries], so take care over values read then written
+/// by calls that might overlap, e.g. with [with]. Each value is locked on its own, only while
+/// it's being read or written
+#[derive(Clone, Default)]
+pub struct Session {
+    values: Arc<Mutex<Values>>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep [value], giving back any already kept of its type
+    pub fn insert<T: Send + 'static>(&self, value: T) -> Option<T> {
+        let previous = self
+            .values
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Slot::new(Box::new(value)))?;
+        previous.take()
+    }
+
+    /// A copy of the value of type [T], if one's kept
+    pub fn get<T: Clone + Send + 'static>(&self) -> Option<T> {
+        self.with(|value: &mut T| value.clone())
+    }
+
+    /// Take out the value of type [T], if one's kept
+    pub fn remove<T: Send + 'static>(&self) -> Option<T> {
+        let slot = self.values.lock().unwrap().remove(&TypeId::of::<T>())?;
+        slot.take()
+    }
+
+    /// Run [f] on the value of type [T], if one's kept, with no other call able to get at it
+    /// meanwhile. [f] can use the session's other values, but panics if it uses this one
+    /// again, other than to insert or remove it, which would otherwise deadlock. A value of type
+    /// [T] inserted meanwhile replaces this one once [f]'s done with it
+    pub fn with<T: Send + 'static, R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let slot = self.values.lock().unwrap().get(&TypeId::of::<T>())?.clone();
+        let mut value = slot.lock();
+        Some(f(value.downcast_mut()?))
+    }
+
+    pub fn contains<T: Send + 'static>(&self) -> bool {
+        self.values.lock().unwrap().contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Session({} values)", self.values.lock().unwrap().len())
+    }
+}
+
+/// The [Session] of the connection the rpc being handled was called over, from the
+/// [crate::CallContext::session] of [crate::call_context]
+pub fn call_session() -> Option<Session> {
+    read_call_context(|context| Some(context.session.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::error::RpcResult;
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, ChannelConnector, Transport, TransportConfig};
+
+    #[derive(Clone)]
+    struct Login(String);
+
+    fn login_rpc() -> Rpc<HelloWorldRpcName, String, ()> {
+        Rpc::new(HelloWorldRpcName::HelloWorld)
+    }
+
+    fn whoami_rpc() -> Rpc<HelloWorldRpcName, (), Option<String>> {
+        Rpc::new(HelloWorldRpcName::GetI)
+    }
+
+    /// Who the server thinks is calling, before and after logging in as [user] if given
+    async fn whoami_around_login(
+        connector: &ChannelConnector,
+        user: Option<&str>,
+    ) -> (RpcResult<Option<String>>, RpcResult<Option<String>>) {
+        let mut transport = Transport::new(
+            connector.connect().await.unwrap(),
+            TransportConfig::default(),
+        );
+        let before = RpcClient::new(whoami_rpc()).call((), &mut transport).await;
+        if let Some(user) = user {
+            RpcClient::new(login_rpc())
+                .call(user.into(), &mut transport)
+                .await
+                .unwrap();
+        }
+        let after = RpcClient::new(whoami_rpc()).call((), &mut transport).await;
+        (before, after)
+    }
+
+    #[tokio::test]
+    async fn session_kept_per_connection() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, user: String| {
+                call_session().unwrap().insert(Login(user));
+                Ok(())
+            }),
+        )));
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::GetI,
+            Box::new(|_state, ()| Ok(call_session().unwrap().get().map(|Login(user)| user))),
+        )));
+        let (connector, listener) = channel_listener(2);
+        let client_calls = async move {
+            let jack = whoami_around_login(&connector, Some("jack")).await;
+            let other = whoami_around_login(&connector, None).await;
+            (jack, other)
+        };
+
+        let ((), (jack, other)) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(None, jack.0.unwrap());
+        assert_eq!(Some(String::from("jack")), jack.1.unwrap());
+        // A new connection starts with a session of its own
+        assert_eq!(None, other.0.unwrap());
+        assert_eq!(None, other.1.unwrap());
+        assert!(call_session().is_none());
+    }
+
+    #[test]
+    fn values_kept_by_type() {
+        let session = Session::new();
+        assert_eq!(None, session.insert(3_u32));
+        assert_eq!(Some(3), session.insert(4_u32));
+        session.insert(String::from("rum"));
+        assert_eq!(
+            Some(5),
+            session.with(|i: &mut u32| {
+                *i += 1;
+                *i
+            })
+        );
+        assert_eq!(Some(String::from("rum")), session.clone().get());
+        assert_eq!(Some(5_u32), session.remove());
+        assert!(!session.contains::<u32>());
+        assert!(session.contains::<String>());
+    }
+
+    #[test]
+    fn with_reentered_without_deadlock() {
+        let session = Session::new();
+        session.insert(3_u32);
+        session.insert(String::from("rum"));
+        let inner = session.with(|i: &mut u32| {
+            *i += 1;
+            // Another value, or inserting this one's replacement, are fine
+            let drink = session.get::<String>();
+            session.insert(10_u32);
+            drink
+        });
+        assert_eq!(Some(Some(String::from("rum"))), inner);
+        assert_eq!(Some(10_u32), session.get());
+        // The same value again panics rather than deadlocking
+        let reentered = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            session.with(|_: &mut u32| session.get::<u32>())
+        }));
+        assert!(reentered.is_err());
+        assert_eq!(Some(10_u32), session.get());
+    }
+}
@@ -0,0 +1,513 @@
+use crate::listener::{Accepting, Listener, TcpListener};
+use crate::transport::{
+    connect_tcp, within_connect_timeout, FrameReader, FrameWriter, InternalTransport,
+    TransportConfig, TransportError,
+};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::CryptoProvider;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{DigitallySignedStruct, SignatureScheme};
+
+fn tls_error(context: &str, e: impl std::fmt::Display) -> TransportError {
+    TransportError::ConnectError(format!("TLS {}: {}", context, e))
+}
+
+fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn root_store(root_certificates: Vec<OwnedBytes>) -> Result<rustls::RootCertStore, TransportError> {
+    let mut roots = rustls::RootCertStore::empty();
+    for der in root_certificates {
+        roots
+            .add(CertificateDer::from(der))
+            .map_err(|e| tls_error("root certificate", e))?;
+    }
+    Ok(roots)
+}
+
+/// Client side TLS settings for [TlsTcpTransport::connect], made with [TlsClientConfig::builder]
+#[derive(Clone)]
+pub struct TlsClientConfig {
+    pub(crate) config: Arc<rustls::ClientConfig>,
+}
+
+impl TlsClientConfig {
+    pub fn builder() -> TlsClientConfigBuilder {
+        TlsClientConfigBuilder::default()
+    }
+}
+
+/// Builds a [TlsClientConfig]. The server's certificate must chain to one of the
+/// [add_root_certificate] roots, unless [danger_accept_invalid_certs] is set
+#[derive(Default)]
+pub struct TlsClientConfigBuilder {
+    root_certificates: Vec<OwnedBytes>,
+    accept_invalid_certs: bool,
+    client_certificate: Option<(Vec<OwnedBytes>, OwnedBytes)>,
+}
+
+impl TlsClientConfigBuilder {
+    /// Trust server certificates issued by this DER encoded CA (or self-signed) certificate
+    pub fn add_root_certificate(mut self, der: OwnedBytes) -> Self {
+        self.root_certificates.push(der);
+        self
+    }
+
+    /// Skip validating the server's certificate entirely. The connection is still encrypted
+    /// but anyone can impersonate the server, so only use this for testing
+    pub fn danger_accept_invalid_certs(mut self, accept_invalid_certs: bool) -> Self {
+        self.accept_invalid_certs = accept_invalid_certs;
+        self
+    }
+
+    /// Show servers that ask for one this DER encoded certificate chain, leaf first, with the
+    /// matching DER encoded private key, see [TlsServerConfigBuilder::add_client_root_certificate]
+    pub fn client_certificate(
+        mut self,
+        certificate_chain: Vec<OwnedBytes>,
+        private_key: OwnedBytes,
+    ) -> Self {
+        self.client_certificate = Some((certificate_chain, private_key));
+        self
+    }
+
+    pub fn build(self) -> Result<TlsClientConfig, TransportError> {
+        let provider = crypto_provider();
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| tls_error("client config", e))?;
+        let builder = if self.accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyServerCert(provider)))
+        } else {
+            builder.with_root_certificates(root_store(self.root_certificates)?)
+        };
+        let config = match self.client_certificate {
+            Some((certificate_chain, private_key)) => builder
+                .with_client_auth_cert(
+                    certificate_chain
+                        .into_iter()
+                        .map(CertificateDer::from)
+                        .collect(),
+                    PrivateKeyDer::try_from(private_key)
+                        .map_err(|e| tls_error("client private key", e))?,
+                )
+                .map_err(|e| tls_error("client certificate", e))?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(TlsClientConfig {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// Server side TLS settings for [TlsTcpTransport::accept] and
+/// [crate::RpcServer::serve_tls], made with [TlsServerConfig::builder]
+#[derive(Clone)]
+pub struct TlsServerConfig {
+    pub(crate) config: Arc<rustls::ServerConfig>,
+}
+
+impl TlsServerConfig {
+    pub fn builder() -> TlsServerConfigBuilder {
+        TlsServerConfigBuilder::default()
+    }
+}
+
+/// Builds a [TlsServerConfig] from the server's DER encoded certificate chain, leaf first,
+/// and the matching DER encoded private key (PKCS#8, PKCS#1 or SEC1). Clients aren't asked for
+/// a certificate unless there are [add_client_root_certificate] roots
+#[derive(Default)]
+pub struct TlsServerConfigBuilder {
+    certificate_chain: Vec<OwnedBytes>,
+    private_key: Option<OwnedBytes>,
+    client_root_certificates: Vec<OwnedBytes>,
+}
+
+impl TlsServerConfigBuilder {
+    pub fn certificate_chain(mut self, certificate_chain: Vec<OwnedBytes>) -> Self {
+        self.certificate_chain = certificate_chain;
+        self
+    }
+
+    pub fn private_key(mut self, der: OwnedBytes) -> Self {
+        self.private_key = Some(der);
+        self
+    }
+
+    /// Require clients to show a certificate issued by this DER encoded CA (or self-signed)
+    /// certificate, see [TlsClientConfigBuilder::client_certificate]. Handlers can then see
+    /// which one in [crate::CallContext::peer_certificate]
+    pub fn add_client_root_certificate(mut self, der: OwnedBytes) -> Self {
+        self.client_root_certificates.push(der);
+        self
+    }
+
+    pub fn build(self) -> Result<TlsServerConfig, TransportError> {
+        let private_key = self
+            .private_key
+            .ok_or_else(|| tls_error("server config", "no private key given"))?;
+        let private_key =
+            PrivateKeyDer::try_from(private_key).map_err(|e| tls_error("private key", e))?;
+        let certificate_chain = self
+            .certificate_chain
+            .into_iter()
+            .map(CertificateDer::from)
+            .collect();
+        let provider = crypto_provider();
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| tls_error("server config", e))?;
+        let builder = if self.client_root_certificates.is_empty() {
+            builder.with_no_client_auth()
+        } else {
+            let roots = root_store(self.client_root_certificates)?;
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| tls_error("client root certificate", e))?;
+            builder.with_client_cert_verifier(verifier)
+        };
+        let config = builder
+            .with_single_cert(certificate_chain, private_key)
+            .map_err(|e| tls_error("server certificate", e))?;
+        Ok(TlsServerConfig {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// [Listener] for [TlsTcpTransport]s, completing the TLS handshake per its [TlsServerConfig] on
+/// each connection. A failed handshake fails that connection alone
+pub struct TlsListener {
+    listener: TcpListener,
+    config: TlsServerConfig,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: TlsServerConfig) -> Self {
+        Self { listener, config }
+    }
+
+    pub async fn bind(
+        addr: impl tokio::net::ToSocketAddrs,
+        config: TlsServerConfig,
+    ) -> Result<Self, TransportError> {
+        Ok(Self::new(TcpListener::bind(addr).await?, config))
+    }
+}
+
+#[async_trait(?Send)]
+impl Listener for TlsListener {
+    type Transport = TlsTcpTransport;
+
+    async fn accept(&mut self) -> Option<Result<Accepting<TlsTcpTransport>, TransportError>> {
+        let tcp_stream = self.listener.accept_tcp().await;
+        let config = self.config.clone();
+        Some(tcp_stream.map(|tcp_stream| -> Accepting<TlsTcpTransport> {
+            Box::pin(async move { TlsTcpTransport::accept(tcp_stream, &config).await })
+        }))
+    }
+}
+
+/// Implementation of [InternalTransport] over a TLS encrypted [tokio::net::TcpStream].
+/// Framing and timeouts behave as for [crate::transport::TcpTransport]
+pub struct TlsTcpTransport {
+    stream: tokio_rustls::TlsStream<tokio::net::TcpStream>,
+    frames: FrameReader,
+    writer: FrameWriter,
+}
+
+impl TlsTcpTransport {
+    /// Connect to [addr] and complete the TLS handshake, checking the server's certificate is
+    /// valid for [server_name] per [config]. Connecting and the handshake together fail with
+    /// [TransportError::ConnectError] past [connect_timeout], if given, e.g. the
+    /// [crate::TransportConfig::connect_timeout] of the transport it's for
+    pub async fn connect(
+        addr: impl tokio::net::ToSocketAddrs,
+        server_name: &str,
+        config: &TlsClientConfig,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Self, TransportError> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| tls_error("server name", e))?;
+        let stream = within_connect_timeout(connect_timeout, async {
+            // Timed out along with the handshake
+            let tcp_stream = connect_tcp(addr, None).await?;
+            tokio_rustls::TlsConnector::from(config.config.clone())
+                .connect(server_name, tcp_stream)
+                .await
+                .map_err(|e| tls_error("handshake", e))
+        })
+        .await?;
+        Ok(Self {
+            stream: stream.into(),
+            frames: FrameReader::default(),
+            writer: FrameWriter::default(),
+        })
+    }
+
+    /// Complete the server side of the TLS handshake on an accepted [tcp_stream]
+    pub async fn accept(
+        tcp_stream: tokio::net::TcpStream,
+        config: &TlsServerConfig,
+    ) -> Result<Self, TransportError> {
+        let stream = tokio_rustls::TlsAcceptor::from(config.config.clone())
+            .accept(tcp_stream)
+            .await
+            .map_err(|e| tls_error("handshake", e))?;
+        Ok(Self {
+            stream: stream.into(),
+            frames: FrameReader::default(),
+            writer: FrameWriter::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl InternalTransport for TlsTcpTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.writer.write_frame(&mut self.stream, b).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn send_vectored(&mut self, parts: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.writer
+            .write_frame_vectored(&mut self.stream, parts)
+            .await
+    }
+
+    async fn send_many(&mut self, messages: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.writer.write_frames(&mut self.stream, messages).await
+    }
+
+    async fn send_vectored_and_wait_for_response(
+        &mut self,
+        parts: &[Bytes<'_>],
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send_vectored(parts).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        self.frames.read_frame(&mut self.stream, timeout).await
+    }
+
+    fn configure(&mut self, config: &TransportConfig) {
+        self.frames.configure(config);
+        self.writer.configure(config);
+    }
+
+    /// No send has failed part way
+    fn is_healthy(&self) -> bool {
+        !self.writer.is_broken()
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.stream.get_ref().0.peer_addr().ok()
+    }
+
+    fn peer_certificate(&self) -> Option<OwnedBytes> {
+        let leaf = self.stream.get_ref().1.peer_certificates()?.first()?;
+        Some(leaf.to_vec())
+    }
+}
+
+/// Certificate verifier for [TlsClientConfigBuilder::danger_accept_invalid_certs]. Signatures
+/// are still checked so the handshake completes, but the certificate itself isn't
+#[derive(Debug)]
+struct AcceptAnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::call_context::CallContext;
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::server::RpcServer;
+    use crate::tests::{make_get_i_rpc, make_get_i_rpc_impl, HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{Transport, TransportConfig};
+    use std::sync::Mutex;
+
+    fn self_signed() -> (OwnedBytes, OwnedBytes) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        (
+            certified.cert.der().to_vec(),
+            certified.signing_key.serialize_der(),
+        )
+    }
+
+    #[tokio::test]
+    async fn tls_server_round_trip() {
+        let (cert, key) = self_signed();
+        let server_tls = TlsServerConfig::builder()
+            .certificate_chain(vec![cert.clone()])
+            .private_key(key)
+            .build()
+            .unwrap();
+        let trusting = TlsClientConfig::builder()
+            .add_root_certificate(cert)
+            .build()
+            .unwrap();
+        let untrusting = TlsClientConfig::builder().build().unwrap();
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_get_i_rpc_impl()));
+        let addr = "127.0.0.1:5560";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let untrusted = TlsTcpTransport::connect(addr, "localhost", &untrusting, None).await;
+            let tls_transport = TlsTcpTransport::connect(addr, "localhost", &trusting, None)
+                .await
+                .unwrap();
+            let mut transport = Transport::new(tls_transport, TransportConfig::default());
+            let get_i = RpcClient::new(make_get_i_rpc())
+                .call((), &mut transport)
+                .await;
+            (untrusted.err(), get_i)
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve_tls(addr, &server_tls) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let (untrusted_error, get_i) = rpc_results.unwrap().unwrap();
+        assert!(matches!(
+            untrusted_error,
+            Some(TransportError::ConnectError(_))
+        ));
+        assert_eq!(3usize, get_i.unwrap());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_given_to_handlers() {
+        let (cert, key) = self_signed();
+        let (client_cert, client_key) = self_signed();
+        let server_tls = TlsServerConfig::builder()
+            .certificate_chain(vec![cert.clone()])
+            .private_key(key)
+            .add_client_root_certificate(client_cert.clone())
+            .build()
+            .unwrap();
+        let with_certificate = TlsClientConfig::builder()
+            .add_root_certificate(cert.clone())
+            .client_certificate(vec![client_cert.clone()], client_key)
+            .build()
+            .unwrap();
+        let without_certificate = TlsClientConfig::builder()
+            .add_root_certificate(cert)
+            .build()
+            .unwrap();
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(RpcImpl::with_context(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, context: CallContext, ()| {
+                let peer_ip = context.peer_addr.map(|peer_addr| peer_addr.ip());
+                Ok((peer_ip, context.peer_certificate.map(|cert| cert.to_vec())))
+            }),
+        )));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let shutdown = server.shutdown_handle();
+        let whoami = Rpc::<_, (), (Option<std::net::IpAddr>, Option<OwnedBytes>)>::new(
+            HelloWorldRpcName::HelloWorld,
+        );
+
+        let client_calls = async {
+            let mut results = Vec::new();
+            for config in [with_certificate, without_certificate] {
+                let result = match TlsTcpTransport::connect(&addr, "localhost", &config, None).await
+                {
+                    Ok(tls_transport) => {
+                        let mut transport =
+                            Transport::new(tls_transport, TransportConfig::default());
+                        RpcClient::new(whoami.clone())
+                            .call((), &mut transport)
+                            .await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                results.push(result);
+            }
+            shutdown.shutdown();
+            results
+        };
+
+        let tls_listener = TlsListener::new(listener, server_tls);
+        let ((), results) = tokio::join!(server.serve_listener(tls_listener), client_calls);
+        let mut results = results.into_iter();
+        let (peer_ip, peer_certificate) = results.next().unwrap().unwrap();
+        assert_eq!(Some("127.0.0.1".parse().unwrap()), peer_ip);
+        assert_eq!(Some(client_cert), peer_certificate);
+        // Clients without a certificate are turned away during the handshake
+        assert!(results.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Spans for rpc calls, made with the "tracing" feature. Without it [CallSpan] does nothing, so
+//! the calls to it needn't be feature gated
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::core::RpcName;
+use crate::error::RpcError;
+use crate::transport::ReceivedQuery;
+
+/// Span over one call, made ("rpc_call") or handled ("rpc_handle"), with fields for the rpc
+/// name, query and response sizes in bytes, latency and any error. Handled calls also have the
+/// connection id, peer address and the caller's trace id, see [ReceivedQuery] and
+/// [crate::TracePropagation]
+#[cfg(feature = "tracing")]
+pub(crate) struct CallSpan(tracing::Span);
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct CallSpan;
+
+#[cfg(feature = "tracing")]
+impl CallSpan {
+    pub(crate) fn client(rpc_name: &dyn Display, query_bytes: usize) -> Self {
+        Self(tracing::info_span!(
+            "rpc_call",
+            rpc = %rpc_name,
+            query_bytes,
+            response_bytes = tracing::field::Empty,
+            latency = tracing::field::Empty,
+            error = tracing::field::Empty,
+        ))
+    }
+
+    pub(crate) fn server<Name: RpcName>(query: &ReceivedQuery<Name>) -> Self {
+        let span = tracing::info_span!(
+            "rpc_handle",
+            rpc = %query.name,
+            connection_id = query.connection_id,
+            peer = tracing::field::Empty,
+            trace_id = tracing::field::Empty,
+            query_bytes = query.query_bytes.len(),
+            response_bytes = tracing::field::Empty,
+            latency = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+        if let Some(peer_addr) = query.peer_addr {
+            span.record("peer", tracing::field::display(peer_addr));
+        }
+        if let Some(context) = query.trace_context() {
+            span.record("trace_id", format!("{:032x}", context.trace_id));
+        }
+        Self(span)
+    }
+
+    /// Poll [future] inside the span, so whatever it logs is tied to the call
+    pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        tracing::Instrument::instrument(future, self.0.clone())
+    }
+
+    /// Record the size of the response, or the error given instead
+    pub(crate) fn record_result(&self, response_bytes: Result<usize, &RpcError>) {
+        match response_bytes {
+            Ok(response_bytes) => self.0.record("response_bytes", response_bytes),
+            Err(error) => self.0.record("error", tracing::field::display(error)),
+        };
+    }
+
+    pub(crate) fn record_latency(&self, latency: Duration) {
+        self.0.record("latency", tracing::field::debug(latency));
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl CallSpan {
+    pub(crate) fn client(_rpc_name: &dyn Display, _query_bytes: usize) -> Self {
+        Self
+    }
+
+    pub(crate) fn server<Name: RpcName>(_query: &ReceivedQuery<Name>) -> Self {
+        Self
+    }
+
+    pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        future
+    }
+
+    pub(crate) fn record_result(&self, _response_bytes: Result<usize, &RpcError>) {}
+
+    pub(crate) fn record_latency(&self, _latency: Duration) {}
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::client::RpcClient;
+    use crate::server::RpcServer;
+    use crate::tests::{make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Name and fields of a span, in the order they were set
+    type RecordedSpan = (&'static str, Vec<String>);
+
+    #[derive(Default)]
+    struct SpanRecorder {
+        next_id: AtomicU64,
+        spans: Arc<Mutex<Vec<RecordedSpan>>>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut Vec<String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = Vec::new();
+            span.record(&mut FieldVisitor(&mut fields));
+            self.spans
+                .lock()
+                .unwrap()
+                .push((span.metadata().name(), fields));
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut FieldVisitor(fields));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[tokio::test]
+    async fn span_per_call_made_and_handled() {
+        let recorder = SpanRecorder::default();
+        let spans = recorder.spans.clone();
+        let _default = tracing::subscriber::set_default(recorder);
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+        let client_calls = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            RpcClient::new(make_hello_world_rpc())
+                .call("Foo".into(), &mut transport)
+                .await
+        };
+
+        let ((), hello) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!("Hello world: 3:\"Foo\"", hello.unwrap());
+        let spans = spans.lock().unwrap();
+        let names: Vec<&str> = spans.iter().map(|(name, _)| *name).collect();
+        assert_eq!(vec!["rpc_call", "rpc_handle"], names);
+        for (_, fields) in spans.iter() {
+            assert!(
+                fields.contains(&String::from("rpc=HelloWorld")),
+                "{:?}",
+                fields
+            );
+            for field in ["query_bytes", "response_bytes", "latency"] {
+                let prefix = format!("{}=", field);
+                assert!(
+                    fields.iter().any(|f| f.starts_with(&prefix)),
+                    "{:?}",
+                    fields
+                );
+            }
+        }
+        assert!(spans[1].1.iter().any(|f| f.starts_with("connection_id=")));
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::Formatter;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+use crate::core::RpcName;
+use crate::error::RpcResult;
+use crate::metadata::call_metadata;
+use crate::middleware::{ClientInterceptor, ClientNext, OutgoingQuery};
+use crate::transport::ReceivedQuery;
+use crate::OwnedBytes;
+
+/// The [crate::Metadata] key a [TraceContext] is sent under, as in W3C Trace Context
+pub const TRACEPARENT: &str = "traceparent";
+/// The [crate::Metadata] key for vendor specific trace state, passed on untouched
+pub const TRACESTATE: &str = "tracestate";
+
+const SAMPLED: u8 = 0x01;
+
+/// Where a call sits in a distributed trace, as sent in the W3C `traceparent` header, e.g.
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`. Sent with a query by
+/// [TracePropagation], so the spans each end records can be stitched into one trace by e.g.
+/// Jaeger or OpenTelemetry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    /// The caller's span, which the callee's spans are children of
+    pub parent_id: u64,
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// The start of a new, sampled, trace
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: (random_id() as u128) << 64 | random_id() as u128,
+            parent_id: random_id(),
+            flags: SAMPLED,
+        }
+    }
+
+    /// The context for a call made while handling one in this context, in the same trace
+    pub fn child(&self) -> Self {
+        Self {
+            parent_id: random_id(),
+            ..*self
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    /// Parse a `traceparent` value, or None if it isn't valid. Versions after 00 are read as
+    /// 00, ignoring anything they add on the end, as the spec asks
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.split('-');
+        let version = parts.next().filter(|version| version.len() == 2)?;
+        let version = u8::from_str_radix(version, 16).ok()?;
+        let trace_id = parts.next().filter(|trace_id| trace_id.len() == 32)?;
+        let parent_id = parts.next().filter(|parent_id| parent_id.len() == 16)?;
+        let flags = parts.next().filter(|flags| flags.len() == 2)?;
+        if version == 0xff || (version == 0 && parts.next().is_some()) {
+            return None;
+        }
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            parent_id: u64::from_str_radix(parent_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+        (context.trace_id != 0 && context.parent_id != 0).then_some(context)
+    }
+}
+
+impl std::fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.parent_id, self.flags
+        )
+    }
+}
+
+/// Unique, non-zero and hard to guess, though not cryptographically random
+pub(crate) fn random_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    if let Ok(since_epoch) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(since_epoch.as_nanos());
+    }
+    hasher.finish().max(1)
+}
+
+/// The trace context the caller of the rpc being handled sent, if any, e.g. to parent the
+/// handler's spans on. As with [crate::call_deadline], this is only set while the handler
+/// itself runs
+pub fn call_trace_context() -> Option<TraceContext> {
+    call_metadata(TRACEPARENT).and_then(|traceparent| TraceContext::parse(&traceparent))
+}
+
+impl<Name: RpcName> ReceivedQuery<Name> {
+    /// The trace context the caller sent, see [TracePropagation]
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.metadata
+            .get(TRACEPARENT)
+            .and_then(|traceparent| TraceContext::parse(traceparent))
+    }
+}
+
+type CurrentContext = Arc<dyn Fn() -> Option<TraceContext> + Send + Sync>;
+
+/// Client [ClientInterceptor] sending a [TraceContext] with every query. By default a call
+/// made from inside a handler carries on the trace it was called in, see [call_trace_context],
+/// and any other call starts a new one. [from_fn] takes the context from elsewhere instead,
+/// e.g. the current OpenTelemetry span, falling back to the default when it gives None
+pub struct TracePropagation {
+    current: Option<CurrentContext>,
+}
+
+impl TracePropagation {
+    pub fn new() -> Self {
+        Self { current: None }
+    }
+
+    /// Send the context [current] gives, called per query. It should give the context of the
+    /// span the call is made from, which the server's spans become children of
+    pub fn from_fn(current: impl Fn() -> Option<TraceContext> + Send + Sync + 'static) -> Self {
+        Self {
+            current: Some(Arc::new(current)),
+        }
+    }
+
+    /// The context to send, and the trace state to go with it if carrying on the caller's
+    fn context(&self) -> (TraceContext, Option<String>) {
+        if let Some(context) = self.current.as_ref().and_then(|current| current()) {
+            return (context, None);
+        }
+        match call_trace_context() {
+            Some(context) => (context.child(), call_metadata(TRACESTATE)),
+            None => (TraceContext::new_root(), None),
+        }
+    }
+}
+
+impl Default for TracePropagation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<Name: RpcName> ClientInterceptor<Name> for TracePropagation {
+    async fn around(
+        &self,
+        mut query: OutgoingQuery<Name>,
+        mut next: ClientNext<'_, Name>,
+    ) -> RpcResult<OwnedBytes> {
+        let (context, tracestate) = self.context();
+        query
+            .metadata
+            .insert(String::from(TRACEPARENT), format!("{}", context));
+        if let Some(tracestate) = tracestate {
+            query.metadata.insert(String::from(TRACESTATE), tracestate);
+        }
+        next.run(query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::call_context::{with_call_context, CallContext};
+    use crate::client::RpcClient;
+    use crate::core::{Rpc, RpcImpl};
+    use crate::metadata::Metadata;
+    use crate::server::RpcServer;
+    use crate::tests::{HelloWorldRpcName, HelloWorldState};
+    use crate::transport::{channel_listener, Transport, TransportConfig};
+    use std::sync::Mutex;
+
+    const TRACEPARENT_EXAMPLE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn traceparent_parsed() {
+        let context = TraceContext::parse(TRACEPARENT_EXAMPLE).unwrap();
+        assert_eq!(0x4bf92f3577b34da6a3ce929d0e0e4736, context.trace_id);
+        assert_eq!(0x00f067aa0ba902b7, context.parent_id);
+        assert!(context.is_sampled());
+        assert_eq!(TRACEPARENT_EXAMPLE, format!("{}", context));
+        let future_version = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra";
+        assert!(!TraceContext::parse(future_version).unwrap().is_sampled());
+        for invalid in [
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(None, TraceContext::parse(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn trace_carried_on_from_call() {
+        let metadata = Metadata::from([
+            (String::from(TRACEPARENT), String::from(TRACEPARENT_EXAMPLE)),
+            (String::from(TRACESTATE), String::from("congo=t61rcWkgMzE")),
+        ]);
+        let call_context = CallContext {
+            metadata,
+            ..Default::default()
+        };
+        let (context, tracestate) =
+            with_call_context(&call_context, || TracePropagation::new().context());
+        let caller = TraceContext::parse(TRACEPARENT_EXAMPLE).unwrap();
+        assert_eq!(caller.trace_id, context.trace_id);
+        assert_ne!(caller.parent_id, context.parent_id);
+        assert_eq!(Some(String::from("congo=t61rcWkgMzE")), tracestate);
+
+        let (root, tracestate) = TracePropagation::new().context();
+        assert_ne!(caller.trace_id, root.trace_id);
+        assert!(root.is_sampled());
+        assert_eq!(None, tracestate);
+    }
+
+    #[tokio::test]
+    async fn trace_context_sent_to_handler() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        // Answers with the trace context it was called in
+        server.add_rpc(Box::new(RpcImpl::new(
+            HelloWorldRpcName::HelloWorld,
+            Box::new(|_state, _query: ()| {
+                Ok(call_trace_context().map(|context| format!("{}", context)))
+            }),
+        )));
+        let (connector, listener) = channel_listener(1);
+        let client_call = async move {
+            let mut transport = Transport::new(
+                connector.connect().await.unwrap(),
+                TransportConfig::default(),
+            );
+            let traced = Rpc::<_, (), Option<String>>::new(HelloWorldRpcName::HelloWorld);
+            let traced = RpcClient::new(traced);
+            let untraced = traced.call((), &mut transport).await;
+            transport.layer(TracePropagation::from_fn(|| {
+                TraceContext::parse(TRACEPARENT_EXAMPLE)
+            }));
+            (untraced, traced.call((), &mut transport).await)
+        };
+
+        let ((), (untraced, traced)) = tokio::join!(server.serve_channel(listener), client_call);
+        assert_eq!(None, untraced.unwrap());
+        assert_eq!(Some(String::from(TRACEPARENT_EXAMPLE)), traced.unwrap());
+    }
+}
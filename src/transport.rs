@@ -1,17 +1,47 @@
+use crate::auth::Identity;
+use crate::bandwidth::{ByteBucket, BytesPerSecond};
+use crate::batch::{Batch, TransportPackageBatch, TransportResponseBatch, BATCH};
+use crate::buffer_pool::BufferPool;
+use crate::call_context::CallContext;
+use crate::clock::{Clock, SystemClock};
+use crate::coalesce::{CoalesceConfig, CoalescedSends};
+use crate::codec::WireCodec;
+use crate::compression::{compress, decompress, decompress_owned, CompressionConfig};
 use crate::core::RpcName;
-use crate::error::{RpcError, RpcResult};
+use crate::error::{ErrorCode, RpcError, RpcErrorPayload, RpcResult};
+use crate::handshake;
+use crate::health::{HealthReport, HEALTH_CHECK};
+use crate::keepalive::{KeepaliveConfig, PING, PONG};
+use crate::metadata::{Metadata, NO_METADATA};
+use crate::middleware::{
+    ClientInterceptor, ClientNext, OutgoingQuery, QuerySender, ServerRequest, ServerRequestKind,
+};
+use crate::pipeline::Pipeline;
+use crate::reflection::{ServerDescription, REFLECT};
+use crate::retry::RetryPolicy;
+use crate::reverse::REVERSE;
+use crate::schema::Schema;
+use crate::server::{turned_away, GO_AWAY};
+use crate::session::Session;
 
-use crate::transport::TransportError::SerialiseError;
+use crate::transport::TransportError::{DeserialiseError, SerialiseError};
 use crate::{Bytes, OwnedBytes};
 use async_trait::async_trait;
-use log::debug;
+use bytes::{Buf, BytesMut};
+use futures_util::Stream;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fmt::Formatter;
+use std::future::Future;
 use std::marker::PhantomData;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 
 /// Errors specific to transport
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum TransportError {
     /// Error when sending (from the perspective of the the local program)
     SendError(String),
@@ -19,12 +49,39 @@ pub enum TransportError {
     ReceiveError(String),
     /// Error when establishing connection
     ConnectError(String),
+    /// The peer closed the connection cleanly before sending anything more
+    ConnectionClosed,
+    /// The peer reset, aborted or otherwise broke the connection. Worth retrying on a new one
+    ConnectionReset(String),
     /// Error from timeout after waiting some [Duration].
     ReceiveTimeout(Duration),
+    /// The bytes received don't make a valid message, e.g. a text WebSocket message
+    MalformedFrame(String),
+    /// A frame's length header gave a payload of zero bytes, which no message is
+    ZeroLengthFrame,
+    /// The connection closed after [got] of the [expected] bytes of a frame's length header
+    TruncatedHeader {
+        expected: usize,
+        got: usize,
+    },
+    /// The connection closed after [got] of the [expected] bytes of a frame's payload
+    TruncatedFrame {
+        expected: usize,
+        got: usize,
+    },
+    /// The message, of the first size in bytes, is over the transport's maximum payload size,
+    /// the second. Send it over a stream transport instead, e.g. [TcpTransport]
+    PayloadTooLarge(usize, usize),
     // Error when serialising data
     SerialiseError(String),
     // Error when deserialising data
     DeserialiseError(String),
+    /// The peer's protocol version or wire formats don't match ours, found by the handshake,
+    /// see [TransportConfig::handshake]
+    IncompatibleVersion(String),
+    /// The message, of at least the first size in bytes, is over the limit of the second, see
+    /// [TransportConfig::max_inbound_message_size] and [TransportConfig::max_outbound_message_size]
+    MessageTooLarge(usize, usize),
 }
 impl std::fmt::Display for TransportError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -32,25 +89,95 @@ impl std::fmt::Display for TransportError {
             TransportError::SendError(s) => write!(f, "SendError({})", s),
             TransportError::ReceiveError(s) => write!(f, "ReceiveError({})", s),
             TransportError::ConnectError(s) => write!(f, "ConnectError({})", s),
+            TransportError::ConnectionClosed => write!(f, "ConnectionClosed"),
+            TransportError::ConnectionReset(s) => write!(f, "ConnectionReset({})", s),
             TransportError::ReceiveTimeout(dur) => write!(f, "ReceiveTimeout({:?})", dur),
+            TransportError::MalformedFrame(s) => write!(f, "MalformedFrame({})", s),
+            TransportError::ZeroLengthFrame => write!(f, "ZeroLengthFrame"),
+            TransportError::TruncatedHeader { expected, got } => {
+                write!(f, "TruncatedHeader({} of {} bytes)", got, expected)
+            }
+            TransportError::TruncatedFrame { expected, got } => {
+                write!(f, "TruncatedFrame({} of {} bytes)", got, expected)
+            }
+            TransportError::PayloadTooLarge(size, max) => {
+                write!(f, "PayloadTooLarge({} > {})", size, max)
+            }
             TransportError::SerialiseError(s) => write!(f, "SerialiseError({})", s),
             TransportError::DeserialiseError(s) => write!(f, "DeserialiseError({})", s),
+            TransportError::IncompatibleVersion(s) => write!(f, "IncompatibleVersion({})", s),
+            TransportError::MessageTooLarge(size, max) => {
+                write!(f, "MessageTooLarge({} > {})", size, max)
+            }
         }
     }
 }
 impl std::error::Error for TransportError {}
 impl TransportError {
-    fn io_send(e: std::io::Error) -> Self {
-        Self::SendError(format!("{:?}", e))
+    pub(crate) fn io_send(e: std::io::Error) -> Self {
+        if Self::is_reset_kind(e.kind()) {
+            Self::ConnectionReset(format!("{:?}", e))
+        } else {
+            Self::SendError(format!("{:?}", e))
+        }
+    }
+    pub(crate) fn io_receive(e: std::io::Error) -> Self {
+        if Self::is_reset_kind(e.kind()) {
+            Self::ConnectionReset(format!("{:?}", e))
+        } else {
+            Self::ReceiveError(format!("{:?}", e))
+        }
+    }
+    fn is_reset_kind(kind: std::io::ErrorKind) -> bool {
+        use std::io::ErrorKind;
+        matches!(
+            kind,
+            ErrorKind::ConnectionReset | ErrorKind::BrokenPipe | ErrorKind::ConnectionAborted
+        )
+    }
+    /// Whether the connection was reset by the peer, which is retryable on a fresh connection,
+    /// unlike e.g. a protocol or (de)serialise error
+    pub fn is_connection_reset(&self) -> bool {
+        matches!(self, Self::ConnectionReset(_))
+    }
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::SendError(_)
+            | Self::ReceiveError(_)
+            | Self::ConnectError(_)
+            | Self::ConnectionClosed
+            | Self::ConnectionReset(_) => ErrorCode::Unavailable,
+            Self::ReceiveTimeout(_) => ErrorCode::DeadlineExceeded,
+            Self::MalformedFrame(_)
+            | Self::ZeroLengthFrame
+            | Self::TruncatedHeader { .. }
+            | Self::TruncatedFrame { .. }
+            | Self::SerialiseError(_)
+            | Self::DeserialiseError(_) => ErrorCode::InvalidData,
+            Self::PayloadTooLarge(_, _) | Self::MessageTooLarge(_, _) => {
+                ErrorCode::ResourceExhausted
+            }
+            Self::IncompatibleVersion(_) => ErrorCode::Incompatible,
+        }
+    }
+    /// See [ErrorCode::is_retryable]
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_retryable()
     }
-    fn io_receive(e: std::io::Error) -> Self {
-        Self::ReceiveError(format!("{:?}", e))
+    /// Prefix a (de)serialise error with the step that failed, e.g. "rpc name Foo".
+    /// Other errors are returned unchanged
+    pub(crate) fn in_step(self, step: impl std::fmt::Display) -> Self {
+        match self {
+            Self::SerialiseError(s) => Self::SerialiseError(format!("{}: {}", step, s)),
+            Self::DeserialiseError(s) => Self::DeserialiseError(format!("{}: {}", step, s)),
+            other => other,
+        }
     }
 }
 
 /// The [InternalTransport] trait defines the transport layer for RPCs between client and server
 #[async_trait]
-pub trait InternalTransport {
+pub trait InternalTransport: Send {
     /// async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError>;
     async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError>;
 
@@ -65,12 +192,100 @@ pub trait InternalTransport {
         timeout: Duration,
     ) -> Result<OwnedBytes, TransportError>;
 
+    /// Send [parts] one after another as a single message, as [send] would their
+    /// concatenation, see [TransportConfig::sectioned_packages]. The stream transports write
+    /// them out with `write_vectored`, where the stream supports it, rather than copying them
+    /// together first, while others can leave this to copy
+    async fn send_vectored(&mut self, parts: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.send(&parts.concat()).await
+    }
+
+    /// As [send_and_wait_for_response], sending [parts] as [send_vectored] does
+    async fn send_vectored_and_wait_for_response(
+        &mut self,
+        parts: &[Bytes<'_>],
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send_and_wait_for_response(&parts.concat(), timeout)
+            .await
+    }
+
+    /// Send each of [messages] as a message of its own, in order, as [send] would one after
+    /// another, see [TransportConfig::coalesce_notifications]. The stream transports write them
+    /// all out at once, rather than with a write each, while others can leave this to send
+    /// them one at a time
+    async fn send_many(&mut self, messages: &[Bytes<'_>]) -> Result<(), TransportError> {
+        for message in messages {
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
     /// async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError>;
+    /// Should be cancel safe, i.e. dropping it part way through a message mustn't lose any of
+    /// it, as client and bidirectional streaming calls wait on it alongside sending.
+    /// The QUIC stream transport isn't, but only ever carries one message each way
     async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError>;
+
+    /// Whether the connection still looks usable, without blocking. Transports that can't tell
+    /// say it is, and leave the next send or receive to find out
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    /// Take up the parts of [config] about sending and receiving messages, as [Transport::new]
+    /// does with its config, each as far as the transport can:
+    /// [TransportConfig::max_inbound_message_size], refusing a bigger message with
+    /// [TransportError::MessageTooLarge] without reading it into memory where its size is
+    /// known first, after which the connection can't be used as the rest is left unread;
+    /// [TransportConfig::buffer_pool] and [TransportConfig::read_buffer_size], for transports
+    /// reading a stream into buffers of their own; [TransportConfig::idle_read_timeout], for
+    /// those receiving a message in parts; and [TransportConfig::send_timeout]. Transports
+    /// with nothing to take up ignore it
+    fn configure(&mut self, _config: &TransportConfig) {}
+
+    /// Address of the other end, for [ReceivedQuery::peer_addr], if it has one
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        None
+    }
+
+    /// The DER encoded certificate the other end showed, for [ReceivedQuery::peer_certificate],
+    /// if it's a TLS transport and the peer showed one
+    fn peer_certificate(&self) -> Option<OwnedBytes> {
+        None
+    }
+}
+
+/// What a package carries. A [Query] opens a call, while [StreamItem]s then [StreamEnd] carry
+/// the client's side of a [crate::ClientStreamRpc] or [crate::BidiStreamRpc] call, under the
+/// opening query's correlation id. [Cancel] asks the server to stop work on the call with its
+/// correlation id, as the caller has given up on it. A [Notification] is a query the server
+/// handles like any other unary one but never answers, not even with an error, see
+/// [crate::NotificationRpc]. New variants go at the end, as some wire formats encode them by
+/// index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PackageKind {
+    Query,
+    StreamItem,
+    StreamEnd,
+    Cancel,
+    Notification,
 }
 
+/// A query as sent on the wire. [correlation_id] pairs the response with its query when calls
+/// share a connection, see [crate::MultiplexedClient], and is 0 for calls made one at a time.
+/// [time_remaining] is how long the caller will wait for the response from when it was sent.
+/// It's relative rather than a point in time so the client and server clocks needn't agree.
+/// [metadata] is only sent with [PackageKind::Query]s and [PackageKind::Notification]s, see
+/// [Metadata]
 #[derive(Serialize, Deserialize)]
 struct TransportPackage<'a> {
+    correlation_id: u64,
+    kind: PackageKind,
+    time_remaining: Option<Duration>,
+    // Peers with nothing to send in self-describing formats, e.g. Python, can leave it out
+    #[serde(default)]
+    metadata: Cow<'a, Metadata>,
     #[serde(borrow)]
     name_bytes: Bytes<'a>,
     #[serde(borrow)]
@@ -78,14 +293,186 @@ struct TransportPackage<'a> {
 }
 #[derive(Serialize, Deserialize)]
 struct TransportPackageOwned {
+    correlation_id: u64,
+    kind: PackageKind,
+    time_remaining: Option<Duration>,
+    #[serde(default)]
+    metadata: Metadata,
     name_bytes: OwnedBytes,
     query_bytes: OwnedBytes,
 }
 
+/// All of a [TransportPackage] but the name and query bytes, serialised alone with
+/// [TransportConfig::sectioned_packages]. The package is then this header and the name, each
+/// behind its length as a big-endian u16, then the query bytes to the end, so the query needs
+/// neither a length nor serialising again
+#[derive(Serialize, Deserialize)]
+struct PackageHeader<'a> {
+    correlation_id: u64,
+    kind: PackageKind,
+    time_remaining: Option<Duration>,
+    #[serde(default)]
+    metadata: Cow<'a, Metadata>,
+}
+
+/// A package ready to send, in the parts [InternalTransport::send_vectored] writes one after
+/// another. With [TransportConfig::sectioned_packages] the query bytes are the last part, just
+/// as the caller serialised them, otherwise they're already in [head] and the last part is
+/// empty
+pub(crate) struct PackageParts<'a> {
+    head: OwnedBytes,
+    query_bytes: Bytes<'a>,
+}
+
+impl PackageParts<'_> {
+    pub(crate) fn parts(&self) -> [Bytes<'_>; 2] {
+        [&self.head, self.query_bytes]
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.head.len() + self.query_bytes.len()
+    }
+
+    /// The whole package in one buffer, copying the query bytes onto the end of [head] if
+    /// they're a part of their own
+    pub(crate) fn into_bytes(self) -> OwnedBytes {
+        let mut bytes = self.head;
+        bytes.extend_from_slice(self.query_bytes);
+        bytes
+    }
+
+    /// The package in one buffer, for anything that can't send it in parts
+    pub(crate) fn into_contiguous(self) -> OwnedBytes {
+        let mut package_bytes = self.head;
+        package_bytes.extend_from_slice(self.query_bytes);
+        package_bytes
+    }
+}
+
+/// What the server sends back for each query: the serialised response, or why there isn't one.
+/// A [crate::StreamRpc] is answered with any number of [StreamItem]s then a [StreamEnd], or an
+/// error in their place, all with the query's correlation id.
+/// [Cancelled] is the last response to a call stopped by a [PackageKind::Cancel]
+/// [Chunk] is part of a response split up to send, see [crate::ServerConfig::response_chunk_size],
+/// its bytes going before those of the next response with the same correlation id
+/// New variants go at the end, as some wire formats encode them by index
+#[derive(Serialize, Deserialize)]
+enum TransportResponse<'a> {
+    #[serde(borrow)]
+    Ok(Bytes<'a>),
+    UnknownRpc(String),
+    HandlerPanic(String),
+    Error(String),
+    #[serde(borrow)]
+    StreamItem(Bytes<'a>),
+    StreamEnd,
+    Cancelled(String),
+    Unauthenticated(String),
+    PermissionDenied(String),
+    Application(RpcErrorPayload),
+    RateLimited(String, Duration),
+    HandlerTimeout(String, Duration),
+    Overloaded(String),
+    #[serde(borrow)]
+    Chunk(Bytes<'a>),
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) enum TransportResponseOwned {
+    Ok(OwnedBytes),
+    UnknownRpc(String),
+    HandlerPanic(String),
+    Error(String),
+    StreamItem(OwnedBytes),
+    StreamEnd,
+    Cancelled(String),
+    Unauthenticated(String),
+    PermissionDenied(String),
+    Application(RpcErrorPayload),
+    RateLimited(String, Duration),
+    HandlerTimeout(String, Duration),
+    Overloaded(String),
+    Chunk(OwnedBytes),
+}
+
+/// The [TransportResponse] to the query with the same [correlation_id]
+#[derive(Serialize, Deserialize)]
+struct TransportResponseEnvelope<'a> {
+    correlation_id: u64,
+    #[serde(borrow)]
+    response: TransportResponse<'a>,
+}
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TransportResponseEnvelopeOwned {
+    pub(crate) correlation_id: u64,
+    pub(crate) response: TransportResponseOwned,
+}
+
+impl<'a> TransportResponse<'a> {
+    fn of_error(error: &RpcError) -> Self {
+        match error {
+            RpcError::UnknownRpc(s) => Self::UnknownRpc(s.clone()),
+            RpcError::HandlerPanic(s) => Self::HandlerPanic(s.clone()),
+            RpcError::Cancelled(s) => Self::Cancelled(s.clone()),
+            RpcError::Unauthenticated(s) => Self::Unauthenticated(s.clone()),
+            RpcError::PermissionDenied(s) => Self::PermissionDenied(s.clone()),
+            RpcError::Application(payload) => Self::Application(payload.clone()),
+            RpcError::RateLimited(s, retry_after) => Self::RateLimited(s.clone(), *retry_after),
+            RpcError::HandlerTimeout(s, timeout) => Self::HandlerTimeout(s.clone(), *timeout),
+            RpcError::Overloaded(s) => Self::Overloaded(s.clone()),
+            other => Self::Error(format!("{}", other)),
+        }
+    }
+}
+
+impl TransportResponseOwned {
+    pub(crate) fn into_result(self) -> RpcResult<OwnedBytes> {
+        match self {
+            Self::Ok(bytes) => Ok(bytes),
+            Self::UnknownRpc(s) => Err(RpcError::UnknownRpc(s)),
+            Self::HandlerPanic(s) => Err(RpcError::HandlerPanic(s)),
+            Self::Error(s) => Err(RpcError::Remote(s)),
+            Self::Cancelled(s) => Err(RpcError::Cancelled(s)),
+            Self::Unauthenticated(s) => Err(RpcError::Unauthenticated(s)),
+            Self::PermissionDenied(s) => Err(RpcError::PermissionDenied(s)),
+            Self::Application(payload) => Err(RpcError::Application(payload)),
+            Self::RateLimited(s, retry_after) => Err(RpcError::RateLimited(s, retry_after)),
+            Self::HandlerTimeout(s, timeout) => Err(RpcError::HandlerTimeout(s, timeout)),
+            Self::Overloaded(s) => Err(RpcError::Overloaded(s)),
+            Self::StreamItem(_) | Self::StreamEnd => Err(RpcError::Custom(String::from(
+                "Expected a single response, got a stream",
+            ))),
+            Self::Chunk(_) => Err(RpcError::Custom(String::from(
+                "Expected a whole response, got a chunk of one",
+            ))),
+        }
+    }
+
+    /// The next response to a [crate::StreamRpc], or None once the stream has ended
+    pub(crate) fn into_stream_item(self) -> RpcResult<Option<OwnedBytes>> {
+        match self {
+            Self::StreamItem(bytes) => Ok(Some(bytes)),
+            Self::StreamEnd => Ok(None),
+            Self::Ok(_) => Err(RpcError::Custom(String::from(
+                "Expected a stream, got a single response",
+            ))),
+            other => other.into_result().map(Some),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tests::HelloWorldRpcName;
+    fn empty_response(wire_config: &TransportWireConfig) -> OwnedBytes {
+        wire_config
+            .serialize(&TransportResponseEnvelope {
+                correlation_id: 0,
+                response: TransportResponse::Ok(&[]),
+            })
+            .unwrap()
+    }
+
     #[test]
     fn transport_package_round_trip() {
         let name = HelloWorldRpcName::HelloWorld;
@@ -99,6 +486,10 @@ mod tests {
         let query_bytes = transport_config.serialize(&query).unwrap();
 
         let package = TransportPackage {
+            correlation_id: 0,
+            kind: PackageKind::Query,
+            time_remaining: None,
+            metadata: Cow::default(),
             name_bytes: &name_bytes,
             query_bytes: &query_bytes,
         };
@@ -113,206 +504,3336 @@ mod tests {
         assert_eq!(name, name2);
         assert_eq!(query, query2);
     }
-}
 
-/// The initial structure handed to the RpcServer, which includes
-pub struct ReceivedQuery<Name: RpcName> {
-    pub name: Name,
-    pub query_bytes: OwnedBytes,
-}
+    #[cfg(feature = "transport_msgpack")]
+    #[test]
+    fn msgpack_package_round_trip() {
+        let name = HelloWorldRpcName::HelloWorld;
+        let query = String::from("Foo");
+        let transport_config = TransportWireConfig::MessagePack;
 
-/// Transport for data betweeen client and server, generic over the rpc names and internal transport
-/// The majority of the heavy lifting is done by the [internal_transport], see the definition of
-/// the [InternalTransport] trait for more information
-pub struct Transport<I, Name> {
-    internal_transport: I,
-    name: PhantomData<Name>,
-    pub config: TransportConfig,
-}
+        let name_bytes = transport_config.serialize(&name).unwrap();
+        let query_bytes = transport_config.serialize(&query).unwrap();
 
-// TODO: Consider making transport Connected/Disconnected
-/*
-pub struct ConnectedTransport<I, Name> {
-    transport: Transport<I, Name>
-}
- */
+        let package = TransportPackage {
+            correlation_id: 7,
+            kind: PackageKind::Query,
+            time_remaining: Some(Duration::from_secs(3)),
+            metadata: Cow::default(),
+            name_bytes: &name_bytes,
+            query_bytes: &query_bytes,
+        };
 
-/// TransportConfig defines various config options for transport handling
-/// [rcv_timeout] is used to protect receiving with a timeout
-/// [wire_config] is for serialising sent data, see the type def for more
-#[derive(Clone, Debug)]
-pub struct TransportConfig {
-    pub rcv_timeout: Duration,
-    pub wire_config: TransportWireConfig,
-}
+        let package_bytes = transport_config.serialize(&package).unwrap();
 
-impl Default for TransportConfig {
-    fn default() -> Self {
-        Self {
-            rcv_timeout: Duration::from_secs(3),
-            wire_config: TransportWireConfig::default(),
-        }
-    }
-}
+        let package2: TransportPackageOwned = transport_config.deserialize(&package_bytes).unwrap();
+        assert_eq!(7, package2.correlation_id);
+        assert_eq!(Some(Duration::from_secs(3)), package2.time_remaining);
 
-/// TransportWireConfig defines how to (de)serialise query/response. Extra methods are available by enabling their feature
-#[non_exhaustive]
-#[derive(Clone, Debug)]
-pub enum TransportWireConfig {
-    Pickle(serde_pickle::DeOptions, serde_pickle::SerOptions),
-    #[cfg(feature = "transport_postcard")]
-    Postcard,
-}
+        let name2: HelloWorldRpcName = transport_config.deserialize(&package2.name_bytes).unwrap();
+        let query2: String = transport_config.deserialize(&package2.query_bytes).unwrap();
 
-// TODO: Handle unwraps here with some sort of [Serialise/DeserialiseError]
-impl TransportWireConfig {
-    pub(crate) fn serialize(&self, val: &impl Serialize) -> Result<OwnedBytes, TransportError> {
-        match self {
-            Self::Pickle(_de_opts, ser_opts) => serde_pickle::ser::to_vec(val, ser_opts.clone())
-                .map_err(|pickle_error| SerialiseError(format!("{:?}", pickle_error))),
-            #[cfg(feature = "transport_postcard")]
-            Self::Postcard => postcard::to_vec(val)
-                .map_err(|postcard_error| SerialiseError(format!("{:?}", postcard_error))),
-        }
-    }
-    pub(crate) fn deserialize<T: for<'de> Deserialize<'de>>(
-        &self,
-        bytes: Bytes,
-    ) -> Result<T, TransportError> {
-        match self {
-            Self::Pickle(de_opts, _ser_opts) => {
-                serde_pickle::de::from_slice(bytes, de_opts.clone()).map_err(|pickle_error| {
-                    TransportError::DeserialiseError(format!("{:?}", pickle_error))
-                })
-            }
-            #[cfg(feature = "transport_postcard")]
-            Self::Postcard => postcard::from_bytes(bytes).map_err(|postcard_error| {
-                TransportError::DeserialiseError(format!("{:?}", postcard_error))
-            }),
-        }
+        assert_eq!(name, name2);
+        assert_eq!(query, query2);
     }
-}
 
-impl Default for TransportWireConfig {
-    fn default() -> Self {
-        Self::Pickle(
-            serde_pickle::DeOptions::new(),
-            serde_pickle::SerOptions::new(),
-        )
+    #[derive(Clone, Hash, Eq, PartialEq, Deserialize)]
+    struct UnserialisableName;
+    impl Serialize for UnserialisableName {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("can't serialise this"))
+        }
     }
-}
-
-impl<I: InternalTransport, Name: RpcName> Transport<I, Name> {
-    pub fn new(internal_transport: I, transport_config: TransportConfig) -> Self {
-        Self {
-            internal_transport,
-            name: PhantomData::default(),
-            config: transport_config,
+    impl std::fmt::Display for UnserialisableName {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Unserialisable")
         }
     }
-    pub async fn send_query(
-        &mut self,
-        query_bytes: Bytes<'_>,
-        rpc_name: &Name,
-    ) -> RpcResult<OwnedBytes> {
-        let name_bytes = self.config.wire_config.serialize(&rpc_name)?;
-        let package = TransportPackage {
-            name_bytes: &name_bytes,
-            query_bytes,
-        };
-        let package_bytes = self.config.wire_config.serialize(&package)?;
-        debug!(
-            "Transport sending {} Bytes:  {:?}",
-            package_bytes.len(),
-            package_bytes
-        );
-        self.internal_transport
-            .send_and_wait_for_response(&package_bytes, self.config.rcv_timeout)
+    impl RpcName for UnserialisableName {}
+
+    #[tokio::test]
+    async fn name_serialise_error_names_step() {
+        let mut transport: Transport<_, UnserialisableName> =
+            Transport::new(QueuedTestingTransport::default(), Default::default());
+        let error = transport
+            .send_query(&[], &UnserialisableName)
             .await
-            .map_err(Into::into)
+            .err()
+            .unwrap();
+        let message = format!("{}", error);
+        assert!(
+            message.starts_with("SerialiseError(rpc name Unserialisable: "),
+            "{}",
+            message
+        );
     }
 
-    pub async fn receive_query(&mut self) -> RpcResult<ReceivedQuery<Name>> {
-        // We receive with no timeout as we want to sit and wait on [internal_transport]
-        match self.internal_transport.receive(None).await {
-            Ok(bytes) => {
-                debug!("Transport {} Bytes:  {:?}", bytes.len(), bytes);
-                let package: TransportPackageOwned = self.config.wire_config.deserialize(&bytes)?;
-                let name = self.config.wire_config.deserialize(&package.name_bytes)?;
-                Ok(ReceivedQuery {
-                    name,
-                    query_bytes: package.query_bytes,
-                })
-            }
-            Err(rpc_error) => Err(RpcError::TransportError(rpc_error)),
-        }
-    }
+    #[tokio::test]
+    async fn raw_package_round_trip() {
+        let wire_config = TransportWireConfig::default();
+        let name_bytes = wire_config
+            .serialize(&HelloWorldRpcName::HelloWorld)
+            .unwrap();
+        let query_bytes = wire_config.serialize(&"Foo").unwrap();
+        let package_bytes = wire_config
+            .serialize(&TransportPackage {
+                correlation_id: 0,
+                kind: PackageKind::Query,
+                time_remaining: None,
+                metadata: Cow::default(),
+                name_bytes: &name_bytes,
+                query_bytes: &query_bytes,
+            })
+            .unwrap();
+        let response_bytes = wire_config.serialize(&"Bar").unwrap();
 
-    pub async fn respond(&mut self, bytes: Bytes<'_>) -> RpcResult<()> {
-        self.internal_transport
-            .send(bytes)
-            .await
-            .map_err(RpcError::TransportError)
+        let mut client: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![response_bytes.clone()].into(),
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        let response = client.send_raw_package(&package_bytes).await.unwrap();
+        assert_eq!(response_bytes, response);
+        assert_eq!(vec![package_bytes], client.internal_transport().sent);
+
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: client.internal_transport().sent.clone().into(),
+                ..Default::default()
+            },
+            Default::default(),
+        );
+        let received = server.receive_query().await.unwrap();
+        assert_eq!(HelloWorldRpcName::HelloWorld, received.name);
+        assert_eq!(query_bytes, received.query_bytes);
     }
-}
 
-#[cfg(test)]
-pub(crate) struct CannedTestingTransport {
-    pub always_respond_with: String,
-    pub receive_times: usize,
-}
+    #[tokio::test]
+    async fn bandwidth_limit_slows_large_transfer() {
+        let payload = vec![0u8; 150_000];
+        let send_payload = |bandwidth_limit| {
+            let payload = payload.clone();
+            async move {
+                let config = TransportConfig {
+                    bandwidth_limit,
+                    ..Default::default()
+                };
+                let internal_transport = QueuedTestingTransport {
+                    to_receive: vec![vec![]].into(),
+                    ..Default::default()
+                };
+                let mut transport: Transport<_, HelloWorldRpcName> =
+                    Transport::new(internal_transport, config);
+                let start = std::time::Instant::now();
+                transport.send_raw_package(&payload).await.unwrap();
+                start.elapsed()
+            }
+        };
 
-#[cfg(test)]
-#[async_trait]
-impl InternalTransport for CannedTestingTransport {
-    async fn send(&mut self, _b: Bytes<'_>) -> Result<(), TransportError> {
-        Ok(())
+        let unlimited = send_payload(None).await;
+        // One second of budget is available up front, the remaining 50KB takes half a second
+        let limited = send_payload(Some(BytesPerSecond(100_000))).await;
+        assert!(unlimited < Duration::from_millis(100), "{:?}", unlimited);
+        assert!(limited >= Duration::from_millis(450), "{:?}", limited);
+        assert!(limited < Duration::from_millis(1000), "{:?}", limited);
     }
 
-    async fn send_and_wait_for_response(
-        &mut self,
-        _b: Bytes<'_>,
-        _timeout: Duration,
-    ) -> Result<OwnedBytes, TransportError> {
-        Ok(
-            serde_pickle::to_vec(&self.always_respond_with, serde_pickle::SerOptions::new())
-                .unwrap(),
-        )
+    /// Fails every send_and_wait_for_response with [make_error]
+    struct FailingTestingTransport {
+        make_error: fn() -> TransportError,
     }
-    async fn receive(&mut self, _timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
-        if self.receive_times > 0 {
-            self.receive_times -= 1;
-            Ok(
-                serde_pickle::to_vec(&self.always_respond_with, serde_pickle::SerOptions::new())
-                    .unwrap(),
-            )
-        } else {
-            Err(TransportError::ReceiveError(String::from(
-                "Run out of receive count",
+    #[async_trait]
+    impl InternalTransport for FailingTestingTransport {
+        async fn send(&mut self, _b: Bytes<'_>) -> Result<(), TransportError> {
+            Ok(())
+        }
+        async fn send_and_wait_for_response(
+            &mut self,
+            _b: Bytes<'_>,
+            _timeout: Duration,
+        ) -> Result<OwnedBytes, TransportError> {
+            Err((self.make_error)())
+        }
+        async fn receive(
+            &mut self,
+            _timeout: Option<Duration>,
+        ) -> Result<OwnedBytes, TransportError> {
+            Err(TransportError::io_receive(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
             )))
         }
     }
-}
 
-/// Pre-packaged implementation of [InternalTransport] using [tokio::net::TcpStream]
-pub struct TcpTransport {
-    stream: tokio::net::TcpStream,
-}
+    #[tokio::test]
+    async fn connection_reset_is_classified() {
+        let internal_transport = FailingTestingTransport {
+            make_error: || {
+                TransportError::io_receive(std::io::Error::from(
+                    std::io::ErrorKind::ConnectionReset,
+                ))
+            },
+        };
+        let mut transport: Transport<_, HelloWorldRpcName> =
+            Transport::new(internal_transport, Default::default());
+        let error = transport
+            .send_query(&[], &HelloWorldRpcName::HelloWorld)
+            .await
+            .err()
+            .unwrap();
+        assert!(error.is_connection_reset(), "{}", error);
 
-impl TcpTransport {
-    pub fn new(stream: tokio::net::TcpStream) -> Self {
-        Self { stream }
+        let error = transport.receive_query().await.err().unwrap();
+        assert!(!error.is_connection_reset(), "{}", error);
     }
-}
 
-#[async_trait]
-impl InternalTransport for TcpTransport {
-    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
-        use tokio::io::AsyncWriteExt;
-        self.stream
-            .write_all(b)
+    #[tokio::test]
+    async fn opcode_name_round_trip() {
+        let config = TransportConfig {
+            name_encoding: NameEncoding::Opcode,
+            ..Default::default()
+        };
+        let mut client: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![empty_response(&config.wire_config)].into(),
+                ..Default::default()
+            },
+            config.clone(),
+        );
+        client
+            .send_query(&[1, 2, 3], &HelloWorldRpcName::MassiveRpc)
             .await
-            .map_err(TransportError::io_send)
+            .unwrap();
+
+        let sent: TransportPackageOwned = config
+            .wire_config
+            .deserialize(&client.internal_transport().sent[0])
+            .unwrap();
+        assert_eq!(vec![0, 3], sent.name_bytes);
+
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: client.internal_transport().sent.clone().into(),
+                ..Default::default()
+            },
+            config,
+        );
+        let received = server.receive_query().await.unwrap();
+        assert_eq!(HelloWorldRpcName::MassiveRpc, received.name);
+        assert_eq!(vec![1, 2, 3], received.query_bytes);
+    }
+
+    #[tokio::test]
+    async fn unknown_opcode_is_unknown_rpc() {
+        let config = TransportConfig {
+            name_encoding: NameEncoding::Opcode,
+            ..Default::default()
+        };
+        let package_bytes = config
+            .wire_config
+            .serialize(&TransportPackage {
+                correlation_id: 0,
+                kind: PackageKind::Query,
+                time_remaining: None,
+                metadata: Cow::default(),
+                name_bytes: &[0xff, 0xff],
+                query_bytes: &[],
+            })
+            .unwrap();
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![package_bytes].into(),
+                ..Default::default()
+            },
+            config,
+        );
+        match server.receive_query().await {
+            Err(RpcError::UnknownRpc(s)) => assert_eq!("opcode 65535", s),
+            Err(e) => panic!("Expected UnknownRpc, got {}", e),
+            Ok(_) => panic!("Expected UnknownRpc, got a query"),
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout_names_rpc() {
+        let internal_transport = FailingTestingTransport {
+            make_error: || TransportError::ReceiveTimeout(Duration::from_secs(3)),
+        };
+        let mut transport: Transport<_, HelloWorldRpcName> =
+            Transport::new(internal_transport, Default::default());
+        let error = transport
+            .send_query(&[], &HelloWorldRpcName::GetI)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, RpcError::RpcTimeout(_, _)));
+        let message = format!("{}", error);
+        assert!(message.contains("GetI"), "{}", message);
+    }
+
+    #[cfg(feature = "transport_postcard")]
+    #[tokio::test]
+    async fn borrowed_package_receive() {
+        assert_borrowed_package_receive(TransportWireConfig::Postcard).await;
+    }
+
+    #[cfg(feature = "transport_bincode")]
+    #[tokio::test]
+    async fn bincode_borrowed_package_receive() {
+        assert_borrowed_package_receive(TransportWireConfig::Bincode).await;
+    }
+
+    #[cfg(any(feature = "transport_postcard", feature = "transport_bincode"))]
+    async fn assert_borrowed_package_receive(wire_config: TransportWireConfig) {
+        let config = TransportConfig {
+            wire_config,
+            ..Default::default()
+        };
+        let query = vec![7u8; 32 * 1024];
+        let mut client: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![empty_response(&config.wire_config)].into(),
+                ..Default::default()
+            },
+            config.clone(),
+        );
+        client
+            .send_query(&query, &HelloWorldRpcName::PreciseRpc)
+            .await
+            .unwrap();
+
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: client.internal_transport().sent.clone().into(),
+                ..Default::default()
+            },
+            config,
+        );
+        assert!(server.can_borrow_package(query.len()));
+        let received = server.receive_query().await.unwrap();
+        assert_eq!(HelloWorldRpcName::PreciseRpc, received.name);
+        assert_eq!(query, received.query_bytes);
+    }
+
+    #[test]
+    fn pickle_cannot_borrow_package() {
+        let wire_config = TransportWireConfig::pickle();
+        assert!(!wire_config.supports_borrowed_bytes());
+        let package_bytes = wire_config
+            .serialize(&TransportPackage {
+                correlation_id: 0,
+                kind: PackageKind::Query,
+                time_remaining: None,
+                metadata: Cow::default(),
+                name_bytes: b"name",
+                query_bytes: b"query",
+            })
+            .unwrap();
+        assert!(wire_config
+            .deserialize::<TransportPackage>(&package_bytes)
+            .is_err());
+        assert!(wire_config
+            .deserialize::<TransportPackageOwned>(&package_bytes)
+            .is_ok());
+    }
+
+    #[cfg(feature = "transport_json")]
+    #[tokio::test]
+    async fn json_package_round_trip() {
+        let config = TransportConfig {
+            wire_config: TransportWireConfig::Json,
+            ..Default::default()
+        };
+        let query = config.wire_config.serialize(&"Foo").unwrap();
+        let mut client: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![empty_response(&config.wire_config)].into(),
+                ..Default::default()
+            },
+            config.clone(),
+        );
+        client
+            .send_query(&query, &HelloWorldRpcName::HelloWorld)
+            .await
+            .unwrap();
+
+        // What was sent is plain JSON, readable without knowing anything about pirates
+        let sent = client.internal_transport().sent.clone();
+        let package: serde_json::Value = serde_json::from_slice(&sent[0]).unwrap();
+        assert_eq!("Query", package["kind"]);
+
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: sent.into(),
+                ..Default::default()
+            },
+            config,
+        );
+        let received = server.receive_query().await.unwrap();
+        assert_eq!(HelloWorldRpcName::HelloWorld, received.name);
+        assert_eq!(query, received.query_bytes);
+        assert_eq!(b"\"Foo\"".to_vec(), received.query_bytes);
+    }
+
+    #[cfg(feature = "transport_cbor")]
+    #[tokio::test]
+    async fn cbor_server_round_trip() {
+        use crate::client::RpcClient;
+        use crate::server::RpcServer;
+        use crate::tests::{make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldState};
+        use std::sync::Mutex;
+        // Opcodes keep the name down to two bytes too, as a small device would want
+        let config = TransportConfig {
+            wire_config: TransportWireConfig::Cbor,
+            name_encoding: NameEncoding::Opcode,
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, config.clone());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+
+        let client_call = async move {
+            let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+            RpcClient::new(make_hello_world_rpc())
+                .call("cbor".into(), &mut transport)
+                .await
+        };
+
+        let ((), hello) = tokio::join!(server.serve_channel(listener), client_call);
+        assert_eq!("Hello world: 3:\"cbor\"", hello.unwrap());
+    }
+
+    #[cfg(all(feature = "transport_postcard", feature = "transport_json"))]
+    #[tokio::test]
+    async fn server_detects_wire_format() {
+        use crate::client::RpcClient;
+        use crate::server::RpcServer;
+        use crate::tests::{make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldState};
+        use std::sync::Mutex;
+        let server_config = TransportConfig {
+            tag_wire_format: true,
+            accepted_wire_configs: vec![TransportWireConfig::Postcard, TransportWireConfig::Json],
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, server_config);
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let (connector, listener) = channel_listener(1);
+
+        let client_calls = async move {
+            let mut hellos = Vec::new();
+            for wire_config in [
+                TransportWireConfig::Json,
+                TransportWireConfig::pickle(),
+                TransportWireConfig::Postcard,
+            ] {
+                let config = TransportConfig {
+                    wire_config,
+                    tag_wire_format: true,
+                    ..Default::default()
+                };
+                let name = config.wire_config.wire_config_name();
+                let mut transport = Transport::new(connector.connect().await.unwrap(), config);
+                let hello = RpcClient::new(make_hello_world_rpc())
+                    .call(name.into(), &mut transport)
+                    .await;
+                hellos.push(hello.unwrap());
+            }
+            hellos
+        };
+
+        let ((), hellos) = tokio::join!(server.serve_channel(listener), client_calls);
+        assert_eq!(
+            vec![
+                "Hello world: 3:\"json\"",
+                "Hello world: 3:\"pickle\"",
+                "Hello world: 3:\"postcard\""
+            ],
+            hellos
+        );
+    }
+
+    #[cfg(feature = "transport_json")]
+    #[tokio::test]
+    async fn wire_format_kept_from_first_package() {
+        let client_config = |wire_config| TransportConfig {
+            wire_config,
+            tag_wire_format: true,
+            ..Default::default()
+        };
+        let package = |config: &TransportConfig| {
+            config
+                .encode_package(
+                    &HelloWorldRpcName::GetI,
+                    PackageKind::Query,
+                    &[9],
+                    0,
+                    None,
+                    &Metadata::new(),
+                )
+                .unwrap()
+        };
+        let json = package(&client_config(TransportWireConfig::Json));
+        let pickle = package(&client_config(TransportWireConfig::pickle()));
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![json.clone(), pickle, json].into(),
+                ..Default::default()
+            },
+            TransportConfig {
+                accepted_wire_configs: vec![TransportWireConfig::Json],
+                ..client_config(TransportWireConfig::pickle())
+            },
+        );
+        assert!(server.receive_query().await.is_ok());
+        assert_eq!("json", server.config.wire_config.wire_config_name());
+        assert!(matches!(
+            server.receive_query().await,
+            Err(RpcError::TransportError(TransportError::DeserialiseError(
+                _
+            )))
+        ));
+        assert!(server.receive_query().await.is_ok());
+    }
+
+    #[test]
+    fn unaccepted_wire_format_tag_rejected() {
+        let config = TransportConfig {
+            tag_wire_format: true,
+            ..Default::default()
+        };
+        let package_bytes = config.tag_package(b"package".to_vec()).unwrap();
+        assert_eq!(config.wire_config.tag(), Some(package_bytes[0]));
+        let (wire_config, untagged) = config.untag_package(&package_bytes).unwrap();
+        assert_eq!("pickle", wire_config.wire_config_name());
+        assert_eq!(b"package", untagged);
+        assert!(matches!(
+            config.untag_package(&[200, 1, 2]),
+            Err(TransportError::DeserialiseError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn utf8_name_from_foreign_client() {
+        let config = TransportConfig {
+            name_encoding: NameEncoding::Utf8String,
+            ..Default::default()
+        };
+        // What e.g. Python would send: struct.pack(">H", 4) + b"GetI" + the package
+        let mut frame = vec![0u8, 4];
+        frame.extend_from_slice("GetI".as_bytes());
+        let package_bytes = config
+            .wire_config
+            .serialize(&TransportPackage {
+                correlation_id: 0,
+                kind: PackageKind::Query,
+                time_remaining: None,
+                metadata: Cow::default(),
+                name_bytes: &[],
+                query_bytes: &[9],
+            })
+            .unwrap();
+        frame.extend_from_slice(&package_bytes);
+
+        let unknown_frame = [&[0u8, 3][..], "Arr".as_bytes(), &package_bytes].concat();
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![frame, unknown_frame].into(),
+                ..Default::default()
+            },
+            config,
+        );
+        let received = server.receive_query().await.unwrap();
+        assert_eq!(HelloWorldRpcName::GetI, received.name);
+        assert_eq!(vec![9], received.query_bytes);
+
+        match server.receive_query().await {
+            Err(RpcError::UnknownRpc(s)) => assert_eq!("Arr", s),
+            Err(e) => panic!("Expected UnknownRpc, got {}", e),
+            Ok(_) => panic!("Expected UnknownRpc, got a query"),
+        }
+    }
+
+    #[tokio::test]
+    async fn utf8_name_round_trip() {
+        let config = TransportConfig {
+            name_encoding: NameEncoding::Utf8String,
+            ..Default::default()
+        };
+        let mut client: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: vec![empty_response(&config.wire_config)].into(),
+                ..Default::default()
+            },
+            config.clone(),
+        );
+        client
+            .send_query(&[1, 2], &HelloWorldRpcName::IncrI)
+            .await
+            .unwrap();
+        assert!(client.internal_transport().sent[0].starts_with(b"\x00\x05IncrI"));
+
+        let mut server: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: client.internal_transport().sent.clone().into(),
+                ..Default::default()
+            },
+            config,
+        );
+        let received = server.receive_query().await.unwrap();
+        assert_eq!(HelloWorldRpcName::IncrI, received.name);
+        assert_eq!(vec![1, 2], received.query_bytes);
+    }
+
+    /// Accepts at most [max_per_write] bytes per write, and nothing once [capacity] is reached.
+    /// Counts the [writes] it's given
+    struct TrickleWriter {
+        written: Vec<u8>,
+        writes: usize,
+        max_per_write: usize,
+        capacity: usize,
+    }
+    impl tokio::io::AsyncWrite for TrickleWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let space = self.capacity - self.written.len();
+            let n = buf.len().min(self.max_per_write).min(space);
+            self.written.extend_from_slice(&buf[..n]);
+            self.writes += 1;
+            std::task::Poll::Ready(Ok(n))
+        }
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn send_all_handles_short_writes() {
+        let frame: Vec<u8> = (0..=255).collect();
+        let mut writer = TrickleWriter {
+            written: Vec::new(),
+            writes: 0,
+            max_per_write: 3,
+            capacity: usize::MAX,
+        };
+        send_all(&mut writer, &[&frame[..100], &frame[100..]])
+            .await
+            .unwrap();
+        assert_eq!(frame, writer.written);
+
+        let mut full_writer = TrickleWriter {
+            written: Vec::new(),
+            writes: 0,
+            max_per_write: 3,
+            capacity: 10,
+        };
+        match send_all(&mut full_writer, &[&frame]).await {
+            Err(TransportError::SendError(s)) => assert!(s.contains("10 of 256"), "{}", s),
+            other => panic!("Expected SendError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_written_together_read_in_order() {
+        let messages: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; i as usize + 1]).collect();
+        let messages: Vec<Bytes> = messages.iter().map(|message| &message[..]).collect();
+        let mut writer = TrickleWriter {
+            written: Vec::new(),
+            writes: 0,
+            max_per_write: usize::MAX,
+            capacity: usize::MAX,
+        };
+        FrameWriter::default()
+            .write_frames(&mut writer, &messages)
+            .await
+            .unwrap();
+        assert_eq!(1, writer.writes);
+
+        let mut frames = FrameReader::default();
+        let mut reader = &writer.written[..];
+        for message in messages {
+            assert_eq!(message, frames.read_frame(&mut reader, None).await.unwrap());
+        }
+        assert!(matches!(
+            frames.read_frame(&mut reader, None).await,
+            Err(TransportError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tcp_frames_round_trip_multi_kilobyte() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Exact multiples of the old 1024 byte read buffer, and sizes TCP will fragment
+        let sizes = [1, 1024, 4096, 8 * 1024 + 7, 256 * 1024];
+        let echo = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = TcpTransport::new(stream);
+            for _ in sizes {
+                let bytes = server.receive(None).await.unwrap();
+                server.send(&bytes).await.unwrap();
+            }
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut client = TcpTransport::new(stream);
+        for size in sizes {
+            let message: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let response = client
+                .send_and_wait_for_response(&message, Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(message, response, "size {}", size);
+        }
+        echo.await.unwrap();
+    }
+
+    #[test]
+    fn sectioned_package_leaves_query_in_place() {
+        let config = TransportConfig {
+            sectioned_packages: true,
+            ..Default::default()
+        };
+        let query_bytes = vec![7u8; 1024];
+        let package = config
+            .encode_package_parts(
+                &HelloWorldRpcName::GetI,
+                PackageKind::Query,
+                &query_bytes,
+                5,
+                None,
+                NO_METADATA,
+            )
+            .unwrap();
+        let [head, query] = package.parts();
+        assert!(std::ptr::eq(query, &query_bytes[..]));
+        let (header_bytes, rest) = split_section(head).unwrap();
+        let (name_bytes, rest) = split_section(rest).unwrap();
+        assert!(rest.is_empty());
+        let header: PackageHeader = config.wire_config.deserialize(header_bytes).unwrap();
+        assert_eq!(5, header.correlation_id);
+        let name: HelloWorldRpcName = config.wire_config.deserialize(name_bytes).unwrap();
+        assert_eq!(HelloWorldRpcName::GetI, name);
+
+        // Without sections, the query is copied into the package
+        let package = TransportConfig::default()
+            .encode_package_parts(
+                &HelloWorldRpcName::GetI,
+                PackageKind::Query,
+                &query_bytes,
+                5,
+                None,
+                NO_METADATA,
+            )
+            .unwrap();
+        assert!(package.parts()[1].is_empty());
+    }
+
+    #[tokio::test]
+    async fn sectioned_packages_round_trip_over_tcp() {
+        let config = TransportConfig {
+            sectioned_packages: true,
+            metadata: Metadata::from([(String::from("tenant"), String::from("ankh"))]),
+            ..Default::default()
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_config = config.clone();
+        let echo = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server: Transport<_, HelloWorldRpcName> =
+                Transport::new(TcpTransport::new(stream), server_config);
+            let received = server.receive_query().await.unwrap();
+            server
+                .respond(received.correlation_id, &received.query_bytes)
+                .await
+                .unwrap();
+            received
+        });
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut client: Transport<_, HelloWorldRpcName> =
+            Transport::new(TcpTransport::new(stream), config);
+        let query_bytes: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let response = client
+            .send_query(&query_bytes, &HelloWorldRpcName::MassiveRpc)
+            .await
+            .unwrap();
+
+        assert_eq!(query_bytes, response);
+        let received = echo.await.unwrap();
+        assert_eq!(HelloWorldRpcName::MassiveRpc, received.name);
+        assert_eq!(PackageKind::Query, received.kind);
+        assert_eq!(
+            Some("ankh"),
+            received.metadata.get("tenant").map(String::as_str)
+        );
+        assert!(received.deadline.is_some());
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_distinct_errors() {
+        let read_frame = |mut reader: &'static [u8]| async move {
+            FrameReader::default().read_frame(&mut reader, None).await
+        };
+        assert!(matches!(
+            read_frame(&[]).await,
+            Err(TransportError::ConnectionClosed)
+        ));
+        assert!(matches!(
+            read_frame(&[0, 0, 0, 0]).await,
+            Err(TransportError::ZeroLengthFrame)
+        ));
+        assert!(matches!(
+            read_frame(&[0, 0]).await,
+            Err(TransportError::TruncatedHeader {
+                expected: 4,
+                got: 2
+            })
+        ));
+        assert!(matches!(
+            read_frame(&[0, 0, 0, 10, 1, 2, 3]).await,
+            Err(TransportError::TruncatedFrame {
+                expected: 10,
+                got: 3
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_refused_from_header() {
+        let mut frames = FrameReader::with_config(&TransportConfig {
+            max_inbound_message_size: Some(4),
+            ..Default::default()
+        });
+        let mut reader: &[u8] = &[0, 0, 0, 10, 1, 2];
+        assert!(matches!(
+            frames.read_frame(&mut reader, None).await,
+            Err(TransportError::MessageTooLarge(10, 4))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cancelled_frame_read_resumes() {
+        use tokio::io::AsyncWriteExt;
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let mut frames = FrameReader::default();
+        writer.write_all(&[0, 0, 0, 5, 1, 2]).await.unwrap();
+        assert!(matches!(
+            frames
+                .read_frame(&mut reader, Some(Duration::from_millis(10)))
+                .await,
+            Err(TransportError::ReceiveTimeout(_))
+        ));
+        // The rest of the frame, and all of the next, arriving in one read
+        writer.write_all(&[3, 4, 5, 0, 0, 0, 1, 6]).await.unwrap();
+        assert_eq!(
+            vec![1, 2, 3, 4, 5],
+            frames.read_frame(&mut reader, None).await.unwrap()
+        );
+        assert_eq!(vec![6], frames.read_frame(&mut reader, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stalled_frame_fails_idle_read_timeout() {
+        use tokio::io::AsyncWriteExt;
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let idle_read_timeout = Duration::from_millis(10);
+        let mut frames = FrameReader::with_config(&TransportConfig {
+            idle_read_timeout: Some(idle_read_timeout),
+            ..Default::default()
+        });
+        // Waiting for a frame to start isn't a stall
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(30),
+            frames.read_frame(&mut reader, None),
+        );
+        assert!(waiting.await.is_err());
+        writer.write_all(&[0, 0, 0, 5, 1, 2]).await.unwrap();
+        match frames.read_frame(&mut reader, None).await {
+            Err(TransportError::ReceiveTimeout(timeout)) => assert_eq!(idle_read_timeout, timeout),
+            other => panic!("Expected ReceiveTimeout, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn trickled_frame_fails_idle_read_timeout() {
+        use tokio::io::AsyncWriteExt;
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let idle_read_timeout = Duration::from_millis(50);
+        let mut frames = FrameReader::with_config(&TransportConfig {
+            idle_read_timeout: Some(idle_read_timeout),
+            ..Default::default()
+        });
+        // Never stalling for the timeout, but taking far longer over the whole frame
+        let trickle = async move {
+            for byte in [0, 0, 0, 10, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10] {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                if writer.write_all(&[byte]).await.is_err() {
+                    return;
+                }
+            }
+        };
+        let (read, ()) = tokio::join!(
+            async {
+                let read = frames.read_frame(&mut reader, None).await;
+                drop(reader);
+                read
+            },
+            trickle
+        );
+        match read {
+            Err(TransportError::ReceiveTimeout(timeout)) => assert_eq!(idle_read_timeout, timeout),
+            other => panic!("Expected ReceiveTimeout, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_to_peer_not_reading_fails_send_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut transport = TcpTransport::new(connect_tcp(addr, None).await.unwrap());
+        // Accepted, but never read from
+        let (_not_reading, _) = listener.accept().await.unwrap();
+        transport.configure(&TransportConfig {
+            send_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        // Far more than the socket buffers hold
+        let message = vec![0u8; 64 * 1024 * 1024];
+        match transport.send(&message).await {
+            Err(TransportError::SendError(s)) => assert!(s.contains("Timed out"), "{}", s),
+            other => panic!("Expected SendError, got {:?}", other),
+        }
+        // Part of it may have gone, so nothing more can be sent after it
+        assert!(!transport.is_healthy());
+        match transport.send(b"hello").await {
+            Err(TransportError::SendError(s)) => assert!(s.contains("broken"), "{}", s),
+            other => panic!("Expected SendError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_fails_connect_timeout() {
+        let connect_timeout = Some(Duration::from_millis(10));
+        let never_connects = std::future::pending::<Result<(), TransportError>>();
+        match within_connect_timeout(connect_timeout, never_connects).await {
+            Err(TransportError::ConnectError(s)) => assert!(s.contains("Timed out"), "{}", s),
+            other => panic!("Expected ConnectError, got {:?}", other),
+        }
+    }
+
+    /// Counts the reads taken to hand over [bytes]
+    struct CountingReader {
+        bytes: Vec<u8>,
+        reads: usize,
+    }
+    impl tokio::io::AsyncRead for CountingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.reads += 1;
+            let n = buf.remaining().min(self.bytes.len());
+            buf.put_slice(&self.bytes[..n]);
+            self.bytes.drain(..n);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn small_frames_read_together_per_read_buffer_size() {
+        let bytes: Vec<u8> = (0..100u8).flat_map(|i| [0, 0, 0, 1, i]).collect();
+        let mut reads = Vec::new();
+        for read_buffer_size in [DEFAULT_READ_BUFFER_SIZE, 1] {
+            let mut reader = CountingReader {
+                bytes: bytes.clone(),
+                reads: 0,
+            };
+            let mut frames = FrameReader::with_config(&TransportConfig {
+                read_buffer_size,
+                ..Default::default()
+            });
+            for i in 0..100u8 {
+                assert_eq!(vec![i], frames.read_frame(&mut reader, None).await.unwrap());
+            }
+            reads.push(reader.reads);
+        }
+        assert_eq!(1, reads[0]);
+        assert!(reads[1] > 50, "{:?}", reads);
+    }
+
+    #[tokio::test]
+    async fn query_stream_ends_on_close() {
+        use futures_util::StreamExt;
+        let wire_config = TransportWireConfig::default();
+        let name_bytes = wire_config.serialize(&HelloWorldRpcName::GetI).unwrap();
+        let packages = (0u8..3)
+            .map(|i| {
+                wire_config
+                    .serialize(&TransportPackage {
+                        correlation_id: 0,
+                        kind: PackageKind::Query,
+                        time_remaining: None,
+                        metadata: Cow::default(),
+                        name_bytes: &name_bytes,
+                        query_bytes: &[i],
+                    })
+                    .unwrap()
+            })
+            .collect();
+        let transport: Transport<_, HelloWorldRpcName> = Transport::new(
+            QueuedTestingTransport {
+                to_receive: packages,
+                ..Default::default()
+            },
+            Default::default(),
+        );
+
+        let queries: Vec<_> = transport.into_query_stream().collect().await;
+        let query_bytes: Vec<_> = queries
+            .into_iter()
+            .map(|query| query.unwrap().query_bytes)
+            .collect();
+        assert_eq!(vec![vec![0], vec![1], vec![2]], query_bytes);
+    }
+
+    #[test]
+    fn default_wire_config_is_pickle() {
+        assert_eq!("pickle", TransportWireConfig::default().wire_config_name());
+        assert_eq!(
+            "pickle",
+            TransportConfig::default().wire_config.wire_config_name()
+        );
+    }
+
+    #[tokio::test]
+    async fn large_query_deserialized_on_blocking_pool() {
+        let config = TransportConfig {
+            deserialize_on_blocking_pool: true,
+            blocking_deserialize_threshold: 1024,
+            ..Default::default()
+        };
+        let query = "Arr".repeat(100_000);
+        let name_bytes = config
+            .wire_config
+            .serialize(&HelloWorldRpcName::HelloWorld)
+            .unwrap();
+        let query_bytes = config.wire_config.serialize(&query).unwrap();
+        let package_bytes = config
+            .wire_config
+            .serialize(&TransportPackage {
+                correlation_id: 0,
+                kind: PackageKind::Query,
+                time_remaining: None,
+                metadata: Cow::default(),
+                name_bytes: &name_bytes,
+                query_bytes: &query_bytes,
+            })
+            .unwrap();
+        assert!(config.use_blocking_pool_for(package_bytes.len()));
+
+        let internal_transport = QueuedTestingTransport {
+            to_receive: vec![package_bytes].into(),
+            ..Default::default()
+        };
+        let mut transport: Transport<_, HelloWorldRpcName> =
+            Transport::new(internal_transport, config);
+        let received = transport.receive_query().await.unwrap();
+        let received_query: String = transport
+            .config
+            .wire_config
+            .deserialize(&received.query_bytes)
+            .unwrap();
+
+        assert_eq!(HelloWorldRpcName::HelloWorld, received.name);
+        assert_eq!(query, received_query);
+    }
+}
+
+/// Prefix [bytes] with their length as a big-endian u16
+fn length_prefixed(bytes: Bytes) -> Result<OwnedBytes, TransportError> {
+    let len = u16::try_from(bytes.len()).map_err(|_| {
+        SerialiseError(format!(
+            "{} bytes is too long to length prefix",
+            bytes.len()
+        ))
+    })?;
+    let mut prefixed = Vec::with_capacity(2 + bytes.len());
+    prefixed.extend_from_slice(&len.to_be_bytes());
+    prefixed.extend_from_slice(bytes);
+    Ok(prefixed)
+}
+
+/// Remove a big-endian u16 length prefixed section from the front of [bytes] and return it
+fn split_length_prefixed(bytes: &mut OwnedBytes) -> Result<OwnedBytes, TransportError> {
+    let section = split_section(bytes)?.0.to_vec();
+    bytes.drain(..2 + section.len());
+    Ok(section)
+}
+
+/// [metadata] as the tail of one of the fixed requests a server answers itself, see
+/// [ServerRequest]: each key and value length prefixed, so it reads the same whatever the
+/// codec, and nothing at all for none
+fn encode_request_metadata(metadata: &Metadata) -> Result<OwnedBytes, TransportError> {
+    let mut encoded = Vec::new();
+    for (key, value) in metadata {
+        encoded.extend(length_prefixed(key.as_bytes())?);
+        encoded.extend(length_prefixed(value.as_bytes())?);
+    }
+    Ok(encoded)
+}
+
+/// The [Metadata] sent on the end of a fixed request, see [encode_request_metadata]
+pub(crate) fn decode_request_metadata(mut bytes: Bytes) -> Result<Metadata, TransportError> {
+    let mut metadata = Metadata::new();
+    let utf8 = |section: Bytes| {
+        String::from_utf8(section.to_vec())
+            .map_err(|e| DeserialiseError(format!("request metadata: {}", e)))
+    };
+    while !bytes.is_empty() {
+        let (key, rest) = split_section(bytes)?;
+        let (value, rest) = split_section(rest)?;
+        metadata.insert(utf8(key)?, utf8(value)?);
+        bytes = rest;
+    }
+    Ok(metadata)
+}
+
+/// The key and [Metadata] of a reverse request, following [REVERSE], see
+/// [Transport::request_reverse]
+pub(crate) fn decode_reverse_request(bytes: Bytes) -> Result<(String, Metadata), TransportError> {
+    let (key, metadata) = split_section(bytes)?;
+    let key = String::from_utf8(key.to_vec())
+        .map_err(|e| DeserialiseError(format!("reverse key: {}", e)))?;
+    Ok((key, decode_request_metadata(metadata)?))
+}
+
+/// Split a big-endian u16 length prefixed section off the front of [bytes], giving the
+/// section and what follows it
+fn split_section(bytes: Bytes) -> Result<(Bytes, Bytes), TransportError> {
+    if bytes.len() < 2 {
+        return Err(TransportError::DeserialiseError(String::from(
+            "missing length prefix",
+        )));
+    }
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    if bytes.len() < 2 + len {
+        return Err(TransportError::DeserialiseError(format!(
+            "length prefix of {} but only {} bytes follow",
+            len,
+            bytes.len() - 2
+        )));
+    }
+    Ok(bytes[2..].split_at(len))
+}
+
+/// The initial structure handed to the RpcServer, which includes
+/// [deadline], when the caller will give up waiting for the response as measured by
+/// [TransportConfig::clock], if it said, and
+/// [identity], who sent it, once a [crate::Authentication] interceptor has checked, and
+/// [connection_id], telling apart the connections it might have come in on, see
+/// [Transport::connection_id], and
+/// [peer_addr], where it came from, for transports that know, see
+/// [InternalTransport::peer_addr], and
+/// [peer_certificate], the one it came with, for TLS transports asking for one, see
+/// [InternalTransport::peer_certificate], and
+/// [wire_format], the [TransportWireConfig::wire_config_name] of the codec it came in, and
+/// [session], that of the connection it came in on, see [Transport::session]
+pub struct ReceivedQuery<Name: RpcName> {
+    pub correlation_id: u64,
+    pub kind: PackageKind,
+    pub name: Name,
+    pub query_bytes: OwnedBytes,
+    pub deadline: Option<Instant>,
+    pub metadata: Metadata,
+    pub identity: Option<Identity>,
+    pub connection_id: u64,
+    pub peer_addr: Option<std::net::SocketAddr>,
+    pub peer_certificate: Option<Arc<OwnedBytes>>,
+    pub wire_format: &'static str,
+    pub session: Session,
+    /// Cancelled once the caller cancels the call, see [crate::call_cancellation]
+    pub cancellation: CancellationToken,
+}
+
+impl<Name: RpcName> ReceivedQuery<Name> {
+    /// Who sent it and what with, as handlers see in [crate::call_context], less the server's
+    /// [CallContext::extensions]
+    pub fn context(&self) -> CallContext {
+        CallContext {
+            connection_id: self.connection_id,
+            peer_addr: self.peer_addr,
+            peer_certificate: self.peer_certificate.clone(),
+            identity: self.identity.clone(),
+            wire_format: self.wire_format,
+            deadline: self.deadline,
+            metadata: self.metadata.clone(),
+            session: self.session.clone(),
+            extensions: Arc::default(),
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<Name: RpcName> ReceivedQuery<Name> {
+    /// A query as if just received for [name], with nothing but [query_bytes]
+    pub(crate) fn query(name: Name, query_bytes: OwnedBytes) -> Self {
+        Self {
+            correlation_id: 0,
+            kind: PackageKind::Query,
+            name,
+            query_bytes,
+            deadline: None,
+            metadata: Metadata::new(),
+            identity: None,
+            connection_id: 0,
+            peer_addr: None,
+            peer_certificate: None,
+            wire_format: TransportWireConfig::default().wire_config_name(),
+            session: Session::new(),
+            cancellation: CancellationToken::new(),
+        }
+    }
+}
+
+/// Transport for data betweeen client and server, generic over the rpc names and internal transport
+/// The majority of the heavy lifting is done by the [internal_transport], see the definition of
+/// the [InternalTransport] trait for more information
+pub struct Transport<I, Name> {
+    internal_transport: I,
+    name: PhantomData<Name>,
+    bandwidth: Option<ByteBucket>,
+    interceptors: Vec<Arc<dyn ClientInterceptor<Name>>>,
+    connection_id: u64,
+    session: Session,
+    /// Whether the codec's been picked from the first package, see
+    /// [TransportConfig::tag_wire_format]
+    wire_config_detected: bool,
+    /// Notifications held back, see [TransportConfig::coalesce_notifications]
+    coalesced: CoalescedSends,
+    pub config: TransportConfig,
+}
+
+/// Source of [Transport::connection_id]s
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// TODO: Consider making transport Connected/Disconnected
+/*
+pub struct ConnectedTransport<I, Name> {
+    transport: Transport<I, Name>
+}
+ */
+
+/// TransportConfig defines various config options for transport handling
+/// [rcv_timeout] is used to protect receiving with a timeout
+/// [wire_config] is for serialising sent data, see the type def for more
+/// [deserialize_on_blocking_pool] moves deserialising of received packages larger than
+/// [blocking_deserialize_threshold] bytes onto tokio's blocking pool, see below
+///
+/// Deserialising (pickle in particular) is CPU bound, so a large query decoded on the async
+/// executor stalls every other task on that thread. Handing it to [tokio::task::spawn_blocking]
+/// keeps the reactor responsive, but costs a thread hand-off per package, so small packages are
+/// always decoded inline. The threshold should sit where decode time starts to outweigh that
+/// hand-off: the default of 64KiB is a reasonable starting point, lower it if you see latency
+/// spikes on other connections while big queries arrive, raise it if most of your queries are
+/// just above it and throughput matters more than latency.
+///
+/// [bandwidth_limit] caps the bytes per second each [Transport] sends and receives. Once the
+/// budget is used up, sends and receives pause until it refills rather than erroring, so slow
+/// but legitimate peers aren't dropped. Up to one second's worth of budget can build up while
+/// a connection is quiet
+///
+/// [name_encoding] picks how the rpc name is put on the wire, see [NameEncoding]
+/// [clock] is used to measure elapsed time, e.g. reported in [RpcError::RpcTimeout]
+/// [stream_item_timeout] limits the wait for each response of a [crate::StreamRpc]. It's None,
+/// waiting indefinitely, by default as streams like log tails can go quiet for a long time
+/// [compression] compresses large packages, see [CompressionConfig]. Both ends must agree
+/// on whether it's on, as it adds a flag byte to every package
+/// [handshake] has each new connection start with both ends swapping their protocol version
+/// and wire formats, failing with [TransportError::IncompatibleVersion] if they don't match
+/// rather than with a confusing error decoding the first package. Both ends must agree on
+/// whether it's on. It's off by default so clients that predate it can still connect
+/// [tag_wire_format] puts the [TransportWireConfig::tag] of the codec used ahead of every
+/// package, so a server can take queries in any of [wire_config] and [accepted_wire_configs],
+/// each connection in the codec its first package came in. One server can then serve pickle,
+/// postcard and JSON clients at once. Both ends must agree on whether it's on, while only the
+/// server needs [accepted_wire_configs]
+/// [metadata] is sent with every query, see [Metadata]
+/// [retry_policy] retries failed calls of idempotent rpcs, see [RetryPolicy]. None by default
+/// [keepalive] pings the server over quiet connections, see [KeepaliveConfig]. None by default
+/// [max_inbound_message_size] is the most bytes a message received can be, so a peer can't
+/// have us buffer as much as it likes. Framed transports check the length header and fail
+/// with [TransportError::MessageTooLarge] before reading the message in, and the connection
+/// is dropped. A compressed package is also failed once it decompresses to more than this.
+/// It's [DEFAULT_MAX_MESSAGE_SIZE] by default, and applies to a [Transport] from when it's made
+/// [max_outbound_message_size] fails sending a larger message with
+/// [TransportError::MessageTooLarge] before any of it is sent. A server response over it is
+/// replaced with that error. None, no limit, by default
+/// [sectioned_packages] lays each package out in sections: the rest of the package, then the
+/// name, each behind its length, then the query bytes as they are, rather than serialising
+/// the name and query bytes into the package along with the rest. The query is then sent
+/// from where it was serialised with [InternalTransport::send_vectored], rather than copied
+/// into the package and then again into the frame, which adds up for large queries. Both
+/// ends must agree on whether it's on. It's off by default, as peers building packages with
+/// their codec, e.g. Python, can't read it
+/// [buffer_pool] has messages received and responses sent go through buffers reused from it,
+/// rather than each allocated afresh, see [BufferPool]. None by default. Like
+/// [max_inbound_message_size], it applies to a [Transport] from when it's made
+/// [read_buffer_size] is the least room the stream transports read into at a time. Many small
+/// messages arriving together are then taken in one read, while a bigger message is read with
+/// room for all of it. Lower it from [DEFAULT_READ_BUFFER_SIZE] for small embedded targets, or
+/// raise it for throughput of many mid-sized messages. It applies from when a [Transport]'s
+/// made, like [max_inbound_message_size], which caps what a message received can be
+/// [idle_read_timeout] fails a receive with [TransportError::ReceiveTimeout] when a message
+/// isn't all there that long after its first byte, so a peer that stalls mid-message, or
+/// sends one a byte at a time, can't hold a connection, and a server drops it. Waiting for a
+/// message to start is left to [rcv_timeout] and [crate::ServerConfig::idle_timeout]. None by
+/// default, and it applies from when a [Transport]'s made
+/// [send_timeout] fails a send with [TransportError::SendError] when the peer won't take the
+/// message for that long, e.g. as it's stopped reading and the socket buffers are full, so a
+/// hung peer can't block the sender forever. Part of the message may have gone, so the
+/// connection can't be used after. None by default, and it applies from when a [Transport]'s
+/// made
+/// [connect_timeout] fails connecting with [TransportError::ConnectError] when it takes that
+/// long, for the helpers connecting from a config, e.g. [crate::connect_tcp_transport] and
+/// [crate::MultiplexedClient::connect], and each attempt of a [crate::ReconnectingTransport].
+/// None by default, leaving it to the OS
+/// [starvation_limit] is how many calls queued behind higher [crate::Priority] ones one can
+/// see go ahead of it before it goes next whatever its priority, so a steady stream of
+/// urgent calls can't hold the rest up forever
+/// [max_queued_sends] is how many queries a [crate::MultiplexedClient] has waiting to be
+/// written at once. A call past it waits for room, within its [rcv_timeout], so callers
+/// faster than the connection can't queue up without bound
+/// [max_in_flight], if set, is how many calls a [crate::MultiplexedClient] has waiting on a
+/// response at once. A call past it waits for one to finish, within its [rcv_timeout], before
+/// it's sent, see [crate::MultiplexedClient::in_flight]
+/// [schema] is checked against the server's, for the helpers connecting from a config, once
+/// they've handshaken, see [Transport::verify_schema]. None by default
+/// [coalesce_notifications] holds notifications back to write bursts of them together, see
+/// [CoalesceConfig], delaying each by up to its [CoalesceConfig::linger]. None, writing each
+/// as it's made, by default
+#[derive(Clone, Debug)]
+pub struct TransportConfig {
+    pub rcv_timeout: Duration,
+    pub stream_item_timeout: Option<Duration>,
+    pub wire_config: TransportWireConfig,
+    pub deserialize_on_blocking_pool: bool,
+    pub blocking_deserialize_threshold: usize,
+    pub bandwidth_limit: Option<BytesPerSecond>,
+    pub name_encoding: NameEncoding,
+    pub clock: Arc<dyn Clock>,
+    pub compression: Option<CompressionConfig>,
+    pub handshake: bool,
+    pub tag_wire_format: bool,
+    pub accepted_wire_configs: Vec<TransportWireConfig>,
+    pub metadata: Metadata,
+    pub retry_policy: Option<RetryPolicy>,
+    pub max_inbound_message_size: Option<usize>,
+    pub max_outbound_message_size: Option<usize>,
+    pub keepalive: Option<KeepaliveConfig>,
+    pub sectioned_packages: bool,
+    pub buffer_pool: Option<BufferPool>,
+    pub read_buffer_size: usize,
+    pub idle_read_timeout: Option<Duration>,
+    pub send_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub starvation_limit: usize,
+    pub max_queued_sends: usize,
+    pub max_in_flight: Option<usize>,
+    pub schema: Option<Schema>,
+    pub coalesce_notifications: Option<CoalesceConfig>,
+}
+
+/// Least room [TransportConfig::read_buffer_size] reads into by default
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Largest message [TransportConfig::max_inbound_message_size] lets through by default
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            rcv_timeout: Duration::from_secs(3),
+            stream_item_timeout: None,
+            wire_config: TransportWireConfig::default(),
+            deserialize_on_blocking_pool: false,
+            blocking_deserialize_threshold: 64 * 1024,
+            bandwidth_limit: None,
+            name_encoding: NameEncoding::default(),
+            clock: Arc::new(SystemClock),
+            compression: None,
+            handshake: false,
+            tag_wire_format: false,
+            accepted_wire_configs: Vec::new(),
+            metadata: Metadata::new(),
+            retry_policy: None,
+            max_inbound_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            max_outbound_message_size: None,
+            keepalive: None,
+            sectioned_packages: false,
+            buffer_pool: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            idle_read_timeout: None,
+            send_timeout: None,
+            connect_timeout: None,
+            starvation_limit: 16,
+            max_queued_sends: 1024,
+            max_in_flight: None,
+            schema: None,
+            coalesce_notifications: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Fail with [TransportError::MessageTooLarge] if a message of [num_bytes] is over
+    /// [max_outbound_message_size]
+    pub(crate) fn check_outbound_size(&self, num_bytes: usize) -> Result<(), TransportError> {
+        match self.max_outbound_message_size {
+            Some(max) if num_bytes > max => Err(TransportError::MessageTooLarge(num_bytes, max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// An empty buffer with room for [capacity] bytes, from [buffer_pool] if there is one
+    pub(crate) fn take_buffer(&self, capacity: usize) -> OwnedBytes {
+        match &self.buffer_pool {
+            Some(buffer_pool) => buffer_pool.take(capacity),
+            None => Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Give [buffer] back to [buffer_pool], if there is one, now it's done with
+    pub(crate) fn give_back_buffer(&self, buffer: OwnedBytes) {
+        if let Some(buffer_pool) = &self.buffer_pool {
+            buffer_pool.give_back(buffer);
+        }
+    }
+
+    fn use_blocking_pool_for(&self, num_bytes: usize) -> bool {
+        self.deserialize_on_blocking_pool && num_bytes > self.blocking_deserialize_threshold
+    }
+
+    /// Every codec packages are taken in: [wire_config], then with [tag_wire_format] on,
+    /// [accepted_wire_configs]
+    pub(crate) fn receivable_wire_configs(&self) -> Vec<&TransportWireConfig> {
+        let accepted = self
+            .accepted_wire_configs
+            .iter()
+            .filter(|_| self.tag_wire_format);
+        std::iter::once(&self.wire_config).chain(accepted).collect()
+    }
+
+    /// Put [wire_config]'s tag ahead of [package_bytes], if [tag_wire_format] is on
+    fn tag_package(&self, mut package_bytes: OwnedBytes) -> Result<OwnedBytes, TransportError> {
+        if self.tag_wire_format {
+            let tag = self.wire_config.tag().ok_or_else(|| {
+                SerialiseError(format!(
+                    "wire format {} has no tag",
+                    self.wire_config.wire_config_name()
+                ))
+            })?;
+            package_bytes.insert(0, tag);
+        }
+        Ok(package_bytes)
+    }
+
+    /// Split the tag off [bytes] if [tag_wire_format] is on, returning the codec it names
+    /// along with the rest of the package
+    fn untag_package<'a>(
+        &'a self,
+        bytes: Bytes<'a>,
+    ) -> Result<(&'a TransportWireConfig, Bytes<'a>), TransportError> {
+        if !self.tag_wire_format {
+            return Ok((&self.wire_config, bytes));
+        }
+        let (tag, package_bytes) = bytes
+            .split_first()
+            .ok_or_else(|| DeserialiseError(String::from("missing wire format tag")))?;
+        let wire_config = self
+            .receivable_wire_configs()
+            .into_iter()
+            .find(|wire_config| wire_config.tag() == Some(*tag))
+            .ok_or_else(|| DeserialiseError(format!("wire format tag {} not accepted", tag)))?;
+        Ok((wire_config, package_bytes))
+    }
+
+    fn encode_name<Name: RpcName>(&self, rpc_name: &Name) -> RpcResult<OwnedBytes> {
+        match self.name_encoding {
+            NameEncoding::Codec => self.wire_config.serialize(rpc_name),
+            NameEncoding::Opcode => match rpc_name.opcode() {
+                Some(opcode) => Ok(opcode.to_be_bytes().to_vec()),
+                None => Err(SerialiseError(String::from("no opcode"))),
+            },
+            NameEncoding::Utf8String => Ok(format!("{}", rpc_name).into_bytes()),
+        }
+        .map_err(|e| e.in_step(format_args!("rpc name {}", rpc_name)).into())
+    }
+
+    /// Serialise the package for a query, with the name encoded per [name_encoding]
+    pub(crate) fn encode_package<Name: RpcName>(
+        &self,
+        rpc_name: &Name,
+        kind: PackageKind,
+        query_bytes: Bytes<'_>,
+        correlation_id: u64,
+        time_remaining: Option<Duration>,
+        metadata: &Metadata,
+    ) -> RpcResult<OwnedBytes> {
+        self.encode_package_parts(
+            rpc_name,
+            kind,
+            query_bytes,
+            correlation_id,
+            time_remaining,
+            metadata,
+        )
+        .map(PackageParts::into_contiguous)
+    }
+
+    /// As [encode_package], but leaving the package in parts to send with
+    /// [InternalTransport::send_vectored]. Compressed packages are put together in one part,
+    /// as compression works on the whole package
+    pub(crate) fn encode_package_parts<'a, Name: RpcName>(
+        &self,
+        rpc_name: &Name,
+        kind: PackageKind,
+        query_bytes: Bytes<'a>,
+        correlation_id: u64,
+        time_remaining: Option<Duration>,
+        metadata: &Metadata,
+    ) -> RpcResult<PackageParts<'a>> {
+        let package = if self.sectioned_packages {
+            let header = PackageHeader {
+                correlation_id,
+                kind,
+                time_remaining,
+                metadata: Cow::Borrowed(metadata),
+            };
+            let header_bytes = self
+                .wire_config
+                .serialize(&header)
+                .map_err(|e| e.in_step(format_args!("package header for rpc {}", rpc_name)))?;
+            let mut head = length_prefixed(&header_bytes)?;
+            head.append(&mut length_prefixed(&self.encode_name(rpc_name)?)?);
+            PackageParts { head, query_bytes }
+        } else {
+            PackageParts {
+                head: self.encode_whole_package(
+                    rpc_name,
+                    kind,
+                    query_bytes,
+                    correlation_id,
+                    time_remaining,
+                    metadata,
+                )?,
+                query_bytes: &[],
+            }
+        };
+        let package = match self.compression {
+            Some(_) => PackageParts {
+                head: compress(self.compression.as_ref(), package.into_contiguous())?,
+                query_bytes: &[],
+            },
+            None => package,
+        };
+        let package = PackageParts {
+            head: self.tag_package(package.head)?,
+            ..package
+        };
+        self.check_outbound_size(package.len())?;
+        Ok(package)
+    }
+
+    /// The package with the name and query bytes serialised into it, as laid out without
+    /// [sectioned_packages]
+    fn encode_whole_package<Name: RpcName>(
+        &self,
+        rpc_name: &Name,
+        kind: PackageKind,
+        query_bytes: Bytes<'_>,
+        correlation_id: u64,
+        time_remaining: Option<Duration>,
+        metadata: &Metadata,
+    ) -> RpcResult<OwnedBytes> {
+        let name_bytes = self.encode_name(rpc_name)?;
+        let (name_prefix, package) = match self.name_encoding {
+            NameEncoding::Utf8String => (
+                Some(length_prefixed(&name_bytes)?),
+                TransportPackage {
+                    correlation_id,
+                    kind,
+                    time_remaining,
+                    metadata: Cow::Borrowed(metadata),
+                    name_bytes: &[],
+                    query_bytes,
+                },
+            ),
+            NameEncoding::Codec | NameEncoding::Opcode => (
+                None,
+                TransportPackage {
+                    correlation_id,
+                    kind,
+                    time_remaining,
+                    metadata: Cow::Borrowed(metadata),
+                    name_bytes: &name_bytes,
+                    query_bytes,
+                },
+            ),
+        };
+        let mut package_bytes = self
+            .wire_config
+            .serialize(&package)
+            .map_err(|e| e.in_step(format_args!("package for rpc {}", rpc_name)))?;
+        if let Some(mut name_prefix) = name_prefix {
+            name_prefix.append(&mut package_bytes);
+            package_bytes = name_prefix;
+        }
+        Ok(package_bytes)
+    }
+
+    /// Serialise the envelope answering the query with [correlation_id] with [result], as
+    /// [Transport::respond] or [Transport::respond_error] would send it
+    pub(crate) fn encode_result(
+        &self,
+        correlation_id: u64,
+        result: &RpcResult<OwnedBytes>,
+    ) -> RpcResult<OwnedBytes> {
+        let response = match result {
+            Ok(bytes) => TransportResponse::Ok(bytes),
+            Err(error) => TransportResponse::of_error(error),
+        };
+        self.encode_envelope(correlation_id, response)
+    }
+
+    fn encode_envelope(
+        &self,
+        correlation_id: u64,
+        response: TransportResponse<'_>,
+    ) -> RpcResult<OwnedBytes> {
+        let envelope = TransportResponseEnvelope {
+            correlation_id,
+            response,
+        };
+        // Any buffer will do, as it grows to fit
+        let mut bytes = self.take_buffer(0);
+        self.wire_config
+            .serialize_into(&envelope, &mut bytes)
+            .map_err(|e| e.in_step("response envelope"))?;
+        let bytes = compress(self.compression.as_ref(), bytes)?;
+        Ok(self.tag_package(bytes)?)
+    }
+
+    /// Serialise [batch] behind [BATCH]. The packages or responses in it are already
+    /// compressed as needed, so only the tag is added
+    fn encode_batch(&self, batch: &impl Serialize) -> RpcResult<OwnedBytes> {
+        let batch_bytes = self
+            .wire_config
+            .serialize(batch)
+            .map_err(|e| e.in_step("batch"))?;
+        let mut bytes = BATCH.to_vec();
+        bytes.append(&mut self.tag_package(batch_bytes)?);
+        self.check_outbound_size(bytes.len())?;
+        Ok(bytes)
+    }
+
+    pub(crate) fn decode_response(
+        &self,
+        response_bytes: Bytes<'_>,
+        rpc_name: impl std::fmt::Display,
+    ) -> RpcResult<TransportResponseEnvelopeOwned> {
+        let (wire_config, response_bytes) = self.untag_package(response_bytes)?;
+        let response_bytes = decompress(
+            self.compression.as_ref(),
+            response_bytes,
+            self.max_inbound_message_size,
+        )?;
+        wire_config.deserialize(&response_bytes).map_err(|e| {
+            e.in_step(format_args!("response envelope for rpc {}", rpc_name))
+                .into()
+        })
+    }
+}
+
+/// NameEncoding defines how the rpc name is encoded in the transport package.
+/// [Codec] serialises the name with the [TransportWireConfig] like any other value.
+/// [Opcode] sends the 2 byte [RpcName::opcode] instead, which is much smaller for high frequency
+/// small rpcs, but requires every rpc to have one.
+/// [Utf8String] sends the name's [std::fmt::Display] form ahead of the package, as a big-endian
+/// u16 byte length then the UTF-8 bytes, parsed back with [RpcName::from_utf8_name]. Routing
+/// then doesn't depend on the codec at all, which makes clients in other languages feasible.
+/// Client and server must agree on this
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NameEncoding {
+    #[default]
+    Codec,
+    Opcode,
+    Utf8String,
+}
+
+/// TransportWireConfig defines how to (de)serialise query/response. Extra methods are available by enabling their feature
+/// [Json] is far from compact, with byte fields sent as arrays of numbers, but every package is
+/// plain text, so can be read and written by hand or from any language with a JSON library
+/// [MessagePack] is a compact binary format, far more widely supported outside Python than
+/// pickle. Byte fields are sent as arrays of small integers rather than MessagePack binary
+/// [Cbor] is the standard binary format (RFC 8949) for constrained devices, with small no_std
+/// implementations for microcontrollers. As with MessagePack, byte fields are sent as arrays
+/// [Bincode] is the fastest to (de)serialise, but the format is specific to Rust's serde data
+/// model, so only suits Rust peers. It uses bincode's standard config, with varint integers.
+/// See `benches/wire_formats.rs` for how the codecs compare
+/// [Custom] plugs in any other format, see [WireCodec]
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub enum TransportWireConfig {
+    Pickle(serde_pickle::DeOptions, serde_pickle::SerOptions),
+    #[cfg(feature = "transport_postcard")]
+    Postcard,
+    #[cfg(feature = "transport_json")]
+    Json,
+    #[cfg(feature = "transport_msgpack")]
+    MessagePack,
+    #[cfg(feature = "transport_cbor")]
+    Cbor,
+    #[cfg(feature = "transport_bincode")]
+    Bincode,
+    Custom(Arc<dyn WireCodec>),
+}
+
+// TODO: Handle unwraps here with some sort of [Serialise/DeserialiseError]
+impl TransportWireConfig {
+    /// Pickle with the default [serde_pickle::DeOptions] and [serde_pickle::SerOptions].
+    /// This is also what [TransportWireConfig::default] gives you, prefer spelling it out so
+    /// the codec in use is obvious at the call site. If you are talking to Python, you'll likely
+    /// want to build [TransportWireConfig::Pickle] yourself with options that suit that peer
+    pub fn pickle() -> Self {
+        Self::Pickle(
+            serde_pickle::DeOptions::new(),
+            serde_pickle::SerOptions::new(),
+        )
+    }
+
+    /// Short name of the codec in use, e.g. for logging or asserting which one is active
+    pub fn wire_config_name(&self) -> &'static str {
+        match self {
+            Self::Pickle(_, _) => "pickle",
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard => "postcard",
+            #[cfg(feature = "transport_json")]
+            Self::Json => "json",
+            #[cfg(feature = "transport_msgpack")]
+            Self::MessagePack => "msgpack",
+            #[cfg(feature = "transport_cbor")]
+            Self::Cbor => "cbor",
+            #[cfg(feature = "transport_bincode")]
+            Self::Bincode => "bincode",
+            Self::Custom(codec) => codec.name(),
+        }
+    }
+
+    /// Whether this codec can deserialise byte fields borrowing straight from the input buffer.
+    /// serde_pickle reads the whole input into its own value tree before handing anything to
+    /// serde, so byte fields come out of that as owned buffers, with nothing left to borrow.
+    /// Pickle packages are therefore always decoded on the owned path
+    pub(crate) fn supports_borrowed_bytes(&self) -> bool {
+        match self {
+            Self::Pickle(_, _) => false,
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard => true,
+            // Byte fields are arrays of numbers, with nothing to borrow
+            #[cfg(feature = "transport_json")]
+            Self::Json => false,
+            // As for JSON, serde hands over byte fields as sequences
+            #[cfg(feature = "transport_msgpack")]
+            Self::MessagePack => false,
+            #[cfg(feature = "transport_cbor")]
+            Self::Cbor => false,
+            #[cfg(feature = "transport_bincode")]
+            Self::Bincode => true,
+            Self::Custom(codec) => codec.supports_borrowed_bytes(),
+        }
+    }
+
+    /// Byte identifying this codec at the start of each package, when
+    /// [TransportConfig::tag_wire_format] is on. Custom codecs pick their own, see
+    /// [WireCodec::tag]
+    pub fn tag(&self) -> Option<u8> {
+        match self {
+            Self::Pickle(_, _) => Some(1),
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard => Some(2),
+            #[cfg(feature = "transport_json")]
+            Self::Json => Some(3),
+            #[cfg(feature = "transport_msgpack")]
+            Self::MessagePack => Some(4),
+            #[cfg(feature = "transport_cbor")]
+            Self::Cbor => Some(5),
+            #[cfg(feature = "transport_bincode")]
+            Self::Bincode => Some(6),
+            Self::Custom(codec) => codec.tag(),
+        }
+    }
+
+    /// Serialise [val] as this codec would for a query or response
+    pub fn serialize(&self, val: &impl Serialize) -> Result<OwnedBytes, TransportError> {
+        let mut bytes = Vec::new();
+        self.serialize_into(val, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// As [serialize], but appending to [bytes], e.g. a buffer taken from a
+    /// [crate::BufferPool]. Custom codecs serialise into a new buffer, then copy it over
+    pub fn serialize_into(
+        &self,
+        val: &impl Serialize,
+        bytes: &mut OwnedBytes,
+    ) -> Result<(), TransportError> {
+        match self {
+            Self::Pickle(_de_opts, ser_opts) => {
+                serde_pickle::ser::to_writer(bytes, val, ser_opts.clone())
+                    .map_err(|pickle_error| SerialiseError(format!("{:?}", pickle_error)))
+            }
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard => postcard::to_extend(val, std::mem::take(bytes))
+                .map(|extended| *bytes = extended)
+                .map_err(|postcard_error| SerialiseError(format!("{:?}", postcard_error))),
+            #[cfg(feature = "transport_json")]
+            Self::Json => serde_json::to_writer(bytes, val)
+                .map_err(|json_error| SerialiseError(format!("{:?}", json_error))),
+            #[cfg(feature = "transport_msgpack")]
+            Self::MessagePack => rmp_serde::encode::write(bytes, val)
+                .map_err(|msgpack_error| SerialiseError(format!("{:?}", msgpack_error))),
+            #[cfg(feature = "transport_cbor")]
+            Self::Cbor => cbor4ii::serde::to_vec(std::mem::take(bytes), val)
+                .map(|extended| *bytes = extended)
+                .map_err(|cbor_error| SerialiseError(format!("{:?}", cbor_error))),
+            #[cfg(feature = "transport_bincode")]
+            Self::Bincode => {
+                bincode::serde::encode_into_std_write(val, bytes, bincode::config::standard())
+                    .map(|_len| ())
+                    .map_err(|bincode_error| SerialiseError(format!("{:?}", bincode_error)))
+            }
+            Self::Custom(codec) => {
+                bytes.extend_from_slice(&codec.serialize(val)?);
+                Ok(())
+            }
+        }
+    }
+    /// Deserialise [bytes] as produced by [serialize]
+    pub fn deserialize<'a, T: Deserialize<'a>>(
+        &self,
+        bytes: Bytes<'a>,
+    ) -> Result<T, TransportError> {
+        match self {
+            Self::Pickle(de_opts, _ser_opts) => {
+                serde_pickle::de::from_slice(bytes, de_opts.clone()).map_err(|pickle_error| {
+                    TransportError::DeserialiseError(format!("{:?}", pickle_error))
+                })
+            }
+            #[cfg(feature = "transport_postcard")]
+            Self::Postcard => postcard::from_bytes(bytes).map_err(|postcard_error| {
+                TransportError::DeserialiseError(format!("{:?}", postcard_error))
+            }),
+            #[cfg(feature = "transport_json")]
+            Self::Json => serde_json::from_slice(bytes).map_err(|json_error| {
+                TransportError::DeserialiseError(format!("{:?}", json_error))
+            }),
+            #[cfg(feature = "transport_msgpack")]
+            Self::MessagePack => rmp_serde::from_slice(bytes).map_err(|msgpack_error| {
+                TransportError::DeserialiseError(format!("{:?}", msgpack_error))
+            }),
+            #[cfg(feature = "transport_cbor")]
+            Self::Cbor => cbor4ii::serde::from_slice(bytes).map_err(|cbor_error| {
+                TransportError::DeserialiseError(format!("{:?}", cbor_error))
+            }),
+            #[cfg(feature = "transport_bincode")]
+            Self::Bincode => {
+                bincode::serde::borrow_decode_from_slice(bytes, bincode::config::standard())
+                    .map(|(val, _len)| val)
+                    .map_err(|bincode_error| {
+                        TransportError::DeserialiseError(format!("{:?}", bincode_error))
+                    })
+            }
+            Self::Custom(codec) => {
+                let mut val = None;
+                codec.deserialize(bytes, &mut |deserializer| {
+                    let deserialized = erased_serde::deserialize(deserializer)
+                        .map_err(|e| TransportError::DeserialiseError(format!("{}", e)))?;
+                    val = Some(deserialized);
+                    Ok(())
+                })?;
+                val.ok_or_else(|| {
+                    TransportError::DeserialiseError(format!(
+                        "codec {} didn't deserialise anything",
+                        codec.name()
+                    ))
+                })
+            }
+        }
+    }
+}
+
+impl Default for TransportWireConfig {
+    fn default() -> Self {
+        Self::pickle()
+    }
+}
+
+impl<I: InternalTransport, Name: RpcName> Transport<I, Name> {
+    pub fn new(mut internal_transport: I, transport_config: TransportConfig) -> Self {
+        internal_transport.configure(&transport_config);
+        Self {
+            internal_transport,
+            name: PhantomData,
+            bandwidth: transport_config.bandwidth_limit.map(ByteBucket::new),
+            interceptors: Vec::new(),
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            session: Session::new(),
+            wire_config_detected: false,
+            coalesced: CoalescedSends::default(),
+            config: transport_config,
+        }
+    }
+
+    /// Unique to this transport among all those made in the process, and given to the
+    /// [ReceivedQuery]s it receives, e.g. for limits per connection
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// Kept for as long as this transport, and given to the [ReceivedQuery]s it receives, for
+    /// handlers to keep values between calls on the connection, see [crate::call_session]
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn internal_transport(&self) -> &I {
+        &self.internal_transport
+    }
+
+    pub(crate) fn into_internal_transport(self) -> I {
+        self.internal_transport
+    }
+
+    /// Swap protocol versions and wire formats with the server, as the first thing on a new
+    /// connection, see [TransportConfig::handshake]. [crate::call_client] and the other
+    /// connecting helpers do this themselves when the config asks for it
+    pub async fn handshake(&mut self) -> RpcResult<()> {
+        Ok(handshake::client_handshake(&mut self.internal_transport, &self.config).await?)
+    }
+
+    /// Answer the client's [handshake]
+    pub(crate) async fn accept_handshake(&mut self) -> RpcResult<()> {
+        Ok(handshake::server_handshake(&mut self.internal_transport, &self.config).await?)
+    }
+
+    pub async fn send_query(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+    ) -> RpcResult<OwnedBytes> {
+        self.send_query_with_timeout(query_bytes, rpc_name, self.config.rcv_timeout)
+            .await
+    }
+
+    /// As [send_query], but waiting [timeout] for the response rather than
+    /// [TransportConfig::rcv_timeout]. Either way the server is told the deadline, so it can
+    /// skip the query if it only gets to it after the deadline has passed
+    pub async fn send_query_with_timeout(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        timeout: Duration,
+    ) -> RpcResult<OwnedBytes> {
+        let metadata = self.config.metadata.clone();
+        self.send_query_with_metadata(query_bytes, rpc_name, timeout, metadata)
+            .await
+    }
+
+    /// As [send_query_with_timeout], sending [metadata] rather than [TransportConfig::metadata]
+    pub(crate) async fn send_query_with_metadata(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        timeout: Duration,
+        metadata: Metadata,
+    ) -> RpcResult<OwnedBytes> {
+        if self.interceptors.is_empty() {
+            return self
+                .send_query_now(query_bytes, rpc_name, timeout, &metadata)
+                .await;
+        }
+        let query = OutgoingQuery {
+            rpc_name: rpc_name.clone(),
+            query_bytes: query_bytes.to_vec(),
+            timeout,
+            metadata,
+        };
+        let interceptors = self.interceptors.clone();
+        ClientNext::new(&interceptors, self).run(query).await
+    }
+
+    /// Run queries sent with [send_query] through [interceptor], see [ClientInterceptor].
+    /// Streaming calls aren't intercepted
+    pub fn layer(&mut self, interceptor: impl ClientInterceptor<Name> + 'static) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    /// [send_query_with_timeout] past any [ClientInterceptor]s
+    async fn send_query_now(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        timeout: Duration,
+        metadata: &Metadata,
+    ) -> RpcResult<OwnedBytes> {
+        let start = self.config.clock.now();
+        let package = self.config.encode_package_parts(
+            rpc_name,
+            PackageKind::Query,
+            query_bytes,
+            0,
+            Some(timeout),
+            metadata,
+        )?;
+        let response_bytes = self
+            .send_parts_and_wait(package.parts(), timeout)
+            .await
+            .map_err(|e| match e {
+                RpcError::TransportError(TransportError::ReceiveTimeout(_)) => {
+                    let elapsed = self.config.clock.now().duration_since(start);
+                    RpcError::RpcTimeout(format!("{}", rpc_name), elapsed)
+                }
+                e => e,
+            })?;
+        let response = self.config.decode_response(&response_bytes, rpc_name);
+        self.config.give_back_buffer(response_bytes);
+        response?.response.into_result()
+    }
+
+    /// Send the query opening a streaming call, without waiting for a response. Follow up with
+    /// [receive_stream_item] until it gives None.
+    /// For a [crate::ClientStreamRpc] or [crate::BidiStreamRpc] the [query_bytes] are ignored, and
+    /// the queries are sent after with [send_stream_item] then [end_request_stream]
+    pub async fn send_stream_query(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+    ) -> RpcResult<()> {
+        self.send_package(rpc_name, PackageKind::Query, query_bytes)
+            .await
+    }
+
+    /// Send a query the server won't answer, returning once it's sent, see
+    /// [PackageKind::Notification], or held back to go with others, with
+    /// [TransportConfig::coalesce_notifications]. Like streaming calls, notifications aren't
+    /// intercepted
+    pub async fn send_notification(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+    ) -> RpcResult<()> {
+        let Some(coalesce) = self.config.coalesce_notifications else {
+            return self
+                .send_package(rpc_name, PackageKind::Notification, query_bytes)
+                .await;
+        };
+        let package_bytes = self
+            .config
+            .encode_package_parts(
+                rpc_name,
+                PackageKind::Notification,
+                query_bytes,
+                0,
+                None,
+                &self.config.metadata,
+            )?
+            .into_bytes();
+        self.consume_bandwidth(package_bytes.len()).await;
+        let now = self.config.clock.now();
+        self.coalesced.push(package_bytes, now);
+        if self.coalesced.is_due(&coalesce, now) {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send the notifications held back by [TransportConfig::coalesce_notifications] now,
+    /// all at once, rather than waiting for more or for [CoalesceConfig::linger]
+    pub async fn flush(&mut self) -> RpcResult<()> {
+        if self.coalesced.is_empty() {
+            return Ok(());
+        }
+        let packages = self.coalesced.take();
+        let messages: Vec<Bytes> = packages.iter().map(|package| &package[..]).collect();
+        Ok(self.internal_transport.send_many(&messages).await?)
+    }
+
+    /// [flush] the notifications held back once the first has waited
+    /// [CoalesceConfig::linger], or wait forever with none held back. For a client to
+    /// `select!` on alongside waiting for its next notification to make, as with [keep_alive],
+    /// so the end of a burst isn't held back until the next send. It's safe to drop while
+    /// waiting, before the flush starts
+    pub async fn linger(&mut self) -> RpcResult<()> {
+        let (Some(coalesce), Some(since)) =
+            (self.config.coalesce_notifications, self.coalesced.since())
+        else {
+            return std::future::pending().await;
+        };
+        let waited = self.config.clock.now().saturating_duration_since(since);
+        tokio::time::sleep(coalesce.linger.saturating_sub(waited)).await;
+        self.flush().await
+    }
+
+    /// Send one query of a client streaming call opened with [send_stream_query]
+    pub async fn send_stream_item(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+    ) -> RpcResult<()> {
+        self.send_package(rpc_name, PackageKind::StreamItem, query_bytes)
+            .await
+    }
+
+    /// Tell the server there are no more queries for the call opened with [send_stream_query]
+    pub async fn end_request_stream(&mut self, rpc_name: &Name) -> RpcResult<()> {
+        self.send_package(rpc_name, PackageKind::StreamEnd, &[])
+            .await
+    }
+
+    /// Stop a streaming call opened with [send_stream_query] that hasn't ended yet, e.g. after
+    /// dropping its [crate::StreamRpcClient] stream part way. Responses already on their way
+    /// are read and discarded, leaving the transport ready for the next call
+    pub async fn cancel_stream(&mut self, rpc_name: &Name) -> RpcResult<()> {
+        self.send_package(rpc_name, PackageKind::Cancel, &[])
+            .await?;
+        loop {
+            match self.receive_stream_item(rpc_name).await {
+                Ok(Some(_)) => (),
+                Ok(None) => return Ok(()),
+                Err(RpcError::TransportError(e)) => return Err(RpcError::TransportError(e)),
+                Err(RpcError::RpcTimeout(rpc_name, elapsed)) => {
+                    return Err(RpcError::RpcTimeout(rpc_name, elapsed))
+                }
+                // Whether cancelled, or failed before it could be, the call is over
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+
+    async fn send_package(
+        &mut self,
+        rpc_name: &Name,
+        kind: PackageKind,
+        query_bytes: Bytes<'_>,
+    ) -> RpcResult<()> {
+        let metadata = match kind {
+            PackageKind::Query | PackageKind::Notification => &self.config.metadata,
+            PackageKind::StreamItem | PackageKind::StreamEnd | PackageKind::Cancel => NO_METADATA,
+        };
+        // A streaming call is given up on if its first response takes too long
+        let time_remaining = match kind {
+            PackageKind::Query => self.config.stream_item_timeout,
+            _ => None,
+        };
+        let package = self.config.encode_package_parts(
+            rpc_name,
+            kind,
+            query_bytes,
+            0,
+            time_remaining,
+            metadata,
+        )?;
+        self.send_parts(package.parts()).await
+    }
+
+    /// Send [parts] one after another as a single message, with
+    /// [InternalTransport::send_vectored] unless the last is empty. Any notifications held
+    /// back are flushed first, so everything's sent in the order it was made
+    async fn send_parts(&mut self, parts: [Bytes<'_>; 2]) -> RpcResult<()> {
+        self.flush().await?;
+        self.consume_bandwidth(parts[0].len() + parts[1].len())
+            .await;
+        match parts {
+            [package_bytes, &[]] => self.internal_transport.send(package_bytes).await,
+            parts => self.internal_transport.send_vectored(&parts).await,
+        }
+        .map_err(RpcError::TransportError)
+    }
+
+    /// Wait for the next response to a call opened with [send_stream_query], giving None once
+    /// the server has ended the stream. An error also ends the stream
+    pub async fn receive_stream_item(&mut self, rpc_name: &Name) -> RpcResult<Option<OwnedBytes>> {
+        let response_bytes = self.receive_stream_bytes(rpc_name).await?;
+        self.decode_stream_item(response_bytes, rpc_name).await
+    }
+
+    /// The receiving half of [receive_stream_item], which unlike it is cancel safe so long as
+    /// the [InternalTransport]'s receive is
+    pub(crate) async fn receive_stream_bytes(&mut self, rpc_name: &Name) -> RpcResult<OwnedBytes> {
+        let start = self.config.clock.now();
+        self.internal_transport
+            .receive(self.config.stream_item_timeout)
+            .await
+            .map_err(|e| match e {
+                TransportError::ReceiveTimeout(_) => {
+                    let elapsed = self.config.clock.now().duration_since(start);
+                    RpcError::RpcTimeout(format!("{}", rpc_name), elapsed)
+                }
+                e => RpcError::TransportError(e),
+            })
+    }
+
+    pub(crate) async fn decode_stream_item(
+        &mut self,
+        response_bytes: OwnedBytes,
+        rpc_name: &Name,
+    ) -> RpcResult<Option<OwnedBytes>> {
+        self.consume_bandwidth(response_bytes.len()).await;
+        self.config
+            .decode_response(&response_bytes, rpc_name)?
+            .response
+            .into_stream_item()
+    }
+
+    /// Send an already serialised package as-is and wait for the response, skipping package
+    /// construction entirely, e.g. for a proxy replaying captured traffic.
+    /// The bytes must be a valid package for the configured [TransportWireConfig], exactly as
+    /// [send_query] would have produced, otherwise the server will fail to decode them.
+    /// The response is returned as received, still wrapped in the server's response envelope
+    pub async fn send_raw_package(&mut self, package_bytes: Bytes<'_>) -> RpcResult<OwnedBytes> {
+        self.config.check_outbound_size(package_bytes.len())?;
+        self.send_package_and_wait(package_bytes, self.config.rcv_timeout)
+            .await
+    }
+
+    async fn send_package_and_wait(
+        &mut self,
+        package_bytes: Bytes<'_>,
+        timeout: Duration,
+    ) -> RpcResult<OwnedBytes> {
+        self.send_parts_and_wait([package_bytes, &[]], timeout)
+            .await
+    }
+
+    /// As [send_package_and_wait], sending [parts] as [send_parts] does
+    async fn send_parts_and_wait(
+        &mut self,
+        parts: [Bytes<'_>; 2],
+        timeout: Duration,
+    ) -> RpcResult<OwnedBytes> {
+        self.flush().await?;
+        let num_bytes = parts[0].len() + parts[1].len();
+        debug_log!("Transport sending {} bytes", num_bytes);
+        self.consume_bandwidth(num_bytes).await;
+        let mut response_bytes = match parts {
+            [package_bytes, &[]] => {
+                self.internal_transport
+                    .send_and_wait_for_response(package_bytes, timeout)
+                    .await?
+            }
+            parts => {
+                self.internal_transport
+                    .send_vectored_and_wait_for_response(&parts, timeout)
+                    .await?
+            }
+        };
+        // Left over from a ping whose wait was cancelled, see [keep_alive]
+        while response_bytes == PONG {
+            response_bytes = self.internal_transport.receive(Some(timeout)).await?;
+        }
+        if turned_away(&response_bytes).is_some() {
+            return Err(RpcError::TransportError(TransportError::ConnectionClosed));
+        }
+        self.consume_bandwidth(response_bytes.len()).await;
+        Ok(response_bytes)
+    }
+
+    /// Check the server is there by sending it a ping, giving the round trip time. It waits
+    /// for the pong up to [KeepaliveConfig::timeout], or [TransportConfig::rcv_timeout]
+    /// without keepalive configured
+    pub async fn ping(&mut self) -> RpcResult<Duration> {
+        let timeout = self
+            .config
+            .keepalive
+            .map_or(self.config.rcv_timeout, |keepalive| keepalive.timeout);
+        self.flush().await?;
+        let start = self.config.clock.now();
+        let reply = self
+            .internal_transport
+            .send_and_wait_for_response(PING, timeout)
+            .await?;
+        if reply != PONG {
+            return Err(RpcError::Custom(String::from(
+                "Expected a pong in reply to ping, the transport may be mid-call",
+            )));
+        }
+        Ok(self.config.clock.now().duration_since(start))
+    }
+
+    /// Ping the server every [KeepaliveConfig::interval] until a ping fails, or forever without
+    /// keepalive configured, see [KeepaliveConfig]. For a client to `select!` on alongside
+    /// waiting for its next call to make, so the connection is kept alive while it's idle.
+    /// It's safe to drop part way, as a late pong is skipped by the next call
+    pub async fn keep_alive(&mut self) -> RpcResult<()> {
+        let Some(keepalive) = self.config.keepalive else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(keepalive.interval).await;
+            self.ping().await?;
+        }
+    }
+
+    /// Ask the server how it is, see [HealthReport]. Any [crate::RpcServer] answers this,
+    /// whatever rpcs it serves, once its [crate::Interceptor]s let it, see
+    /// [crate::ServerRequest], so [TransportConfig::metadata] is sent along
+    pub async fn check_health(&mut self) -> RpcResult<HealthReport> {
+        let mut request = HEALTH_CHECK.to_vec();
+        request.extend(encode_request_metadata(&self.config.metadata)?);
+        self.ask_server(&request, "health report").await
+    }
+
+    /// Ask the server what it serves, see [ServerDescription]. Fails with
+    /// [RpcError::UnknownRpc] unless the server has [crate::ServerConfig::reflection] on, and
+    /// goes past its [crate::Interceptor]s like [check_health]
+    pub async fn reflect(&mut self) -> RpcResult<ServerDescription> {
+        let mut request = REFLECT.to_vec();
+        request.extend(encode_request_metadata(&self.config.metadata)?);
+        self.ask_server(&request, "server description").await
+    }
+
+    /// Check the server serves each rpc in [schema] as it expects, failing with
+    /// [RpcError::SchemaMismatch] saying how it doesn't. Needs the server to have
+    /// [crate::ServerConfig::reflection] on
+    pub async fn verify_schema(&mut self, schema: &Schema) -> RpcResult<()> {
+        let server = Schema::from(&self.reflect().await?);
+        let mismatches = schema.mismatches(&server);
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+        Err(RpcError::SchemaMismatch(mismatches.join("; ")))
+    }
+
+    /// Send [request], one of the fixed requests any server answers, and decode its answer
+    async fn ask_server<T: DeserializeOwned>(
+        &mut self,
+        request: &[u8],
+        step: &str,
+    ) -> RpcResult<T> {
+        let response_bytes = self
+            .send_package_and_wait(request, self.config.rcv_timeout)
+            .await?;
+        let answer_bytes = self
+            .config
+            .decode_response(&response_bytes, step)?
+            .response
+            .into_result()?;
+        Ok(self
+            .config
+            .wire_config
+            .deserialize(&answer_bytes)
+            .map_err(|e| e.in_step(step))?)
+    }
+
+    /// Gather unary calls to send together, see [Batch]
+    pub fn batch(&mut self) -> Batch<'_, I, Name> {
+        Batch::new(self)
+    }
+
+    /// Send calls without waiting for their responses in between, see [Pipeline]
+    pub fn pipeline(&mut self) -> Pipeline<'_, I, Name> {
+        Pipeline::new(self)
+    }
+
+    /// Send a query with [correlation_id], without waiting for the response, see [Pipeline]
+    pub(crate) async fn send_pipelined_query(
+        &mut self,
+        query_bytes: Bytes<'_>,
+        rpc_name: &Name,
+        correlation_id: u64,
+    ) -> RpcResult<()> {
+        let package = self.config.encode_package_parts(
+            rpc_name,
+            PackageKind::Query,
+            query_bytes,
+            correlation_id,
+            Some(self.config.rcv_timeout),
+            &self.config.metadata,
+        )?;
+        self.send_parts(package.parts()).await
+    }
+
+    /// Wait up to [TransportConfig::rcv_timeout] for the next response, to whichever query
+    pub(crate) async fn receive_response(
+        &mut self,
+        rpc_name: &Name,
+    ) -> RpcResult<TransportResponseEnvelopeOwned> {
+        let start = self.config.clock.now();
+        let timeout = Some(self.config.rcv_timeout);
+        let receive_timeout = |e| match e {
+            TransportError::ReceiveTimeout(_) => {
+                let elapsed = self.config.clock.now().duration_since(start);
+                RpcError::RpcTimeout(format!("{}", rpc_name), elapsed)
+            }
+            e => RpcError::TransportError(e),
+        };
+        let mut response_bytes = self
+            .internal_transport
+            .receive(timeout)
+            .await
+            .map_err(receive_timeout)?;
+        // Left over from a ping whose wait was cancelled, see [keep_alive]
+        while response_bytes == PONG {
+            response_bytes = self
+                .internal_transport
+                .receive(timeout)
+                .await
+                .map_err(receive_timeout)?;
+        }
+        if turned_away(&response_bytes).is_some() {
+            return Err(RpcError::TransportError(TransportError::ConnectionClosed));
+        }
+        self.consume_bandwidth(response_bytes.len()).await;
+        self.config.decode_response(&response_bytes, rpc_name)
+    }
+
+    /// Send [batch] and wait for the responses to its packages, in order. A server that
+    /// couldn't handle the batch as a whole answers with a single error instead
+    pub(crate) async fn send_batch(
+        &mut self,
+        batch: &TransportPackageBatch,
+    ) -> RpcResult<Vec<OwnedBytes>> {
+        let batch_bytes = self.config.encode_batch(batch)?;
+        let response_bytes = self
+            .send_package_and_wait(&batch_bytes, self.config.rcv_timeout)
+            .await?;
+        let Some(response_bytes) = response_bytes.strip_prefix(BATCH) else {
+            let envelope = self.config.decode_response(&response_bytes, "batch")?;
+            return Err(envelope
+                .response
+                .into_result()
+                .err()
+                .unwrap_or_else(|| RpcError::Custom(String::from("Expected batch responses"))));
+        };
+        let (wire_config, response_bytes) = self.config.untag_package(response_bytes)?;
+        let batch: TransportResponseBatch = wire_config
+            .deserialize(response_bytes)
+            .map_err(|e| e.in_step("batch responses"))?;
+        Ok(batch.responses)
+    }
+
+    /// Decode a [TransportPackageBatch] received in place of a query, switching to the codec
+    /// it's tagged with like [decode_query]. Its packages are decoded by [decode_query] after
+    pub(crate) async fn decode_batch(
+        &mut self,
+        bytes: OwnedBytes,
+    ) -> RpcResult<TransportPackageBatch> {
+        self.consume_bandwidth(bytes.len()).await;
+        let bytes = self.detect_wire_config(bytes[BATCH.len()..].to_vec())?;
+        Ok(self
+            .config
+            .wire_config
+            .deserialize(&bytes)
+            .map_err(|e| e.in_step("batch"))?)
+    }
+
+    /// Answer a [TransportPackageBatch] with the envelopes answering each of its packages, see
+    /// [TransportConfig::encode_result]
+    pub(crate) async fn respond_batch(&mut self, responses: Vec<OwnedBytes>) -> RpcResult<()> {
+        let bytes = self
+            .config
+            .encode_batch(&TransportResponseBatch { responses })?;
+        self.consume_bandwidth(bytes.len()).await;
+        self.internal_transport
+            .send(&bytes)
+            .await
+            .map_err(RpcError::TransportError)
+    }
+
+    /// Hand the connection over for the server to call this end's rpcs over, as the client
+    /// known by [key], see [crate::ReverseConnections]. [TransportConfig::metadata] is sent
+    /// along, e.g. for the server to authenticate the request with
+    pub(crate) async fn request_reverse(&mut self, key: &str) -> RpcResult<()> {
+        let mut request = REVERSE.to_vec();
+        request.extend(length_prefixed(key.as_bytes())?);
+        request.extend(encode_request_metadata(&self.config.metadata)?);
+        let reply = self
+            .send_package_and_wait(&request, self.config.rcv_timeout)
+            .await?;
+        if reply == REVERSE {
+            return Ok(());
+        }
+        self.config
+            .decode_response(&reply, "reverse connection")?
+            .response
+            .into_result()?;
+        Err(RpcError::Custom(String::from(
+            "Expected the server to take the reversed connection",
+        )))
+    }
+
+    /// Tell the client its connection's been taken, see [request_reverse]
+    pub(crate) async fn accept_reverse(&mut self) -> RpcResult<()> {
+        Ok(self.internal_transport.send(REVERSE).await?)
+    }
+
+    /// Answer a ping received in place of a query
+    pub(crate) async fn pong(&mut self) -> RpcResult<()> {
+        Ok(self.internal_transport.send(PONG).await?)
+    }
+
+    /// Answer the query with [correlation_id] with [GO_AWAY], rather than handling it
+    pub(crate) async fn go_away(&mut self, correlation_id: u64) -> RpcResult<()> {
+        let parts = [GO_AWAY, &correlation_id.to_be_bytes()];
+        Ok(self.internal_transport.send_vectored(&parts).await?)
+    }
+
+    pub async fn receive_query(&mut self) -> RpcResult<ReceivedQuery<Name>> {
+        let bytes = self.receive_query_bytes().await?;
+        self.decode_query(bytes).await
+    }
+
+    /// The receiving half of [receive_query], which unlike it is cancel safe so long as the
+    /// [InternalTransport]'s receive is
+    pub(crate) async fn receive_query_bytes(&mut self) -> RpcResult<OwnedBytes> {
+        // We receive with no timeout as we want to sit and wait on [internal_transport]
+        self.internal_transport
+            .receive(None)
+            .await
+            .map_err(RpcError::TransportError)
+    }
+
+    pub(crate) async fn decode_query(
+        &mut self,
+        bytes: OwnedBytes,
+    ) -> RpcResult<ReceivedQuery<Name>> {
+        debug_log!("Transport received {} bytes", bytes.len());
+        self.consume_bandwidth(bytes.len()).await;
+        let bytes = self.detect_wire_config(bytes)?;
+        let mut bytes = decompress_owned(
+            self.config.compression.as_ref(),
+            bytes,
+            self.config.max_inbound_message_size,
+        )?;
+        if self.config.sectioned_packages {
+            return self.decode_sectioned_package(bytes);
+        }
+        let prefixed_name = match self.config.name_encoding {
+            NameEncoding::Utf8String => {
+                Some(self.decode_name(&split_length_prefixed(&mut bytes)?)?)
+            }
+            NameEncoding::Codec | NameEncoding::Opcode => None,
+        };
+        if self.can_borrow_package(bytes.len()) {
+            let received_query = self.decode_borrowed_package(&bytes, prefixed_name);
+            self.config.give_back_buffer(bytes);
+            return received_query;
+        }
+        let package = self
+            .deserialize_package(bytes)
+            .await
+            .map_err(|e| e.in_step("package"))?;
+        let name = match prefixed_name {
+            Some(name) => name,
+            None => self.decode_name(&package.name_bytes)?,
+        };
+        let header = PackageHeader {
+            correlation_id: package.correlation_id,
+            kind: package.kind,
+            time_remaining: package.time_remaining,
+            metadata: Cow::Owned(package.metadata),
+        };
+        Ok(self.received_query(header, name, package.query_bytes))
+    }
+
+    /// Decode a package laid out in sections, see [TransportConfig::sectioned_packages]. The
+    /// header and name are decoded in place, and the query bytes are what's left of the
+    /// received buffer once they're taken off the front, so aren't copied into a new one
+    fn decode_sectioned_package(&self, mut bytes: OwnedBytes) -> RpcResult<ReceivedQuery<Name>> {
+        let (header_bytes, rest) = split_section(&bytes)?;
+        let (name_bytes, query_bytes) = split_section(rest)?;
+        // Nothing in the header borrows, so it outlives [bytes]
+        let header: PackageHeader<'static> = self
+            .config
+            .wire_config
+            .deserialize(header_bytes)
+            .map_err(|e| e.in_step("package header"))?;
+        let name = self.decode_name(name_bytes)?;
+        bytes.drain(..bytes.len() - query_bytes.len());
+        Ok(self.received_query(header, name, bytes))
+    }
+
+    /// The query [header] introduced, as received over this transport
+    /// A [ServerRequest] of [kind] received with [metadata], for the [crate::Interceptor]s to
+    /// check
+    pub(crate) fn server_request(
+        &self,
+        kind: ServerRequestKind,
+        metadata: Metadata,
+    ) -> ServerRequest {
+        ServerRequest {
+            kind,
+            metadata,
+            identity: None,
+            connection_id: self.connection_id,
+            peer_addr: self.internal_transport.peer_addr(),
+            peer_certificate: self.internal_transport.peer_certificate().map(Arc::new),
+        }
+    }
+
+    fn received_query(
+        &self,
+        header: PackageHeader<'static>,
+        name: Name,
+        query_bytes: OwnedBytes,
+    ) -> ReceivedQuery<Name> {
+        ReceivedQuery {
+            correlation_id: header.correlation_id,
+            kind: header.kind,
+            name,
+            query_bytes,
+            deadline: self.deadline(header.time_remaining),
+            metadata: header.metadata.into_owned(),
+            identity: None,
+            connection_id: self.connection_id,
+            peer_addr: self.internal_transport.peer_addr(),
+            // Read per query, as a reconnecting transport's peer can change
+            peer_certificate: self.internal_transport.peer_certificate().map(Arc::new),
+            wire_format: self.config.wire_config.wire_config_name(),
+            session: self.session.clone(),
+            cancellation: CancellationToken::new(),
+        }
+    }
+
+    /// Turn this transport into a [Stream] of queries as they arrive, for use with `select!` and
+    /// stream combinators rather than a hand written receive loop. The stream ends when the
+    /// peer closes the connection. Errors decoding a query are yielded and the stream carries
+    /// on, while errors from the connection itself are yielded and then end the stream
+    pub fn into_query_stream(self) -> impl Stream<Item = RpcResult<ReceivedQuery<Name>>> {
+        futures_util::stream::unfold(Some(self), |transport| async move {
+            let mut transport = transport?;
+            match transport.receive_query().await {
+                Err(RpcError::TransportError(TransportError::ConnectionClosed)) => None,
+                Err(e @ RpcError::TransportError(TransportError::ReceiveError(_)))
+                | Err(e @ RpcError::TransportError(TransportError::ConnectionReset(_)))
+                | Err(e @ RpcError::TransportError(TransportError::MalformedFrame(_)))
+                | Err(e @ RpcError::TransportError(TransportError::ZeroLengthFrame))
+                | Err(e @ RpcError::TransportError(TransportError::TruncatedHeader { .. }))
+                | Err(e @ RpcError::TransportError(TransportError::TruncatedFrame { .. })) => {
+                    Some((Err(e), None))
+                }
+                result => Some((result, Some(transport))),
+            }
+        })
+    }
+
+    fn can_borrow_package(&self, num_bytes: usize) -> bool {
+        self.config.wire_config.supports_borrowed_bytes()
+            && !self.config.use_blocking_pool_for(num_bytes)
+    }
+
+    /// Decode the package borrowing from the received buffer, and the name in place from that.
+    /// Only the query bytes are copied out, where the owned path allocates and copies both the
+    /// name and query bytes before decoding the name, see `benches/receive_query.rs`
+    fn decode_borrowed_package(
+        &self,
+        bytes: Bytes,
+        prefixed_name: Option<Name>,
+    ) -> RpcResult<ReceivedQuery<Name>> {
+        let package: TransportPackage = self
+            .config
+            .wire_config
+            .deserialize(bytes)
+            .map_err(|e| e.in_step("package"))?;
+        let name = match prefixed_name {
+            Some(name) => name,
+            None => self.decode_name(package.name_bytes)?,
+        };
+        let header = PackageHeader {
+            correlation_id: package.correlation_id,
+            kind: package.kind,
+            time_remaining: package.time_remaining,
+            metadata: Cow::Owned(package.metadata.into_owned()),
+        };
+        Ok(self.received_query(header, name, package.query_bytes.to_vec()))
+    }
+
+    /// With [TransportConfig::tag_wire_format] on, switch to the codec the first package is
+    /// tagged with, so the connection's packages are decoded and answered with it, and split
+    /// the tag off. The codec's then kept for the connection, and a later package tagged with
+    /// another is refused, as queries still being answered have their responses encoded with
+    /// whichever codec the connection has when they're sent
+    fn detect_wire_config(&mut self, mut bytes: OwnedBytes) -> RpcResult<OwnedBytes> {
+        if !self.config.tag_wire_format {
+            return Ok(bytes);
+        }
+        if !self.wire_config_detected {
+            let wire_config = self.config.untag_package(&bytes)?.0.clone();
+            self.config.wire_config = wire_config;
+            self.wire_config_detected = true;
+        } else if bytes.first() != self.config.wire_config.tag().as_ref() {
+            return Err(TransportError::DeserialiseError(format!(
+                "wire format tag {:?} doesn't match the connection's {}",
+                bytes.first(),
+                self.config.wire_config.wire_config_name()
+            ))
+            .into());
+        }
+        bytes.remove(0);
+        Ok(bytes)
+    }
+
+    /// When the caller gives up on a query received now with [time_remaining]
+    fn deadline(&self, time_remaining: Option<Duration>) -> Option<Instant> {
+        time_remaining
+            .and_then(|time_remaining| self.config.clock.now().checked_add(time_remaining))
+    }
+
+    fn decode_name(&self, name_bytes: Bytes) -> RpcResult<Name> {
+        match self.config.name_encoding {
+            NameEncoding::Codec => self
+                .config
+                .wire_config
+                .deserialize(name_bytes)
+                .map_err(|e| e.in_step("rpc name").into()),
+            NameEncoding::Opcode => {
+                let opcode_bytes: [u8; 2] = name_bytes.try_into().map_err(|_| {
+                    TransportError::DeserialiseError(format!(
+                        "rpc name: expected 2 byte opcode, got {} bytes",
+                        name_bytes.len()
+                    ))
+                })?;
+                let opcode = u16::from_be_bytes(opcode_bytes);
+                Name::from_opcode(opcode)
+                    .ok_or_else(|| RpcError::UnknownRpc(format!("opcode {}", opcode)))
+            }
+            NameEncoding::Utf8String => {
+                let name = std::str::from_utf8(name_bytes)
+                    .map_err(|e| TransportError::DeserialiseError(format!("rpc name: {}", e)))?;
+                Name::from_utf8_name(name).ok_or_else(|| RpcError::UnknownRpc(name.to_string()))
+            }
+        }
+    }
+
+    async fn consume_bandwidth(&mut self, num_bytes: usize) {
+        if let Some(bucket) = &mut self.bandwidth {
+            bucket.take(num_bytes).await;
+        }
+    }
+
+    async fn deserialize_package(
+        &self,
+        bytes: OwnedBytes,
+    ) -> Result<TransportPackageOwned, TransportError> {
+        let (package, bytes) = if self.config.use_blocking_pool_for(bytes.len()) {
+            let wire_config = self.config.wire_config.clone();
+            tokio::task::spawn_blocking(move || (wire_config.deserialize(&bytes), bytes))
+                .await
+                .map_err(|join_error| TransportError::DeserialiseError(format!("{}", join_error)))?
+        } else {
+            (self.config.wire_config.deserialize(&bytes), bytes)
+        };
+        self.config.give_back_buffer(bytes);
+        package
+    }
+
+    /// Respond to the received query with [correlation_id] with the serialised response
+    pub async fn respond(&mut self, correlation_id: u64, bytes: Bytes<'_>) -> RpcResult<()> {
+        self.send_response(correlation_id, TransportResponse::Ok(bytes))
+            .await
+    }
+
+    /// Respond to a received query with the error that stopped it being handled, which the
+    /// client's [send_query] will return
+    pub async fn respond_error(&mut self, correlation_id: u64, error: &RpcError) -> RpcResult<()> {
+        self.send_response(correlation_id, TransportResponse::of_error(error))
+            .await
+    }
+
+    /// Send one response to the [crate::StreamRpc] query with [correlation_id]
+    pub async fn respond_stream_item(
+        &mut self,
+        correlation_id: u64,
+        bytes: Bytes<'_>,
+    ) -> RpcResult<()> {
+        self.send_response(correlation_id, TransportResponse::StreamItem(bytes))
+            .await
+    }
+
+    /// Send part of the response to the query with [correlation_id], to be joined with what's
+    /// sent after it, see [crate::ServerConfig::response_chunk_size]
+    pub async fn respond_chunk(&mut self, correlation_id: u64, bytes: Bytes<'_>) -> RpcResult<()> {
+        self.send_response(correlation_id, TransportResponse::Chunk(bytes))
+            .await
+    }
+
+    /// Tell the client there are no more responses to the [crate::StreamRpc] query with
+    /// [correlation_id]
+    pub async fn end_stream(&mut self, correlation_id: u64) -> RpcResult<()> {
+        self.send_response(correlation_id, TransportResponse::StreamEnd)
+            .await
+    }
+
+    async fn send_response(
+        &mut self,
+        correlation_id: u64,
+        response: TransportResponse<'_>,
+    ) -> RpcResult<()> {
+        let bytes = self.config.encode_envelope(correlation_id, response)?;
+        self.config.check_outbound_size(bytes.len())?;
+        self.consume_bandwidth(bytes.len()).await;
+        let sent = self.internal_transport.send(&bytes).await;
+        self.config.give_back_buffer(bytes);
+        sent.map_err(RpcError::TransportError)
+    }
+}
+
+#[async_trait]
+impl<I: InternalTransport, Name: RpcName> QuerySender<Name> for Transport<I, Name> {
+    async fn send_intercepted_query(
+        &mut self,
+        query: &OutgoingQuery<Name>,
+    ) -> RpcResult<OwnedBytes> {
+        self.send_query_now(
+            &query.query_bytes,
+            &query.rpc_name,
+            query.timeout,
+            &query.metadata,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+pub(crate) struct CannedTestingTransport {
+    pub always_respond_with: String,
+    pub receive_times: usize,
+}
+
+/// Receives the queued bytes in order, and records everything sent, and in how many [sends]
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct QueuedTestingTransport {
+    pub to_receive: std::collections::VecDeque<OwnedBytes>,
+    pub sent: Vec<OwnedBytes>,
+    pub sends: usize,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl InternalTransport for QueuedTestingTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.sent.push(b.to_vec());
+        self.sends += 1;
+        Ok(())
+    }
+
+    async fn send_many(&mut self, messages: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.sent
+            .extend(messages.iter().map(|message| message.to_vec()));
+        self.sends += 1;
+        Ok(())
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, _timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        self.to_receive
+            .pop_front()
+            .ok_or(TransportError::ConnectionClosed)
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl InternalTransport for CannedTestingTransport {
+    async fn send(&mut self, _b: Bytes<'_>) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        _b: Bytes<'_>,
+        _timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        let response_bytes =
+            serde_pickle::to_vec(&self.always_respond_with, serde_pickle::SerOptions::new())
+                .unwrap();
+        Ok(serde_pickle::to_vec(
+            &TransportResponseEnvelope {
+                correlation_id: 0,
+                response: TransportResponse::Ok(&response_bytes),
+            },
+            serde_pickle::SerOptions::new(),
+        )
+        .unwrap())
+    }
+    async fn receive(&mut self, _timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        if self.receive_times > 0 {
+            self.receive_times -= 1;
+            Ok(
+                serde_pickle::to_vec(&self.always_respond_with, serde_pickle::SerOptions::new())
+                    .unwrap(),
+            )
+        } else {
+            Err(TransportError::ReceiveError(String::from(
+                "Run out of receive count",
+            )))
+        }
+    }
+}
+
+/// Write all of [parts] to [writer], one after another, looping over short writes, then flush.
+/// Several parts are written with `write_vectored` where [writer] supports it, so needn't be
+/// copied into one buffer first, and are otherwise copied together and written as one.
+/// Every [InternalTransport] writing to a stream should send through this (or equivalent
+/// `write_all` semantics), as a silently truncated message desyncs the receiver.
+/// On failure the [TransportError::SendError] says how many bytes did get written
+pub(crate) async fn send_all<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    parts: &[Bytes<'_>],
+) -> Result<(), TransportError> {
+    use std::io::IoSlice;
+    use tokio::io::AsyncWriteExt;
+    let joined;
+    let parts = match parts {
+        [_] => parts,
+        _ if writer.is_write_vectored() => parts,
+        _ => {
+            joined = parts.concat();
+            &[&joined[..]][..]
+        }
+    };
+    let total: usize = parts.iter().map(|part| part.len()).sum();
+    let mut slices: Vec<IoSlice> = parts.iter().map(|part| IoSlice::new(part)).collect();
+    let mut remaining = &mut slices[..];
+    let mut written = 0;
+    while written < total {
+        let write = match remaining {
+            [one] => writer.write(one).await,
+            _ => writer.write_vectored(remaining).await,
+        };
+        match write {
+            Ok(0) => {
+                return Err(TransportError::SendError(format!(
+                    "Writer accepted no more bytes after writing {} of {}",
+                    written, total
+                )))
+            }
+            Ok(n) => {
+                written += n;
+                IoSlice::advance_slices(&mut remaining, n);
+            }
+            Err(e) if TransportError::is_reset_kind(e.kind()) => {
+                return Err(TransportError::io_send(e))
+            }
+            Err(e) => {
+                return Err(TransportError::SendError(format!(
+                    "{:?} after writing {} of {} bytes",
+                    e, written, total
+                )))
+            }
+        }
+    }
+    writer.flush().await.map_err(TransportError::io_send)
+}
+
+/// Number of bytes in the big-endian length header at the start of every frame
+const FRAME_HEADER_LEN: usize = 4;
+
+/// Write [bytes] as one frame: a u32 big-endian length header followed by the payload.
+/// Stream based [InternalTransport]s send through this so the receiver knows exactly where
+/// each message ends, however the stream splits or joins the underlying writes
+pub(crate) async fn write_frame<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    bytes: Bytes<'_>,
+) -> Result<(), TransportError> {
+    let header = frame_header(bytes.len())?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + bytes.len());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(bytes);
+    send_all(writer, &[&frame]).await
+}
+
+/// Write [parts] as one frame, as [write_frame] would their concatenation, for
+/// [InternalTransport::send_vectored]. The header and parts are written with `write_vectored`
+/// rather than copied into a buffer first, where [writer] supports it
+pub(crate) async fn write_frame_vectored<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    parts: &[Bytes<'_>],
+) -> Result<(), TransportError> {
+    let header = frame_header(parts.iter().map(|part| part.len()).sum())?;
+    let mut frame = Vec::with_capacity(1 + parts.len());
+    frame.push(&header[..]);
+    frame.extend_from_slice(parts);
+    send_all(writer, &frame).await
+}
+
+/// Write each of [messages] as a frame of its own, as [write_frame] would one after another,
+/// but with all the headers and payloads given to [send_all] together, so a burst of small
+/// messages goes out in one write rather than a write each
+pub(crate) async fn write_frames<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    messages: &[Bytes<'_>],
+) -> Result<(), TransportError> {
+    let headers = messages
+        .iter()
+        .map(|message| frame_header(message.len()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let parts: Vec<Bytes> = headers
+        .iter()
+        .zip(messages)
+        .flat_map(|(header, message)| [&header[..], message])
+        .collect();
+    send_all(writer, &parts).await
+}
+
+/// The header of a frame with a payload of [payload_len] bytes
+fn frame_header(payload_len: usize) -> Result<[u8; FRAME_HEADER_LEN], TransportError> {
+    let len = u32::try_from(payload_len).map_err(|_| {
+        TransportError::SendError(format!(
+            "Message of {} bytes is too large for one frame",
+            payload_len
+        ))
+    })?;
+    Ok(len.to_be_bytes())
+}
+
+/// Writes frames for the stream transports, as [FrameReader] reads them, within
+/// [TransportConfig::send_timeout]. A send that fails, e.g. timing out, may leave the stream
+/// part way through a frame, so the writer's then broken: later sends fail straight away, and
+/// the transport should say it's not [InternalTransport::is_healthy]
+#[derive(Default)]
+pub(crate) struct FrameWriter {
+    send_timeout: Option<Duration>,
+    broken: bool,
+}
+
+impl FrameWriter {
+    pub(crate) fn configure(&mut self, config: &TransportConfig) {
+        self.send_timeout = config.send_timeout;
+    }
+
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// [write_frame] [bytes] to [writer]
+    pub(crate) async fn write_frame<W: tokio::io::AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        bytes: Bytes<'_>,
+    ) -> Result<(), TransportError> {
+        self.check_not_broken()?;
+        let result = within_send_timeout(self.send_timeout, write_frame(writer, bytes)).await;
+        self.broken = result.is_err();
+        result
+    }
+
+    /// [write_frame_vectored] [parts] to [writer]
+    pub(crate) async fn write_frame_vectored<W: tokio::io::AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        parts: &[Bytes<'_>],
+    ) -> Result<(), TransportError> {
+        self.check_not_broken()?;
+        let send = write_frame_vectored(writer, parts);
+        let result = within_send_timeout(self.send_timeout, send).await;
+        self.broken = result.is_err();
+        result
+    }
+
+    /// [write_frames] [messages] to [writer]
+    pub(crate) async fn write_frames<W: tokio::io::AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        messages: &[Bytes<'_>],
+    ) -> Result<(), TransportError> {
+        self.check_not_broken()?;
+        let send = write_frames(writer, messages);
+        let result = within_send_timeout(self.send_timeout, send).await;
+        self.broken = result.is_err();
+        result
+    }
+
+    fn check_not_broken(&self) -> Result<(), TransportError> {
+        if self.broken {
+            return Err(TransportError::SendError(String::from(
+                "Connection broken by an earlier send failing part way",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Run [send], failing with [TransportError::SendError] if it's not done within
+/// [send_timeout], see [TransportConfig::send_timeout]
+pub(crate) async fn within_send_timeout(
+    send_timeout: Option<Duration>,
+    send: impl Future<Output = Result<(), TransportError>>,
+) -> Result<(), TransportError> {
+    match send_timeout {
+        Some(send_timeout) => tokio::time::timeout(send_timeout, send)
+            .await
+            .unwrap_or_else(|_| {
+                Err(TransportError::SendError(format!(
+                    "Timed out after {:?}, the peer isn't reading",
+                    send_timeout
+                )))
+            }),
+        None => send.await,
+    }
+}
+
+/// Run [connect], failing with [TransportError::ConnectError] if it's not done within
+/// [connect_timeout], see [TransportConfig::connect_timeout]
+pub(crate) async fn within_connect_timeout<T>(
+    connect_timeout: Option<Duration>,
+    connect: impl Future<Output = Result<T, TransportError>>,
+) -> Result<T, TransportError> {
+    match connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, connect)
+            .await
+            .unwrap_or_else(|_| {
+                Err(TransportError::ConnectError(format!(
+                    "Timed out after {:?}",
+                    connect_timeout
+                )))
+            }),
+        None => connect.await,
+    }
+}
+
+/// Connect over TCP to [addr], within [connect_timeout] if given
+pub(crate) async fn connect_tcp(
+    addr: impl tokio::net::ToSocketAddrs,
+    connect_timeout: Option<Duration>,
+) -> Result<tokio::net::TcpStream, TransportError> {
+    within_connect_timeout(connect_timeout, async {
+        tokio::net::TcpStream::connect(addr)
+            .await
+            .map_err(|e| TransportError::ConnectError(format!("{}", e)))
+    })
+    .await
+}
+
+/// Reads frames written by [write_frame]. Bytes read past the end of one frame are kept for
+/// the next, as is a partly read frame when [read_frame] is cancelled, e.g. by a timeout or
+/// `select!`, so cancelling a read never loses data or desyncs the stream
+pub(crate) struct FrameReader {
+    buffer: BytesMut,
+    max_frame_len: Option<usize>,
+    buffer_pool: Option<BufferPool>,
+    read_buffer_size: usize,
+    idle_read_timeout: Option<Duration>,
+    /// When the frame part way read must be all there by, per [idle_read_timeout], set at its
+    /// first byte
+    frame_deadline: Option<tokio::time::Instant>,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            max_frame_len: None,
+            buffer_pool: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            idle_read_timeout: None,
+            frame_deadline: None,
+        }
+    }
+}
+
+impl FrameReader {
+    /// Settings from [config] for the frames read, as [Transport::new] gives a framed
+    /// [InternalTransport], for those reading frames outside of one
+    pub(crate) fn with_config(config: &TransportConfig) -> Self {
+        let mut frames = Self::default();
+        frames.configure(config);
+        frames
+    }
+
+    /// Fail frames with a payload over [TransportConfig::max_inbound_message_size] from their
+    /// header alone, copy payloads out into buffers from [TransportConfig::buffer_pool], read
+    /// [TransportConfig::read_buffer_size] at a time and fail a frame not all there within
+    /// [TransportConfig::idle_read_timeout] of its first byte, see
+    /// [InternalTransport::configure]
+    pub(crate) fn configure(&mut self, config: &TransportConfig) {
+        self.max_frame_len = config.max_inbound_message_size;
+        self.buffer_pool = config.buffer_pool.clone();
+        self.read_buffer_size = config.read_buffer_size.max(1);
+        self.idle_read_timeout = config.idle_read_timeout;
+    }
+
+    /// Read one frame, returning its payload.
+    /// Frames are read into one buffer kept across reads, so many small frames arriving
+    /// together take one read. Taking a frame off the front of the buffer doesn't move what's
+    /// left behind it, and later reads reuse the space taken off the front rather than growing
+    /// the buffer. Each payload is still copied out once, as [OwnedBytes] is a `Vec`: handing
+    /// out the frame split off the buffer as it is would mean changing [OwnedBytes] throughout.
+    /// Reading a large payload straight into a buffer of its own instead, to skip that copy,
+    /// was measured slower, see `benches/frame_reader.rs`, as the fresh pages cost more to read
+    /// into than the copy out of pages already in use. Reading with io_uring, via tokio-uring,
+    /// hasn't been tried.
+    /// [timeout] covers the whole frame rather than each individual read.
+    /// A clean close before any of the header gives [TransportError::ConnectionClosed], while
+    /// a close anywhere later gives [TransportError::TruncatedHeader] or
+    /// [TransportError::TruncatedFrame], and a zero length [TransportError::ZeroLengthFrame]
+    pub(crate) async fn read_frame<R: tokio::io::AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        timeout: Option<Duration>,
+    ) -> Result<OwnedBytes, TransportError> {
+        match timeout {
+            Some(timeout_) => {
+                match tokio::time::timeout(timeout_, self.read_frame_inner(reader)).await {
+                    Ok(r) => r,
+                    Err(_) => Err(TransportError::ReceiveTimeout(timeout_)),
+                }
+            }
+            None => self.read_frame_inner(reader).await,
+        }
+    }
+
+    async fn read_frame_inner<R: tokio::io::AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<OwnedBytes, TransportError> {
+        use tokio::io::AsyncReadExt;
+        loop {
+            let wanted = match self.frame_len()? {
+                Some(len) if self.buffer.len() >= FRAME_HEADER_LEN + len => {
+                    let frame = &self.buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len];
+                    let payload = match &self.buffer_pool {
+                        Some(buffer_pool) => {
+                            let mut payload = buffer_pool.take(len);
+                            payload.extend_from_slice(frame);
+                            payload
+                        }
+                        None => frame.to_vec(),
+                    };
+                    self.buffer.advance(FRAME_HEADER_LEN + len);
+                    self.frame_deadline = None;
+                    return Ok(payload);
+                }
+                Some(len) => FRAME_HEADER_LEN + len - self.buffer.len(),
+                None => FRAME_HEADER_LEN - self.buffer.len(),
+            };
+            self.buffer.reserve(wanted.max(self.read_buffer_size));
+            // Waiting for a frame to start is up to the caller's timeout, while once it has the
+            // whole frame must arrive in time, however it trickles in. Bytes of it read along
+            // with the last frame count as its start
+            if self.frame_deadline.is_none() && !self.buffer.is_empty() {
+                self.frame_deadline = self
+                    .idle_read_timeout
+                    .map(|idle_read_timeout| tokio::time::Instant::now() + idle_read_timeout);
+            }
+            // read_buf only appends what it read, so is safe to cancel
+            let read = reader.read_buf(&mut self.buffer);
+            let read = match (self.frame_deadline, self.idle_read_timeout) {
+                (Some(frame_deadline), Some(idle_read_timeout)) => {
+                    tokio::time::timeout_at(frame_deadline, read)
+                        .await
+                        .map_err(|_| TransportError::ReceiveTimeout(idle_read_timeout))?
+                }
+                _ => read.await,
+            };
+            match read {
+                Ok(0) => return Err(self.closed_error()),
+                Ok(_) => (),
+                Err(e) => return Err(TransportError::io_receive(e)),
+            }
+        }
+    }
+
+    /// Payload length of the frame at the front of the buffer, once its header is all there
+    fn frame_len(&self) -> Result<Option<usize>, TransportError> {
+        if self.buffer.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header.copy_from_slice(&self.buffer[..FRAME_HEADER_LEN]);
+        match u32::from_be_bytes(header) as usize {
+            0 => Err(TransportError::ZeroLengthFrame),
+            len => match self.max_frame_len {
+                Some(max) if len > max => Err(TransportError::MessageTooLarge(len, max)),
+                _ => Ok(Some(len)),
+            },
+        }
+    }
+
+    fn closed_error(&self) -> TransportError {
+        let buffered = self.buffer.len();
+        if buffered == 0 {
+            return TransportError::ConnectionClosed;
+        }
+        match self.frame_len() {
+            Ok(Some(len)) => TransportError::TruncatedFrame {
+                expected: len,
+                got: buffered - FRAME_HEADER_LEN,
+            },
+            _ => TransportError::TruncatedHeader {
+                expected: FRAME_HEADER_LEN,
+                got: buffered,
+            },
+        }
+    }
+}
+
+/// Pre-packaged implementation of [InternalTransport] using [tokio::net::TcpStream].
+/// Messages are length-prefixed frames, see [write_frame]
+pub struct TcpTransport {
+    stream: tokio::net::TcpStream,
+    frames: FrameReader,
+    writer: FrameWriter,
+}
+
+impl TcpTransport {
+    pub fn new(stream: tokio::net::TcpStream) -> Self {
+        Self {
+            stream,
+            frames: FrameReader::default(),
+            writer: FrameWriter::default(),
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    /// The underlying stream, once nothing more is expected to have been read into the frame
+    /// buffer, e.g. after a handshake
+    pub(crate) fn into_stream(self) -> tokio::net::TcpStream {
+        self.stream
+    }
+}
+
+#[async_trait]
+impl InternalTransport for TcpTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.writer.write_frame(&mut self.stream, b).await
     }
 
     async fn send_and_wait_for_response(
@@ -324,34 +3845,347 @@ impl InternalTransport for TcpTransport {
         self.receive(Some(timeout)).await
     }
 
+    async fn send_vectored(&mut self, parts: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.writer
+            .write_frame_vectored(&mut self.stream, parts)
+            .await
+    }
+
+    async fn send_many(&mut self, messages: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.writer.write_frames(&mut self.stream, messages).await
+    }
+
+    async fn send_vectored_and_wait_for_response(
+        &mut self,
+        parts: &[Bytes<'_>],
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send_vectored(parts).await?;
+        self.receive(Some(timeout)).await
+    }
+
     async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
-        use tokio::io::AsyncReadExt;
-        // 1024 * 8 = 8192 bits = 256 * u32s
-        let mut buf = [0u8; 1024];
-        let mut return_bytes = Vec::new();
+        self.frames.read_frame(&mut self.stream, timeout).await
+    }
+
+    fn configure(&mut self, config: &TransportConfig) {
+        self.frames.configure(config);
+        self.writer.configure(config);
+    }
+
+    /// The peer hasn't closed the connection, no send has failed part way, and there's no
+    /// unread data left over that would be mistaken for the next response
+    fn is_healthy(&self) -> bool {
+        if self.writer.is_broken() {
+            return false;
+        }
+        let mut buf = [0u8; 1];
+        match self.stream.try_read(&mut buf) {
+            Err(e) => e.kind() == std::io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        }
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.stream.peer_addr().ok()
+    }
+}
+
+/// Largest UDP datagram payload [UdpTransport] sends by default, which fits in a single
+/// packet on most paths without IP fragmentation
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1200;
+/// Largest payload a UDP datagram can carry, so a buffer this big never truncates one
+pub(crate) const MAX_UDP_PAYLOAD: usize = 65_507;
+
+/// Pre-packaged implementation of [InternalTransport] using [tokio::net::UdpSocket], for small,
+/// loss-tolerant calls such as telemetry pings and heartbeats. Each message is one datagram,
+/// with no retransmission, so a lost query or response shows as a receive timeout.
+/// Messages over [max_datagram_size] fail with [TransportError::PayloadTooLarge]
+pub struct UdpTransport {
+    socket: Arc<tokio::net::UdpSocket>,
+    peer: std::net::SocketAddr,
+    max_datagram_size: usize,
+    received: Option<OwnedBytes>,
+}
+
+impl UdpTransport {
+    /// Bind a local socket to send datagrams to [addr]
+    pub async fn connect(addr: std::net::SocketAddr) -> Result<Self, TransportError> {
+        let bind_addr: std::net::SocketAddr = if addr.is_ipv6() {
+            "[::]:0".parse().unwrap()
+        } else {
+            "0.0.0.0:0".parse().unwrap()
+        };
+        let socket = tokio::net::UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|e| TransportError::ConnectError(format!("{}", e)))?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            peer: addr,
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            received: None,
+        })
+    }
+
+    /// A transport replying to [peer] on a server's shared [socket], having already received
+    /// its query [datagram]
+    pub(crate) fn for_peer(
+        socket: Arc<tokio::net::UdpSocket>,
+        peer: std::net::SocketAddr,
+        datagram: OwnedBytes,
+    ) -> Self {
+        Self {
+            socket,
+            peer,
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            received: Some(datagram),
+        }
+    }
+
+    pub fn with_max_datagram_size(mut self, max_datagram_size: usize) -> Self {
+        self.max_datagram_size = max_datagram_size;
+        self
+    }
+
+    async fn receive_from_peer(&mut self) -> Result<OwnedBytes, TransportError> {
+        let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
         loop {
-            let read_fut = self.stream.read(&mut buf);
-            let result = match timeout {
-                Some(timeout_) => match tokio::time::timeout(timeout_, read_fut).await {
+            let (n, from) = self
+                .socket
+                .recv_from(&mut buf)
+                .await
+                .map_err(TransportError::io_receive)?;
+            // Anything else arriving on the socket isn't part of this exchange
+            if from == self.peer {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl InternalTransport for UdpTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        if b.len() > self.max_datagram_size {
+            return Err(TransportError::PayloadTooLarge(
+                b.len(),
+                self.max_datagram_size,
+            ));
+        }
+        self.socket
+            .send_to(b, self.peer)
+            .await
+            .map(|_| ())
+            .map_err(TransportError::io_send)
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        if let Some(datagram) = self.received.take() {
+            return Ok(datagram);
+        }
+        match timeout {
+            Some(timeout_) => {
+                match tokio::time::timeout(timeout_, self.receive_from_peer()).await {
                     Ok(r) => r,
-                    Err(_) => return Err(TransportError::ReceiveTimeout(timeout_)),
-                },
-                None => read_fut.await,
-            };
-            match result {
-                Ok(0) => {
-                    return Ok(return_bytes);
+                    Err(_) => Err(TransportError::ReceiveTimeout(timeout_)),
                 }
-                Ok(bytes_received) => {
-                    return_bytes.extend_from_slice(&buf[0..bytes_received]);
-                    if bytes_received < buf.len() {
-                        return Ok(return_bytes);
-                    }
-                }
-                Err(e) => {
-                    return Err(TransportError::io_receive(e));
-                }
-            };
+            }
+            None => self.receive_from_peer().await,
+        }
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        Some(self.peer)
+    }
+}
+
+/// In-process implementation of [InternalTransport] over [tokio::sync::mpsc] channels, for
+/// fast deterministic tests of rpc handlers, or a server and client embedded in one binary.
+/// Make a connected pair with [ChannelTransport::pair], or serve many with [channel_listener]
+pub struct ChannelTransport {
+    sender: tokio::sync::mpsc::Sender<OwnedBytes>,
+    receiver: tokio::sync::mpsc::Receiver<OwnedBytes>,
+}
+
+impl ChannelTransport {
+    /// Two transports connected to each other, each holding up to [buffer] unreceived messages
+    pub fn pair(buffer: usize) -> (Self, Self) {
+        let (a_sender, b_receiver) = tokio::sync::mpsc::channel(buffer);
+        let (b_sender, a_receiver) = tokio::sync::mpsc::channel(buffer);
+        (
+            Self {
+                sender: a_sender,
+                receiver: a_receiver,
+            },
+            Self {
+                sender: b_sender,
+                receiver: b_receiver,
+            },
+        )
+    }
+}
+
+#[async_trait]
+impl InternalTransport for ChannelTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.sender
+            .send(b.to_vec())
+            .await
+            .map_err(|_| TransportError::ConnectionReset("Channel peer dropped".to_string()))
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        let received = match timeout {
+            Some(timeout_) => match tokio::time::timeout(timeout_, self.receiver.recv()).await {
+                Ok(r) => r,
+                Err(_) => return Err(TransportError::ReceiveTimeout(timeout_)),
+            },
+            None => self.receiver.recv().await,
+        };
+        received.ok_or(TransportError::ConnectionClosed)
+    }
+}
+
+/// A [ChannelConnector] to open in-process connections with, and the [ChannelListener] that
+/// accepts them, e.g. for [crate::RpcServer::serve_channel]
+pub fn channel_listener(buffer: usize) -> (ChannelConnector, ChannelListener) {
+    let (sender, receiver) = tokio::sync::mpsc::channel(buffer);
+    (
+        ChannelConnector { sender, buffer },
+        ChannelListener { receiver },
+    )
+}
+
+/// The client end of [channel_listener]. Clone it to connect from several places
+#[derive(Clone)]
+pub struct ChannelConnector {
+    sender: tokio::sync::mpsc::Sender<ChannelTransport>,
+    buffer: usize,
+}
+
+impl ChannelConnector {
+    /// Open a new connection, handing the other end to the [ChannelListener]
+    pub async fn connect(&self) -> Result<ChannelTransport, TransportError> {
+        let (client, server) = ChannelTransport::pair(self.buffer);
+        self.sender
+            .send(server)
+            .await
+            .map_err(|_| TransportError::ConnectError("Channel listener dropped".to_string()))?;
+        Ok(client)
+    }
+}
+
+/// The server end of [channel_listener]
+pub struct ChannelListener {
+    receiver: tokio::sync::mpsc::Receiver<ChannelTransport>,
+}
+
+impl ChannelListener {
+    /// Wait for the next connection, or [None] once every [ChannelConnector] is dropped
+    pub async fn accept(&mut self) -> Option<ChannelTransport> {
+        self.receiver.recv().await
+    }
+}
+
+/// Pre-packaged implementation of [InternalTransport] using [tokio::net::UnixStream], for local
+/// daemons that shouldn't open a TCP port. Framing and timeouts behave as for [TcpTransport]
+#[cfg(unix)]
+pub struct UnixTransport {
+    stream: tokio::net::UnixStream,
+    frames: FrameReader,
+    writer: FrameWriter,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub fn new(stream: tokio::net::UnixStream) -> Self {
+        Self {
+            stream,
+            frames: FrameReader::default(),
+            writer: FrameWriter::default(),
+        }
+    }
+
+    pub async fn connect(path: impl AsRef<std::path::Path>) -> Result<Self, TransportError> {
+        tokio::net::UnixStream::connect(path)
+            .await
+            .map(Self::new)
+            .map_err(|e| TransportError::ConnectError(format!("{}", e)))
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl InternalTransport for UnixTransport {
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        self.writer.write_frame(&mut self.stream, b).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn send_vectored(&mut self, parts: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.writer
+            .write_frame_vectored(&mut self.stream, parts)
+            .await
+    }
+
+    async fn send_many(&mut self, messages: &[Bytes<'_>]) -> Result<(), TransportError> {
+        self.writer.write_frames(&mut self.stream, messages).await
+    }
+
+    async fn send_vectored_and_wait_for_response(
+        &mut self,
+        parts: &[Bytes<'_>],
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send_vectored(parts).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        self.frames.read_frame(&mut self.stream, timeout).await
+    }
+
+    fn configure(&mut self, config: &TransportConfig) {
+        self.frames.configure(config);
+        self.writer.configure(config);
+    }
+
+    /// As for [TcpTransport]
+    fn is_healthy(&self) -> bool {
+        if self.writer.is_broken() {
+            return false;
+        }
+        let mut buf = [0u8; 1];
+        match self.stream.try_read(&mut buf) {
+            Err(e) => e.kind() == std::io::ErrorKind::WouldBlock,
+            Ok(_) => false,
         }
     }
 }
@@ -0,0 +1,276 @@
+use crate::listener::{Accepting, Listener, TcpListener};
+use crate::transport::{
+    within_connect_timeout, within_send_timeout, InternalTransport, TransportConfig, TransportError,
+};
+use crate::{Bytes, OwnedBytes};
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Implementation of [InternalTransport] over a WebSocket, for browser and other WS clients.
+/// Each package is sent as one binary message, so no extra framing is needed.
+/// Pings are answered and skipped over while receiving, and text messages are rejected.
+/// The WebSocket stream itself only refuses a message over
+/// [TransportConfig::max_inbound_message_size] before reading it in if it was made with it,
+/// e.g. by [WebSocketTransport::connect_with_config] or [WebSocketListener::with_config].
+/// Otherwise it's read and then refused
+pub struct WebSocketTransport<S> {
+    stream: WebSocketStream<S>,
+    max_receive_size: Option<usize>,
+    send_timeout: Option<Duration>,
+}
+
+impl<S> WebSocketTransport<S> {
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        Self {
+            stream,
+            max_receive_size: None,
+            send_timeout: None,
+        }
+    }
+}
+
+/// The WebSocket settings for [config], capping messages and frames received at its
+/// [TransportConfig::max_inbound_message_size]
+fn websocket_config(config: &TransportConfig) -> WebSocketConfig {
+    WebSocketConfig::default()
+        .max_message_size(config.max_inbound_message_size)
+        .max_frame_size(config.max_inbound_message_size)
+}
+
+impl WebSocketTransport<MaybeTlsStream<tokio::net::TcpStream>> {
+    /// Connect to a `ws://` [url] and complete the WebSocket handshake
+    pub async fn connect(url: &str) -> Result<Self, TransportError> {
+        Self::connect_with_config(url, &TransportConfig::default()).await
+    }
+
+    /// As [connect], with the message size limit of [config], and within its
+    /// [TransportConfig::connect_timeout]
+    pub async fn connect_with_config(
+        url: &str,
+        config: &TransportConfig,
+    ) -> Result<Self, TransportError> {
+        let (stream, _response) = within_connect_timeout(config.connect_timeout, async {
+            tokio_tungstenite::connect_async_with_config(url, Some(websocket_config(config)), false)
+                .await
+                .map_err(|e| TransportError::ConnectError(format!("{}", e)))
+        })
+        .await?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl WebSocketTransport<tokio::net::TcpStream> {
+    /// Complete the server side of the WebSocket handshake on an accepted [tcp_stream]
+    pub async fn accept(tcp_stream: tokio::net::TcpStream) -> Result<Self, TransportError> {
+        Self::accept_with_config(tcp_stream, &TransportConfig::default()).await
+    }
+
+    /// As [accept], with the message size limit of [config]
+    pub async fn accept_with_config(
+        tcp_stream: tokio::net::TcpStream,
+        config: &TransportConfig,
+    ) -> Result<Self, TransportError> {
+        let stream =
+            tokio_tungstenite::accept_async_with_config(tcp_stream, Some(websocket_config(config)))
+                .await
+                .map_err(|e| TransportError::ConnectError(format!("WebSocket handshake: {}", e)))?;
+        Ok(Self::new(stream))
+    }
+}
+
+/// [Listener] for [WebSocketTransport]s, completing the WebSocket handshake on each
+/// connection. A failed handshake fails that connection alone
+pub struct WebSocketListener {
+    listener: TcpListener,
+    config: TransportConfig,
+}
+
+impl WebSocketListener {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            config: TransportConfig::default(),
+        }
+    }
+
+    pub async fn bind(addr: impl tokio::net::ToSocketAddrs) -> Result<Self, TransportError> {
+        Ok(Self::new(TcpListener::bind(addr).await?))
+    }
+
+    /// Accept connections with the message size limit of [config], see
+    /// [WebSocketTransport::accept_with_config], e.g. that of the server serving them
+    pub fn with_config(mut self, config: &TransportConfig) -> Self {
+        self.config = config.clone();
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl Listener for WebSocketListener {
+    type Transport = WebSocketTransport<tokio::net::TcpStream>;
+
+    async fn accept(&mut self) -> Option<Result<Accepting<Self::Transport>, TransportError>> {
+        let tcp_stream = self.listener.accept_tcp().await;
+        let config = self.config.clone();
+        Some(tcp_stream.map(|tcp_stream| -> Accepting<Self::Transport> {
+            Box::pin(
+                async move { WebSocketTransport::accept_with_config(tcp_stream, &config).await },
+            )
+        }))
+    }
+}
+
+impl<S> WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn receive_binary(&mut self) -> Result<OwnedBytes, TransportError> {
+        loop {
+            match self.stream.next().await {
+                None => return Err(TransportError::ConnectionClosed),
+                Some(Ok(Message::Binary(bytes))) => {
+                    return match self.max_receive_size {
+                        Some(max_size) if bytes.len() > max_size => {
+                            Err(TransportError::MessageTooLarge(bytes.len(), max_size))
+                        }
+                        _ => Ok(bytes.to_vec()),
+                    }
+                }
+                Some(Ok(Message::Close(_))) => return Err(TransportError::ConnectionClosed),
+                Some(Ok(Message::Text(_))) => {
+                    return Err(TransportError::MalformedFrame(
+                        "Expected a binary WebSocket message, got text".to_string(),
+                    ))
+                }
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => (),
+                Some(Err(WsError::ConnectionClosed | WsError::AlreadyClosed)) => {
+                    return Err(TransportError::ConnectionClosed)
+                }
+                Some(Err(WsError::Io(e))) => return Err(TransportError::io_receive(e)),
+                Some(Err(WsError::Capacity(CapacityError::MessageTooLong { size, max_size }))) => {
+                    return Err(TransportError::MessageTooLarge(size, max_size))
+                }
+                Some(Err(e)) => return Err(TransportError::ReceiveError(format!("{}", e))),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S> InternalTransport for WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn send(&mut self, b: Bytes<'_>) -> Result<(), TransportError> {
+        let send = async {
+            self.stream
+                .send(Message::binary(b.to_vec()))
+                .await
+                .map_err(|e| match e {
+                    WsError::Io(e) => TransportError::io_send(e),
+                    e => TransportError::SendError(format!("{}", e)),
+                })
+        };
+        within_send_timeout(self.send_timeout, send).await
+    }
+
+    async fn send_and_wait_for_response(
+        &mut self,
+        b: Bytes<'_>,
+        timeout: Duration,
+    ) -> Result<OwnedBytes, TransportError> {
+        self.send(b).await?;
+        self.receive(Some(timeout)).await
+    }
+
+    async fn receive(&mut self, timeout: Option<Duration>) -> Result<OwnedBytes, TransportError> {
+        match timeout {
+            Some(timeout_) => match tokio::time::timeout(timeout_, self.receive_binary()).await {
+                Ok(r) => r,
+                Err(_) => Err(TransportError::ReceiveTimeout(timeout_)),
+            },
+            None => self.receive_binary().await,
+        }
+    }
+
+    /// Messages are refused over [TransportConfig::max_inbound_message_size] once read, as the
+    /// stream's already made, and sent within [TransportConfig::send_timeout]
+    fn configure(&mut self, config: &TransportConfig) {
+        self.max_receive_size = config.max_inbound_message_size;
+        self.send_timeout = config.send_timeout;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::RpcClient;
+    use crate::server::RpcServer;
+    use crate::tests::{make_hello_world_rpc, make_hello_world_rpc_impl, HelloWorldState};
+    use crate::transport::{Transport, TransportConfig};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn websocket_server_round_trip() {
+        let state = Arc::new(Mutex::new(HelloWorldState { i: 3 }));
+        let mut server = RpcServer::new(state, TransportConfig::default());
+        server.add_rpc(Box::new(make_hello_world_rpc_impl()));
+        let addr = "127.0.0.1:5561";
+
+        let mut rpc_results = None;
+        let mut client_call_task = tokio::spawn(async move {
+            let ws_transport = WebSocketTransport::connect("ws://127.0.0.1:5561")
+                .await
+                .unwrap();
+            let mut transport = Transport::new(ws_transport, TransportConfig::default());
+            RpcClient::new(make_hello_world_rpc())
+                .call("browser".into(), &mut transport)
+                .await
+        });
+
+        while rpc_results.is_none() {
+            tokio::select! {
+                _ = server.serve_websocket(addr) => {},
+                client_output = &mut client_call_task => {rpc_results = Some(client_output)},
+            }
+        }
+
+        let hello = rpc_results.unwrap().unwrap();
+        assert_eq!("Hello world: 3:\"browser\"", hello.unwrap());
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_refused() {
+        let config = TransportConfig {
+            max_inbound_message_size: Some(64),
+            ..Default::default()
+        };
+        let mut listener = WebSocketListener::new(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .with_config(&config);
+        let url = format!("ws://{}", listener.listener.local_addr().unwrap());
+        let client = async {
+            let mut transport = WebSocketTransport::connect(&url).await.unwrap();
+            transport.send(&[0; 32]).await.unwrap();
+            transport.send(&[0; 1000]).await.unwrap();
+            transport
+        };
+        let server = async {
+            let accepting = listener.accept().await.unwrap().unwrap();
+            let mut transport = accepting.await.unwrap();
+            (transport.receive(None).await, transport.receive(None).await)
+        };
+
+        let (_client, (small, large)) = tokio::join!(client, server);
+        assert_eq!(32, small.unwrap().len());
+        assert!(matches!(
+            large,
+            Err(TransportError::MessageTooLarge(1000, 64))
+        ));
+    }
+}